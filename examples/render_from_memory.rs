@@ -0,0 +1,57 @@
+//! Render a topic held in memory to one self-contained HTML page and print it:
+//! `cargo run --example render_from_memory`.
+//!
+//! The library reads the topic from a file, like the CLI, so the JSON goes through a scratch directory.
+
+use std::ffi::OsString;
+
+use clap::Parser as _;
+use discourse_topic_render::CliArgs;
+
+pub const TOPIC_JSON: &str = r#"{
+  "id": 123,
+  "title": "Rendering from memory",
+  "post_stream": {
+    "posts": [
+      { "post_number": 1, "username": "alice", "cooked": "<p>The whole topic fits in a string.</p>" },
+      { "post_number": 2, "username": "bob", "cooked": "<p>And so does the page.</p>", "reply_to_post_number": 1 }
+    ]
+  }
+}"#;
+
+/// `topic_json` rendered with the builtin theme as a single page. Without avatars, images or site CSS to
+/// download, nothing goes over the network.
+pub async fn render(topic_json: &str) -> anyhow::Result<String> {
+    let dir = tempfile::tempdir()?;
+    let input = dir.path().join("topic.json");
+    let out = dir.path().join("topic.html");
+    std::fs::write(&input, topic_json)?;
+
+    let mut argv: Vec<OsString> = [
+        "discourse-topic-render",
+        "--base-url",
+        "https://forum.example.com",
+        "--builtin-css",
+        "--mode",
+        "single",
+        "--progress",
+        "never",
+        "--no-stamp",
+    ]
+    .map(OsString::from)
+    .into();
+    argv.extend([
+        "--input".into(),
+        input.into_os_string(),
+        "--out".into(),
+        out.clone().into_os_string(),
+    ]);
+    discourse_topic_render::run(CliArgs::try_parse_from(argv)?).await?;
+    Ok(std::fs::read_to_string(out)?)
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    print!("{}", render(TOPIC_JSON).await?);
+    Ok(())
+}
@@ -1,14 +1,19 @@
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use anyhow::Context as _;
 use base64::Engine as _;
+use serde::Serialize;
 use url::Url;
 
-use crate::fetcher::Fetcher;
+use crate::fetcher::{AssetTooLarge, Fetcher, HttpStatus, LargeAssetSkipped, OverBudget};
+use crate::output;
 use crate::progress::{DownloadKind, Progress};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum AssetKind {
     Avatar,
     Image,
@@ -19,6 +24,8 @@ pub enum AssetKind {
 #[derive(Debug, Clone)]
 pub enum AssetSource {
     Remote(Url),
+    /// Remote URLs tried in order while the server answers 404, e.g. an avatar at smaller and smaller sizes.
+    FirstFound(Vec<Url>),
     Local(PathBuf),
 }
 
@@ -28,21 +35,130 @@ pub struct AssetRequest {
     pub source: AssetSource,
 }
 
+impl AssetRequest {
+    /// Requests with the same key share one download.
+    pub fn key(&self) -> String {
+        request_key(self)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum OutputMode {
     Dir,
     Single,
 }
 
+/// A cached failure. Oversized assets stay typed so callers can link to the original instead.
+#[derive(Debug, Clone)]
+enum StoredError {
+    TooLarge(AssetTooLarge),
+    /// Skipped by `--skip-large-assets` and not an image, so there is no placeholder for it.
+    Skipped(Url, u64),
+    /// Left out by the download budget and not an image.
+    OverBudget(OverBudget),
+    Other(String),
+}
+
+impl From<anyhow::Error> for StoredError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast_ref::<AssetTooLarge>() {
+            Some(too_large) => StoredError::TooLarge(too_large.clone()),
+            None => StoredError::Other(format!("{:#}", e)),
+        }
+    }
+}
+
+/// A stored asset: what the page refers to it by, and what the asset manifest says about it.
+#[derive(Debug, Clone)]
+struct Stored {
+    local: String,
+    source: String,
+    kind: AssetKind,
+    hash: blake3::Hash,
+    bytes: u64,
+    mime: String,
+    generated: bool,
+}
+
+/// One asset in `topic-{id}.assets-manifest.json`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestEntry {
+    /// The URL it was downloaded from (for an avatar tried at several sizes, the one the server had), or the local
+    /// file it was read from.
+    pub source: String,
+    pub kind: AssetKind,
+    /// blake3 of the content, as in the file name of a stored asset.
+    pub hash: String,
+    pub bytes: u64,
+    pub mime: String,
+    /// Relative to the output directory.
+    pub path: String,
+    /// A letter avatar or size placeholder made in place of `source`, or the bundled stylesheet; not downloaded.
+    pub generated: bool,
+}
+
+impl ManifestEntry {
+    /// A file the render wrote next to the stored assets itself: the bundled stylesheet or an injected script.
+    pub fn written(
+        source: String,
+        path: String,
+        mime: &str,
+        content: &[u8],
+        generated: bool,
+    ) -> Self {
+        Self {
+            source,
+            kind: AssetKind::Other,
+            hash: blake3::hash(content).to_hex().to_string(),
+            bytes: content.len() as u64,
+            mime: mime.to_string(),
+            path,
+            generated,
+        }
+    }
+}
+
+/// Distinct assets stored and their total size.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AssetStats {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+tokio::task_local! {
+    /// Time the current task has spent in [`AssetStore::get`] and [`AssetStore::fetch_remote_text`], when it is
+    /// being measured by [`with_download_time`].
+    static DOWNLOAD_TIME: Cell<Duration>;
+}
+
+/// Run `future`, also returning how long it waited on the store for downloads (or for another task's download of
+/// the same asset).
+pub async fn with_download_time<F: std::future::Future>(future: F) -> (F::Output, Duration) {
+    DOWNLOAD_TIME
+        .scope(Cell::new(Duration::ZERO), async {
+            let output = future.await;
+            (output, DOWNLOAD_TIME.with(Cell::get))
+        })
+        .await
+}
+
+fn add_download_time(started: Instant) {
+    let _ = DOWNLOAD_TIME.try_with(|time| time.set(time.get() + started.elapsed()));
+}
+
+type EntryCell = std::sync::Arc<tokio::sync::OnceCell<Result<Stored, StoredError>>>;
+/// A downloaded page or stylesheet and its `Content-Type`, or the error message.
+type TextCell = std::sync::Arc<tokio::sync::OnceCell<Result<(String, Option<String>), String>>>;
+
 pub struct AssetStore {
     mode: OutputMode,
     out_dir: PathBuf,
     assets_dir_name: String,
     fetcher: Fetcher,
     progress: Option<std::sync::Arc<Progress>>,
-    entries: tokio::sync::Mutex<
-        HashMap<String, std::sync::Arc<tokio::sync::OnceCell<Result<String, String>>>>,
-    >,
+    entries: tokio::sync::Mutex<HashMap<String, EntryCell>>,
+    /// Text downloads by URL without its fragment, so a stylesheet linked twice is fetched once.
+    text_entries: tokio::sync::Mutex<HashMap<String, TextCell>>,
 }
 
 impl AssetStore {
@@ -59,6 +175,7 @@ impl AssetStore {
             fetcher,
             progress,
             entries: tokio::sync::Mutex::new(HashMap::new()),
+            text_entries: tokio::sync::Mutex::new(HashMap::new()),
         }
     }
 
@@ -74,10 +191,12 @@ impl AssetStore {
             fetcher,
             progress,
             entries: tokio::sync::Mutex::new(HashMap::new()),
+            text_entries: tokio::sync::Mutex::new(HashMap::new()),
         }
     }
 
     pub async fn get(&self, request: AssetRequest) -> anyhow::Result<String> {
+        let started = Instant::now();
         let kind = request.kind;
         let key = request_key(&request);
         let (cell, is_unique) = {
@@ -100,22 +219,131 @@ impl AssetStore {
             .get_or_init(|| async {
                 match self.fetch_and_store(&request).await {
                     Ok(v) => Ok(v),
-                    Err(e) => Err(format!("{:#}", e)),
+                    // A missing or bogus avatar is never worth failing the render over.
+                    Err(e) if matches!(kind, AssetKind::Avatar) => {
+                        tracing::warn!(error = %format!("{:#}", e), "avatar unavailable; using a letter avatar");
+                        if let Some(p) = &self.progress {
+                            p.avatar_fallback();
+                        }
+                        self.store_letter_avatar(&request).map_err(StoredError::from)
+                    }
+                    Err(e) if e.is::<OverBudget>() => {
+                        let over = e.downcast::<OverBudget>().expect("checked above");
+                        tracing::warn!(url = %over.url, limit = over.limit, "download budget spent; asset left out");
+                        if let Some(p) = &self.progress {
+                            p.asset_over_budget(&over.url);
+                        }
+                        match kind {
+                            AssetKind::Image => self
+                                .store_placeholder(
+                                    &request,
+                                    &format!("not downloaded ({} reached)", over.limit),
+                                )
+                                .map_err(StoredError::from),
+                            _ => Err(StoredError::OverBudget(over)),
+                        }
+                    }
+                    Err(e) => match e.downcast_ref::<LargeAssetSkipped>() {
+                        // Fonts and other `url()`s keep pointing at the original rather than at an SVG.
+                        Some(skipped) if !matches!(kind, AssetKind::Image) => {
+                            tracing::warn!(url = %skipped.url, bytes = skipped.bytes, "large asset skipped; linking to the original");
+                            if let Some(p) = &self.progress {
+                                p.large_asset_linked(&skipped.url, skipped.bytes);
+                            }
+                            Err(StoredError::Skipped(skipped.url.clone(), skipped.bytes))
+                        }
+                        Some(skipped) => self
+                            .store_placeholder(
+                                &request,
+                                &format!(
+                                    "large file skipped ({})",
+                                    crate::report::human_bytes(skipped.bytes)
+                                ),
+                            )
+                            .map_err(StoredError::from),
+                        None => Err(StoredError::from(e)),
+                    },
                 }
             })
             .await;
+        add_download_time(started);
 
         match stored {
-            Ok(v) => Ok(v.clone()),
-            Err(e) => Err(anyhow::anyhow!("{e}")),
+            Ok(v) => Ok(v.local.clone()),
+            Err(StoredError::TooLarge(e)) => Err(e.clone().into()),
+            Err(StoredError::Skipped(url, bytes)) => Err(LargeAssetSkipped {
+                url: url.clone(),
+                bytes: *bytes,
+            }
+            .into()),
+            Err(StoredError::OverBudget(e)) => Err(e.clone().into()),
+            Err(StoredError::Other(e)) => Err(anyhow::anyhow!("{e}")),
         }
     }
 
-    pub async fn fetch_remote_text(&self, url: Url, kind: DownloadKind) -> anyhow::Result<String> {
-        let (bytes, _headers) = self.fetcher.get_bytes(url.clone(), kind).await?;
-        let text = String::from_utf8(bytes.to_vec())
-            .with_context(|| format!("remote text at {} is not valid utf-8", url))?;
-        Ok(text)
+    /// Download a text resource, as `user_agent` when given; returns the text and the server's `Content-Type`.
+    pub async fn fetch_remote_text(
+        &self,
+        url: Url,
+        kind: DownloadKind,
+        user_agent: Option<&str>,
+    ) -> anyhow::Result<(String, Option<String>)> {
+        let started = Instant::now();
+        let mut key = url.clone();
+        key.set_fragment(None);
+        let cell = {
+            let mut entries = self.text_entries.lock().await;
+            match entries.entry(key.into()) {
+                std::collections::hash_map::Entry::Occupied(e) => {
+                    if let Some(p) = &self.progress {
+                        p.text_cache_hit();
+                    }
+                    e.get().clone()
+                }
+                std::collections::hash_map::Entry::Vacant(e) => e
+                    .insert(std::sync::Arc::new(tokio::sync::OnceCell::new()))
+                    .clone(),
+            }
+        };
+        let stored = cell
+            .get_or_init(|| async {
+                self.download_text(url, kind, user_agent)
+                    .await
+                    .map_err(|e| format!("{:#}", e))
+            })
+            .await;
+        add_download_time(started);
+        stored.clone().map_err(|e| anyhow::anyhow!("{e}"))
+    }
+
+    async fn download_text(
+        &self,
+        url: Url,
+        kind: DownloadKind,
+        user_agent: Option<&str>,
+    ) -> anyhow::Result<(String, Option<String>)> {
+        let fetched = self
+            .fetcher
+            .get_bytes_as(url.clone(), kind, user_agent)
+            .await?;
+        let content_type = content_type(&fetched.headers);
+        let text = decode_text(&fetched.bytes, content_type.as_deref(), kind);
+        Ok((text, content_type))
+    }
+
+    /// The preflight size of what `request` downloads (its first URL), when `--preflight-above-bytes` is on and
+    /// the server sends a `Content-Length`.
+    pub async fn expected_size(&self, request: &AssetRequest) -> Option<u64> {
+        let url = match &request.source {
+            AssetSource::Remote(url) => url,
+            AssetSource::FirstFound(urls) => urls.first()?,
+            AssetSource::Local(_) => return None,
+        };
+        self.fetcher
+            .expected_size(url, DownloadKind::Asset(request.kind))
+            .await
+            .ok()
+            .flatten()
     }
 
     pub fn output_mode(&self) -> OutputMode {
@@ -130,54 +358,436 @@ impl AssetStore {
         self.progress.as_deref()
     }
 
-    async fn fetch_and_store(&self, request: &AssetRequest) -> anyhow::Result<String> {
-        let (bytes, content_type_hint) = match &request.source {
+    /// Every asset stored so far, once per source and path, sorted by path. Dir mode; in single mode the paths
+    /// would be the `data:` URIs themselves.
+    pub async fn manifest(&self) -> Vec<ManifestEntry> {
+        let entries = self.entries.lock().await;
+        let mut manifest: Vec<ManifestEntry> = entries
+            .values()
+            .filter_map(|cell| cell.get()?.as_ref().ok())
+            .map(|stored| ManifestEntry {
+                source: stored.source.clone(),
+                kind: stored.kind,
+                hash: stored.hash.to_hex().to_string(),
+                bytes: stored.bytes,
+                mime: stored.mime.clone(),
+                path: stored.local.clone(),
+                generated: stored.generated,
+            })
+            .collect();
+        manifest.sort_by(|a, b| (&a.path, &a.source).cmp(&(&b.path, &b.source)));
+        manifest.dedup_by(|a, b| (&a.path, &a.source) == (&b.path, &b.source));
+        manifest
+    }
+
+    /// The assets stored so far, each file (or `data:` URI) counted once however many requests led to it.
+    pub async fn stats(&self) -> AssetStats {
+        let entries = self.entries.lock().await;
+        let mut seen = std::collections::HashSet::new();
+        let mut stats = AssetStats::default();
+        for stored in entries
+            .values()
+            .filter_map(|cell| cell.get()?.as_ref().ok())
+        {
+            if seen.insert(stored.local.as_str()) {
+                stats.count += 1;
+                stats.bytes += stored.bytes;
+            }
+        }
+        stats
+    }
+
+    async fn fetch_and_store(&self, request: &AssetRequest) -> anyhow::Result<Stored> {
+        if let (OutputMode::Dir, AssetSource::Remote(url)) = (self.mode, &request.source) {
+            return self.download_to_dir(url, request).await;
+        }
+
+        // The reservation keeps the body counted against --max-memory-mb until it is encoded or written.
+        let (bytes, content_type_hint, _memory) = match &request.source {
             AssetSource::Remote(url) => {
-                let (bytes, headers) = self
+                let fetched = self
                     .fetcher
                     .get_bytes(url.clone(), DownloadKind::Asset(request.kind))
                     .await?;
-                let ct = headers
-                    .get(reqwest::header::CONTENT_TYPE)
-                    .and_then(|v| v.to_str().ok())
-                    .map(|s| s.to_string());
-                (bytes.to_vec(), ct)
+                let ct = content_type(&fetched.headers);
+                check_downloaded(
+                    request.kind,
+                    url,
+                    &fetched.final_url,
+                    &fetched.bytes,
+                    ct.as_deref(),
+                )?;
+                (Vec::from(fetched.bytes), ct, Some(fetched.memory))
+            }
+            AssetSource::FirstFound(urls) => {
+                return self.fetch_first_found(request.kind, urls).await;
             }
             AssetSource::Local(path) => {
                 let bytes = std::fs::read(path)
                     .with_context(|| format!("read local asset {}", path.display()))?;
-                (bytes, None)
+                (bytes, None, None)
             }
         };
 
         let (mime, ext) = sniff_mime_and_ext(&bytes, content_type_hint.as_deref(), request);
+        self.store_bytes(request.kind, &bytes, &mime, &ext, request_source(request))
+    }
 
-        match self.mode {
-            OutputMode::Single => {
-                let b64 = base64::engine::general_purpose::STANDARD.encode(&bytes);
-                Ok(format!("data:{};base64,{}", mime, b64))
+    /// The first of `urls` the server has; any error other than a 404 ends the search. A 404 on every URL returns
+    /// the first one's.
+    async fn fetch_first_found(&self, kind: AssetKind, urls: &[Url]) -> anyhow::Result<Stored> {
+        let mut not_found = None;
+        for url in urls {
+            let request = AssetRequest {
+                kind,
+                source: AssetSource::Remote(url.clone()),
+            };
+            // Boxed: the call recurses through `fetch_and_store`.
+            match Box::pin(self.fetch_and_store(&request)).await {
+                Ok(local) => {
+                    if not_found.is_some() {
+                        tracing::info!(requested = %urls[0], used = %url, "not found at the requested size; using a smaller one");
+                    }
+                    return Ok(local);
+                }
+                Err(e) if is_not_found(&e) => {
+                    not_found.get_or_insert(e);
+                }
+                Err(e) => return Err(e),
             }
-            OutputMode::Dir => {
-                let rel_path = write_asset_file(
-                    &self.out_dir,
-                    &self.assets_dir_name,
-                    request.kind,
-                    &bytes,
-                    &ext,
-                )?;
-                Ok(rel_path)
+        }
+        Err(not_found.unwrap_or_else(|| anyhow::anyhow!("no URL to fetch")))
+    }
+
+    /// Dir mode: stream the download into the assets dir, then rename it to its content hash.
+    async fn download_to_dir(&self, url: &Url, request: &AssetRequest) -> anyhow::Result<Stored> {
+        let dir = self
+            .out_dir
+            .join(&self.assets_dir_name)
+            .join(kind_subdir(request.kind));
+        output::create_dir_all(&dir)?;
+        let tmp = dir.join(format!(
+            ".download-{}-{}.part",
+            std::process::id(),
+            NEXT_DOWNLOAD.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+
+        let fetched = self
+            .fetcher
+            .download_to_file(url.clone(), DownloadKind::Asset(request.kind), &tmp)
+            .await?;
+        let ct = content_type(&fetched.headers);
+        let stored = check_downloaded(
+            request.kind,
+            url,
+            &fetched.final_url,
+            &fetched.head,
+            ct.as_deref(),
+        )
+        .and_then(|()| {
+            let (mime, ext) = sniff_mime_and_ext(&fetched.head, ct.as_deref(), request);
+            let rel = asset_rel_path(
+                &self.assets_dir_name,
+                request.kind,
+                &fetched.hash.to_hex(),
+                &ext,
+            );
+            let abs = self.out_dir.join(&rel);
+            if abs.exists() {
+                std::fs::remove_file(&tmp).ok();
+            } else {
+                std::fs::rename(&tmp, &abs).map_err(|e| output::classify("write", &abs, e))?;
+            }
+            let bytes = std::fs::metadata(&abs).map(|m| m.len()).unwrap_or(0);
+            Ok(Stored {
+                local: rel,
+                source: url.to_string(),
+                kind: request.kind,
+                hash: fetched.hash,
+                bytes,
+                mime,
+                generated: false,
+            })
+        });
+        if stored.is_err() {
+            std::fs::remove_file(&tmp).ok();
+        }
+        stored
+    }
+
+    /// Generated SVG with the first letter of the username in the avatar URL.
+    fn store_letter_avatar(&self, request: &AssetRequest) -> anyhow::Result<Stored> {
+        let name = match &request.source {
+            AssetSource::Remote(url) => avatar_username(url),
+            AssetSource::FirstFound(urls) => urls.first().map(avatar_username).unwrap_or_default(),
+            AssetSource::Local(path) => path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        };
+        let svg = letter_avatar_svg(&name);
+        let stored = self.store_bytes(
+            AssetKind::Avatar,
+            svg.as_bytes(),
+            "image/svg+xml",
+            "svg",
+            request_source(request),
+        )?;
+        Ok(Stored {
+            generated: true,
+            ..stored
+        })
+    }
+
+    /// [`placeholder_svg`] in place of an image that was not downloaded.
+    fn store_placeholder(&self, request: &AssetRequest, label: &str) -> anyhow::Result<Stored> {
+        let svg = placeholder_svg(label);
+        let stored = self.store_bytes(
+            request.kind,
+            svg.as_bytes(),
+            "image/svg+xml",
+            "svg",
+            request_source(request),
+        )?;
+        Ok(Stored {
+            generated: true,
+            ..stored
+        })
+    }
+
+    fn store_bytes(
+        &self,
+        kind: AssetKind,
+        bytes: &[u8],
+        mime: &str,
+        ext: &str,
+        source: String,
+    ) -> anyhow::Result<Stored> {
+        let hash = blake3::hash(bytes);
+        let local = match self.mode {
+            OutputMode::Single => {
+                let b64 = base64::engine::general_purpose::STANDARD.encode(bytes);
+                format!("data:{};base64,{}", mime, b64)
             }
+            OutputMode::Dir => write_asset_file(
+                &self.out_dir,
+                &self.assets_dir_name,
+                kind,
+                bytes,
+                ext,
+                &hash,
+            )?,
+        };
+        Ok(Stored {
+            local,
+            source,
+            kind,
+            hash,
+            bytes: bytes.len() as u64,
+            mime: mime.to_string(),
+            generated: false,
+        })
+    }
+}
+
+/// Numbers temp files of concurrent downloads.
+static NEXT_DOWNLOAD: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+fn content_type(headers: &reqwest::header::HeaderMap) -> Option<String> {
+    headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string())
+}
+
+/// A downloaded page or stylesheet as text. The encoding is the `charset` of its `Content-Type`, else the one it
+/// declares itself (`@charset` / `<meta charset>`), else UTF-8; a byte order mark overrides all three, as in
+/// browsers. Bytes that don't decode become U+FFFD rather than failing the render.
+fn decode_text(bytes: &[u8], content_type: Option<&str>, kind: DownloadKind) -> String {
+    let encoding = content_type
+        .and_then(header_charset)
+        .or_else(|| match kind {
+            DownloadKind::Css => css_charset_rule(bytes).and_then(|(label, _)| {
+                encoding_rs::Encoding::for_label(label).map(|e| e.output_encoding())
+            }),
+            DownloadKind::Html => META_CHARSET_RE
+                .captures(&bytes[..bytes.len().min(1024)])
+                .and_then(|caps| encoding_rs::Encoding::for_label(&caps[1]))
+                .map(|e| e.output_encoding()),
+            DownloadKind::Asset(_) => None,
+        })
+        .unwrap_or(encoding_rs::UTF_8);
+    let mut text = encoding.decode(bytes).0.into_owned();
+    // The text is written out as UTF-8, so a rule naming the old encoding would now mislead the browser.
+    if matches!(kind, DownloadKind::Css)
+        && let Some((label, len)) = css_charset_rule(text.as_bytes())
+        && encoding_rs::Encoding::for_label(label) != Some(encoding_rs::UTF_8)
+    {
+        text.drain(..len);
+    }
+    text
+}
+
+/// The encoding named by the `charset` parameter of a `Content-Type`, if it is one encoding_rs knows.
+fn header_charset(content_type: &str) -> Option<&'static encoding_rs::Encoding> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("charset") {
+            return None;
         }
+        encoding_rs::Encoding::for_label(value.trim().trim_matches(['"', '\'']).as_bytes())
+    })
+}
+
+/// The label and byte length of a leading `@charset "...";` rule. Only that exact spelling counts, per CSS Syntax.
+fn css_charset_rule(bytes: &[u8]) -> Option<(&[u8], usize)> {
+    let rest = bytes.strip_prefix(b"@charset \"")?;
+    let end = rest.iter().take(1024).position(|&b| b == b'"')?;
+    (rest.get(end + 1) == Some(&b';')).then(|| (&rest[..end], "@charset \"".len() + end + 2))
+}
+
+/// `<meta charset=...>`, or the `charset=` in a `<meta http-equiv="Content-Type" content=...>`.
+static META_CHARSET_RE: std::sync::LazyLock<regex::bytes::Regex> = std::sync::LazyLock::new(|| {
+    regex::bytes::Regex::new(r#"(?i)<meta\s[^>]*?charset\s*=\s*["']?([-\w:.]+)"#)
+        .expect("meta charset regex")
+});
+
+/// Refuse avatars that aren't images and images that are HTML pages; `head` is (the start of) the body.
+fn check_downloaded(
+    kind: AssetKind,
+    url: &Url,
+    final_url: &Url,
+    head: &[u8],
+    content_type: Option<&str>,
+) -> anyhow::Result<()> {
+    if matches!(kind, AssetKind::Avatar) && !is_image(head, content_type) {
+        anyhow::bail!(
+            "avatar {} (served from {}) is not an image (content-type {})",
+            url,
+            final_url,
+            content_type.unwrap_or("none")
+        );
+    }
+    // CDNs sometimes answer pruned uploads with a 200 "not found" page.
+    if matches!(kind, AssetKind::Image) && looks_like_html(head, content_type) {
+        anyhow::bail!(
+            "image {} (served from {}) is an HTML page, not an image (content-type {})",
+            url,
+            final_url,
+            content_type.unwrap_or("none")
+        );
+    }
+    Ok(())
+}
+
+/// Stand-in for an image skipped by `--skip-large-assets` or left out by the download budget, saying why.
+fn placeholder_svg(label: &str) -> String {
+    format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="320" height="80" viewBox="0 0 320 80">"#,
+            r#"<rect width="320" height="80" fill="lightgray"/>"#,
+            r#"<text x="160" y="40" dy=".35em" text-anchor="middle" font-family="sans-serif" font-size="16" fill="dimgray">{}</text>"#,
+            "</svg>"
+        ),
+        crate::xml::escape_xml(label)
+    )
+}
+
+/// Whether `bytes` look like an image: by magic bytes, or an SVG document / `image/*` content type.
+fn is_image(bytes: &[u8], content_type: Option<&str>) -> bool {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n")
+        || bytes.starts_with(b"\xff\xd8\xff")
+        || bytes.starts_with(b"GIF87a")
+        || bytes.starts_with(b"GIF89a")
+        || (bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP"))
+        || bytes.get(4..12) == Some(b"ftypavif")
+        || bytes.starts_with(b"\x00\x00\x01\x00")
+    {
+        return true;
+    }
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(512)]).to_ascii_lowercase();
+    let head = head.trim_start();
+    if head.starts_with("<svg") || (head.starts_with("<?xml") && head.contains("<svg")) {
+        return true;
+    }
+    // Unknown binary formats are trusted to the server's content type, markup is not.
+    let ct = content_type
+        .and_then(|ct| ct.split(';').next())
+        .map(|ct| ct.trim().to_ascii_lowercase());
+    ct.is_some_and(|ct| ct.starts_with("image/")) && !head.starts_with('<')
+}
+
+/// An HTML document by content type or leading markup (`<!doctype` / `<html`).
+pub(crate) fn looks_like_html(bytes: &[u8], content_type: Option<&str>) -> bool {
+    let ct_html = content_type
+        .and_then(|ct| ct.split(';').next())
+        .is_some_and(|ct| ct.trim().eq_ignore_ascii_case("text/html"));
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(512)]).to_ascii_lowercase();
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    ct_html || head.starts_with("<!doctype") || head.starts_with("<html")
+}
+
+/// Username from a Discourse avatar URL (`/user_avatar/{host}/{username}/{size}/...`), else the last path segment.
+fn avatar_username(url: &Url) -> String {
+    let segs: Vec<&str> = url.path_segments().map(|s| s.collect()).unwrap_or_default();
+    if let Some(idx) = segs.iter().position(|s| *s == "user_avatar")
+        && let Some(name) = segs.get(idx + 2)
+    {
+        return name.to_string();
     }
+    segs.last().map(|s| s.to_string()).unwrap_or_default()
+}
+
+fn letter_avatar_svg(name: &str) -> String {
+    let letter = name
+        .chars()
+        .find(|c| c.is_alphanumeric())
+        .map(|c| c.to_uppercase().to_string())
+        .unwrap_or_else(|| "?".to_string());
+    let hash = blake3::hash(name.to_lowercase().as_bytes());
+    let rgb = &hash.as_bytes()[..3];
+    let color = format!(
+        "#{:02x}{:02x}{:02x}",
+        rgb[0] / 2 + 40,
+        rgb[1] / 2 + 40,
+        rgb[2] / 2 + 40
+    );
+    format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="120" height="120" viewBox="0 0 120 120">"#,
+            r#"<rect width="120" height="120" fill="{}"/>"#,
+            r#"<text x="60" y="60" dy=".35em" text-anchor="middle" font-family="sans-serif" font-size="60" fill="white">{}</text>"#,
+            "</svg>"
+        ),
+        color, letter
+    )
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<HttpStatus>()
+        .is_some_and(|s| s.status == reqwest::StatusCode::NOT_FOUND)
 }
 
 fn request_key(request: &AssetRequest) -> String {
     match &request.source {
         AssetSource::Remote(url) => url.as_str().to_string(),
+        AssetSource::FirstFound(urls) => {
+            let urls: Vec<&str> = urls.iter().map(Url::as_str).collect();
+            format!("first-found:{}", urls.join(" "))
+        }
         AssetSource::Local(path) => format!("file:{}", path.display()),
     }
 }
 
+/// What a request names, for the manifest: a URL or a local path.
+fn request_source(request: &AssetRequest) -> String {
+    match &request.source {
+        AssetSource::Remote(url) => url.to_string(),
+        AssetSource::FirstFound(urls) => urls.first().map(Url::to_string).unwrap_or_default(),
+        AssetSource::Local(path) => path.display().to_string(),
+    }
+}
+
 fn kind_subdir(kind: AssetKind) -> &'static str {
     match kind {
         AssetKind::Avatar => "avatar",
@@ -187,21 +797,26 @@ fn kind_subdir(kind: AssetKind) -> &'static str {
     }
 }
 
+/// `{assets}/{kind}/{blake3}.{ext}`: content-addressed, so identical downloads share a file.
+fn asset_rel_path(assets_dir_name: &str, kind: AssetKind, hash: &str, ext: &str) -> String {
+    format!("{}/{}/{}.{}", assets_dir_name, kind_subdir(kind), hash, ext)
+}
+
 fn write_asset_file(
     out_dir: &Path,
     assets_dir_name: &str,
     kind: AssetKind,
     bytes: &[u8],
     ext: &str,
+    hash: &blake3::Hash,
 ) -> anyhow::Result<String> {
-    let hash = blake3::hash(bytes).to_hex().to_string();
-    let rel = format!("{}/{}/{}.{}", assets_dir_name, kind_subdir(kind), hash, ext);
+    let rel = asset_rel_path(assets_dir_name, kind, &hash.to_hex(), ext);
     let abs = out_dir.join(&rel);
     if let Some(parent) = abs.parent() {
-        std::fs::create_dir_all(parent).with_context(|| format!("create {}", parent.display()))?;
+        output::create_dir_all(parent)?;
     }
     if !abs.exists() {
-        std::fs::write(&abs, bytes).with_context(|| format!("write {}", abs.display()))?;
+        output::write(&abs, bytes)?;
     }
     Ok(rel)
 }
@@ -211,10 +826,10 @@ fn sniff_mime_and_ext(
     content_type_hint: Option<&str>,
     request: &AssetRequest,
 ) -> (String, String) {
-    if let Some(ct) = content_type_hint.and_then(|s| s.split(';').next()) {
-        if let Some((mime, ext)) = mime_to_ext(ct.trim(), request) {
-            return (mime.to_string(), ext.to_string());
-        }
+    if let Some(ct) = content_type_hint.and_then(|s| s.split(';').next())
+        && let Some((mime, ext)) = mime_to_ext(ct.trim(), request)
+    {
+        return (mime.to_string(), ext.to_string());
     }
 
     // Best-effort magic bytes
@@ -230,6 +845,9 @@ fn sniff_mime_and_ext(
     if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
         return ("image/webp".to_string(), "webp".to_string());
     }
+    if bytes.get(4..12) == Some(b"ftypavif") {
+        return ("image/avif".to_string(), "avif".to_string());
+    }
     if bytes.starts_with(b"wOFF") {
         return ("font/woff".to_string(), "woff".to_string());
     }
@@ -244,10 +862,10 @@ fn sniff_mime_and_ext(
     }
 
     // Fall back to URL extension for remote assets.
-    if let AssetSource::Remote(url) = &request.source {
-        if let Some((mime, ext)) = ext_from_url(url, request) {
-            return (mime, ext);
-        }
+    if let AssetSource::Remote(url) = &request.source
+        && let Some((mime, ext)) = ext_from_url(url, request)
+    {
+        return (mime, ext);
     }
 
     // Default.
@@ -260,6 +878,7 @@ fn mime_to_ext(mime: &str, request: &AssetRequest) -> Option<(&'static str, &'st
         "image/jpeg" => Some(("image/jpeg", "jpg")),
         "image/gif" => Some(("image/gif", "gif")),
         "image/webp" => Some(("image/webp", "webp")),
+        "image/avif" => Some(("image/avif", "avif")),
         "image/svg+xml" => Some(("image/svg+xml", "svg")),
         "font/woff2" => Some(("font/woff2", "woff2")),
         "font/woff" => Some(("font/woff", "woff")),
@@ -281,6 +900,7 @@ fn ext_from_url(url: &Url, request: &AssetRequest) -> Option<(String, String)> {
         "jpg" | "jpeg" => ("image/jpeg", "jpg"),
         "gif" => ("image/gif", "gif"),
         "webp" => ("image/webp", "webp"),
+        "avif" => ("image/avif", "avif"),
         "svg" => ("image/svg+xml", "svg"),
         "woff2" => ("font/woff2", "woff2"),
         "woff" => ("font/woff", "woff"),
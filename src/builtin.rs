@@ -55,3 +55,115 @@ pub const THEME_TOGGLE_JS: &str = r#"(function () {
     });
   }
 })();"#;
+
+/// Client-side search over the `#dtr-search-data` JSON blob (`[{"n": post_number, "u": username, "t": text}]`).
+/// Adds its own input to the top bar, so nothing shows when JS is disabled.
+pub const SEARCH_JS: &str = r#"(function () {
+  var data = document.getElementById("dtr-search-data");
+  var bar = document.querySelector(".dtr-topbar-inner");
+  if (!data || !bar) return;
+
+  var index;
+  try {
+    index = JSON.parse(data.textContent);
+  } catch (_) {
+    return;
+  }
+
+  var entries = [];
+  for (var i = 0; i < index.length; i++) {
+    var el = document.getElementById("post_" + index[i].n);
+    if (el) {
+      entries.push({ el: el, text: (index[i].u + " " + index[i].t).toLowerCase() });
+    }
+  }
+
+  var input = document.createElement("input");
+  input.type = "search";
+  input.className = "dtr-search";
+  input.placeholder = "Search posts";
+  input.setAttribute("aria-label", "Search posts");
+  var status = document.createElement("span");
+  status.className = "dtr-search-status";
+  status.setAttribute("aria-live", "polite");
+  var toggle = document.getElementById("dtr-theme-toggle");
+  bar.insertBefore(input, toggle);
+  bar.insertBefore(status, toggle);
+
+  function clearMarks(root) {
+    var marks = root.querySelectorAll("mark.dtr-hit");
+    for (var i = 0; i < marks.length; i++) {
+      var mark = marks[i];
+      var parent = mark.parentNode;
+      parent.replaceChild(document.createTextNode(mark.textContent), mark);
+      parent.normalize();
+    }
+  }
+
+  function highlight(root, query) {
+    var walker = document.createTreeWalker(root, NodeFilter.SHOW_TEXT, null);
+    var nodes = [];
+    while (walker.nextNode()) nodes.push(walker.currentNode);
+    for (var i = 0; i < nodes.length; i++) {
+      var node = nodes[i];
+      while (node) {
+        var at = node.nodeValue.toLowerCase().indexOf(query);
+        if (at === -1) break;
+        var match = node.splitText(at);
+        var rest = match.splitText(query.length);
+        var mark = document.createElement("mark");
+        mark.className = "dtr-hit";
+        match.parentNode.replaceChild(mark, match);
+        mark.appendChild(match);
+        node = rest;
+      }
+    }
+  }
+
+  function run() {
+    var query = input.value.trim().toLowerCase();
+    var shown = 0;
+    for (var i = 0; i < entries.length; i++) {
+      var entry = entries[i];
+      clearMarks(entry.el);
+      if (!query) {
+        entry.el.hidden = false;
+        continue;
+      }
+      var hit = entry.text.indexOf(query) !== -1;
+      entry.el.hidden = !hit;
+      if (hit) {
+        shown++;
+        highlight(entry.el, query);
+      }
+    }
+    status.textContent = query ? shown + " / " + entries.length : "";
+  }
+
+  var timer = null;
+  input.addEventListener("input", function () {
+    clearTimeout(timer);
+    timer = setTimeout(run, 120);
+  });
+})();"#;
+
+/// Copies the `<code>` named by a `.dtr-copy-btn`'s `data-target`, then says "Copied!" for 1.5s.
+pub const CODE_COPY_JS: &str = r#"(function () {
+  var buttons = document.querySelectorAll(".dtr-copy-btn");
+  Array.prototype.forEach.call(buttons, function (button) {
+    var timer = null;
+    button.addEventListener("click", function () {
+      var code = document.getElementById(button.getAttribute("data-target"));
+      if (!code || !navigator.clipboard) return;
+      navigator.clipboard.writeText(code.textContent).then(function () {
+        button.textContent = "Copied!";
+        button.classList.add("dtr-copied");
+        clearTimeout(timer);
+        timer = setTimeout(function () {
+          button.textContent = "Copy";
+          button.classList.remove("dtr-copied");
+        }, 1500);
+      });
+    });
+  });
+})();"#;
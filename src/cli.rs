@@ -7,6 +7,8 @@ use url::Url;
 pub enum Mode {
     Dir,
     Single,
+    /// An EPUB 3 book for e-readers, one chapter per `--epub-posts-per-chapter` posts.
+    Epub,
 }
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
@@ -26,6 +28,72 @@ pub enum ProgressMode {
     Always,
     /// Never show progress UI.
     Never,
+    /// No UI; one JSON object per line on stderr for each change (`stage`, `post_done`, `http_ok`, …) and each log
+    /// line (`log`), for programs driving the tool.
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum SummaryFormat {
+    /// A table on stderr, unless `--progress never`.
+    Table,
+    /// One JSON object on stdout; log lines go to stderr instead.
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum FetchOrder {
+    /// Smallest expected downloads first: by their `--preflight-above-bytes` `HEAD` size when known, else avatars,
+    /// emoji, images, then full-size originals.
+    Size,
+    /// As the assets appear in the topic.
+    Document,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum SmallActionsMode {
+    /// Render moderator small actions as a compact one-line entry.
+    Render,
+    /// Leave small actions out of the output.
+    Skip,
+}
+
+/// Inclusive range of post numbers, parsed from `a..b`, `..b` or `a..`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PostRange {
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+}
+
+impl PostRange {
+    pub fn contains(&self, post_number: u64) -> bool {
+        self.start.is_none_or(|s| post_number >= s) && self.end.is_none_or(|e| post_number <= e)
+    }
+}
+
+fn parse_post_range(s: &str) -> Result<PostRange, String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| format!("expected `a..b`, `..b` or `a..`, got `{s}`"))?;
+    let bound = |v: &str| -> Result<Option<u64>, String> {
+        let v = v.trim();
+        if v.is_empty() {
+            return Ok(None);
+        }
+        v.parse::<u64>()
+            .map(Some)
+            .map_err(|_| format!("invalid post number `{v}`"))
+    };
+    let range = PostRange {
+        start: bound(start)?,
+        end: bound(end)?,
+    };
+    if let (Some(a), Some(b)) = (range.start, range.end)
+        && a > b
+    {
+        return Err(format!("empty post range `{s}`"));
+    }
+    Ok(range)
 }
 
 #[derive(Debug, Parser)]
@@ -35,6 +103,11 @@ pub struct Args {
     #[arg(long)]
     pub input: PathBuf,
 
+    /// Refuse topic files larger than this many MiB; a whole category exported by mistake would otherwise be
+    /// parsed into memory.
+    #[arg(long, value_name = "MB", default_value_t = 256)]
+    pub max_input_mb: u64,
+
     /// Base URL of the Discourse site, used to resolve relative URLs (e.g. `https://forum.example.com`).
     #[arg(long)]
     pub base_url: Url,
@@ -46,27 +119,194 @@ pub struct Args {
     #[arg(long)]
     pub css: Vec<PathBuf>,
 
+    /// Remote stylesheet to bundle, e.g. `https://forum.example.com/stylesheets/desktop.css` (repeatable).
+    ///
+    /// Bundled after the `--css` files; its `url()`s and `@import`s resolve against its own URL. Also skips
+    /// auto-discovery.
+    #[arg(long, value_name = "URL")]
+    pub css_url: Vec<Url>,
+
     /// Use the built-in minimal theme CSS (light/dark) and skip crawling site CSS.
     ///
     /// When enabled, the tool will NOT auto-discover stylesheets from `--base-url`, and will ignore `--css`.
     #[arg(long)]
     pub builtin_css: bool,
 
-    /// Output mode: `dir` (HTML + assets/) or `single` (one self-contained HTML).
+    /// Abort CSS bundling once the loaded stylesheets (after `@import` expansion) exceed this many bytes.
+    #[arg(long, value_name = "BYTES", default_value_t = 20 * 1024 * 1024)]
+    pub max_css_bytes: usize,
+
+    /// Stamp the output with the topic's last activity date instead of the current time, so re-renders are
+    /// byte-identical.
+    #[arg(long, conflicts_with = "no_stamp")]
+    pub reproducible: bool,
+
+    /// Don't add the "generated by" comment to the HTML and CSS.
+    #[arg(long)]
+    pub no_stamp: bool,
+
+    /// Drop `@font-face` rules for families that neither the bundled CSS nor the posts' inline styles use, without
+    /// downloading their fonts.
+    #[arg(long)]
+    pub prune_fonts: bool,
+
+    /// Drop the style rules of the bundled CSS that match nothing in the rendered pages, before downloading the
+    /// assets they refer to. Selectors are matched without their pseudo-classes and attribute conditions, so rules
+    /// for hover, open or checked states are kept. Classes added by scripts at runtime are not seen.
+    #[arg(long)]
+    pub purge_css: bool,
+
+    /// Drop rule blocks the bundled CSS repeats byte for byte in the same `@media`/`@supports` context, keeping the
+    /// last copy. On by default; the overlapping stylesheets of a Discourse site repeat thousands of rules.
+    #[arg(long, overrides_with = "no_dedupe_css")]
+    pub dedupe_css: bool,
+
+    /// Keep duplicate rule blocks in the bundled CSS, e.g. to compare it with the site's stylesheets.
+    #[arg(long, overrides_with = "dedupe_css")]
+    pub no_dedupe_css: bool,
+
+    /// Skip remote stylesheets that turn out to be HTML pages (e.g. a login wall) instead of failing.
+    #[arg(long)]
+    pub lenient_css: bool,
+
+    /// Don't add system font fallbacks for scripts in the text that no embedded font covers (they are still
+    /// reported).
+    #[arg(long)]
+    pub no_font_fallback_injection: bool,
+
+    /// Extra local CSS files appended to the bundled CSS (repeatable).
+    ///
+    /// Injection happens after bundling (site, `--css` or builtin), so injected rules take precedence by cascade
+    /// order. Files are included verbatim: no `@import` inlining and no `url()` rewriting.
+    #[arg(long, value_name = "FILE")]
+    pub inject_css: Vec<PathBuf>,
+
+    /// Extra local JavaScript files added before `</body>` (repeatable).
+    ///
+    /// In `dir` mode each file is copied to `<assets>/js/<filename>` (with a content-hash suffix when an earlier file
+    /// had the same name) and referenced with `<script src>`; in `single` mode its contents are inlined.
+    #[arg(long, value_name = "FILE")]
+    pub inject_js: Vec<PathBuf>,
+
+    /// Do not embed the builtin theme toggle script (e.g. when `--inject-js` supplies its own).
+    #[arg(long)]
+    pub no_builtin_js: bool,
+
+    /// Produce script-free output: no theme toggle (theming follows `prefers-color-scheme`), no search, and the
+    /// strict check also fails on any `<script>` or `on*=` attribute left in the HTML.
+    #[arg(long, conflicts_with_all = ["inject_js", "search", "code_copy_button"])]
+    pub no_js: bool,
+
+    /// Only render posts whose `post_number` is in this inclusive range: `1500..1600`, `..100` or `500..`.
+    #[arg(long, value_name = "RANGE", value_parser = parse_post_range)]
+    pub posts: Option<PostRange>,
+
+    /// Add a table of contents (builtin theme only) of every post's headings, nested by level. Left out when no
+    /// post has a heading.
+    #[arg(long)]
+    pub toc: bool,
+
+    /// Deepest heading level listed by `--toc` (`1` for `<h1>` only, up to `6`).
+    #[arg(long, value_name = "N", default_value_t = 3, value_parser = clap::value_parser!(u8).range(1..=6))]
+    pub toc_max_depth: u8,
+
+    /// Embed a client-side full-text search box (builtin theme only; needs JS, works offline).
+    #[arg(long)]
+    pub search: bool,
+
+    /// Number the lines of code blocks (builtin theme styles them; copying the code leaves the numbers out).
+    #[arg(long)]
+    pub line_numbers: bool,
+
+    /// Put a "Copy" button above each code block (builtin theme only; needs JS).
+    #[arg(long)]
+    pub code_copy_button: bool,
+
+    /// Put a small QR code of each post's forum URL in its header, for printed archives. Shown only when printing
+    /// unless `--qr-always` is set.
+    #[arg(long)]
+    pub qr_permalinks: bool,
+
+    /// Show the `--qr-permalinks` codes on screen too.
+    #[arg(long, requires = "qr_permalinks")]
+    pub qr_always: bool,
+
+    /// Let search engines index a rendered private message; PM pages get `noindex` by default.
+    #[arg(long)]
+    pub include_private: bool,
+
+    /// Dir mode: split the output into `topic-{id}-p1.html`, `-p2.html`, ... with N posts each.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub paginate: Option<u64>,
+
+    /// Only render posts by this user (matched case-insensitively against username or display name).
+    /// Can be passed multiple times.
+    #[arg(long = "only-user", value_name = "NAME")]
+    pub only_user: Vec<String>,
+
+    /// Leave out posts by this user (matched case-insensitively against username or display name).
+    /// Can be passed multiple times.
+    #[arg(long = "exclude-user", value_name = "NAME")]
+    pub exclude_user: Vec<String>,
+
+    /// Remove reaction and "N people liked this" summaries from posts instead of localizing their icons.
+    #[arg(long)]
+    pub strip_reactions: bool,
+
+    /// Remove elements matching this CSS selector from posts. Can be passed multiple times.
+    #[arg(long = "strip-selector", value_name = "CSS")]
+    pub strip_selector: Vec<String>,
+
+    /// How to handle small-action posts ("closed this topic", "split posts", ...): `render` or `skip`.
+    #[arg(long, value_enum, default_value = "render")]
+    pub small_actions: SmallActionsMode,
+
+    /// Show how many times an edited post was edited instead of just "(edited)".
+    #[arg(long)]
+    pub show_revision_count: bool,
+
+    /// Include whisper posts (`post_type == 4`), which are skipped by default.
+    #[arg(long)]
+    pub include_whispers: bool,
+
+    /// Output mode: `dir` (HTML + assets/), `single` (one self-contained HTML) or `epub` (an e-book with the assets
+    /// inside).
     #[arg(long, value_enum, default_value = "dir")]
     pub mode: Mode,
 
+    /// Epub mode: put this many posts in each chapter.
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    pub epub_posts_per_chapter: u64,
+
     /// Offline mode (v1 only supports `strict`).
     #[arg(long, value_enum, default_value = "strict")]
     pub offline: OfflineMode,
 
-    /// Output path. For `dir` mode: a directory. For `single` mode: an HTML file path.
+    /// Output path. For `dir` mode: a directory. For `single` mode: an HTML file path. For `epub` mode: an `.epub`
+    /// file path.
     #[arg(long)]
     pub out: Option<PathBuf>,
 
-    /// Avatar size for `{size}` substitution in `avatar_template`.
-    #[arg(long, default_value_t = 120)]
-    pub avatar_size: u32,
+    /// Skip the topic when the previous output was rendered from the same inputs: the topic JSON, the local CSS
+    /// and injected files, and every flag that affects the output. Auto-discovered site CSS is not re-checked.
+    #[arg(long)]
+    pub skip_unchanged: bool,
+
+    /// Avatar size for `{size}` substitution in `avatar_template`. Repeat it (e.g. `--avatar-size 45 --avatar-size
+    /// 90`) to download every size and give the avatars a `srcset` for high-density screens; the smallest is the
+    /// `src`.
+    #[arg(long, value_name = "PX", default_values_t = [120], value_parser = clap::value_parser!(u32).range(1..))]
+    pub avatar_size: Vec<u32>,
+
+    /// Add a subresource integrity hash (`integrity="sha384-…"`) to the stylesheet link in `dir` mode. Browsers only
+    /// verify it when the pages are served over HTTP(S); opened from disk, they refuse the stylesheet.
+    #[arg(long)]
+    pub css_integrity: bool,
+
+    /// Dir mode: add `<link rel="preload">` hints for the stylesheet and the first N local avatars and post images
+    /// of each page, in document order.
+    #[arg(long, value_name = "N")]
+    pub emit_preload_hints: Option<usize>,
 
     /// Assets directory name for `dir` mode.
     #[arg(long, default_value = "assets")]
@@ -76,11 +316,268 @@ pub struct Args {
     #[arg(long, default_value_t = 8)]
     pub max_concurrency: usize,
 
-    /// HTTP User-Agent used for downloading assets.
-    #[arg(long, default_value = "discourse-topic-render/0.1")]
-    pub user_agent: String,
+    /// Max concurrent downloads per host (capped at `--max-concurrency`).
+    #[arg(long, default_value_t = 3)]
+    pub per_domain_concurrency: usize,
+
+    /// Deadline in seconds for each download, including retries.
+    #[arg(long, default_value_t = 60)]
+    pub timeout_secs: u64,
+
+    /// Deadline in seconds for avatar downloads (default: a quarter of `--timeout-secs`).
+    #[arg(long, value_name = "SECS")]
+    pub timeout_avatar: Option<u64>,
+
+    /// Deadline in seconds for post image downloads (default: `--timeout-secs`).
+    #[arg(long, value_name = "SECS")]
+    pub timeout_image: Option<u64>,
+
+    /// Deadline in seconds for font downloads (default: half of `--timeout-secs`).
+    #[arg(long, value_name = "SECS")]
+    pub timeout_font: Option<u64>,
+
+    /// Stop retrying a throttled (429/503) request after this many seconds in total.
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    pub max_retry_total_secs: u64,
+
+    /// Retry a request this many times after a connection error or timeout. Each attempt waits for the response for
+    /// an even share of what is left of the download's deadline.
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    pub max_connect_retries: usize,
+
+    /// Order of asset downloads: `size` fetches small assets (avatars, emoji) before large ones, so an
+    /// interrupted or partially failed run still has most of them; `document` follows the topic order.
+    #[arg(long, value_enum, default_value = "size")]
+    pub fetch_order: FetchOrder,
+
+    /// Refuse any single response larger than this many bytes.
+    #[arg(long, value_name = "BYTES", default_value_t = 50 * 1024 * 1024)]
+    pub max_response_bytes: u64,
+
+    /// Link to assets larger than this many bytes instead of downloading them.
+    #[arg(long = "max-asset-size", value_name = "BYTES")]
+    pub max_asset_size: Option<u64>,
+
+    /// Send a `HEAD` before downloading each asset and log those larger than this many bytes.
+    #[arg(long, value_name = "BYTES")]
+    pub preflight_above_bytes: Option<u64>,
+
+    /// Skip assets over `--preflight-above-bytes`: images get a placeholder, fonts and other stylesheet `url()`s keep
+    /// linking to the original.
+    #[arg(long, requires = "preflight_above_bytes")]
+    pub skip_large_assets: bool,
+
+    /// Stop downloading assets once this many bytes of them have come in: the ones not started yet become
+    /// placeholders (images) or stay linked (fonts), instead of failing the render. With `--fetch-order size`,
+    /// that leaves the large ones out.
+    #[arg(long, value_name = "BYTES")]
+    pub max_total_bytes: Option<u64>,
+
+    /// Stop downloading assets this many seconds into the render, like `--max-total-bytes`.
+    #[arg(long, value_name = "SECS")]
+    pub fetch_deadline_secs: Option<u64>,
+
+    /// Cap response bodies held in memory at once to this many MiB; downloads wait for room before reading
+    /// their body. Dir mode streams assets to disk, so only CSS and HTML count there.
+    #[arg(long, value_name = "MB")]
+    pub max_memory_mb: Option<u64>,
+
+    /// Keep rendering when a post image or avatar fails to download: images become links to the original and
+    /// avatars are omitted.
+    #[arg(long)]
+    pub keep_going: bool,
+
+    /// Fail when a post has images without an `alt` attribute, listing them; for accessibility audits. Images
+    /// with an empty `alt` (decorative) pass.
+    #[arg(long)]
+    pub require_alt_text: bool,
+
+    /// Record where each localized image, avatar and lightbox link came from, as the absolute remote URL in a
+    /// `data-dtr-original-src` (or `data-dtr-original-href`) attribute.
+    #[arg(long)]
+    pub keep_original_urls: bool,
+
+    /// Warn when topic.json holds less than this percentage of the posts the topic declares (`posts_count`).
+    #[arg(long, value_name = "PERCENT", default_value_t = 90, value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub min_completeness: u8,
+
+    /// Fail instead of warning when topic.json holds less than `--min-completeness` of the topic's posts.
+    #[arg(long)]
+    pub require_complete: bool,
+
+    /// Send every request through this proxy (e.g. `http://proxy.corp.example.com:3128`).
+    /// Defaults to `http_proxy`/`https_proxy` from the environment.
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<String>,
+
+    /// Host that bypasses the proxy. Can be passed multiple times. Defaults to `no_proxy` from the environment.
+    #[arg(long = "no-proxy", value_name = "HOST")]
+    pub no_proxy: Vec<String>,
+
+    /// HTTP User-Agent for every request, replacing the default `discourse-topic-render/<version>` and any
+    /// `--ua-contact`.
+    #[arg(long, value_name = "STRING", env = "DTR_USER_AGENT")]
+    pub user_agent: Option<String>,
+
+    /// How forum admins can reach whoever runs the archive (a URL or email), added to the default User-Agent as
+    /// `discourse-topic-render/<version> (+contact)`.
+    #[arg(long, value_name = "CONTACT", env = "DTR_UA_CONTACT")]
+    pub ua_contact: Option<String>,
+
+    /// Also write the rendered posts as an RSS 2.0 feed to this path, one item per post linking to it on the forum.
+    #[arg(long, value_name = "PATH")]
+    pub rss: Option<PathBuf>,
+
+    /// Also write the rendered posts as an Atom 1.0 feed to this path, with each post's HTML as the entry content.
+    #[arg(long, value_name = "PATH")]
+    pub atom: Option<PathBuf>,
+
+    /// Also write `topic-{id}.meta.json` next to the output: the topic's id, title, category, tags, counts and
+    /// dates, and when and how (with which User-Agent) it was rendered with how many assets, for tools that inventory
+    /// archived topics.
+    #[arg(long)]
+    pub meta_sidecar: bool,
+
+    /// Write a JSON report of the strict-offline checks (rules evaluated, elements inspected, violations) to this path.
+    ///
+    /// The report is written even when the checks fail.
+    #[arg(long, value_name = "PATH")]
+    pub emit_strict_report: Option<PathBuf>,
+
+    /// Log same-page links (`href="#…"`) that point at no element of the page as warnings instead of failing the
+    /// strict check on them.
+    #[arg(long)]
+    pub allow_dangling_anchors: bool,
+
+    /// Session cookie `name=value` sent with requests to the `--base-url` host only (repeatable).
+    #[arg(long, value_name = "NAME=VALUE")]
+    pub cookie: Vec<String>,
+
+    /// Netscape-format cookie file (as written by `curl --cookie-jar`); only cookies for the `--base-url` host are
+    /// used.
+    #[arg(long, value_name = "PATH")]
+    pub cookie_file: Option<PathBuf>,
 
     /// Progress display: `auto`, `always`, or `never`.
     #[arg(long, value_enum, default_value = "auto")]
     pub progress: ProgressMode,
+
+    /// End-of-run summary (requests, bytes per kind, cache hits, output size, largest downloads): `table` or
+    /// `json`.
+    #[arg(long, value_enum, value_name = "FORMAT", default_value = "table")]
+    pub summary: SummaryFormat,
+
+    /// Warn when a post takes longer than this to render (asset scan, downloads and rewrite), naming it and where
+    /// its time went; 0 disables the warning. The summary lists the slowest posts either way.
+    #[arg(long, value_name = "SECS", default_value_t = 20)]
+    pub slow_post_secs: u64,
+
+    /// Do not color the end-of-run summary (also honored: a non-empty `NO_COLOR`).
+    #[arg(long)]
+    pub no_color: bool,
+
+    /// Print every option's resolved value, and whether it came from the command line or its default, as JSON
+    /// and exit without rendering. Cookie values are redacted.
+    #[arg(long)]
+    pub print_config: bool,
+}
+
+/// `discourse-topic-render serve <out-dir>`: preview dir-mode output over HTTP.
+#[derive(Debug, Parser)]
+#[command(
+    name = "serve",
+    about = "Serve a dir-mode output directory on localhost"
+)]
+pub struct ServeArgs {
+    /// Output directory of a `--mode dir` render.
+    pub dir: PathBuf,
+
+    /// Port to listen on (default: a free port).
+    #[arg(long, default_value_t = 0)]
+    pub port: u16,
+
+    /// Open the served page in the default browser.
+    #[arg(long)]
+    pub open: bool,
+}
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "verify",
+    about = "Run the strict-offline checks on already rendered HTML files"
+)]
+pub struct VerifyArgs {
+    /// Rendered HTML pages; stylesheets they link to locally are checked too.
+    #[arg(required = true)]
+    pub files: Vec<PathBuf>,
+
+    /// Also fail on any `<script>`, as a `--no-js` render would.
+    #[arg(long)]
+    pub no_js: bool,
+
+    /// Only warn about dangling same-page links, as an `--allow-dangling-anchors` render does.
+    #[arg(long)]
+    pub allow_dangling_anchors: bool,
+
+    /// Disable colored output.
+    #[arg(long)]
+    pub no_color: bool,
+}
+
+/// `discourse-topic-render gc <out-dir>`: remove assets left behind by earlier renders.
+#[derive(Debug, Parser)]
+#[command(
+    name = "gc",
+    about = "Delete the assets of a dir-mode output directory that no page or stylesheet refers to"
+)]
+pub struct GcArgs {
+    /// Output directory of one or more `--mode dir` renders.
+    pub dir: PathBuf,
+
+    /// Assets directory name used by the renders.
+    #[arg(long, default_value = "assets")]
+    pub assets_dir_name: String,
+
+    /// List the unreferenced assets without deleting them.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Collect even when the directory has no `topic-{id}.assets-manifest.json`, i.e. it may not be this tool's
+    /// output.
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn post_range_parsing() {
+        let r = parse_post_range("1500..1600").unwrap();
+        assert!(r.contains(1500) && r.contains(1600) && !r.contains(1601));
+        let r = parse_post_range("..100").unwrap();
+        assert!(r.contains(1) && !r.contains(101));
+        let r = parse_post_range("500..").unwrap();
+        assert!(!r.contains(499) && r.contains(5000));
+        assert!(parse_post_range("10..5").is_err());
+        assert!(parse_post_range("10").is_err());
+    }
+
+    #[test]
+    fn avatar_sizes_are_positive() {
+        let parse = |size: &str| {
+            Args::try_parse_from([
+                "discourse-topic-render",
+                "--input",
+                "topic.json",
+                "--base-url",
+                "https://forum.example.com/",
+                "--avatar-size",
+                size,
+            ])
+        };
+        assert_eq!(parse("45").unwrap().avatar_size, [45]);
+        assert!(parse("0").is_err());
+    }
 }
@@ -1,68 +1,539 @@
 use std::collections::HashSet;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
+use std::sync::LazyLock;
 
 use anyhow::Context as _;
 use kuchiki::traits::TendrilSink as _;
 use regex::Regex;
 use url::Url;
 
-use crate::assets::{AssetKind, AssetRequest, AssetSource, AssetStore};
+use crate::assets::{AssetKind, AssetRequest, AssetSource, AssetStore, looks_like_html};
+use crate::fetcher::{AssetTooLarge, LargeAssetSkipped, OverBudget};
 use crate::progress::DownloadKind;
+use crate::purge::{PurgeStats, Purger};
+use crate::urlres::{self, Resolved};
 
 #[derive(Debug, Clone)]
 pub enum CssOrigin {
     Local(PathBuf),
     Remote(Url),
+    /// Contents of a `<style>` element of the `--base-url` page; its URLs resolve against that page.
+    Inline(String),
 }
 
+impl CssOrigin {
+    /// Where the stylesheet's references resolve from.
+    fn document<'a>(&'a self, base_url: &'a Url) -> urlres::Origin<'a> {
+        match self {
+            CssOrigin::Local(path) => urlres::Origin::Local(path),
+            CssOrigin::Remote(url) => urlres::Origin::Remote(url),
+            CssOrigin::Inline(_) => urlres::Origin::Remote(base_url),
+        }
+    }
+}
+
+/// A stylesheet to bundle, and the media query of the `<link>`/`<style>` it came from, which wraps its bundled
+/// rules in `@media` so e.g. a `(prefers-color-scheme: dark)` sheet still applies only in dark mode.
+#[derive(Debug, Clone)]
+pub struct CssSource {
+    pub origin: CssOrigin,
+    pub media: Option<String>,
+}
+
+impl From<CssOrigin> for CssSource {
+    fn from(origin: CssOrigin) -> Self {
+        CssSource {
+            origin,
+            media: None,
+        }
+    }
+}
+
+/// What a bundle leaves out because the rendered page doesn't use it.
+#[derive(Clone, Copy, Default)]
+pub struct Pruning<'a> {
+    /// `--prune-fonts`: the families used outside the bundle (the HTML, see [`font_families_in_html`], and `--inject-css`).
+    pub fonts: Option<&'a HashSet<String>>,
+    /// `--purge-css`: the rendered pages, to drop the style rules that match none of them.
+    pub rules: Option<&'a Purger>,
+    /// `--dedupe-css`: rule blocks repeated later in the same context, see [`dedupe`](crate::dedupe).
+    pub duplicates: bool,
+}
+
+/// Bookkeeping shared by every stylesheet of one bundle.
+struct BundleState<'a> {
+    /// Origins already inlined (exact URL or path).
+    visited: HashSet<String>,
+    /// Content hashes of inlined stylesheets, catching the same file under different URLs.
+    inlined: HashSet<blake3::Hash>,
+    /// Stylesheet bytes loaded so far.
+    bytes: usize,
+    max_bytes: usize,
+    /// Skip stylesheets that turn out to be HTML pages instead of failing.
+    lenient: bool,
+    /// The CSS ends up in a `<style>` element of the page rather than in the bundled stylesheet, so local asset
+    /// paths stay relative to the page.
+    embedded: bool,
+    /// `--prune-fonts`: `@font-face` rules set aside (and left as a placeholder comment) until the whole bundle
+    /// is known, so unused ones are never downloaded.
+    deferred_font_faces: Option<Vec<DeferredFontFace>>,
+    /// `--purge-css`: applied to each stylesheet before its `url()`s are localized, so the assets of dropped rules
+    /// are never downloaded.
+    purger: Option<&'a Purger>,
+    purged: PurgeStats,
+}
+
+struct DeferredFontFace {
+    origin: CssOrigin,
+    css: String,
+}
+
+/// Bundle the local `css_files`, then the remote `css_urls`.
 pub async fn bundle_css(
     base_url: &Url,
     css_files: &[PathBuf],
+    css_urls: &[Url],
     store: &AssetStore,
+    max_bytes: usize,
+    lenient: bool,
+    pruning: Pruning<'_>,
 ) -> anyhow::Result<String> {
-    let origins: Vec<CssOrigin> = css_files.iter().cloned().map(CssOrigin::Local).collect();
-    bundle_css_origins(base_url, &origins, store).await
+    let origins: Vec<CssSource> = css_files
+        .iter()
+        .cloned()
+        .map(CssOrigin::Local)
+        .chain(css_urls.iter().cloned().map(CssOrigin::Remote))
+        .map(CssSource::from)
+        .collect();
+    bundle_css_origins(base_url, &origins, store, max_bytes, lenient, pruning).await
 }
 
+/// Inline `@import`s and localize `url()`s of each origin, in order, each wrapped in its `@media`. Fails once the loaded stylesheets add up
+/// to more than `max_bytes`, or when a remote stylesheet is really an HTML page (unless `lenient`).
+///
+/// With `pruning.fonts`, `@font-face` rules for families that neither the bundle nor the HTML mention are dropped
+/// without downloading their fonts; with `pruning.rules`, so are the style rules no rendered page can match. With
+/// `pruning.duplicates`, blocks the finished bundle repeats are dropped.
 pub async fn bundle_css_origins(
     base_url: &Url,
-    origins: &[CssOrigin],
+    origins: &[CssSource],
     store: &AssetStore,
+    max_bytes: usize,
+    lenient: bool,
+    pruning: Pruning<'_>,
 ) -> anyhow::Result<String> {
-    let mut visited = HashSet::<String>::new();
+    let mut state = BundleState {
+        visited: HashSet::new(),
+        inlined: HashSet::new(),
+        bytes: 0,
+        max_bytes,
+        lenient,
+        embedded: false,
+        deferred_font_faces: pruning.fonts.map(|_| Vec::new()),
+        purger: pruning.rules,
+        purged: PurgeStats::default(),
+    };
     let mut bundled = String::new();
 
-    for (idx, origin) in origins.iter().enumerate() {
-        let css = load_css_recursive(base_url, origin.clone(), store, &mut visited)
+    for (idx, source) in origins.iter().enumerate() {
+        let origin = &source.origin;
+        let css = load_css_recursive(base_url, origin.clone(), store, &mut state)
             .await
             .with_context(|| format!("process css {}", origin_key(origin)))?;
         if idx != 0 {
             bundled.push('\n');
         }
-        bundled.push_str(&css);
+        match &source.media {
+            Some(media) if !css.trim().is_empty() => {
+                bundled.push_str(&format!("@media {media} {{\n{css}\n}}"));
+            }
+            _ => bundled.push_str(&css),
+        }
         bundled.push('\n');
     }
 
+    if state.purger.is_some() {
+        let PurgeStats {
+            rules_dropped,
+            bytes_before,
+            bytes_after,
+        } = state.purged;
+        tracing::info!(
+            rules = rules_dropped,
+            before = bytes_before,
+            after = bytes_after,
+            "purged CSS rules no rendered page matches"
+        );
+        if let Some(p) = store.progress() {
+            p.css_purged(state.purged);
+        }
+    }
+    if let (Some(used_outside), Some(faces)) = (pruning.fonts, state.deferred_font_faces.take()) {
+        bundled = restore_used_font_faces(base_url, store, &bundled, faces, used_outside).await?;
+    }
+    if pruning.duplicates {
+        let (deduped, stats) = crate::dedupe::dedupe(&bundled);
+        if stats.rules_removed > 0 {
+            tracing::info!(
+                rules = stats.rules_removed,
+                bytes = stats.bytes_saved,
+                "removed duplicate CSS rules"
+            );
+        }
+        bundled = deduped;
+    }
     Ok(bundled)
 }
 
+static FONT_FACE_PLACEHOLDER_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"/\* dtr-font-face (\d+) \*/").expect("font-face placeholder regex")
+});
+
+/// Put back (and localize) the deferred `@font-face` rules whose family the bundle uses or `used_outside` lists; replace the
+/// others with a comment.
+async fn restore_used_font_faces(
+    base_url: &Url,
+    store: &AssetStore,
+    bundled: &str,
+    faces: Vec<DeferredFontFace>,
+    used_outside: &HashSet<String>,
+) -> anyhow::Result<String> {
+    let mut used = font_families_in_css(bundled);
+    used.extend(used_outside.iter().cloned());
+
+    let (mut pruned_rules, mut pruned_urls) = (0usize, 0usize);
+    let mut out = String::with_capacity(bundled.len());
+    let mut last = 0usize;
+    for caps in FONT_FACE_PLACEHOLDER_RE.captures_iter(bundled) {
+        let m = caps.get(0).expect("match");
+        out.push_str(&bundled[last..m.start()]);
+        last = m.end();
+        let Some(face) = caps[1].parse::<usize>().ok().and_then(|i| faces.get(i)) else {
+            continue;
+        };
+        match font_face_family(&face.css) {
+            Some(family) if !used.contains(&family) => {
+                pruned_rules += 1;
+                pruned_urls += URL_RE
+                    .captures_iter(&keep_best_font_sources(&face.css))
+                    .filter(|c| !is_non_fetchable_url(url_arg(c)))
+                    .count();
+                out.push_str(&format!(
+                    "/* pruned unused @font-face {} */",
+                    comment_safe(&family)
+                ));
+            }
+            _ => {
+                let css = rewrite_css_urls(base_url, &face.origin, store, false, &face.css).await?;
+                out.push_str(&css);
+            }
+        }
+    }
+    out.push_str(&bundled[last..]);
+
+    if pruned_rules > 0 {
+        tracing::info!(
+            rules = pruned_rules,
+            fonts = pruned_urls,
+            "pruned @font-face rules for unused families"
+        );
+        if let Some(p) = store.progress() {
+            p.fonts_pruned(pruned_rules, pruned_urls);
+        }
+    }
+    Ok(out)
+}
+
+static FONT_FACE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)@font-face\s*\{").expect("font-face regex"));
+
+/// Byte ranges of the `@font-face { ... }` rules in `css`, outside comments.
+fn font_face_blocks(css: &str) -> Vec<(usize, usize)> {
+    let comments = comment_ranges(css);
+    let mut blocks = Vec::new();
+    let mut from = 0usize;
+    while let Some(m) = FONT_FACE_RE.find_at(css, from) {
+        if comments.iter().any(|(s, e)| (*s..*e).contains(&m.start())) {
+            from = m.end();
+            continue;
+        }
+        let mut i = m.end();
+        let end = loop {
+            let Some(c) = css[i..].chars().next() else {
+                break css.len();
+            };
+            match c {
+                '"' | '\'' => {
+                    i = string_end(css, i, c);
+                    continue;
+                }
+                '}' => break i + 1,
+                _ => {}
+            }
+            i += c.len_utf8();
+        };
+        blocks.push((m.start(), end));
+        from = end;
+    }
+    blocks
+}
+
+/// Reduce the `src` of each `@font-face` to what a current browser loads: the `local()` entries, then the single
+/// best `url()` by format (WOFF2, then WOFF, then TrueType/OpenType). Earlier `src` declarations, as in the `.eot`
+/// hack for old IE, are dropped along with it. A rule with no supported format is left alone.
+fn keep_best_font_sources(css: &str) -> String {
+    let mut out = String::with_capacity(css.len());
+    let mut last = 0usize;
+    for (start, end) in font_face_blocks(css) {
+        let Some(open) = css[start..end].find('{') else {
+            continue;
+        };
+        let body_start = start + open + 1;
+        let body_end = if css[..end].ends_with('}') {
+            end - 1
+        } else {
+            end
+        };
+        out.push_str(&css[last..body_start]);
+        out.push_str(&best_font_sources(&css[body_start..body_end]));
+        last = body_end;
+    }
+    out.push_str(&css[last..]);
+    out
+}
+
+/// The declarations of one `@font-face` with only the effective (last) `src` kept, reduced to its best sources.
+fn best_font_sources(body: &str) -> String {
+    let declarations = split_top_level(body, ';');
+    let is_src = |declaration: &str| {
+        declaration
+            .split_once(':')
+            .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("src"))
+    };
+    let Some(effective) = declarations.iter().rposition(|d| is_src(d)) else {
+        return body.to_string();
+    };
+    let (name, value) = declarations[effective]
+        .split_once(':')
+        .expect("src declaration");
+    let Some(best) = best_font_src_value(value) else {
+        return body.to_string();
+    };
+    let leading = &value[..value.len() - value.trim_start().len()];
+    let trailing = &value[value.trim_end().len()..];
+    declarations
+        .iter()
+        .enumerate()
+        .filter(|&(i, d)| i == effective || !is_src(d))
+        .map(|(i, d)| {
+            if i == effective {
+                format!("{name}:{leading}{best}{trailing}")
+            } else {
+                d.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// `local()` entries and the best supported `url()` of a `src` list, or `None` when no `url()` is supported.
+fn best_font_src_value(value: &str) -> Option<String> {
+    let entries: Vec<&str> = split_top_level(value, ',')
+        .into_iter()
+        .map(str::trim)
+        .collect();
+    let starts_with = |entry: &str, prefix: &str| {
+        entry
+            .get(..prefix.len())
+            .is_some_and(|p| p.eq_ignore_ascii_case(prefix))
+    };
+    let mut best: Option<(u8, &str)> = None;
+    for &entry in &entries {
+        if !starts_with(entry, "url(") {
+            continue;
+        }
+        if let Some(rank) = font_source_rank(entry)
+            && best.is_none_or(|(r, _)| rank < r)
+        {
+            best = Some((rank, entry));
+        }
+    }
+    let (_, best) = best?;
+    let kept: Vec<&str> = entries
+        .iter()
+        .copied()
+        .filter(|&e| e == best || starts_with(e, "local("))
+        .collect();
+    Some(kept.join(", "))
+}
+
+static FONT_FORMAT_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)\bformat\(\s*["']?([-\w]+)"#).expect("font format regex"));
+
+/// Preference of a `url() [format()]` source, lower is better; from the URL's extension when there is no
+/// `format()`. `None` for formats current browsers don't load (`embedded-opentype`, `svg`, ...).
+fn font_source_rank(entry: &str) -> Option<u8> {
+    let format = match FONT_FORMAT_RE.captures(entry) {
+        Some(caps) => caps[1].to_ascii_lowercase(),
+        None => {
+            let caps = URL_RE.captures(entry)?;
+            let url = url_arg(&caps);
+            let path = url.split(['?', '#']).next().unwrap_or(url);
+            path.rsplit_once('.')?.1.to_ascii_lowercase()
+        }
+    };
+    match format.strip_suffix("-variations").unwrap_or(&format) {
+        "woff2" => Some(0),
+        "woff" => Some(1),
+        "truetype" | "opentype" | "ttf" | "otf" => Some(2),
+        _ => None,
+    }
+}
+
+/// A property whose name mentions `font` (`font-family`, the `font` shorthand, `--heading-font-family`, ...).
+static FONT_DECLARATION_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)(?:^|[\s{;])([-\w]*font[-\w]*)\s*:\s*([^;{}]*)")
+        .expect("font declaration regex")
+});
+
+/// Lowercased family names `css` may refer to. Unquoted values contribute every trailing run of words, so
+/// `bold 14px Open Sans` yields `open sans` among others; a few extra names only keep a rule that could be pruned.
+pub fn font_families_in_css(css: &str) -> HashSet<String> {
+    // Declarations inside comments and `@font-face` rules name a family without using it.
+    let mut skipped = comment_ranges(css);
+    skipped.extend(font_face_blocks(css));
+    skipped.sort_unstable();
+    let mut visible = String::with_capacity(css.len());
+    let mut last = 0usize;
+    for (start, end) in skipped {
+        if start >= last {
+            visible.push_str(&css[last..start]);
+            visible.push(' ');
+            last = end;
+        }
+    }
+    visible.push_str(&css[last.min(css.len())..]);
+
+    let mut out = HashSet::new();
+    for caps in FONT_DECLARATION_RE.captures_iter(&visible) {
+        if caps[1].eq_ignore_ascii_case("font-face") {
+            continue;
+        }
+        let value = caps[2].replace("!important", "");
+        for part in split_top_level(&value, ',') {
+            let part = part.trim();
+            if let Some(q @ ('"' | '\'')) = part.chars().next() {
+                out.insert(part.trim_matches(q).to_lowercase());
+                continue;
+            }
+            let words: Vec<&str> = part.split_whitespace().collect();
+            for i in 0..words.len() {
+                out.insert(words[i..].join(" ").to_lowercase());
+            }
+        }
+    }
+    out
+}
+
+/// Families used by inline `style` attributes, `<style>` elements and `<font face>` in `html`.
+pub fn font_families_in_html(html: &str) -> HashSet<String> {
+    let document = kuchiki::parse_html().one(html);
+    let mut out = HashSet::new();
+    if let Ok(nodes) = document.select("[style]") {
+        for node in nodes {
+            if let Some(style) = node.attributes.borrow().get("style") {
+                out.extend(font_families_in_css(&format!(";{style}")));
+            }
+        }
+    }
+    if let Ok(nodes) = document.select("style") {
+        for node in nodes {
+            out.extend(font_families_in_css(&node.text_contents()));
+        }
+    }
+    if let Ok(nodes) = document.select("font[face]") {
+        for node in nodes {
+            if let Some(face) = node.attributes.borrow().get("face") {
+                out.extend(font_families_in_css(&format!(";font-family:{face}")));
+            }
+        }
+    }
+    out
+}
+
+/// The lowercased `font-family` of an `@font-face` rule.
+fn font_face_family(rule: &str) -> Option<String> {
+    let body = &rule[rule.find('{')? + 1..];
+    let caps = FONT_DECLARATION_RE
+        .captures_iter(body)
+        .find(|c| c[1].eq_ignore_ascii_case("font-family"))?;
+    let family = caps[2].trim().trim_matches(['"', '\'']);
+    let family = family.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!family.is_empty()).then(|| family.to_lowercase())
+}
+
+/// Localize the `url()`s and inline the `@import`s of a `<style>` element from a post's cooked HTML. Relative
+/// URLs resolve against the forum, like everything else in cooked HTML.
+pub async fn rewrite_embedded_css(
+    base_url: &Url,
+    css: &str,
+    store: &AssetStore,
+    max_bytes: usize,
+    lenient: bool,
+) -> anyhow::Result<String> {
+    let mut state = BundleState {
+        visited: HashSet::new(),
+        inlined: HashSet::new(),
+        bytes: css.len(),
+        max_bytes,
+        lenient,
+        embedded: true,
+        deferred_font_faces: None,
+        purger: None,
+        purged: PurgeStats::default(),
+    };
+    let origin = CssOrigin::Remote(base_url.clone());
+    inline_imports_and_rewrite_urls(base_url, &origin, store, &mut state, css).await
+}
+
+/// The stylesheets of the `--base-url` page in document order, so the bundle keeps its cascade: linked (and
+/// preloaded) sheets and inline `<style>` elements, with their `media`.
 pub async fn discover_css_origins_from_base_url(
     base_url: &Url,
     store: &AssetStore,
-) -> anyhow::Result<Vec<CssOrigin>> {
-    let html = store
-        .fetch_remote_text(base_url.clone(), DownloadKind::Html)
+) -> anyhow::Result<Vec<CssSource>> {
+    let (html, _) = store
+        .fetch_remote_text(base_url.clone(), DownloadKind::Html, None)
         .await
         .with_context(|| format!("download html {}", base_url))?;
 
     let doc = kuchiki::parse_html().one(html);
 
-    let mut out = Vec::<CssOrigin>::new();
+    let mut out = Vec::<CssSource>::new();
     let mut seen = HashSet::<String>::new();
 
-    if let Ok(nodes) = doc.select("link[href]") {
+    if let Ok(nodes) = doc.select("link[href], style") {
         for node in nodes {
             let attrs = node.attributes.borrow();
+            let Some(media) = media_query(attrs.get("media")) else {
+                tracing::warn!(
+                    media = attrs.get("media").unwrap_or(""),
+                    "skipping stylesheet with an unusable media attribute"
+                );
+                continue;
+            };
+
+            if node.name.local.as_ref() == "style" {
+                let css = node.text_contents();
+                if !css.trim().is_empty() {
+                    out.push(CssSource {
+                        origin: CssOrigin::Inline(css),
+                        media,
+                    });
+                }
+                continue;
+            }
+
             let rel = attrs.get("rel").unwrap_or("");
             if !is_css_link_rel(rel, attrs.get("as")) {
                 continue;
@@ -73,11 +544,14 @@ pub async fn discover_css_origins_from_base_url(
                 continue;
             }
 
-            let url = resolve_html_href(base_url, href)
+            let url = urlres::resolve_url(base_url, href)
                 .with_context(|| format!("resolve css href {}", href))?;
             let key = url.as_str().to_string();
             if seen.insert(key) {
-                out.push(CssOrigin::Remote(url));
+                out.push(CssSource {
+                    origin: CssOrigin::Remote(url),
+                    media,
+                });
             }
         }
     }
@@ -85,36 +559,104 @@ pub async fn discover_css_origins_from_base_url(
     Ok(out)
 }
 
+/// The `media` attribute as the prelude of an `@media` wrapper: `Some(None)` when it applies everywhere, `None`
+/// when it can't be spliced into CSS safely.
+fn media_query(media: Option<&str>) -> Option<Option<String>> {
+    let media = media.unwrap_or("").trim();
+    if media.is_empty() || media.eq_ignore_ascii_case("all") {
+        return Some(None);
+    }
+    if media.contains(['{', '}', ';']) || media.contains("/*") {
+        return None;
+    }
+    Some(Some(media.to_string()))
+}
+
+/// Google Fonts tailors its CSS to the browser: unknown clients get TrueType files, often split into many
+/// `unicode-range` blocks. A current Chrome gets WOFF2.
+const GOOGLE_FONTS_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+     (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36";
+
+/// User agent override for fetching the stylesheet at `url`.
+fn css_user_agent(url: &Url) -> Option<&'static str> {
+    url.host_str()
+        .is_some_and(|host| host.eq_ignore_ascii_case("fonts.googleapis.com"))
+        .then_some(GOOGLE_FONTS_USER_AGENT)
+}
+
 #[async_recursion::async_recursion]
 async fn load_css_recursive(
     base_url: &Url,
     origin: CssOrigin,
     store: &AssetStore,
-    visited: &mut HashSet<String>,
+    state: &mut BundleState<'_>,
 ) -> anyhow::Result<String> {
     let key = origin_key(&origin);
-    if visited.contains(&key) {
+    if state.visited.contains(&key) {
         return Ok(String::new());
     }
-    visited.insert(key);
+    state.visited.insert(key.clone());
 
     let css = match &origin {
         CssOrigin::Local(path) => {
             std::fs::read_to_string(path).with_context(|| format!("read css {}", path.display()))?
         }
-        CssOrigin::Remote(url) => store
-            .fetch_remote_text(url.clone(), DownloadKind::Css)
-            .await
-            .with_context(|| format!("download css {}", url))?,
+        CssOrigin::Inline(css) => css.clone(),
+        CssOrigin::Remote(url) => {
+            let (css, content_type) = store
+                .fetch_remote_text(url.clone(), DownloadKind::Css, css_user_agent(url))
+                .await
+                .with_context(|| format!("download css {}", url))?;
+            // Login walls and bot challenges answer with an HTML page instead of the stylesheet.
+            if looks_like_html(css.as_bytes(), content_type.as_deref()) {
+                let msg = format!(
+                    "got HTML instead of CSS from {}; the site may require authentication — see --cookie/--cookie-file",
+                    url
+                );
+                if !state.lenient {
+                    anyhow::bail!(msg);
+                }
+                tracing::warn!("{}; skipping it (--lenient-css)", msg);
+                return Ok(format!(
+                    "/* skipped {}: got HTML instead of CSS */\n",
+                    comment_safe(&key)
+                ));
+            }
+            css
+        }
     };
 
-    inline_imports_and_rewrite_urls(base_url, &origin, store, visited, &css).await
+    if !state.inlined.insert(blake3::hash(css.as_bytes())) {
+        return Ok(format!(
+            "/* skipped {}: same content as an already inlined stylesheet */\n",
+            comment_safe(&key)
+        ));
+    }
+    state.bytes += css.len();
+    if state.bytes > state.max_bytes {
+        anyhow::bail!(
+            "bundled css exceeds --max-css-bytes ({} bytes) after loading {}",
+            state.max_bytes,
+            key
+        );
+    }
+
+    inline_imports_and_rewrite_urls(base_url, &origin, store, state, &css).await
+}
+
+/// `key` with `*/` escaped, for use inside a CSS comment.
+fn comment_safe(key: &str) -> String {
+    key.replace("*/", "*%2F")
 }
 
 fn origin_key(origin: &CssOrigin) -> String {
     match origin {
         CssOrigin::Local(path) => format!("file:{}", path.display()),
         CssOrigin::Remote(url) => url.as_str().to_string(),
+        CssOrigin::Inline(css) => format!(
+            "inline <style> {}",
+            &blake3::hash(css.as_bytes()).to_hex()[..16]
+        ),
     }
 }
 
@@ -130,48 +672,51 @@ fn is_css_link_rel(rel: &str, as_attr: Option<&str>) -> bool {
     }
 
     // Some sites use <link rel="preload" as="style" href="...">.
-    if rel_tokens.iter().any(|t| t == "preload") {
-        if let Some(as_attr) = as_attr {
-            if as_attr.eq_ignore_ascii_case("style") {
-                return true;
-            }
-        }
+    if rel_tokens.iter().any(|t| t == "preload")
+        && let Some(as_attr) = as_attr
+        && as_attr.eq_ignore_ascii_case("style")
+    {
+        return true;
     }
 
     false
 }
 
-fn resolve_html_href(base_url: &Url, href: &str) -> anyhow::Result<Url> {
-    let h = href.trim();
-    if h.starts_with("http://") || h.starts_with("https://") {
-        return Ok(Url::parse(h)?);
-    }
-    if h.starts_with("//") {
-        return Ok(Url::parse(&format!("{}:{}", base_url.scheme(), h))?);
-    }
-    Ok(base_url.join(h)?)
+/// `url(...)` with a double-quoted, single-quoted or bare argument; see [`url_arg`].
+pub(crate) static URL_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"url\(\s*(?:(?:"(?P<u_d>[^"]+)"|'(?P<u_s>[^']+)'|(?P<u2>[^)]+)))\s*\)"#)
+        .expect("url regex")
+});
+
+static IMPORT_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"@import\s+(?:url\(\s*)?(?:(?:"(?P<u_d>[^"]+)"|'(?P<u_s>[^']+)'|(?P<u2>[^);]+)))\s*\)?\s*(?P<media>[^;]*)\s*;"#,
+    )
+    .expect("import regex")
+});
+
+/// The argument of a [`URL_RE`] match, with surrounding whitespace and quotes removed.
+pub(crate) fn url_arg<'h>(caps: &regex::Captures<'h>) -> &'h str {
+    caps.name("u_d")
+        .or_else(|| caps.name("u_s"))
+        .or_else(|| caps.name("u2"))
+        .map(|m| m.as_str().trim().trim_matches('"').trim_matches('\''))
+        .unwrap_or_default()
 }
 
 async fn inline_imports_and_rewrite_urls(
     base_url: &Url,
     origin: &CssOrigin,
     store: &AssetStore,
-    visited: &mut HashSet<String>,
+    state: &mut BundleState<'_>,
     css: &str,
 ) -> anyhow::Result<String> {
-    let import_re = Regex::new(
-        r#"@import\s+(?:url\(\s*)?(?:(?:"(?P<u_d>[^"]+)"|'(?P<u_s>[^']+)'|(?P<u2>[^);]+)))\s*\)?\s*(?P<media>[^;]*)\s*;"#,
-    )
-    .expect("import regex");
-
     let mut out = String::with_capacity(css.len());
     let mut last = 0usize;
-    for caps in import_re.captures_iter(css) {
+    for caps in IMPORT_RE.captures_iter(css) {
         let m = caps.get(0).expect("match");
         out.push_str(
-            rewrite_css_urls(base_url, origin, store, &css[last..m.start()])
-                .await?
-                .as_str(),
+            &rewrite_segment(base_url, origin, store, state, &css[last..m.start()]).await?,
         );
 
         let url_raw = caps
@@ -180,55 +725,77 @@ async fn inline_imports_and_rewrite_urls(
             .or_else(|| caps.name("u2"))
             .map(|m| m.as_str().trim())
             .unwrap_or_default();
-        let media = caps.name("media").map(|m| m.as_str().trim()).unwrap_or("");
+        let prelude = caps.name("media").map(|m| m.as_str().trim()).unwrap_or("");
+        let Some(conditions) = ImportConditions::parse(prelude) else {
+            tracing::warn!(import = %m.as_str(), "can't parse @import conditions; leaving it as is");
+            out.push_str(m.as_str());
+            last = m.end();
+            continue;
+        };
 
         let imported_origin = resolve_import_origin(base_url, origin, url_raw)
             .with_context(|| format!("resolve @import {}", url_raw))?;
-        let imported_css = load_css_recursive(base_url, imported_origin, store, visited).await?;
-
-        if media.is_empty() {
-            out.push_str(&imported_css);
-        } else {
-            out.push_str("@media ");
-            out.push_str(media);
-            out.push_str(" {");
-            out.push_str(&imported_css);
-            out.push_str("}\n");
-        }
+        let imported_css = load_css_recursive(base_url, imported_origin, store, state).await?;
+        out.push_str(&conditions.wrap(&imported_css));
 
         last = m.end();
     }
 
-    out.push_str(
-        rewrite_css_urls(base_url, origin, store, &css[last..])
-            .await?
-            .as_str(),
-    );
+    out.push_str(&rewrite_segment(base_url, origin, store, state, &css[last..]).await?);
     Ok(out)
 }
 
-async fn rewrite_css_urls(
+/// [`rewrite_css_urls`], except that under `--purge-css` unused style rules are dropped first, and under
+/// `--prune-fonts` each `@font-face` rule is set aside for [`restore_used_font_faces`] and left as a placeholder
+/// comment.
+async fn rewrite_segment(
     base_url: &Url,
     origin: &CssOrigin,
     store: &AssetStore,
+    state: &mut BundleState<'_>,
     css: &str,
 ) -> anyhow::Result<String> {
-    let url_re =
-        Regex::new(r#"url\(\s*(?:(?:"(?P<u_d>[^"]+)"|'(?P<u_s>[^']+)'|(?P<u2>[^)]+)))\s*\)"#)
-            .expect("url regex");
+    let purged;
+    let css = match state.purger {
+        Some(purger) => {
+            purged = purger.purge(css, &mut state.purged);
+            purged.as_str()
+        }
+        None => css,
+    };
+    let Some(deferred) = state.deferred_font_faces.as_mut() else {
+        return rewrite_css_urls(base_url, origin, store, state.embedded, css).await;
+    };
+    let mut rest = String::with_capacity(css.len());
+    let mut last = 0usize;
+    for (start, end) in font_face_blocks(css) {
+        rest.push_str(&css[last..start]);
+        rest.push_str(&format!("/* dtr-font-face {} */", deferred.len()));
+        deferred.push(DeferredFontFace {
+            origin: origin.clone(),
+            css: css[start..end].to_string(),
+        });
+        last = end;
+    }
+    rest.push_str(&css[last..]);
+    rewrite_css_urls(base_url, origin, store, state.embedded, &rest).await
+}
 
+async fn rewrite_css_urls(
+    base_url: &Url,
+    origin: &CssOrigin,
+    store: &AssetStore,
+    embedded: bool,
+    css: &str,
+) -> anyhow::Result<String> {
+    let css = &keep_best_font_sources(&expand_image_sets(css));
     let mut out = String::with_capacity(css.len());
     let mut last = 0usize;
-    for caps in url_re.captures_iter(css) {
+    for caps in URL_RE.captures_iter(css) {
         let m = caps.get(0).expect("match");
         out.push_str(&css[last..m.start()]);
 
-        let url_raw = caps
-            .name("u_d")
-            .or_else(|| caps.name("u_s"))
-            .or_else(|| caps.name("u2"))
-            .map(|m| m.as_str().trim().trim_matches('"').trim_matches('\''))
-            .unwrap_or_default();
+        let url_raw = url_arg(&caps);
 
         if is_non_fetchable_url(url_raw) {
             out.push_str(m.as_str());
@@ -236,15 +803,25 @@ async fn rewrite_css_urls(
             continue;
         }
 
-        let resolved = resolve_css_url(base_url, origin, url_raw)
-            .with_context(|| format!("resolve css url {}", url_raw))?;
+        let resolved = urlres::resolve(
+            base_url,
+            origin.document(base_url),
+            url_raw,
+            urlres::FETCHABLE,
+        )
+        .with_context(|| format!("resolve css url {}", url_raw))?;
         let kind = guess_asset_kind(&resolved, url_raw);
+        // What the stylesheet links to when the asset is not downloaded.
+        let original = match &resolved {
+            Resolved::Remote(url) => url.to_string(),
+            Resolved::Local(_) => url_raw.to_string(),
+        };
         let req = match resolved {
-            ResolvedAsset::Remote(url) => AssetRequest {
+            Resolved::Remote(url) => AssetRequest {
                 kind,
                 source: AssetSource::Remote(url),
             },
-            ResolvedAsset::Local(path) => AssetRequest {
+            Resolved::Local(path) => AssetRequest {
                 kind,
                 source: AssetSource::Local(path),
             },
@@ -252,6 +829,15 @@ async fn rewrite_css_urls(
 
         let replacement = match store.get(req).await {
             Ok(v) => v,
+            // Over `--max-asset-size`, skipped by `--skip-large-assets` or left out by the download budget: the
+            // stylesheet keeps pointing at the original, as a post links to it.
+            Err(e)
+                if e.is::<AssetTooLarge>()
+                    || e.is::<LargeAssetSkipped>()
+                    || e.is::<OverBudget>() =>
+            {
+                original
+            }
             Err(e) => {
                 if matches!(kind, AssetKind::Font) {
                     tracing::warn!(error = %e, url = %url_raw, "font download failed; falling back");
@@ -263,11 +849,12 @@ async fn rewrite_css_urls(
             }
         };
 
-        let replacement = if matches!(store.output_mode(), crate::assets::OutputMode::Dir) {
-            relativize_for_bundled_css(&replacement, store.assets_dir_name())
-        } else {
-            replacement
-        };
+        let replacement =
+            if !embedded && matches!(store.output_mode(), crate::assets::OutputMode::Dir) {
+                relativize_for_bundled_css(&replacement, store.assets_dir_name())
+            } else {
+                replacement
+            };
 
         out.push_str("url(\"");
         out.push_str(&escape_double_quotes(&replacement));
@@ -280,62 +867,369 @@ async fn rewrite_css_urls(
     Ok(out)
 }
 
-#[derive(Debug)]
-enum ResolvedAsset {
-    Remote(Url),
-    Local(PathBuf),
+/// What follows the URL of an `@import`: `[layer | layer(name)] [supports(condition)] [media queries]`.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ImportConditions {
+    /// `Some("")` for an anonymous `layer`.
+    layer: Option<String>,
+    supports: Option<String>,
+    media: Option<String>,
 }
 
-fn resolve_import_origin(
-    base_url: &Url,
-    origin: &CssOrigin,
-    raw: &str,
-) -> anyhow::Result<CssOrigin> {
-    if raw.starts_with("http://") || raw.starts_with("https://") {
-        return Ok(CssOrigin::Remote(Url::parse(raw)?));
+impl ImportConditions {
+    /// `None` when the prelude is out of order or unbalanced, so the import is better left alone than rewritten
+    /// into something else.
+    fn parse(prelude: &str) -> Option<Self> {
+        let mut out = Self::default();
+        let mut rest = prelude.trim();
+        if let Some((name, tail)) = import_function(rest, "layer")? {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || "-_.".contains(c))
+            {
+                return None;
+            }
+            out.layer = Some(name.to_string());
+            rest = tail;
+        } else if let Some(tail) = strip_keyword(rest, "layer") {
+            out.layer = Some(String::new());
+            rest = tail;
+        }
+        if let Some((condition, tail)) = import_function(rest, "supports")? {
+            if condition.is_empty() {
+                return None;
+            }
+            out.supports = Some(condition.to_string());
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            let lower = rest.to_ascii_lowercase();
+            if lower.contains("layer")
+                || lower.contains("supports(")
+                || closing_paren_balance(rest) != 0
+            {
+                return None;
+            }
+            out.media = Some(rest.to_string());
+        }
+        Some(out)
     }
-    if raw.starts_with("//") {
-        let u = Url::parse(&format!("{}:{}", base_url.scheme(), raw))?;
-        return Ok(CssOrigin::Remote(u));
+
+    /// `css` nested in `@media`, `@supports` and `@layer`, outermost first, as the import would have applied them.
+    fn wrap(&self, css: &str) -> String {
+        let mut out = css.to_string();
+        if let Some(layer) = &self.layer {
+            out = if layer.is_empty() {
+                format!("@layer {{\n{out}}}\n")
+            } else {
+                format!("@layer {layer} {{\n{out}}}\n")
+            };
+        }
+        if let Some(condition) = &self.supports {
+            out = format!("@supports {} {{\n{out}}}\n", supports_condition(condition));
+        }
+        if let Some(media) = &self.media {
+            out = format!("@media {media} {{\n{out}}}\n");
+        }
+        out
     }
-    if raw.starts_with('/') {
-        return match origin {
-            CssOrigin::Remote(url) => Ok(CssOrigin::Remote(url.join(raw)?)),
-            CssOrigin::Local(_) => Ok(CssOrigin::Remote(base_url.join(raw)?)),
-        };
+}
+
+/// `name(args) tail` at the start of `s` (name in any case): `Some(Some((args, tail)))`; `Some(None)` when `s`
+/// starts with something else; `None` when the parentheses don't close.
+fn import_function<'a>(s: &'a str, name: &str) -> Option<Option<(&'a str, &'a str)>> {
+    let open = name.len();
+    let matches =
+        s.get(..open).is_some_and(|h| h.eq_ignore_ascii_case(name)) && s[open..].starts_with('(');
+    if !matches {
+        return Some(None);
     }
+    let close = closing_paren(s, open)?;
+    Some(Some((
+        s[open + 1..close].trim(),
+        s[close + 1..].trim_start(),
+    )))
+}
 
-    match origin {
-        CssOrigin::Local(path) => {
-            let base = path.parent().unwrap_or(Path::new("."));
-            Ok(CssOrigin::Local(base.join(raw)))
+/// The rest of `s` after a standalone `keyword` (any case).
+fn strip_keyword<'a>(s: &'a str, keyword: &str) -> Option<&'a str> {
+    let head = s.get(..keyword.len())?;
+    let tail = &s[keyword.len()..];
+    (head.eq_ignore_ascii_case(keyword) && tail.chars().next().is_none_or(char::is_whitespace))
+        .then(|| tail.trim_start())
+}
+
+/// `supports(display: grid)` holds a bare declaration, which `@supports` wants in parentheses; conditions such as
+/// `(a: b) and (c: d)` or `selector(:has(a))` are used as they are.
+fn supports_condition(condition: &str) -> String {
+    let is_declaration = condition.split_once(':').is_some_and(|(property, _)| {
+        let property = property.trim();
+        !property.is_empty() && property.chars().all(|c| c.is_alphanumeric() || c == '-')
+    });
+    if is_declaration {
+        format!("({condition})")
+    } else {
+        condition.to_string()
+    }
+}
+
+/// Opening minus closing parentheses outside strings.
+fn closing_paren_balance(s: &str) -> i64 {
+    let mut balance = 0i64;
+    let mut i = 0usize;
+    while let Some(c) = s[i..].chars().next() {
+        match c {
+            '"' | '\'' => {
+                i = string_end(s, i, c);
+                continue;
+            }
+            '(' => balance += 1,
+            ')' => balance -= 1,
+            _ => {}
         }
-        CssOrigin::Remote(url) => Ok(CssOrigin::Remote(url.join(raw)?)),
+        i += c.len_utf8();
     }
+    balance
 }
 
-fn resolve_css_url(base_url: &Url, origin: &CssOrigin, raw: &str) -> anyhow::Result<ResolvedAsset> {
-    if raw.starts_with("http://") || raw.starts_with("https://") {
-        return Ok(ResolvedAsset::Remote(Url::parse(raw)?));
+/// `image-set(` and `-webkit-image-set(`, any case.
+static IMAGE_SET_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)(?:-webkit-)?image-set\(").expect("image-set regex"));
+
+/// Rewrite every declaration that uses `image-set()` so [`URL_RE`] sees all of its images: string candidates
+/// (`"a.png" 1x`) become `url("a.png") 1x`, and a copy of the declaration with each `image-set()` replaced by its
+/// 1x image goes in front of it for browsers that don't support the function.
+fn expand_image_sets(css: &str) -> String {
+    let comments = comment_ranges(css);
+    let mut out = String::with_capacity(css.len());
+    let mut last = 0usize;
+    while let Some(m) = IMAGE_SET_RE.find_at(css, last) {
+        if let Some(&(_, end)) = comments.iter().find(|(s, e)| (*s..*e).contains(&m.start())) {
+            out.push_str(&css[last..end]);
+            last = end;
+            continue;
+        }
+        let after_comment = comments
+            .iter()
+            .map(|&(_, e)| e)
+            .filter(|&e| e <= m.start())
+            .max()
+            .unwrap_or(0);
+        let start = css[..m.start()]
+            .rfind(['{', '}', ';'])
+            .map_or(0, |i| i + 1)
+            .max(after_comment)
+            .max(last);
+        let end = declaration_end(css, m.start());
+        let declaration = &css[start..end];
+        out.push_str(&css[last..start]);
+        let (normalized, fallback) = split_image_sets(declaration);
+        if declaration.contains(':') {
+            out.push_str(fallback.trim_end());
+            out.push(';');
+        }
+        out.push_str(&normalized);
+        last = end;
     }
-    if raw.starts_with("//") {
-        let u = Url::parse(&format!("{}:{}", base_url.scheme(), raw))?;
-        return Ok(ResolvedAsset::Remote(u));
+    out.push_str(&css[last..]);
+    out
+}
+
+/// Byte ranges of the `/* ... */` comments in `css`.
+fn comment_ranges(css: &str) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut from = 0usize;
+    while let Some(open) = css[from..].find("/*") {
+        let start = from + open;
+        let end = css[start + 2..]
+            .find("*/")
+            .map_or(css.len(), |e| start + 2 + e + 2);
+        ranges.push((start, end));
+        from = end;
     }
-    if raw.starts_with('/') {
-        return match origin {
-            CssOrigin::Remote(url) => Ok(ResolvedAsset::Remote(url.join(raw)?)),
-            CssOrigin::Local(_) => Ok(ResolvedAsset::Remote(base_url.join(raw)?)),
+    ranges
+}
+
+/// The declaration with its `image-set()` candidates normalized, and the same declaration with each `image-set()`
+/// replaced by its 1x image (or its first one).
+fn split_image_sets(declaration: &str) -> (String, String) {
+    let (mut normalized, mut fallback) = (String::new(), String::new());
+    let mut last = 0usize;
+    for m in IMAGE_SET_RE.find_iter(declaration) {
+        if m.start() < last {
+            continue;
+        }
+        let Some(close) = closing_paren(declaration, m.end() - 1) else {
+            break;
         };
+        let head = &declaration[last..m.start()];
+        normalized.push_str(head);
+        fallback.push_str(head);
+
+        let candidates: Vec<(String, &str)> = split_top_level(&declaration[m.end()..close], ',')
+            .into_iter()
+            .map(|candidate| image_set_candidate(candidate.trim()))
+            .filter(|(image, _)| !image.is_empty())
+            .collect();
+        let list: Vec<String> = candidates
+            .iter()
+            .map(|(image, descriptors)| {
+                if descriptors.is_empty() {
+                    image.clone()
+                } else {
+                    format!("{} {}", image, descriptors)
+                }
+            })
+            .collect();
+        normalized.push_str(m.as_str());
+        normalized.push_str(&list.join(", "));
+        normalized.push(')');
+        let one_x = candidates
+            .iter()
+            .find(|(_, d)| {
+                !d.split_whitespace().any(is_resolution) || d.split_whitespace().any(|t| t == "1x")
+            })
+            .or(candidates.first());
+        match one_x {
+            Some((image, _)) => fallback.push_str(image),
+            None => fallback.push_str(&declaration[m.start()..=close]),
+        }
+        last = close + 1;
     }
+    normalized.push_str(&declaration[last..]);
+    fallback.push_str(&declaration[last..]);
+    (normalized, fallback)
+}
 
-    match origin {
-        CssOrigin::Local(path) => {
-            let base = path.parent().unwrap_or(Path::new("."));
-            Ok(ResolvedAsset::Local(base.join(raw)))
+/// The image of one `image-set()` candidate (a string turned into `url()`) and its descriptors, e.g.
+/// `2x type("image/avif")`.
+fn image_set_candidate(candidate: &str) -> (String, &str) {
+    let image_end = match candidate.chars().next() {
+        Some(q @ ('"' | '\'')) => string_end(candidate, 0, q),
+        _ => match candidate.find(|c: char| c == '(' || c.is_whitespace()) {
+            Some(i) if candidate[i..].starts_with('(') => {
+                closing_paren(candidate, i).map_or(candidate.len(), |c| c + 1)
+            }
+            Some(i) => i,
+            None => candidate.len(),
+        },
+    };
+    let (image, descriptors) = candidate.split_at(image_end);
+    let image = if image.starts_with(['"', '\'']) {
+        format!("url({})", image)
+    } else {
+        image.to_string()
+    };
+    (image, descriptors.trim())
+}
+
+fn is_resolution(token: &str) -> bool {
+    let unit = token.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+    unit.len() < token.len()
+        && matches!(
+            unit.to_ascii_lowercase().as_str(),
+            "x" | "dppx" | "dpi" | "dpcm"
+        )
+}
+
+/// Byte offset just past the string starting with `quote` at `start`.
+fn string_end(s: &str, start: usize, quote: char) -> usize {
+    let mut chars = s[start + 1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            c if c == quote || c == '\n' => return start + 1 + i + c.len_utf8(),
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+/// Offset of the `)` matching the `(` at `open`, skipping strings and nested parentheses.
+fn closing_paren(s: &str, open: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut i = open;
+    while let Some(c) = s[i..].chars().next() {
+        match c {
+            '"' | '\'' => {
+                i = string_end(s, i, c);
+                continue;
+            }
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
         }
-        CssOrigin::Remote(url) => Ok(ResolvedAsset::Remote(url.join(raw)?)),
+        i += c.len_utf8();
     }
+    None
+}
+
+/// Offset of the `;` or `}` ending the declaration that contains `from`, or the end of `css`.
+fn declaration_end(css: &str, from: usize) -> usize {
+    let mut i = from;
+    while let Some(c) = css[i..].chars().next() {
+        match c {
+            '"' | '\'' => {
+                i = string_end(css, i, c);
+                continue;
+            }
+            '(' => {
+                i = closing_paren(css, i).map_or(css.len(), |c| c + 1);
+                continue;
+            }
+            ';' | '}' => return i,
+            _ => {}
+        }
+        i += c.len_utf8();
+    }
+    css.len()
+}
+
+/// `s` split on `separator` outside strings and parentheses.
+fn split_top_level(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut i) = (0usize, 0usize);
+    while let Some(c) = s[i..].chars().next() {
+        match c {
+            '"' | '\'' => {
+                i = string_end(s, i, c);
+                continue;
+            }
+            '(' => {
+                i = closing_paren(s, i).map_or(s.len(), |c| c + 1);
+                continue;
+            }
+            c if c == separator => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+        i += c.len_utf8();
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn resolve_import_origin(
+    base_url: &Url,
+    origin: &CssOrigin,
+    raw: &str,
+) -> anyhow::Result<CssOrigin> {
+    Ok(
+        match urlres::resolve(base_url, origin.document(base_url), raw, urlres::FETCHABLE)? {
+            Resolved::Remote(url) => CssOrigin::Remote(url),
+            Resolved::Local(path) => CssOrigin::Local(path),
+        },
+    )
 }
 
 fn is_non_fetchable_url(url: &str) -> bool {
@@ -364,15 +1258,15 @@ fn relativize_for_bundled_css(replacement: &str, assets_dir_name: &str) -> Strin
     replacement.to_string()
 }
 
-fn guess_asset_kind(resolved: &ResolvedAsset, raw: &str) -> AssetKind {
+fn guess_asset_kind(resolved: &Resolved, raw: &str) -> AssetKind {
     let ext = match resolved {
-        ResolvedAsset::Remote(url) => url
+        Resolved::Remote(url) => url
             .path()
             .rsplit('.')
             .next()
             .unwrap_or("")
             .to_ascii_lowercase(),
-        ResolvedAsset::Local(path) => path
+        Resolved::Local(path) => path
             .extension()
             .and_then(|s| s.to_str())
             .unwrap_or("")
@@ -383,7 +1277,7 @@ fn guess_asset_kind(resolved: &ResolvedAsset, raw: &str) -> AssetKind {
     }
     if matches!(
         ext.as_str(),
-        "png" | "jpg" | "jpeg" | "gif" | "webp" | "svg" | "avif"
+        "png" | "jpg" | "jpeg" | "gif" | "webp" | "svg" | "avif" | "cur" | "ico"
     ) {
         return AssetKind::Image;
     }
@@ -392,3 +1286,154 @@ fn guess_asset_kind(resolved: &ResolvedAsset, raw: &str) -> AssetKind {
     }
     AssetKind::Other
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_pattern_handles_quoting_whitespace_and_data_uris() {
+        let css = r#"a { b: url("x.png"); c: url( 'y y.png' ); d: url(  z.png  ); e: url(data:image/png;base64,AAAA); f: url( " w.png " ) }"#;
+        let args: Vec<&str> = URL_RE.captures_iter(css).map(|c| url_arg(&c)).collect();
+        assert_eq!(
+            args,
+            [
+                "x.png",
+                "y y.png",
+                "z.png",
+                "data:image/png;base64,AAAA",
+                "w.png"
+            ]
+        );
+
+        let imports: Vec<(&str, &str)> = IMPORT_RE
+            .captures_iter(r#"@import "a.css"; @import url('b.css') print; @import url(c.css);"#)
+            .map(|c| (url_arg(&c), c.name("media").map_or("", |m| m.as_str())))
+            .collect();
+        assert_eq!(imports, [("a.css", ""), ("b.css", "print"), ("c.css", "")]);
+    }
+
+    #[test]
+    fn image_sets_expose_every_candidate_and_get_a_fallback() {
+        assert_eq!(
+            expand_image_sets(
+                "a { b: 1; background-image: image-set(\"a.png\" 1x, 'b.png' 2x type(\"image/png\")) }"
+            ),
+            "a { b: 1; background-image: url(\"a.png\"); background-image: image-set(url(\"a.png\") 1x, \
+             url('b.png') 2x type(\"image/png\")) }"
+        );
+        assert_eq!(
+            expand_image_sets(
+                "a{b:-webkit-image-set(url(x.png) 2x,url(data:image/png;base64,AA==) 1x) !important}"
+            ),
+            "a{b:url(data:image/png;base64,AA==) !important;\
+             b:-webkit-image-set(url(x.png) 2x, url(data:image/png;base64,AA==) 1x) !important}"
+        );
+        let plain = "a { cursor: url(c.cur) 4 12, pointer; }";
+        assert_eq!(expand_image_sets(plain), plain);
+    }
+
+    #[test]
+    fn font_faces_keep_local_sources_and_the_best_url() {
+        assert_eq!(
+            keep_best_font_sources(
+                "@font-face { font-family: A; src: local(\"A\"), url(a.ttf) format(\"truetype\"), \
+                 url(a.woff) format('woff'), local(A-Regular), url(a.woff2) format(woff2); }"
+            ),
+            "@font-face { font-family: A; src: local(\"A\"), local(A-Regular), url(a.woff2) format(woff2); }"
+        );
+        // The IE hack: an `.eot` src, then a list led by the same `.eot` with a query string.
+        assert_eq!(
+            keep_best_font_sources(
+                "@font-face{font-family:B;src:url(b.eot);\n  src:url(b.eot?#iefix) format(\"embedded-opentype\"),\
+                 url(b.woff) format(\"woff\"),url(b.svg#B) format(\"svg\");font-weight:700}"
+            ),
+            "@font-face{font-family:B;\n  src:url(b.woff) format(\"woff\");font-weight:700}"
+        );
+        // Without `format()`, the extension decides; unsupported-only lists and other rules are untouched.
+        assert_eq!(
+            keep_best_font_sources("@font-face{src:url('c.otf?v=2'),url(\"c.woff2#x\")}"),
+            "@font-face{src:url(\"c.woff2#x\")}"
+        );
+        let eot_only = "@font-face{src:url(d.eot)} a{src:url(e.ttf),url(e.woff2)}";
+        assert_eq!(keep_best_font_sources(eot_only), eot_only);
+    }
+
+    #[test]
+    fn import_conditions_become_nested_blocks() {
+        let conditions = |prelude: &str| ImportConditions::parse(prelude);
+        let wrapped = |prelude: &str| conditions(prelude).unwrap().wrap("a{}");
+
+        assert_eq!(conditions(""), Some(ImportConditions::default()));
+        assert_eq!(wrapped(""), "a{}");
+        assert_eq!(wrapped("print"), "@media print {\na{}}\n");
+        assert_eq!(wrapped("layer(theme)"), "@layer theme {\na{}}\n");
+        assert_eq!(wrapped("LAYER"), "@layer {\na{}}\n");
+        assert_eq!(
+            wrapped("layer screen"),
+            "@media screen {\n@layer {\na{}}\n}\n"
+        );
+        assert_eq!(
+            wrapped("supports(display: grid)"),
+            "@supports (display: grid) {\na{}}\n"
+        );
+        assert_eq!(
+            wrapped("supports(selector(:has(a)))"),
+            "@supports selector(:has(a)) {\na{}}\n"
+        );
+        assert_eq!(
+            wrapped("supports((display: grid) and (gap: 1em)) print"),
+            "@media print {\n@supports (display: grid) and (gap: 1em) {\na{}}\n}\n"
+        );
+        assert_eq!(
+            wrapped("layer(base.reset) supports(display: grid) screen and (min-width: 600px)"),
+            "@media screen and (min-width: 600px) {\n@supports (display: grid) {\n\
+             @layer base.reset {\na{}}\n}\n}\n"
+        );
+
+        for malformed in [
+            "layer()",
+            "layer(a b)",
+            "layer(theme",
+            "supports()",
+            "supports(display: grid",
+            "screen layer(theme)",
+            "print supports(display: grid)",
+            "screen and (min-width: 600px",
+        ] {
+            assert_eq!(conditions(malformed), None, "{malformed}");
+        }
+    }
+
+    #[test]
+    fn font_families_are_found_in_declarations_and_html() {
+        let css = "/* font-family: Commented */ body { font: bold 14px/1.2 Open Sans, 'Noto Serif', serif; }\n\
+                   :root { --heading-font-family: \"Inter\" !important }\n\
+                   @font-face { font-family: Unused; src: url(u.woff2); }";
+        let families = font_families_in_css(css);
+        for used in ["open sans", "noto serif", "serif", "inter"] {
+            assert!(families.contains(used), "{used}: {families:?}");
+        }
+        assert!(!families.contains("unused"));
+        assert!(!families.contains("commented"));
+
+        let html = r#"<p style="color: red; font-family: &quot;Comic Neue&quot;">a</p><font face="Lobster, cursive">b</font>"#;
+        let families = font_families_in_html(html);
+        for used in ["comic neue", "lobster", "cursive"] {
+            assert!(families.contains(used), "{used}: {families:?}");
+        }
+
+        let blocks = font_face_blocks(css);
+        assert_eq!(blocks.len(), 1);
+        let rule = &css[blocks[0].0..blocks[0].1];
+        assert_eq!(
+            rule,
+            "@font-face { font-family: Unused; src: url(u.woff2); }"
+        );
+        assert_eq!(font_face_family(rule).as_deref(), Some("unused"));
+        assert_eq!(
+            font_face_family("@font-face{font-family:\"Open  Sans\";}").as_deref(),
+            Some("open sans")
+        );
+    }
+}
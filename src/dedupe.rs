@@ -0,0 +1,156 @@
+//! `--dedupe-css`: drop rule blocks that a bundle of overlapping stylesheets repeats byte for byte.
+//!
+//! Of identical blocks in the same context (the same chain of enclosing `@media`, `@supports`, … preludes) only
+//! the last one is kept. The last copy is the one that wins the cascade, so removing the earlier ones never changes
+//! which declaration applies, even with an override in between. Blocks that differ in any byte — the same selector
+//! with other declarations included — are left alone.
+
+use std::collections::HashMap;
+
+use cssparser::{ParseError, Parser, ParserInput, Token};
+
+use crate::purge::GROUPING_AT_RULES;
+
+/// Rule blocks removed and the bytes they took.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DedupeStats {
+    pub rules_removed: usize,
+    pub bytes_saved: usize,
+}
+
+/// A complete rule block (style rule or non-grouping at-rule) and the context it applies in.
+struct Block {
+    start: usize,
+    end: usize,
+    context: String,
+}
+
+/// `css` without the rule blocks that occur again later in the same context. Everything else, comments included,
+/// is copied through byte for byte.
+pub fn dedupe(css: &str) -> (String, DedupeStats) {
+    let mut input = ParserInput::new(css);
+    let mut parser = Parser::new(&mut input);
+    let mut blocks = Vec::new();
+    let mut anonymous_layers = 0usize;
+    collect_blocks(&mut parser, "", &mut anonymous_layers, &mut blocks);
+
+    let mut last = HashMap::new();
+    for (i, block) in blocks.iter().enumerate() {
+        last.insert((block.context.as_str(), &css[block.start..block.end]), i);
+    }
+
+    let mut stats = DedupeStats::default();
+    let mut out = String::with_capacity(css.len());
+    let mut copied = 0usize;
+    for (i, block) in blocks.iter().enumerate() {
+        if last[&(block.context.as_str(), &css[block.start..block.end])] != i {
+            out.push_str(&css[copied..block.start]);
+            copied = block.end;
+            stats.rules_removed += 1;
+            stats.bytes_saved += block.end - block.start;
+        }
+    }
+    out.push_str(&css[copied..]);
+    (out, stats)
+}
+
+/// The rule blocks of a rule list, descending into grouping at-rules with their prelude added to `context`.
+fn collect_blocks(
+    parser: &mut Parser<'_, '_>,
+    context: &str,
+    anonymous_layers: &mut usize,
+    blocks: &mut Vec<Block>,
+) {
+    loop {
+        let start = parser.position();
+        let (at_rule, mut has_block) = match parser.next_including_whitespace_and_comments() {
+            Err(_) => return,
+            Ok(Token::WhiteSpace(_) | Token::Comment(_) | Token::Semicolon) => continue,
+            Ok(Token::AtKeyword(name)) => (Some(name.to_ascii_lowercase()), false),
+            Ok(Token::CurlyBracketBlock) => (None, true),
+            Ok(_) => (None, false),
+        };
+        while !has_block {
+            match parser.next_including_whitespace_and_comments() {
+                Err(_) | Ok(Token::Semicolon) => break,
+                Ok(Token::CurlyBracketBlock) => {
+                    has_block = true;
+                    break;
+                }
+                Ok(_) => {}
+            }
+        }
+        if !has_block {
+            continue;
+        }
+        let prelude = parser.slice_from(start);
+        let prelude = prelude[..prelude.len() - 1].trim();
+        match at_rule {
+            Some(name) if GROUPING_AT_RULES.contains(&name.as_str()) => {
+                // Every `@layer { … }` without a name is a layer of its own.
+                let inner = match (name.as_str(), prelude.eq_ignore_ascii_case("@layer")) {
+                    ("layer", true) => {
+                        *anonymous_layers += 1;
+                        format!("{context}\n@layer #{anonymous_layers}")
+                    }
+                    _ => format!("{context}\n{prelude}"),
+                };
+                let _ = parser.parse_nested_block(|nested| {
+                    collect_blocks(nested, &inner, anonymous_layers, blocks);
+                    Ok::<_, ParseError<'_, ()>>(())
+                });
+            }
+            _ => {
+                let _ = parser.parse_nested_block(|nested| {
+                    while nested.next_including_whitespace_and_comments().is_ok() {}
+                    Ok::<_, ParseError<'_, ()>>(())
+                });
+                let end = parser.position();
+                // A block cut off by the end of the input is kept.
+                if parser.slice(start..end).ends_with('}') {
+                    blocks.push(Block {
+                        start: start.byte_index(),
+                        end: end.byte_index(),
+                        context: context.to_string(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_last_identical_block_of_a_context_is_kept() {
+        let css = ".a { color: red }\n\
+            .b { color: blue }\n\
+            @media print { .a { color: red } }\n\
+            .a { color: red }\n\
+            @layer { .c { margin: 0 } }\n\
+            @layer { .c { margin: 0 } }\n\
+            @media print { .a { color: red } }\n\
+            .b { color: green }";
+        let (deduped, stats) = dedupe(css);
+        assert_eq!(
+            deduped,
+            "\n\
+            .b { color: blue }\n\
+            @media print {  }\n\
+            .a { color: red }\n\
+            @layer { .c { margin: 0 } }\n\
+            @layer { .c { margin: 0 } }\n\
+            @media print { .a { color: red } }\n\
+            .b { color: green }"
+        );
+        assert_eq!(
+            stats,
+            DedupeStats {
+                rules_removed: 2,
+                bytes_saved: 2 * ".a { color: red }".len(),
+            }
+        );
+    }
+}
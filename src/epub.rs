@@ -0,0 +1,390 @@
+//! `--mode epub`: the rendered posts packed as an EPUB 3 book. Chapters are the posts serialized as XHTML, the
+//! assets are the files a dir-mode render writes, and the container is a plain ZIP archive.
+
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use kuchiki::traits::TendrilSink as _;
+use maud::{Markup, html};
+
+use crate::output;
+use crate::xml::{escape_xml, escape_xml_into};
+
+/// Folder of the book's content inside the archive.
+const CONTENT_DIR: &str = "OEBPS";
+
+const CONTAINER_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+const XHTML_NS: &str = "http://www.w3.org/1999/xhtml";
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const MATHML_NS: &str = "http://www.w3.org/1998/Math/MathML";
+
+pub struct Chapter {
+    /// File name in the book; the pages of `--paginate` are named alike, so links between posts resolve.
+    pub file_name: String,
+    pub title: String,
+    /// XHTML, from [`to_xhtml`].
+    pub content: String,
+}
+
+pub struct Book<'a> {
+    /// `dc:identifier`, e.g. the topic's forum URL.
+    pub identifier: String,
+    pub title: &'a str,
+    /// BCP 47 tag.
+    pub language: String,
+    /// `dcterms:modified`, `YYYY-MM-DDThh:mm:ssZ`.
+    pub modified: String,
+    pub chapters: Vec<Chapter>,
+    /// Directory the assets were downloaded to; `{assets_root}/{assets_dir_name}` goes into the book as is.
+    pub assets_root: &'a Path,
+    pub assets_dir_name: &'a str,
+    /// Path of the cover image relative to the chapters, as the posts refer to it.
+    pub cover: Option<String>,
+}
+
+/// Write `book` to `path` as an `.epub`.
+pub fn write(book: &Book<'_>, path: &Path) -> anyhow::Result<()> {
+    let assets = asset_files(&book.assets_root.join(book.assets_dir_name))?
+        .into_iter()
+        .map(|rel| format!("{}/{}", book.assets_dir_name, rel))
+        .collect::<Vec<_>>();
+
+    let file = std::fs::File::create(path).map_err(|e| output::classify("create", path, e))?;
+    let mut zip = zip::ZipWriter::new(std::io::BufWriter::new(file));
+    // The OCF spec wants `mimetype` first and stored, so the format can be sniffed from the archive's first bytes.
+    add(&mut zip, "mimetype", b"application/epub+zip", false)?;
+    add(
+        &mut zip,
+        "META-INF/container.xml",
+        CONTAINER_XML.as_bytes(),
+        true,
+    )?;
+    add(
+        &mut zip,
+        &format!("{CONTENT_DIR}/content.opf"),
+        package_document(book, &assets).as_bytes(),
+        true,
+    )?;
+    add(
+        &mut zip,
+        &format!("{CONTENT_DIR}/nav.xhtml"),
+        to_xhtml(&nav_document(book).into_string()).as_bytes(),
+        true,
+    )?;
+    for chapter in &book.chapters {
+        add(
+            &mut zip,
+            &format!("{CONTENT_DIR}/{}", chapter.file_name),
+            chapter.content.as_bytes(),
+            true,
+        )?;
+    }
+    for rel in &assets {
+        let abs = book.assets_root.join(rel);
+        let bytes = std::fs::read(&abs).with_context(|| format!("read {}", abs.display()))?;
+        add(
+            &mut zip,
+            &format!("{CONTENT_DIR}/{rel}"),
+            &bytes,
+            !is_compressed(rel),
+        )?;
+    }
+    zip.finish()
+        .with_context(|| format!("write {}", path.display()))?
+        .flush()
+        .map_err(|e| output::classify("write", path, e))
+}
+
+/// Add `data` to the archive as `name`, deflated or stored. Entries are dated 1980-01-01, so the archive is
+/// reproducible.
+fn add<W: std::io::Write + std::io::Seek>(
+    zip: &mut zip::ZipWriter<W>,
+    name: &str,
+    data: &[u8],
+    deflate: bool,
+) -> anyhow::Result<()> {
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(match deflate {
+            true => zip::CompressionMethod::Deflated,
+            false => zip::CompressionMethod::Stored,
+        })
+        .last_modified_time(zip::DateTime::DEFAULT)
+        .large_file(data.len() as u64 >= u32::MAX as u64);
+    zip.start_file(name, options)
+        .with_context(|| format!("add {name} to the EPUB"))?;
+    zip.write_all(data)
+        .with_context(|| format!("add {name} to the EPUB"))
+}
+
+/// Files under `dir`, relative to it with `/` separators. Sorted, so the archive is reproducible.
+fn asset_files(dir: &Path) -> anyhow::Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(rel) = pending.pop() {
+        let abs = dir.join(&rel);
+        let Ok(entries) = std::fs::read_dir(&abs) else {
+            continue;
+        };
+        for entry in entries {
+            let entry = entry.with_context(|| format!("list {}", abs.display()))?;
+            let rel = rel.join(entry.file_name());
+            if entry.path().is_dir() {
+                pending.push(rel);
+            } else {
+                let parts: Vec<_> = rel
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                    .collect();
+                files.push(parts.join("/"));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// `content.opf`: metadata, every file of the book, and the chapters in reading order.
+fn package_document(book: &Book<'_>, assets: &[String]) -> String {
+    let mut manifest = String::from(
+        "    <item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n",
+    );
+    let mut spine = String::new();
+    for (i, chapter) in book.chapters.iter().enumerate() {
+        let id = format!("chapter-{}", i + 1);
+        // Inline SVG (e.g. the `--qr-permalinks` codes) has to be declared.
+        let properties = if chapter.content.contains("<svg") {
+            " properties=\"svg\""
+        } else {
+            ""
+        };
+        manifest.push_str(&format!(
+            "    <item id=\"{id}\" href=\"{}\" media-type=\"application/xhtml+xml\"{properties}/>\n",
+            escape_xml(&chapter.file_name)
+        ));
+        spine.push_str(&format!("    <itemref idref=\"{id}\"/>\n"));
+    }
+    for (i, rel) in assets.iter().enumerate() {
+        let (id, properties) = match &book.cover {
+            Some(cover) if cover == rel => {
+                ("cover-image".to_string(), " properties=\"cover-image\"")
+            }
+            _ => (format!("asset-{}", i + 1), ""),
+        };
+        manifest.push_str(&format!(
+            "    <item id=\"{id}\" href=\"{}\" media-type=\"{}\"{properties}/>\n",
+            escape_xml(rel),
+            media_type(rel)
+        ));
+    }
+    let cover_meta = match &book.cover {
+        Some(_) => "    <meta name=\"cover\" content=\"cover-image\"/>\n",
+        None => "",
+    };
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="book-id" xml:lang="{lang}">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="book-id">{identifier}</dc:identifier>
+    <dc:title>{title}</dc:title>
+    <dc:language>{lang}</dc:language>
+    <meta property="dcterms:modified">{modified}</meta>
+{cover_meta}  </metadata>
+  <manifest>
+{manifest}  </manifest>
+  <spine>
+{spine}  </spine>
+</package>
+"#,
+        lang = escape_xml(&book.language),
+        identifier = escape_xml(&book.identifier),
+        title = escape_xml(book.title),
+        modified = escape_xml(&book.modified),
+    )
+}
+
+/// `nav.xhtml`, the table of contents e-readers show: one entry per chapter.
+fn nav_document(book: &Book<'_>) -> Markup {
+    html! {
+        html lang=(book.language) {
+            head {
+                title { (book.title) }
+            }
+            body {
+                nav epub:type="toc" id="toc" {
+                    h1 { (book.title) }
+                    ol {
+                        @for chapter in &book.chapters {
+                            li { a href=(chapter.file_name) { (chapter.title) } }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn media_type(path: &str) -> &'static str {
+    let ext = path.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    match ext.as_str() {
+        "html" | "xhtml" => "application/xhtml+xml",
+        "css" => "text/css",
+        "js" => "application/javascript",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "woff2" => "font/woff2",
+        "woff" => "font/woff",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Formats that gain nothing from deflate; they are stored as is.
+fn is_compressed(path: &str) -> bool {
+    let ext = path.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    matches!(
+        ext.as_str(),
+        "png" | "jpg" | "jpeg" | "gif" | "webp" | "avif" | "woff" | "woff2"
+    )
+}
+
+/// `html` re-serialized as an XHTML document: void elements closed, every attribute quoted and escaped, the
+/// namespaces declared, comments dropped.
+pub fn to_xhtml(html: &str) -> String {
+    let document = kuchiki::parse_html().one(html);
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE html>\n");
+    if let Ok(root) = document.select_first("html") {
+        write_xhtml(root.as_node(), &mut out);
+    }
+    out.push('\n');
+    out
+}
+
+fn write_xhtml(node: &kuchiki::NodeRef, out: &mut String) {
+    if let Some(text) = node.as_text() {
+        escape_xml_into(&text.borrow(), false, out);
+        return;
+    }
+    let Some(element) = node.as_element() else {
+        return;
+    };
+    let name = element.name.local.as_ref();
+    let ns: &str = &element.name.ns;
+    // Stray `<` in the source text can parse as an element named e.g. `b<`; keep its content only.
+    if !is_xml_name(name) {
+        for child in node.children() {
+            write_xhtml(&child, out);
+        }
+        return;
+    }
+    out.push('<');
+    out.push_str(name);
+    let parent_ns = node
+        .parent()
+        .and_then(|p| p.as_element().map(|e| e.name.ns.to_string()));
+    if parent_ns.as_deref() != Some(ns) {
+        match ns {
+            XHTML_NS => out.push_str(&format!(
+                " xmlns=\"{XHTML_NS}\" xmlns:epub=\"http://www.idpf.org/2007/ops\" xmlns:xlink=\"http://www.w3.org/1999/xlink\""
+            )),
+            SVG_NS | MATHML_NS => out.push_str(&format!(" xmlns=\"{ns}\"")),
+            _ => {}
+        }
+    }
+    for (attr_name, attr) in &element.attributes.borrow().map {
+        let qualified = match &attr.prefix {
+            Some(prefix) => format!("{}:{}", prefix, attr_name.local),
+            None => attr_name.local.to_string(),
+        };
+        if !is_writable_attribute(&qualified) {
+            continue;
+        }
+        out.push(' ');
+        out.push_str(&qualified);
+        out.push_str("=\"");
+        escape_xml_into(&attr.value, true, out);
+        out.push('"');
+    }
+    if ns == XHTML_NS && is_void(name) {
+        out.push_str("/>");
+        return;
+    }
+    out.push('>');
+    for child in node.children() {
+        write_xhtml(&child, out);
+    }
+    out.push_str("</");
+    out.push_str(name);
+    out.push('>');
+}
+
+fn is_void(name: &str) -> bool {
+    matches!(
+        name,
+        "area"
+            | "base"
+            | "br"
+            | "col"
+            | "embed"
+            | "hr"
+            | "img"
+            | "input"
+            | "link"
+            | "meta"
+            | "source"
+            | "track"
+            | "wbr"
+    )
+}
+
+/// A valid XML attribute name whose prefix, if any, is one of the declared ones. Namespace declarations are
+/// written by [`write_xhtml`] itself.
+fn is_writable_attribute(name: &str) -> bool {
+    if name == "xmlns" || name.starts_with("xmlns:") {
+        return false;
+    }
+    let local = match name.split_once(':') {
+        Some(("xml" | "xlink" | "epub", local)) => local,
+        Some(_) => return false,
+        None => name,
+    };
+    is_xml_name(local)
+}
+
+/// An XML name without a prefix, restricted to the characters HTML names use in practice.
+fn is_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_becomes_well_formed_xhtml() {
+        let html = r##"<html lang="en"><head><title>A & B</title><link rel="stylesheet" href="assets/css/site.css"></head><body><!-- note --><p title='say "hi"' data-x:y="1">1 < 2<br>x&nbsp;y<img src="a.png" alt=""></p><svg viewBox="0 0 1 1"><use xlink:href="#q"></use></svg></body></html>"##;
+        assert_eq!(
+            to_xhtml(html),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE html>\n\
+             <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" lang=\"en\">\
+             <head><title>A &amp; B</title><link href=\"assets/css/site.css\" rel=\"stylesheet\"/></head>\
+             <body><p title=\"say &quot;hi&quot;\">1 &lt; 2<br/>x\u{a0}y<img alt=\"\" src=\"a.png\"/></p>\
+             <svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 1 1\"><use xlink:href=\"#q\"></use></svg></body></html>\n"
+        );
+        assert!(to_xhtml("<p><code>a<b</code></p>").contains("<p><code>a</code></p>"));
+    }
+}
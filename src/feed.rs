@@ -0,0 +1,288 @@
+//! `--rss` and `--atom`: the rendered posts as a feed, one item per post linking back to the forum.
+
+use std::fmt::Write as _;
+
+use chrono::{DateTime, FixedOffset, SecondsFormat};
+use quick_xml::Writer;
+use quick_xml::events::{BytesCData, BytesDecl, BytesText, Event};
+use url::Url;
+
+use crate::html::{self, RenderedPost};
+use crate::topic::TopicJson;
+use crate::urlres::forum_route;
+use crate::xml::xml_chars;
+
+/// Characters of post text in an item's description.
+const DESCRIPTION_CHARS: usize = 500;
+
+/// What both feed formats say about a post.
+struct PostExportItem<'a> {
+    post_number: u64,
+    /// `Post #N by user`.
+    title: String,
+    username: &'a str,
+    /// The post on the forum.
+    link: String,
+    created: Option<DateTime<FixedOffset>>,
+    /// Last edit, or creation when never edited.
+    updated: Option<DateTime<FixedOffset>>,
+    /// Plain text, cut to [`DESCRIPTION_CHARS`].
+    summary: String,
+    /// The cooked HTML as on the forum, with absolute URLs: the rendered one points into the archive.
+    html: String,
+}
+
+impl<'a> PostExportItem<'a> {
+    fn new(topic: &TopicJson, post: &'a RenderedPost, base_url: &Url) -> Self {
+        let created = post.created_at.as_deref().and_then(parse_timestamp);
+        Self {
+            post_number: post.post_number,
+            title: item_title(post),
+            username: &post.username,
+            link: forum_url(base_url, topic, Some(post.post_number)),
+            created,
+            updated: post
+                .updated_at
+                .as_deref()
+                .and_then(parse_timestamp)
+                .or(created),
+            summary: description(post),
+            html: topic
+                .post_stream
+                .posts
+                .iter()
+                .find(|p| p.post_number == post.post_number)
+                .and_then(|p| p.cooked.as_deref())
+                .map(|cooked| html::absolute_cooked(cooked, base_url))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// RSS 2.0 document of `posts`.
+pub fn rss(topic: &TopicJson, posts: &[RenderedPost], base_url: &Url) -> String {
+    let topic_url = forum_url(base_url, topic, None);
+    document(|w| {
+        w.create_element("rss")
+            .with_attribute(("version", "2.0"))
+            .write_inner_content(|w| {
+                w.create_element("channel").write_inner_content(|w| {
+                    text_element(w, "title", &topic.title)?;
+                    text_element(w, "link", &topic_url)?;
+                    text_element(w, "description", &topic.title)?;
+                    if let Some(lang) = topic.language_tag() {
+                        text_element(w, "language", &lang)?;
+                    }
+                    for p in posts {
+                        let item = PostExportItem::new(topic, p, base_url);
+                        w.create_element("item").write_inner_content(|w| {
+                            text_element(w, "title", &item.title)?;
+                            text_element(w, "link", &item.link)?;
+                            w.create_element("guid")
+                                .with_attribute(("isPermaLink", "true"))
+                                .write_text_content(BytesText::new(&xml_chars(&item.link)))?;
+                            if let Some(date) = item.created {
+                                text_element(w, "pubDate", &date.to_rfc2822())?;
+                            }
+                            text_element(w, "description", &item.summary)
+                        })?;
+                    }
+                    Ok(())
+                })?;
+                Ok(())
+            })?;
+        Ok(())
+    })
+}
+
+/// Atom 1.0 document of `posts`, with each post's HTML as the entry content. `self_href` is where the feed is
+/// written.
+pub fn atom(topic: &TopicJson, posts: &[RenderedPost], base_url: &Url, self_href: &str) -> String {
+    let items: Vec<_> = posts
+        .iter()
+        .map(|p| PostExportItem::new(topic, p, base_url))
+        .collect();
+    // The newest entry, so the feed only changes with its posts.
+    let updated = items
+        .iter()
+        .filter_map(|item| item.updated)
+        .max()
+        .or_else(|| topic.last_activity().and_then(parse_timestamp))
+        .map(atom_date)
+        .unwrap_or_else(|| "1970-01-01T00:00:00Z".to_string());
+
+    document(|w| {
+        w.create_element("feed")
+            .with_attribute(("xmlns", "http://www.w3.org/2005/Atom"))
+            .write_inner_content(|w| {
+                text_element(w, "id", &format!("urn:discourse:{}", topic.id))?;
+                text_element(w, "title", &topic.title)?;
+                text_element(w, "updated", &updated)?;
+                link_element(w, "self", "application/atom+xml", self_href)?;
+                link_element(
+                    w,
+                    "alternate",
+                    "text/html",
+                    &forum_url(base_url, topic, None),
+                )?;
+                for item in &items {
+                    w.create_element("entry").write_inner_content(|w| {
+                        text_element(
+                            w,
+                            "id",
+                            &format!("urn:discourse:{}:post_{}", topic.id, item.post_number),
+                        )?;
+                        text_element(w, "title", &item.title)?;
+                        text_element(
+                            w,
+                            "updated",
+                            item.updated.map(atom_date).as_deref().unwrap_or(&updated),
+                        )?;
+                        if let Some(created) = item.created {
+                            text_element(w, "published", &atom_date(created))?;
+                        }
+                        let author = match item.username.is_empty() {
+                            true => "unknown",
+                            false => item.username,
+                        };
+                        w.create_element("author")
+                            .write_inner_content(|w| text_element(w, "name", author))?;
+                        link_element(w, "alternate", "text/html", &item.link)?;
+                        text_element(w, "summary", &item.summary)?;
+                        html_content(w, &item.html)
+                    })?;
+                }
+                Ok(())
+            })?;
+        Ok(())
+    })
+}
+
+/// An XML document, indented by two spaces, with the root element `build` writes.
+fn document(build: impl FnOnce(&mut Writer<Vec<u8>>) -> std::io::Result<()>) -> String {
+    let mut w = Writer::new_with_indent(Vec::new(), b' ', 2);
+    w.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))
+        .and_then(|()| build(&mut w))
+        .expect("writing XML to memory cannot fail");
+    let mut out = String::from_utf8(w.into_inner()).expect("the XML writer was given only UTF-8");
+    out.push('\n');
+    out
+}
+
+fn text_element(w: &mut Writer<Vec<u8>>, name: &str, text: &str) -> std::io::Result<()> {
+    w.create_element(name)
+        .write_text_content(BytesText::new(&xml_chars(text)))?;
+    Ok(())
+}
+
+/// `<content type="html">` holding `html` as CDATA; a `]]>` inside is split across two sections.
+fn html_content(w: &mut Writer<Vec<u8>>, html: &str) -> std::io::Result<()> {
+    w.create_element("content")
+        .with_attribute(("type", "html"))
+        .write_inner_content(|w| {
+            let html = xml_chars(html);
+            for section in BytesCData::escaped(&html) {
+                w.write_event(Event::CData(section))?;
+            }
+            Ok(())
+        })?;
+    Ok(())
+}
+
+/// An Atom `<link rel type href/>`.
+fn link_element(w: &mut Writer<Vec<u8>>, rel: &str, mime: &str, href: &str) -> std::io::Result<()> {
+    w.create_element("link")
+        .with_attribute(("rel", rel))
+        .with_attribute(("type", mime))
+        .with_attribute(("href", xml_chars(href).as_ref()))
+        .write_empty()?;
+    Ok(())
+}
+
+/// `{base_url}/t/{slug}/{id}/{post_number}`, or without the slug when the export has none.
+fn forum_url(base_url: &Url, topic: &TopicJson, post_number: Option<u64>) -> String {
+    let mut path = match topic.slug.as_deref().filter(|s| !s.is_empty()) {
+        Some(slug) => format!("t/{}/{}", slug, topic.id),
+        None => format!("t/{}", topic.id),
+    };
+    if let Some(n) = post_number {
+        let _ = write!(path, "/{n}");
+    }
+    forum_route(base_url, &path)
+        .map(|u| u.to_string())
+        .unwrap_or(path)
+}
+
+fn item_title(p: &RenderedPost) -> String {
+    match p.username.is_empty() {
+        true => format!("Post #{}", p.post_number),
+        false => format!("Post #{} by {}", p.post_number, p.username),
+    }
+}
+
+/// The post's plain text, or the label of a small action, cut to [`DESCRIPTION_CHARS`].
+fn description(p: &RenderedPost) -> String {
+    let text = html::plain_text(&p.cooked_html);
+    let text = match (text.trim().is_empty(), &p.small_action) {
+        (true, Some(label)) => label.clone(),
+        _ => text,
+    };
+    html::excerpt(text.trim(), DESCRIPTION_CHARS)
+}
+
+/// A Discourse timestamp (RFC 3339).
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(timestamp).ok()
+}
+
+fn atom_date(date: DateTime<FixedOffset>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_become_rfc822_and_links_carry_the_slug() {
+        let date = parse_timestamp("2024-03-05T07:08:09.123Z").unwrap();
+        assert_eq!(date.to_rfc2822(), "Tue, 5 Mar 2024 07:08:09 +0000");
+        assert_eq!(atom_date(date), "2024-03-05T07:08:09Z");
+        assert_eq!(parse_timestamp("yesterday"), None);
+
+        let base = Url::parse("https://forum.example.com/").unwrap();
+        let mut topic: TopicJson = serde_json::from_str(
+            r#"{"id": 7, "title": "T", "slug": "a-topic", "post_stream": {"posts": []}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            forum_url(&base, &topic, Some(3)),
+            "https://forum.example.com/t/a-topic/7/3"
+        );
+        topic.slug = None;
+        assert_eq!(
+            forum_url(&base, &topic, None),
+            "https://forum.example.com/t/7"
+        );
+        let subfolder = Url::parse("https://example.com/forum/").unwrap();
+        assert_eq!(
+            forum_url(&subfolder, &topic, Some(2)),
+            "https://example.com/forum/t/7/2"
+        );
+    }
+
+    #[test]
+    fn content_cannot_close_its_cdata_early() {
+        let xml = document(|w| {
+            w.create_element("entry").write_inner_content(|w| {
+                text_element(w, "title", "a\u{1}<b>")?;
+                html_content(w, "<p>a]]>b\u{8}</p>")
+            })?;
+            Ok(())
+        });
+        assert_eq!(
+            xml,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<entry>\n  <title>a&lt;b&gt;</title>\n  <content type=\"html\"><![CDATA[<p>a]]]]><![CDATA[>b</p>]]></content>\n</entry>\n"
+        );
+    }
+}
@@ -1,88 +1,943 @@
+use std::collections::HashMap;
+use std::path::Path;
 use std::time::Duration;
 
 use anyhow::{Context as _, anyhow};
 use bytes::Bytes;
 use reqwest::header::{HeaderMap, RETRY_AFTER};
+use tokio::io::AsyncWriteExt as _;
 use tokio::sync::Semaphore;
 use url::Url;
 
+use crate::assets::AssetKind;
+use crate::output;
 use crate::progress::{DownloadKind, Progress};
+use crate::report;
+
+/// `--user-agent` as given, else `discourse-topic-render/<version>` with `(+contact)` when there is a contact.
+/// Blank values count as unset, and control characters (not allowed in a header) are dropped.
+pub fn user_agent(user_agent: Option<&str>, contact: Option<&str>) -> String {
+    let clean = |s: &str| -> String { s.chars().filter(|c| !c.is_control()).collect::<String>() };
+    if let Some(user_agent) = user_agent.map(clean).filter(|s| !s.trim().is_empty()) {
+        return user_agent.trim().to_string();
+    }
+    let tool = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+    match contact.map(clean).filter(|s| !s.trim().is_empty()) {
+        Some(contact) => format!("{tool} (+{})", contact.trim()),
+        None => tool.to_string(),
+    }
+}
+
+pub struct FetcherConfig<'a> {
+    pub user_agent: &'a str,
+    pub max_concurrency: usize,
+    /// Concurrent downloads per host; clamped to `max_concurrency`.
+    pub per_domain_concurrency: usize,
+    /// The Discourse site; session cookies are only ever sent to this host.
+    pub base_url: &'a Url,
+    /// `name=value` cookie pairs.
+    pub cookies: Vec<String>,
+    pub timeouts: Timeouts,
+    /// Proxy for every request; falls back to `http_proxy`/`https_proxy` from the environment.
+    pub proxy: Option<&'a str>,
+    /// Hosts that bypass the proxy; falls back to `no_proxy` from the environment.
+    pub no_proxy: &'a [String],
+    /// Give up retrying a throttled request once this much time has been spent on it.
+    pub max_retry_total: Duration,
+    /// Retries after a connection error or request timeout.
+    pub max_connect_retries: usize,
+    /// Largest accepted response body, per request.
+    pub max_response_bytes: u64,
+    /// Largest accepted asset; bigger ones fail with [`AssetTooLarge`].
+    pub max_asset_bytes: Option<u64>,
+    /// Send a `HEAD` first for assets and log any whose `Content-Length` is over this size.
+    pub preflight_above_bytes: Option<u64>,
+    /// Don't download assets the preflight found to be over `preflight_above_bytes`.
+    pub skip_large_assets: bool,
+    /// Response bodies buffered in memory at once, across all downloads; see [`MemoryBudget`].
+    pub max_buffered_bytes: Option<u64>,
+    /// Asset bytes to download in total; see [`DownloadBudget`].
+    pub max_total_bytes: Option<u64>,
+    /// Time from the fetcher's creation after which no more assets are downloaded; see [`DownloadBudget`].
+    pub fetch_deadline: Option<Duration>,
+}
+
+/// Deadline for a whole `get_bytes` call (including retries), per kind of download. Only time spent on the
+/// request counts: waiting for a download permit or for the memory budget does not.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub global: Duration,
+    pub avatar: Duration,
+    pub image: Duration,
+    pub font: Duration,
+}
+
+impl Timeouts {
+    fn for_kind(&self, kind: DownloadKind) -> Duration {
+        match kind {
+            DownloadKind::Asset(AssetKind::Avatar) => self.avatar,
+            DownloadKind::Asset(AssetKind::Image) => self.image,
+            DownloadKind::Asset(AssetKind::Font) => self.font,
+            DownloadKind::Html | DownloadKind::Css | DownloadKind::Asset(AssetKind::Other) => {
+                self.global
+            }
+        }
+    }
+}
+
+/// A successful download.
+#[derive(Debug)]
+pub struct Fetched {
+    pub bytes: Bytes,
+    pub headers: HeaderMap,
+    /// Where the body actually came from, after redirects.
+    pub final_url: Url,
+    /// Keeps `bytes` counted against the memory budget; drop it once they have been written out.
+    pub memory: MemoryReservation,
+}
+
+/// A download streamed to disk by [`Fetcher::download_to_file`].
+#[derive(Debug)]
+pub struct FetchedFile {
+    pub hash: blake3::Hash,
+    /// The first bytes of the body, for sniffing the format.
+    pub head: Vec<u8>,
+    pub headers: HeaderMap,
+    pub final_url: Url,
+}
+
+/// Where a response body goes while it streams in.
+trait BodySink {
+    /// Called with the expected body size before it is read; sinks that buffer in memory reserve it. Returns
+    /// `false`, reserving nothing, when that would have to wait.
+    fn try_reserve(&mut self, _budget: &std::sync::Arc<MemoryBudget>, _bytes: u64) -> bool {
+        true
+    }
+    /// [`BodySink::try_reserve`], waiting for the budget.
+    async fn reserve(&mut self, _budget: &std::sync::Arc<MemoryBudget>, _bytes: u64) {}
+    async fn write(&mut self, chunk: &[u8]) -> anyhow::Result<()>;
+    fn len(&self) -> u64;
+}
+
+impl BodySink for MemorySink {
+    fn try_reserve(&mut self, budget: &std::sync::Arc<MemoryBudget>, bytes: u64) -> bool {
+        self.memory = budget.try_reserve(bytes);
+        self.memory.is_some()
+    }
+
+    async fn reserve(&mut self, budget: &std::sync::Arc<MemoryBudget>, bytes: u64) {
+        self.memory = Some(budget.reserve(bytes).await);
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> anyhow::Result<()> {
+        self.buf.extend_from_slice(chunk);
+        if let Some(memory) = &mut self.memory {
+            memory.add(chunk.len() as u64);
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.buf.len() as u64
+    }
+}
+
+#[derive(Default)]
+struct MemorySink {
+    buf: Vec<u8>,
+    memory: Option<MemoryReservation>,
+}
+
+/// Caps the response bytes buffered in memory at once (`--max-memory-mb`): a body reserves its expected size
+/// before it is read, waiting while others hold the budget. Bodies streamed to disk don't take part.
+///
+/// Permits are KiB so budgets past 4 GiB still fit the semaphore. Without a limit nothing waits, but the
+/// buffered bytes are still reported for the summary.
+pub struct MemoryBudget {
+    limit_kib: Option<u32>,
+    permits: Semaphore,
+    buffered: std::sync::atomic::AtomicU64,
+    progress: Option<std::sync::Arc<Progress>>,
+}
+
+impl MemoryBudget {
+    fn new(limit_bytes: Option<u64>, progress: Option<std::sync::Arc<Progress>>) -> Self {
+        let limit_kib = limit_bytes.map(|b| b.div_ceil(1024).clamp(1, u32::MAX as u64) as u32);
+        Self {
+            limit_kib,
+            permits: Semaphore::new(limit_kib.unwrap_or(0) as usize),
+            buffered: Default::default(),
+            progress,
+        }
+    }
+
+    /// Wait until `bytes` fit. A body bigger than the whole budget waits until it has the budget to itself.
+    async fn reserve(self: &std::sync::Arc<Self>, bytes: u64) -> MemoryReservation {
+        let kib = self.kib(bytes);
+        if kib > 0 {
+            // The semaphore is never closed.
+            if let Ok(permit) = self.permits.acquire_many(kib).await {
+                permit.forget();
+            }
+        }
+        MemoryReservation {
+            budget: self.clone(),
+            kib,
+            buffered: 0,
+        }
+    }
+
+    /// [`MemoryBudget::reserve`], or `None` when `bytes` don't fit right now.
+    fn try_reserve(self: &std::sync::Arc<Self>, bytes: u64) -> Option<MemoryReservation> {
+        let kib = self.kib(bytes);
+        if kib > 0 {
+            self.permits.try_acquire_many(kib).ok()?.forget();
+        }
+        Some(MemoryReservation {
+            budget: self.clone(),
+            kib,
+            buffered: 0,
+        })
+    }
+
+    fn kib(&self, bytes: u64) -> u32 {
+        match self.limit_kib {
+            Some(limit) => bytes.div_ceil(1024).min(limit as u64) as u32,
+            None => 0,
+        }
+    }
+}
+
+/// Part of the [`MemoryBudget`] held by one buffered body; given back on drop.
+pub struct MemoryReservation {
+    budget: std::sync::Arc<MemoryBudget>,
+    kib: u32,
+    buffered: u64,
+}
+
+impl MemoryReservation {
+    fn add(&mut self, bytes: u64) {
+        self.buffered += bytes;
+        let total = self
+            .budget
+            .buffered
+            .fetch_add(bytes, std::sync::atomic::Ordering::Relaxed)
+            + bytes;
+        if let Some(p) = &self.budget.progress {
+            p.buffered(total);
+        }
+    }
+
+    /// Give back what was reserved beyond the bytes actually read.
+    fn shrink_to_fit(&mut self) {
+        let needed = self.buffered.div_ceil(1024).min(self.kib as u64) as u32;
+        self.budget
+            .permits
+            .add_permits((self.kib - needed) as usize);
+        self.kib = needed;
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.budget.permits.add_permits(self.kib as usize);
+        self.budget
+            .buffered
+            .fetch_sub(self.buffered, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+impl std::fmt::Debug for MemoryReservation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryReservation")
+            .field("kib", &self.kib)
+            .field("buffered", &self.buffered)
+            .finish()
+    }
+}
+
+const SNIFF_HEAD_BYTES: usize = 512;
+
+struct FileSink {
+    file: tokio::fs::File,
+    hasher: blake3::Hasher,
+    head: Vec<u8>,
+    len: u64,
+}
+
+impl FileSink {
+    async fn finish(mut self, path: &Path) -> anyhow::Result<(blake3::Hash, Vec<u8>)> {
+        self.file
+            .flush()
+            .await
+            .with_context(|| format!("write {}", path.display()))?;
+        Ok((self.hasher.finalize(), self.head))
+    }
+}
+
+impl BodySink for FileSink {
+    async fn write(&mut self, chunk: &[u8]) -> anyhow::Result<()> {
+        self.file.write_all(chunk).await.context("write download")?;
+        self.hasher.update(chunk);
+        let room = SNIFF_HEAD_BYTES.saturating_sub(self.head.len());
+        self.head.extend_from_slice(&chunk[..room.min(chunk.len())]);
+        self.len += chunk.len() as u64;
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+/// `--max-total-bytes` and `--fetch-deadline-secs`: once the asset bytes downloaded reach the limit or the deadline
+/// passes, assets not yet started fail with [`OverBudget`]. Downloads already under way finish, so the byte limit
+/// can be overshot by what was in flight.
+struct DownloadBudget {
+    max_bytes: Option<u64>,
+    deadline: Option<tokio::time::Instant>,
+    downloaded: std::sync::atomic::AtomicU64,
+}
+
+impl DownloadBudget {
+    fn check(&self, url: &Url) -> Result<(), OverBudget> {
+        let spent = if self
+            .deadline
+            .is_some_and(|deadline| tokio::time::Instant::now() >= deadline)
+        {
+            Some("--fetch-deadline-secs")
+        } else if self
+            .max_bytes
+            .is_some_and(|max| self.downloaded.load(std::sync::atomic::Ordering::Relaxed) >= max)
+        {
+            Some("--max-total-bytes")
+        } else {
+            None
+        };
+        match spent {
+            Some(limit) => Err(OverBudget {
+                url: url.clone(),
+                limit,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn spend(&self, bytes: u64) {
+        self.downloaded
+            .fetch_add(bytes, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// An asset not downloaded because `--max-total-bytes` or `--fetch-deadline-secs` ran out first; callers substitute
+/// a placeholder or link to the original.
+#[derive(Debug, Clone)]
+pub struct OverBudget {
+    pub url: Url,
+    /// The flag that ran out.
+    pub limit: &'static str,
+}
+
+impl std::fmt::Display for OverBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "skipped {} ({} reached)", self.url, self.limit)
+    }
+}
+
+impl std::error::Error for OverBudget {}
+
+/// An asset skipped by `--skip-large-assets`; callers substitute a placeholder.
+#[derive(Debug)]
+pub struct LargeAssetSkipped {
+    pub url: Url,
+    pub bytes: u64,
+}
+
+impl std::fmt::Display for LargeAssetSkipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "skipped {} ({} bytes, over --preflight-above-bytes)",
+            self.url, self.bytes
+        )
+    }
+}
+
+impl std::error::Error for LargeAssetSkipped {}
+
+/// A response with an error status that was not retried, e.g. a 404.
+#[derive(Debug)]
+pub struct HttpStatus {
+    pub url: Url,
+    pub status: reqwest::StatusCode,
+}
+
+impl std::fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "GET {} failed with status {}", self.url, self.status)
+    }
+}
+
+impl std::error::Error for HttpStatus {}
+
+/// An asset over `--max-asset-size`; callers link to the original instead.
+#[derive(Debug, Clone)]
+pub struct AssetTooLarge {
+    pub url: Url,
+    /// From `Content-Length`; `None` when the cap was hit while streaming.
+    pub bytes: Option<u64>,
+    pub limit: u64,
+}
+
+impl AssetTooLarge {
+    /// Human-readable size, e.g. `4.8 MiB` or `over 1.0 MiB`.
+    pub fn size_label(&self) -> String {
+        match self.bytes {
+            Some(bytes) => report::human_bytes(bytes),
+            None => format!("over {}", report::human_bytes(self.limit)),
+        }
+    }
+}
+
+impl std::fmt::Display for AssetTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.bytes {
+            Some(bytes) => write!(
+                f,
+                "GET {} response is {} bytes, over --max-asset-size {}",
+                self.url, bytes, self.limit
+            ),
+            None => write!(
+                f,
+                "GET {} response exceeded --max-asset-size {}",
+                self.url, self.limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AssetTooLarge {}
 
 #[derive(Clone)]
 pub struct Fetcher {
     client: reqwest::Client,
     semaphore: std::sync::Arc<Semaphore>,
+    host_semaphores: std::sync::Arc<std::sync::Mutex<HashMap<String, std::sync::Arc<Semaphore>>>>,
+    circuits: std::sync::Arc<std::sync::Mutex<HashMap<String, Circuit>>>,
+    per_domain_concurrency: usize,
     progress: Option<std::sync::Arc<Progress>>,
+    timeouts: Timeouts,
+    max_retry_total: Duration,
+    max_connect_retries: usize,
+    max_response_bytes: u64,
+    max_asset_bytes: Option<u64>,
+    preflight_above_bytes: Option<u64>,
+    /// `Content-Length` of each asset preflighted so far, `None` when the `HEAD` gave none.
+    preflight_sizes: std::sync::Arc<std::sync::Mutex<HashMap<Url, Option<u64>>>>,
+    skip_large_assets: bool,
+    memory: std::sync::Arc<MemoryBudget>,
+    budget: std::sync::Arc<DownloadBudget>,
 }
 
 impl Fetcher {
     pub fn new(
-        user_agent: &str,
-        max_concurrency: usize,
+        config: FetcherConfig<'_>,
         progress: Option<std::sync::Arc<Progress>>,
     ) -> anyhow::Result<Self> {
-        let client = reqwest::Client::builder()
-            .user_agent(user_agent)
-            .redirect(reqwest::redirect::Policy::limited(10))
-            .build()
-            .context("build reqwest client")?;
+        let mut builder = reqwest::Client::builder()
+            .user_agent(config.user_agent)
+            .redirect(reqwest::redirect::Policy::limited(10));
+        if !config.cookies.is_empty() {
+            // Host-only cookies (no Domain attribute): never sent to CDNs or other subdomains.
+            let jar = reqwest::cookie::Jar::default();
+            for cookie in &config.cookies {
+                jar.add_cookie_str(cookie, config.base_url);
+            }
+            builder = builder.cookie_provider(std::sync::Arc::new(jar));
+        }
+        for proxy in proxies(config.proxy, config.no_proxy)? {
+            builder = builder.proxy(proxy);
+        }
+        let client = builder.build().context("build reqwest client")?;
         Ok(Self {
             client,
-            semaphore: std::sync::Arc::new(Semaphore::new(max_concurrency.max(1))),
+            semaphore: std::sync::Arc::new(Semaphore::new(config.max_concurrency.max(1))),
+            host_semaphores: Default::default(),
+            circuits: Default::default(),
+            per_domain_concurrency: config
+                .per_domain_concurrency
+                .clamp(1, config.max_concurrency.max(1)),
+            timeouts: config.timeouts,
+            max_retry_total: config.max_retry_total,
+            max_connect_retries: config.max_connect_retries,
+            max_response_bytes: config.max_response_bytes,
+            max_asset_bytes: config.max_asset_bytes,
+            preflight_above_bytes: config.preflight_above_bytes,
+            preflight_sizes: Default::default(),
+            skip_large_assets: config.skip_large_assets,
+            memory: std::sync::Arc::new(MemoryBudget::new(
+                config.max_buffered_bytes,
+                progress.clone(),
+            )),
+            budget: std::sync::Arc::new(DownloadBudget {
+                max_bytes: config.max_total_bytes,
+                deadline: config
+                    .fetch_deadline
+                    .map(|d| tokio::time::Instant::now() + d),
+                downloaded: Default::default(),
+            }),
             progress,
         })
     }
 
-    pub async fn get_bytes(
+    pub async fn get_bytes(&self, url: Url, kind: DownloadKind) -> anyhow::Result<Fetched> {
+        self.get_bytes_as(url, kind, None).await
+    }
+
+    /// [`Fetcher::get_bytes`], sending `user_agent` instead of `--user-agent` when given.
+    pub async fn get_bytes_as(
         &self,
         url: Url,
         kind: DownloadKind,
-    ) -> anyhow::Result<(Bytes, HeaderMap)> {
-        let _permit = self
+        user_agent: Option<&str>,
+    ) -> anyhow::Result<Fetched> {
+        let (sink, headers, final_url) = self
+            .fetch_into(url, kind, user_agent, MemorySink::default())
+            .await?;
+        let mut memory = match sink.memory {
+            Some(memory) => memory,
+            None => self.memory.reserve(0).await,
+        };
+        memory.shrink_to_fit();
+        Ok(Fetched {
+            bytes: Bytes::from(sink.buf),
+            headers,
+            final_url,
+            memory,
+        })
+    }
+
+    /// Stream the body into a new file at `path`, hashing it on the way; the file is removed on failure.
+    pub async fn download_to_file(
+        &self,
+        url: Url,
+        kind: DownloadKind,
+        path: &Path,
+    ) -> anyhow::Result<FetchedFile> {
+        let file = tokio::fs::File::create(path)
+            .await
+            .map_err(|e| output::classify("create", path, e))?;
+        let sink = FileSink {
+            file,
+            hasher: blake3::Hasher::new(),
+            head: Vec::new(),
+            len: 0,
+        };
+        let res = match self.fetch_into(url, kind, None, sink).await {
+            Ok((sink, headers, final_url)) => {
+                sink.finish(path).await.map(|(hash, head)| FetchedFile {
+                    hash,
+                    head,
+                    headers,
+                    final_url,
+                })
+            }
+            Err(e) => Err(e),
+        };
+        if res.is_err() {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        res
+    }
+
+    async fn fetch_into<S: BodySink>(
+        &self,
+        url: Url,
+        kind: DownloadKind,
+        user_agent: Option<&str>,
+        sink: S,
+    ) -> anyhow::Result<(S, HeaderMap, Url)> {
+        let is_asset = matches!(kind, DownloadKind::Asset(_));
+        if is_asset {
+            self.budget.check(&url)?;
+        }
+        if let Some(threshold) = self.preflight_above_bytes
+            && let Some(bytes) = self.expected_size(&url, kind).await?
+            && bytes > threshold
+        {
+            tracing::info!(%url, bytes, "large asset");
+            if self.skip_large_assets {
+                return Err(LargeAssetSkipped { url, bytes }.into());
+            }
+        }
+
+        if let Some(p) = &self.progress {
+            p.http_start(kind, &url);
+        }
+
+        let mut budget = TimeBudget::new(self.timeouts.for_kind(kind));
+        let fetched = self
+            .get_with_retries(&url, kind, user_agent, sink, &mut budget)
+            .await?;
+        if is_asset {
+            self.budget.spend(fetched.0.len());
+        }
+        Ok(fetched)
+    }
+
+    /// A global and a per-host download permit for `url`, once its host's circuit is closed.
+    async fn permits(&self, url: &Url) -> anyhow::Result<Permits<'_>> {
+        // Before taking any permit, so one throttling host doesn't hold up downloads from the others.
+        self.wait_for_circuit(url).await;
+        // Per-host first, so requests queued for a busy host don't hold global permits.
+        let host = self
+            .host_semaphore(url.host_str().unwrap_or(""))
+            .acquire_owned()
+            .await
+            .context("acquire per-host download permit")?;
+        // Again: the circuit may have opened while this request queued behind the host's others.
+        self.wait_for_circuit(url).await;
+        let global = self
             .semaphore
             .acquire()
             .await
             .context("acquire download permit")?;
+        Ok(Permits {
+            _host: host,
+            _global: global,
+        })
+    }
+
+    /// The size of the asset at `url` by a preflight `HEAD`, under `--preflight-above-bytes`. Each URL is asked
+    /// once, so the prefetch plan and the download share the answer.
+    pub async fn expected_size(
+        &self,
+        url: &Url,
+        kind: DownloadKind,
+    ) -> anyhow::Result<Option<u64>> {
+        if self.preflight_above_bytes.is_none() || !matches!(kind, DownloadKind::Asset(_)) {
+            return Ok(None);
+        }
+        let known = self
+            .preflight_sizes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(url)
+            .copied();
+        if let Some(bytes) = known {
+            return Ok(bytes);
+        }
+        let bytes = {
+            let _permits = self.permits(url).await?;
+            self.preflight(url, kind).await
+        };
+        self.preflight_sizes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(url.clone(), bytes);
+        Ok(bytes)
+    }
 
+    /// `Content-Length` from a `HEAD` request, if the server sends one. Failures are left for the `GET` to report.
+    async fn preflight(&self, url: &Url, kind: DownloadKind) -> Option<u64> {
         if let Some(p) = &self.progress {
-            p.http_start(kind, &url);
+            p.http_start(kind, url);
+        }
+        let timeout = self.timeouts.for_kind(kind);
+        let resp = tokio::time::timeout(timeout, self.client.head(url.clone()).send()).await;
+        match resp {
+            Ok(Ok(resp)) => {
+                if let Some(p) = &self.progress {
+                    p.http_ok(kind, url, 0);
+                }
+                if !resp.status().is_success() {
+                    return None;
+                }
+                resp.headers()
+                    .get(reqwest::header::CONTENT_LENGTH)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.trim().parse().ok())
+            }
+            _ => {
+                if let Some(p) = &self.progress {
+                    p.http_err(kind, url);
+                }
+                tracing::debug!(%url, "HEAD preflight failed");
+                None
+            }
+        }
+    }
+
+    /// Read the body, refusing anything over `max_response_bytes`, or `max_asset_bytes` for assets when set, even
+    /// if it is the larger (by `Content-Length` up front, else while streaming).
+    async fn read_body<'a, S: BodySink>(
+        &'a self,
+        mut resp: reqwest::Response,
+        url: &Url,
+        kind: DownloadKind,
+        mut sink: S,
+        permits: &mut Option<Permits<'a>>,
+        budget: &mut TimeBudget,
+    ) -> anyhow::Result<S> {
+        let asset_limit = self
+            .max_asset_bytes
+            .filter(|_| matches!(kind, DownloadKind::Asset(_)));
+        let limit = asset_limit.unwrap_or(self.max_response_bytes);
+        let too_large = |bytes: Option<u64>| -> anyhow::Error {
+            if let Some(p) = &self.progress {
+                p.asset_oversized(url, bytes);
+            }
+            AssetTooLarge {
+                url: url.clone(),
+                bytes,
+                limit,
+            }
+            .into()
+        };
+
+        if let Some(len) = resp.content_length()
+            && len > limit
+        {
+            if asset_limit.is_some() {
+                return Err(too_large(Some(len)));
+            }
+            return Err(anyhow!(
+                "GET {} response is {} bytes, over --max-response-bytes {}",
+                url,
+                len,
+                limit
+            ));
+        }
+
+        // Bodies of unknown length reserve up to the limit, and give back the rest once read. Waiting for the
+        // budget gives the permits back, so other downloads carry on, and doesn't count against the timeout.
+        let expected = resp.content_length().unwrap_or(limit);
+        if !sink.try_reserve(&self.memory, expected) {
+            *permits = None;
+            sink.reserve(&self.memory, expected).await;
+            *permits = Some(self.permits(url).await?);
         }
 
+        let mut read = 0u64;
+        while let Some(chunk) = budget
+            .run(url, resp.chunk())
+            .await?
+            .context("read response body")?
+        {
+            if read + chunk.len() as u64 > limit {
+                if asset_limit.is_some() {
+                    return Err(too_large(None));
+                }
+                return Err(anyhow!(
+                    "GET {} response exceeded --max-response-bytes {} (stopped after {} bytes)",
+                    url,
+                    limit,
+                    read
+                ));
+            }
+            read += chunk.len() as u64;
+            sink.write(&chunk).await?;
+            if let Some(p) = &self.progress {
+                p.http_chunk(kind, chunk.len());
+            }
+        }
+        Ok(sink)
+    }
+
+    /// Sleep until the circuit for `url`'s host closes, if it is open.
+    async fn wait_for_circuit(&self, url: &Url) {
+        let host = url.host_str().unwrap_or("").to_ascii_lowercase();
+        let open_until = {
+            let circuits = self.circuits.lock().unwrap_or_else(|e| e.into_inner());
+            circuits.get(&host).and_then(|c| c.open_until)
+        };
+        let Some(open_until) = open_until else {
+            return;
+        };
+        let now = tokio::time::Instant::now();
+        if open_until > now {
+            if let Some(p) = &self.progress {
+                p.circuit_open(&host, open_until - now);
+            }
+            tokio::time::sleep_until(open_until).await;
+        }
+    }
+
+    /// Count a 429/503 from `url`'s host. Returns how long the circuit is open for when this throttle opened
+    /// it, or it was already open.
+    fn circuit_throttled(&self, url: &Url, retry_after: Option<Duration>) -> Option<Duration> {
+        let host = url.host_str().unwrap_or("").to_ascii_lowercase();
+        let mut circuits = self.circuits.lock().unwrap_or_else(|e| e.into_inner());
+        let circuit = circuits.entry(host).or_default();
+        circuit.consecutive_throttles += 1;
+        let now = tokio::time::Instant::now();
+        if let Some(open_until) = circuit.open_until
+            && open_until > now
+        {
+            return Some(open_until - now);
+        }
+        if circuit.consecutive_throttles < CIRCUIT_THRESHOLD {
+            return None;
+        }
+        let open_for = retry_after.unwrap_or(CIRCUIT_DEFAULT_OPEN);
+        circuit.open_until = Some(now + open_for);
+        Some(open_for)
+    }
+
+    fn circuit_success(&self, url: &Url) {
+        let host = url.host_str().unwrap_or("").to_ascii_lowercase();
+        let mut circuits = self.circuits.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(circuit) = circuits.get_mut(&host) {
+            *circuit = Circuit::default();
+        }
+    }
+
+    fn host_semaphore(&self, host: &str) -> std::sync::Arc<Semaphore> {
+        let mut map = self
+            .host_semaphores
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        map.entry(host.to_ascii_lowercase())
+            .or_insert_with(|| std::sync::Arc::new(Semaphore::new(self.per_domain_concurrency)))
+            .clone()
+    }
+
+    async fn get_with_retries<S: BodySink>(
+        &self,
+        url: &Url,
+        kind: DownloadKind,
+        user_agent: Option<&str>,
+        sink: S,
+        budget: &mut TimeBudget,
+    ) -> anyhow::Result<(S, HeaderMap, Url)> {
         let mut backoff = Duration::from_millis(250);
-        let max_attempts = 5usize;
+        let max_throttled_attempts = 5usize;
+        let started = std::time::Instant::now();
+        let mut throttled = 0usize;
+        let mut connect_retries = 0usize;
 
-        for attempt in 1..=max_attempts {
-            let resp = match self.client.get(url.clone()).send().await {
+        for attempt in 1.. {
+            // Taken for each attempt, so none are held while backing off or waiting on the circuit.
+            let mut permits = Some(self.permits(url).await?);
+            let mut request = self.client.get(url.clone());
+            if let Some(user_agent) = user_agent {
+                request = request.header(reqwest::header::USER_AGENT, user_agent);
+            }
+            // Each attempt gets its share of what is left, so one that stalls leaves time for the retries.
+            let share = budget.share(self.max_connect_retries - connect_retries);
+            let sent = match budget
+                .run(url, tokio::time::timeout(share, request.send()))
+                .await
+            {
+                Ok(Ok(sent)) => sent.map_err(anyhow::Error::from),
+                Ok(Err(_)) => {
+                    if let Some(p) = &self.progress {
+                        p.http_timeout(kind, url);
+                    }
+                    Err(TimedOut {
+                        url: url.clone(),
+                        timeout: share,
+                    }
+                    .into())
+                }
+                Err(timed_out) => {
+                    if let Some(p) = &self.progress {
+                        p.http_timeout(kind, url);
+                        p.http_err(kind, url);
+                    }
+                    return Err(timed_out.into());
+                }
+            };
+            let resp = match sent {
                 Ok(r) => r,
+                Err(e) if is_transient(&e) && connect_retries < self.max_connect_retries => {
+                    connect_retries += 1;
+                    let wait = with_jitter(backoff, &mut rand::rng());
+                    tracing::warn!(
+                        error = %e,
+                        attempt,
+                        wait_ms = wait.as_millis(),
+                        "connection failed or timed out; retrying"
+                    );
+                    drop(permits.take());
+                    if let Err(timed_out) = budget.run(url, tokio::time::sleep(wait)).await {
+                        if let Some(p) = &self.progress {
+                            p.http_timeout(kind, url);
+                            p.http_err(kind, url);
+                        }
+                        return Err(timed_out.into());
+                    }
+                    backoff = (backoff * 2).min(Duration::from_secs(10));
+                    continue;
+                }
                 Err(e) => {
                     if let Some(p) = &self.progress {
-                        p.http_err(kind, &url);
+                        p.http_err(kind, url);
                     }
-                    return Err(e).with_context(|| format!("GET {}", url));
+                    return Err(e).with_context(|| format!("GET {} (attempt {})", url, attempt));
                 }
             };
 
             let status = resp.status();
             let headers = resp.headers().clone();
+            let final_url = resp.url().clone();
 
             if status.is_success() {
-                let bytes = match resp.bytes().await {
-                    Ok(b) => b,
+                let sink = match self
+                    .read_body(resp, url, kind, sink, &mut permits, budget)
+                    .await
+                {
+                    Ok(sink) => sink,
                     Err(e) => {
                         if let Some(p) = &self.progress {
-                            p.http_err(kind, &url);
+                            if e.is::<TimedOut>() {
+                                p.http_timeout(kind, url);
+                            }
+                            p.http_err(kind, url);
                         }
-                        return Err(e).context("read response body");
+                        return Err(e);
                     }
                 };
                 if let Some(p) = &self.progress {
-                    p.http_ok(kind, &url, bytes.len());
+                    p.http_ok(kind, url, sink.len());
                 }
-                return Ok((bytes, headers));
+                self.circuit_success(url);
+                return Ok((sink, headers, final_url));
             }
 
-            if status.as_u16() == 429 || status.as_u16() == 503 {
-                let wait = retry_after_duration(&headers).unwrap_or(backoff);
+            if (status.as_u16() == 429 || status.as_u16() == 503)
+                && throttled + 1 < max_throttled_attempts
+            {
+                throttled += 1;
+                let retry_after = retry_after_duration(&headers);
+                if let Some(open_for) = self.circuit_throttled(url, retry_after) {
+                    // Everyone waits on the host's circuit at the top of the loop instead of their own ladder.
+                    if started.elapsed() + open_for > self.max_retry_total {
+                        if let Some(p) = &self.progress {
+                            p.http_err(kind, url);
+                        }
+                        return Err(anyhow!(
+                            "GET {} still throttled ({}) after retrying for {}s",
+                            url,
+                            status,
+                            started.elapsed().as_secs()
+                        ));
+                    }
+                    tracing::warn!(%status, attempt, "host keeps throttling; circuit open");
+                    continue;
+                }
+                let wait = retry_after.unwrap_or_else(|| with_jitter(backoff, &mut rand::rng()));
+                if started.elapsed() + wait > self.max_retry_total {
+                    if let Some(p) = &self.progress {
+                        p.http_err(kind, url);
+                    }
+                    return Err(anyhow!(
+                        "GET {} still throttled ({}) after retrying for {}s",
+                        url,
+                        status,
+                        started.elapsed().as_secs()
+                    ));
+                }
                 tracing::warn!(
                     %status,
                     attempt,
@@ -90,29 +945,607 @@ impl Fetcher {
                     "throttled; backing off"
                 );
                 if let Some(p) = &self.progress {
-                    p.http_throttled(kind, &url, status.as_u16(), wait);
+                    p.http_throttled(kind, url, status.as_u16(), wait);
+                }
+                drop(permits.take());
+                if let Err(timed_out) = budget.run(url, tokio::time::sleep(wait)).await {
+                    if let Some(p) = &self.progress {
+                        p.http_timeout(kind, url);
+                        p.http_err(kind, url);
+                    }
+                    return Err(timed_out.into());
                 }
-                tokio::time::sleep(wait).await;
                 backoff = (backoff * 2).min(Duration::from_secs(10));
                 continue;
             }
 
             if let Some(p) = &self.progress {
-                p.http_err(kind, &url);
+                p.http_err(kind, url);
             }
-            return Err(anyhow!("GET {} failed with status {}", url, status));
+            if throttled > 0 {
+                return Err(anyhow!("GET {} failed after retries ({})", url, status));
+            }
+            return Err(HttpStatus {
+                url: url.clone(),
+                status,
+            }
+            .into());
         }
 
-        if let Some(p) = &self.progress {
-            p.http_err(kind, &url);
+        unreachable!("retry loop only exits by returning")
+    }
+}
+
+/// A global and a per-host download permit, held while a request is on the wire.
+struct Permits<'a> {
+    _host: tokio::sync::OwnedSemaphorePermit,
+    _global: tokio::sync::SemaphorePermit<'a>,
+}
+
+/// What is left of a download's [`Timeouts`] entry, spent only while the request is on the wire or backing off.
+struct TimeBudget {
+    timeout: Duration,
+    left: Duration,
+}
+
+impl TimeBudget {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            left: timeout,
         }
-        Err(anyhow!("GET {} failed after retries", url))
+    }
+
+    /// An even split of what is left between this attempt and `retries` more.
+    fn share(&self, retries: usize) -> Duration {
+        self.left / (retries as u32).saturating_add(1)
+    }
+
+    /// Run `future` on what is left of the budget.
+    async fn run<F: std::future::Future>(
+        &mut self,
+        url: &Url,
+        future: F,
+    ) -> Result<F::Output, TimedOut> {
+        let started = tokio::time::Instant::now();
+        let res = tokio::time::timeout(self.left, future).await;
+        self.left = self.left.saturating_sub(started.elapsed());
+        res.map_err(|_| TimedOut {
+            url: url.clone(),
+            timeout: self.timeout,
+        })
     }
 }
 
+/// A download that used up its [`Timeouts`] entry, or one attempt at it that used up its share.
+#[derive(Debug)]
+pub struct TimedOut {
+    pub url: Url,
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "GET {} timed out after {}s (timeout)",
+            self.url,
+            self.timeout.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// A connection failure or timeout, which another attempt may get past.
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.is::<TimedOut>()
+        || e.downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_connect() || e.is_timeout())
+}
+
+/// Consecutive 429/503 responses from one host that open its circuit.
+const CIRCUIT_THRESHOLD: u32 = 3;
+/// How long an open circuit holds requests back when the server sends no `Retry-After`.
+const CIRCUIT_DEFAULT_OPEN: Duration = Duration::from_secs(5);
+
+/// Per-host throttling state. Once a host has throttled [`CIRCUIT_THRESHOLD`] requests in a row, new and
+/// retried requests to it all wait for the same deadline rather than each backing off on its own; the first
+/// success closes it again.
+#[derive(Debug, Default)]
+struct Circuit {
+    consecutive_throttles: u32,
+    open_until: Option<tokio::time::Instant>,
+}
+
+/// Proxies from `--proxy`/`--no-proxy`, or from the conventional environment variables when the flags are absent.
+fn proxies(proxy: Option<&str>, no_proxy: &[String]) -> anyhow::Result<Vec<reqwest::Proxy>> {
+    let no_proxy = if no_proxy.is_empty() {
+        env_var(&["no_proxy", "NO_PROXY"]).and_then(|v| reqwest::NoProxy::from_string(&v))
+    } else {
+        reqwest::NoProxy::from_string(&no_proxy.join(","))
+    };
+
+    if let Some(url) = proxy {
+        let proxy =
+            reqwest::Proxy::all(url).with_context(|| format!("invalid --proxy url {}", url))?;
+        return Ok(vec![proxy.no_proxy(no_proxy)]);
+    }
+
+    let mut out = Vec::new();
+    if let Some(url) = env_var(&["http_proxy", "HTTP_PROXY"]) {
+        let proxy =
+            reqwest::Proxy::http(&url).with_context(|| format!("invalid http_proxy {}", url))?;
+        out.push(proxy.no_proxy(no_proxy.clone()));
+    }
+    if let Some(url) = env_var(&["https_proxy", "HTTPS_PROXY"]) {
+        let proxy =
+            reqwest::Proxy::https(&url).with_context(|| format!("invalid https_proxy {}", url))?;
+        out.push(proxy.no_proxy(no_proxy.clone()));
+    }
+    Ok(out)
+}
+
+fn env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|n| std::env::var(n).ok())
+        .find(|v| !v.trim().is_empty())
+}
+
+/// Read a Netscape-format cookie file (as written by `curl --cookie-jar`) and return the `name=value; Path=...`
+/// cookies whose domain matches `host`.
+pub fn read_netscape_cookie_file(path: &Path, host: &str) -> anyhow::Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("read cookie file {}", path.display()))?;
+    let host = host.to_ascii_lowercase();
+    let mut cookies = Vec::new();
+    for line in text.lines() {
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 7 {
+            tracing::warn!(line, "skipping malformed cookie file line");
+            continue;
+        }
+        let domain = fields[0].trim_start_matches('.').to_ascii_lowercase();
+        if host != domain && !host.ends_with(&format!(".{}", domain)) {
+            continue;
+        }
+        cookies.push(format!("{}={}; Path={}", fields[5], fields[6], fields[2]));
+    }
+    Ok(cookies)
+}
+
+/// Spread `wait` by ±25% so that many clients backing off at once don't retry in lockstep.
+fn with_jitter(wait: Duration, rng: &mut impl rand::Rng) -> Duration {
+    wait.mul_f64(rng.random_range(0.75..=1.25))
+}
+
 fn retry_after_duration(headers: &HeaderMap) -> Option<Duration> {
     let v = headers.get(RETRY_AFTER)?;
     let s = v.to_str().ok()?.trim();
     let seconds: u64 = s.parse().ok()?;
     Some(Duration::from_secs(seconds))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_agent_is_the_tool_plus_contact_unless_overridden() {
+        let tool = format!("discourse-topic-render/{}", env!("CARGO_PKG_VERSION"));
+        assert_eq!(user_agent(None, None), tool);
+        assert_eq!(
+            user_agent(None, Some(" mailto:archive@example.org ")),
+            format!("{tool} (+mailto:archive@example.org)")
+        );
+        assert_eq!(user_agent(None, Some("  ")), tool);
+        assert_eq!(
+            user_agent(Some("MyArchiver/2"), Some("https://example.org/bot")),
+            "MyArchiver/2"
+        );
+        assert_eq!(
+            user_agent(Some(""), Some("a@b.c")),
+            format!("{tool} (+a@b.c)")
+        );
+        assert_eq!(
+            user_agent(None, Some("a@b.c\r\nX-Injected: 1")),
+            format!("{tool} (+a@b.cX-Injected: 1)")
+        );
+    }
+
+    fn test_fetcher(base_url: &Url, per_domain: usize, connect_retries: usize) -> Fetcher {
+        Fetcher::new(test_config(base_url, per_domain, connect_retries), None).unwrap()
+    }
+
+    fn test_config(base_url: &Url, per_domain: usize, connect_retries: usize) -> FetcherConfig<'_> {
+        FetcherConfig {
+            user_agent: "test",
+            max_concurrency: 2,
+            per_domain_concurrency: per_domain,
+            base_url,
+            cookies: Vec::new(),
+            timeouts: Timeouts {
+                global: Duration::from_secs(10),
+                avatar: Duration::from_secs(10),
+                image: Duration::from_secs(10),
+                font: Duration::from_secs(10),
+            },
+            proxy: None,
+            no_proxy: &[],
+            max_retry_total: Duration::from_secs(10),
+            max_connect_retries: connect_retries,
+            max_response_bytes: 1024,
+            max_asset_bytes: None,
+            preflight_above_bytes: None,
+            skip_large_assets: false,
+            max_buffered_bytes: None,
+            max_total_bytes: None,
+            fetch_deadline: None,
+        }
+    }
+
+    #[test]
+    fn per_host_permits_are_shared_and_clamped() {
+        let base_url = Url::parse("https://forum.example.com/").unwrap();
+        let fetcher = test_fetcher(&base_url, 3, 0);
+
+        let a = fetcher.host_semaphore("cdn.example.com");
+        let b = fetcher.clone().host_semaphore("CDN.example.com");
+        assert!(std::sync::Arc::ptr_eq(&a, &b));
+        assert_eq!(a.available_permits(), 2);
+        assert!(!std::sync::Arc::ptr_eq(
+            &a,
+            &fetcher.host_semaphore("forum.example.com")
+        ));
+    }
+
+    #[test]
+    fn backoff_jitter_stays_within_a_quarter() {
+        let base = Duration::from_millis(1000);
+        let mut rng = rand::rng();
+        let waits: Vec<Duration> = (0..200).map(|_| with_jitter(base, &mut rng)).collect();
+        assert!(
+            waits
+                .iter()
+                .all(|w| *w >= Duration::from_millis(750) && *w <= Duration::from_millis(1250))
+        );
+        assert!(waits.iter().any(|w| *w != waits[0]));
+    }
+
+    #[tokio::test]
+    async fn connection_errors_are_retried_then_reported() {
+        // Nothing listens on port 1; connecting fails immediately.
+        let url = Url::parse("http://127.0.0.1:1/a.png").unwrap();
+        let fetcher = test_fetcher(&url, 1, 2);
+        let err = fetcher
+            .get_bytes(url, DownloadKind::Asset(AssetKind::Image))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("(attempt 3)"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn a_request_that_times_out_is_retried() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/a.png", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            // The first connection never gets an answer; the next one does.
+            let (_stalled, _) = listener.accept().await.unwrap();
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = tokio::io::AsyncReadExt::read(&mut socket, &mut buf)
+                    .await
+                    .unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let mut config = test_config(&url, 1, 1);
+        config.timeouts.image = Duration::from_secs(2);
+        let progress = Progress::new(false, 1);
+        let fetcher = Fetcher::new(config, Some(progress.clone())).unwrap();
+        let fetched = fetcher
+            .get_bytes(url, DownloadKind::Asset(AssetKind::Image))
+            .await
+            .unwrap();
+        assert_eq!(&fetched.bytes[..], b"ok");
+        let totals = progress.totals();
+        assert_eq!((totals.http_requests, totals.http_failed), (1, 0));
+        assert_eq!(
+            totals.timeouts_by_kind,
+            std::collections::BTreeMap::from([("image", 1)])
+        );
+        assert_eq!(
+            totals.timeouts_by_host,
+            std::collections::BTreeMap::from([("127.0.0.1".to_string(), 1)])
+        );
+
+        assert!(is_transient(&anyhow::Error::from(TimedOut {
+            url: Url::parse("https://cdn.example.com/a.png").unwrap(),
+            timeout: Duration::from_secs(1),
+        })));
+        assert!(!is_transient(&anyhow!("GET failed with status 404")));
+    }
+
+    #[tokio::test]
+    async fn oversized_responses_are_refused() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.path("/small.png");
+            then.status(200).body(vec![0u8; 1024]);
+        });
+        server.mock(|when, then| {
+            when.path("/big.png");
+            then.status(200).body(vec![0u8; 1025]);
+        });
+
+        let base = Url::parse(&server.url("/")).unwrap();
+        let fetcher = test_fetcher(&base, 1, 0);
+        let kind = DownloadKind::Asset(AssetKind::Image);
+        let small = fetcher
+            .get_bytes(base.join("small.png").unwrap(), kind)
+            .await;
+        assert_eq!(small.unwrap().bytes.len(), 1024);
+        let err = fetcher
+            .get_bytes(base.join("big.png").unwrap(), kind)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("over --max-response-bytes 1024"),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn the_asset_limit_applies_even_above_the_response_limit() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.path("/big.png");
+            then.status(200).body(vec![0u8; 2049]);
+        });
+
+        let base = Url::parse(&server.url("/")).unwrap();
+        let fetcher = Fetcher::new(
+            FetcherConfig {
+                max_asset_bytes: Some(2048),
+                ..test_config(&base, 1, 0)
+            },
+            None,
+        )
+        .unwrap();
+        let err = fetcher
+            .get_bytes(
+                base.join("big.png").unwrap(),
+                DownloadKind::Asset(AssetKind::Image),
+            )
+            .await
+            .unwrap_err();
+        // Too large to download, so it gets linked instead of failing the render.
+        let too_large = err
+            .downcast_ref::<AssetTooLarge>()
+            .unwrap_or_else(|| panic!("{err:#}"));
+        assert_eq!((too_large.bytes, too_large.limit), (Some(2049), 2048));
+    }
+
+    #[tokio::test]
+    async fn downloads_stream_to_a_hashed_file() {
+        let server = httpmock::MockServer::start();
+        let body: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        server.mock(|when, then| {
+            when.path("/a.png");
+            then.status(200).body(body.clone());
+        });
+        server.mock(|when, then| {
+            when.path("/big.png");
+            then.status(200).body(vec![0u8; 1025]);
+        });
+
+        let tmp = tempfile::tempdir().unwrap();
+        let base = Url::parse(&server.url("/")).unwrap();
+        let fetcher = test_fetcher(&base, 1, 0);
+        let kind = DownloadKind::Asset(AssetKind::Image);
+
+        let path = tmp.path().join("a.part");
+        let fetched = fetcher
+            .download_to_file(base.join("a.png").unwrap(), kind, &path)
+            .await
+            .unwrap();
+        assert_eq!(fetched.hash, blake3::hash(&body));
+        assert_eq!(fetched.head, body[..SNIFF_HEAD_BYTES]);
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+
+        let path = tmp.path().join("big.part");
+        fetcher
+            .download_to_file(base.join("big.png").unwrap(), kind, &path)
+            .await
+            .unwrap_err();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn throttling_host_opens_a_shared_circuit() {
+        let server = httpmock::MockServer::start_async().await;
+        let throttled = server
+            .mock_async(|when, then| {
+                when.path_contains("/p");
+                then.status(429).header("Retry-After", "1");
+            })
+            .await;
+
+        let base = Url::parse(&server.url("/")).unwrap();
+        let fetcher = Fetcher::new(
+            FetcherConfig {
+                max_concurrency: 4,
+                ..test_config(&base, 4, 0)
+            },
+            None,
+        )
+        .unwrap();
+        let kind = DownloadKind::Asset(AssetKind::Image);
+        let requests =
+            (0..20).map(|i| fetcher.get_bytes(base.join(&format!("p{i}.png")).unwrap(), kind));
+        let recover = async {
+            tokio::time::sleep(Duration::from_millis(800)).await;
+            let hits = throttled.hits_async().await;
+            throttled.delete_async().await;
+            server
+                .mock_async(|when, then| {
+                    when.path_contains("/p");
+                    then.status(200).body("ok");
+                })
+                .await;
+            hits
+        };
+        let (results, hits) = tokio::join!(futures_util::future::join_all(requests), recover);
+
+        assert!(results.iter().all(|r| r.is_ok()));
+        // Per-request backoff would have sent each of the 20 requests into a 429 at least once.
+        assert!(hits <= 8, "{hits} throttled attempts");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_open_circuit_holds_back_only_its_own_host() {
+        let a = Url::parse("http://a.example/x.png").unwrap();
+        let b = Url::parse("http://b.example/x.png").unwrap();
+        let fetcher = std::sync::Arc::new(
+            Fetcher::new(
+                FetcherConfig {
+                    max_concurrency: 1,
+                    ..test_config(&a, 4, 0)
+                },
+                None,
+            )
+            .unwrap(),
+        );
+        for _ in 0..CIRCUIT_THRESHOLD {
+            fetcher.circuit_throttled(&a, Some(Duration::from_secs(60)));
+        }
+        let start = tokio::time::Instant::now();
+        let waiting: Vec<_> = (0..3)
+            .map(|_| {
+                let fetcher = fetcher.clone();
+                let a = a.clone();
+                tokio::spawn(async move {
+                    drop(fetcher.permits(&a).await.unwrap());
+                    tokio::time::Instant::now()
+                })
+            })
+            .collect();
+        tokio::task::yield_now().await;
+
+        // The only global permit is free while `a`'s requests wait out its circuit.
+        drop(fetcher.permits(&b).await.unwrap());
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        for task in waiting {
+            let done = task.await.unwrap();
+            assert_eq!(done - start, Duration::from_secs(60));
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_budget_serializes_buffered_bodies() {
+        let server = httpmock::MockServer::start();
+        for name in ["a", "b", "c"] {
+            server.mock(|when, then| {
+                when.path(format!("/{name}.png"));
+                then.status(200).body(vec![0u8; 1536]);
+            });
+        }
+
+        let base = Url::parse(&server.url("/")).unwrap();
+        let progress = Progress::new(false, 3);
+        let fetcher = Fetcher::new(
+            FetcherConfig {
+                max_concurrency: 3,
+                max_response_bytes: 4096,
+                max_buffered_bytes: Some(2048),
+                ..test_config(&base, 3, 0)
+            },
+            Some(progress.clone()),
+        )
+        .unwrap();
+
+        // Each body is held for a while after it arrives; only one fits the budget at a time.
+        let fetch = |name: &'static str| {
+            let fetcher = fetcher.clone();
+            let url = base.join(name).unwrap();
+            async move {
+                let fetched = fetcher
+                    .get_bytes(url, DownloadKind::Asset(AssetKind::Image))
+                    .await
+                    .unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                fetched.bytes.len()
+            }
+        };
+        let lens = tokio::join!(fetch("a.png"), fetch("b.png"), fetch("c.png"));
+        assert_eq!(lens, (1536, 1536, 1536));
+
+        let summary = progress.summary().render(false);
+        assert!(
+            summary.contains("at most 1.5 KiB of response bodies buffered in memory at once"),
+            "{summary}"
+        );
+    }
+
+    #[tokio::test]
+    async fn waiting_for_the_memory_budget_frees_the_download_permit() {
+        let server = httpmock::MockServer::start();
+        for name in ["a", "b", "c"] {
+            server.mock(|when, then| {
+                when.path(format!("/{name}.png"));
+                then.status(200).body(vec![0u8; 1536]);
+            });
+        }
+
+        let base = Url::parse(&server.url("/")).unwrap();
+        let fetcher = Fetcher::new(
+            FetcherConfig {
+                max_concurrency: 1,
+                max_response_bytes: 4096,
+                max_buffered_bytes: Some(2048),
+                ..test_config(&base, 1, 0)
+            },
+            None,
+        )
+        .unwrap();
+        let kind = DownloadKind::Asset(AssetKind::Image);
+
+        let a = fetcher
+            .get_bytes(base.join("a.png").unwrap(), kind)
+            .await
+            .unwrap();
+        let b = tokio::spawn({
+            let fetcher = fetcher.clone();
+            let url = base.join("b.png").unwrap();
+            async move { fetcher.get_bytes(url, kind).await.map(|f| f.bytes.len()) }
+        });
+        // Give `b` time to get its response and start waiting on `a`'s share of the budget. It must not hold the
+        // only permit while it does, so a download to disk still goes ahead.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("c.part");
+        let to_disk = fetcher.download_to_file(base.join("c.png").unwrap(), kind, &path);
+        tokio::time::timeout(Duration::from_secs(5), to_disk)
+            .await
+            .expect("download stuck behind the memory budget")
+            .unwrap();
+        assert!(!b.is_finished());
+
+        drop(a);
+        assert_eq!(b.await.unwrap().unwrap(), 1536);
+    }
+}
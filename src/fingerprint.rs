@@ -0,0 +1,202 @@
+//! Input fingerprint behind `--skip-unchanged`, stored in the rendered page as `<meta name="dtr-fingerprint">`.
+
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::Context as _;
+use kuchiki::traits::TendrilSink as _;
+
+use crate::cli::Args;
+
+pub const META_NAME: &str = "dtr-fingerprint";
+
+/// Hash of the tool version, the topic JSON (hashed while it is parsed), the local files the output is built from,
+/// and the effective render options.
+pub fn compute(args: &Args, topic_json: &blake3::Hash) -> anyhow::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"), "\n").as_bytes());
+    hash_part(&mut hasher, "topic", topic_json.as_bytes());
+    hash_part(&mut hasher, "options", render_options(args).as_bytes());
+    let css: &[_] = if args.builtin_css { &[] } else { &args.css };
+    for (kind, paths) in [
+        ("css", css),
+        ("inject-css", &args.inject_css),
+        ("inject-js", &args.inject_js),
+    ] {
+        for path in paths {
+            let bytes = std::fs::read(path).with_context(|| format!("read {}", path.display()))?;
+            // Injected scripts keep their file name in dir mode, so the name is part of the input.
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy())
+                .unwrap_or_default();
+            hash_part(&mut hasher, &format!("{kind} {name}"), &bytes);
+        }
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Length-prefixed, so no two sequences of parts hash alike.
+fn hash_part(hasher: &mut blake3::Hasher, label: &str, bytes: &[u8]) {
+    hasher.update(format!("{label} {}\n", bytes.len()).as_bytes());
+    hasher.update(bytes);
+}
+
+/// One `name=value` line per flag that changes what gets written. The destructuring is exhaustive, so a new flag
+/// does not compile until it is sorted into one group or the other.
+fn render_options(args: &Args) -> String {
+    let Args {
+        // Hashed by content.
+        input: _,
+        css: _,
+        inject_css: _,
+        inject_js: _,
+        // Where and how the output is fetched and written, not what it contains.
+        out: _,
+        skip_unchanged: _,
+        max_input_mb: _,
+        max_css_bytes: _,
+        max_concurrency: _,
+        per_domain_concurrency: _,
+        timeout_secs: _,
+        timeout_avatar: _,
+        timeout_image: _,
+        timeout_font: _,
+        max_retry_total_secs: _,
+        max_connect_retries: _,
+        fetch_order: _,
+        max_response_bytes: _,
+        max_memory_mb: _,
+        proxy: _,
+        no_proxy: _,
+        user_agent: _,
+        ua_contact: _,
+        emit_strict_report: _,
+        allow_dangling_anchors: _,
+        // Only undoes `--no-dedupe-css`.
+        dedupe_css: _,
+        require_alt_text: _,
+        min_completeness: _,
+        require_complete: _,
+        cookie: _,
+        cookie_file: _,
+        progress: _,
+        summary: _,
+        slow_post_secs: _,
+        no_color: _,
+        print_config: _,
+        base_url,
+        css_url,
+        builtin_css,
+        reproducible,
+        no_stamp,
+        lenient_css,
+        prune_fonts,
+        purge_css,
+        no_dedupe_css,
+        no_font_fallback_injection,
+        no_builtin_js,
+        no_js,
+        posts,
+        toc,
+        toc_max_depth,
+        search,
+        line_numbers,
+        code_copy_button,
+        qr_permalinks,
+        qr_always,
+        include_private,
+        paginate,
+        only_user,
+        exclude_user,
+        strip_reactions,
+        strip_selector,
+        small_actions,
+        show_revision_count,
+        include_whispers,
+        mode,
+        epub_posts_per_chapter,
+        offline,
+        avatar_size,
+        css_integrity,
+        emit_preload_hints,
+        assets_dir_name,
+        max_asset_size,
+        preflight_above_bytes,
+        skip_large_assets,
+        max_total_bytes,
+        fetch_deadline_secs,
+        keep_going,
+        keep_original_urls,
+        // Extra files a skipped render would leave missing or stale.
+        rss,
+        atom,
+        meta_sidecar,
+    } = args;
+
+    let mut out = String::new();
+    let mut line = |name: &str, value: &dyn std::fmt::Debug| {
+        let _ = writeln!(out, "{name}={value:?}");
+    };
+    line("base_url", &base_url.as_str());
+    line(
+        "css_url",
+        &css_url.iter().map(url::Url::as_str).collect::<Vec<_>>(),
+    );
+    line("builtin_css", builtin_css);
+    line("reproducible", reproducible);
+    line("no_stamp", no_stamp);
+    line("lenient_css", lenient_css);
+    line("prune_fonts", prune_fonts);
+    line("purge_css", purge_css);
+    line("no_dedupe_css", no_dedupe_css);
+    line("no_font_fallback_injection", no_font_fallback_injection);
+    line("no_builtin_js", no_builtin_js);
+    line("no_js", no_js);
+    line("posts", posts);
+    line("toc", toc);
+    line("toc_max_depth", toc_max_depth);
+    line("search", search);
+    line("line_numbers", line_numbers);
+    line("code_copy_button", code_copy_button);
+    line("qr_permalinks", qr_permalinks);
+    line("qr_always", qr_always);
+    line("include_private", include_private);
+    line("paginate", paginate);
+    line("only_user", only_user);
+    line("exclude_user", exclude_user);
+    line("strip_reactions", strip_reactions);
+    line("strip_selector", strip_selector);
+    line("small_actions", small_actions);
+    line("show_revision_count", show_revision_count);
+    line("include_whispers", include_whispers);
+    line("mode", mode);
+    line("epub_posts_per_chapter", epub_posts_per_chapter);
+    line("offline", offline);
+    line("avatar_size", avatar_size);
+    line("css_integrity", css_integrity);
+    line("emit_preload_hints", emit_preload_hints);
+    line("assets_dir_name", assets_dir_name);
+    line("max_asset_size", max_asset_size);
+    line("preflight_above_bytes", preflight_above_bytes);
+    line("skip_large_assets", skip_large_assets);
+    line("max_total_bytes", max_total_bytes);
+    line("fetch_deadline_secs", fetch_deadline_secs);
+    line("keep_going", keep_going);
+    line("keep_original_urls", keep_original_urls);
+    line("rss", rss);
+    line("atom", atom);
+    line("meta_sidecar", meta_sidecar);
+    out
+}
+
+/// Fingerprint recorded in a previously rendered page, if the page exists and has one.
+pub fn previous(page: &Path) -> Option<String> {
+    let html = std::fs::read_to_string(page).ok()?;
+    let document = kuchiki::parse_html().one(html);
+    let meta = document
+        .select_first(&format!("meta[name=\"{META_NAME}\"]"))
+        .ok()?;
+    let content = meta.attributes.borrow().get("content")?.to_string();
+    Some(content)
+}
@@ -0,0 +1,240 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use kuchiki::traits::TendrilSink as _;
+use url::Url;
+
+use crate::cli::GcArgs;
+use crate::css::{URL_RE, url_arg};
+use crate::report;
+
+/// `gc`: delete (or with `--dry-run`, list) the files under the assets dir that no page or stylesheet refers to, and
+/// drop them from the asset manifests.
+pub fn gc(args: GcArgs) -> anyhow::Result<()> {
+    if !args.force && manifests(&args.dir)?.is_empty() {
+        anyhow::bail!(
+            "{} has no topic-{{id}}.assets-manifest.json, so it may not be discourse-topic-render output; pass --force to collect its assets anyway",
+            args.dir.display()
+        );
+    }
+    let orphans = find_orphans(&args.dir, &args.assets_dir_name)?;
+    let mut bytes = 0u64;
+    for path in &orphans {
+        bytes += std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        if args.dry_run {
+            println!("would remove {}", path.display());
+        } else {
+            std::fs::remove_file(path).with_context(|| format!("remove {}", path.display()))?;
+            println!("removed {}", path.display());
+        }
+    }
+    if !args.dry_run {
+        prune_manifests(&args.dir, &orphans)?;
+    }
+    eprintln!(
+        "{} {} unreferenced asset(s) ({})",
+        if args.dry_run { "found" } else { "removed" },
+        report::thousands(orphans.len() as u64),
+        report::human_bytes(bytes)
+    );
+    Ok(())
+}
+
+/// Files under `{dir}/{assets_dir_name}` that are not reachable from the HTML pages in `dir`, directly or through
+/// the stylesheets they link. Sorted.
+pub fn find_orphans(dir: &Path, assets_dir_name: &str) -> anyhow::Result<Vec<PathBuf>> {
+    // Canonical, so the walked files compare equal to the references resolved from the pages.
+    let dir = std::fs::canonicalize(dir).with_context(|| format!("resolve {}", dir.display()))?;
+    let assets = dir.join(assets_dir_name);
+
+    let mut referenced = HashSet::new();
+    let mut stylesheets = Vec::new();
+    for page in pages(&dir)? {
+        let html =
+            std::fs::read_to_string(&page).with_context(|| format!("read {}", page.display()))?;
+        for path in html_references(&page, &html) {
+            if path.starts_with(&assets) && referenced.insert(path.clone()) {
+                stylesheets.push(path);
+            }
+        }
+    }
+    // Stylesheets refer to fonts and images, and to each other.
+    while let Some(path) = stylesheets.pop() {
+        if !path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("css"))
+        {
+            continue;
+        }
+        let Ok(css) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        for url in css_references(&path, &css) {
+            if url.starts_with(&assets) && referenced.insert(url.clone()) {
+                stylesheets.push(url);
+            }
+        }
+    }
+
+    let mut orphans = Vec::new();
+    let mut pending = vec![assets];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries {
+            let path = entry
+                .with_context(|| format!("list {}", dir.display()))?
+                .path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if !referenced.contains(&path) {
+                orphans.push(path);
+            }
+        }
+    }
+    orphans.sort();
+    Ok(orphans)
+}
+
+/// Drop the entries for `removed` files from the `topic-{id}.assets-manifest.json` files in `dir`.
+fn prune_manifests(dir: &Path, removed: &[PathBuf]) -> anyhow::Result<()> {
+    let dir = std::fs::canonicalize(dir).with_context(|| format!("resolve {}", dir.display()))?;
+    let removed: HashSet<&PathBuf> = removed.iter().collect();
+    for path in manifests(&dir)? {
+        let json = std::fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+        let mut entries: Vec<serde_json::Value> =
+            serde_json::from_slice(&json).with_context(|| format!("parse {}", path.display()))?;
+        let before = entries.len();
+        entries.retain(|e| {
+            e["path"]
+                .as_str()
+                .is_none_or(|p| !removed.contains(&dir.join(p)))
+        });
+        if entries.len() != before {
+            let json = serde_json::to_vec_pretty(&entries).context("serialize asset manifest")?;
+            std::fs::write(&path, json).with_context(|| format!("write {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// The `*.html` files directly in `dir`, where renders put their pages.
+fn pages(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut pages = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("list {}", dir.display()))? {
+        let path = entry
+            .with_context(|| format!("list {}", dir.display()))?
+            .path();
+        if path.is_file()
+            && path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("html"))
+        {
+            pages.push(path);
+        }
+    }
+    Ok(pages)
+}
+
+/// The `topic-{id}.assets-manifest.json` files directly in `dir`; renders write one per topic, so a directory
+/// without any is not this tool's output.
+fn manifests(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut manifests = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("list {}", dir.display()))? {
+        let path = entry
+            .with_context(|| format!("list {}", dir.display()))?
+            .path();
+        if path.is_file()
+            && path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.ends_with(".assets-manifest.json"))
+        {
+            manifests.push(path);
+        }
+    }
+    Ok(manifests)
+}
+
+/// Local files an HTML page may load or link to. Every attribute value is tried as a relative URL, so nothing
+/// the page points at is missed; values that don't name a file under the assets dir are discarded by the caller.
+fn html_references(page: &Path, html: &str) -> Vec<PathBuf> {
+    let document = kuchiki::parse_html().one(html);
+    let mut out = Vec::new();
+    for node in document.descendants() {
+        if let Some(element) = node.as_element() {
+            for (name, attr) in &element.attributes.borrow().map {
+                match name.local.as_ref() {
+                    "srcset" => out.extend(
+                        attr.value
+                            .split(',')
+                            .filter_map(|c| c.split_whitespace().next())
+                            .filter_map(|c| local_path(page, c)),
+                    ),
+                    "style" => out.extend(css_references(page, &attr.value)),
+                    _ => out.extend(local_path(page, attr.value.trim())),
+                }
+            }
+            if element.name.local.as_ref() == "style" {
+                out.extend(css_references(page, &node.text_contents()));
+            }
+        }
+    }
+    out
+}
+
+/// Local files named by the `url()`s of a stylesheet at `from`.
+fn css_references(from: &Path, css: &str) -> Vec<PathBuf> {
+    URL_RE
+        .captures_iter(css)
+        .filter_map(|caps| local_path(from, url_arg(&caps)))
+        .collect()
+}
+
+/// `reference` resolved against the file `from`, when it names a local file; query and fragment dropped.
+fn local_path(from: &Path, reference: &str) -> Option<PathBuf> {
+    if reference.is_empty() || reference.starts_with('#') {
+        return None;
+    }
+    let base = Url::from_file_path(from).ok()?;
+    let url = base.join(reference).ok()?;
+    if url.scheme() != "file" {
+        return None;
+    }
+    url.to_file_path().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn references_resolve_relative_to_their_file() {
+        let page = Path::new("/out/topic-1.html");
+        let html = r##"<link rel="stylesheet" href="assets/css/site.css?v=1"><img src="assets/img/a%20b.png" srcset="assets/img/c.png 1x, assets/img/d.png 2x"><p style="background: url('assets/img/e.png')">x</p><a href="https://forum.example.com/t/1">f</a><a href="#post_2">g</a>"##;
+        let assets: Vec<PathBuf> = html_references(page, html)
+            .into_iter()
+            .filter(|p| p.starts_with("/out/assets"))
+            .collect();
+        assert_eq!(
+            assets,
+            [
+                "/out/assets/css/site.css",
+                "/out/assets/img/a b.png",
+                "/out/assets/img/c.png",
+                "/out/assets/img/d.png",
+                "/out/assets/img/e.png",
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(
+            css_references(
+                Path::new("/out/assets/css/site.css"),
+                "@font-face { src: url(\"../font/f.woff2\") } a { background: url(data:image/png;base64,AA==) }"
+            ),
+            [PathBuf::from("/out/assets/font/f.woff2")]
+        );
+    }
+}
@@ -0,0 +1,515 @@
+use std::collections::{BTreeSet, HashSet};
+use std::io::Read as _;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use base64::Engine as _;
+use kuchiki::traits::TendrilSink as _;
+use regex::Regex;
+
+use url::Url;
+
+use crate::css::{URL_RE, url_arg};
+use crate::urlres::{self, Origin, Resolved};
+
+/// Family the builtin theme lists last in its stack; only defined when fallbacks are injected.
+pub const BUILTIN_FALLBACK_FAMILY: &str = "dtr-fallback";
+
+/// A writing system whose glyphs Latin webfonts usually lack, with system fonts known to cover it.
+pub struct Script {
+    pub name: &'static str,
+    ranges: &'static [(u32, u32)],
+    fonts: &'static [&'static str],
+}
+
+impl Script {
+    fn contains(&self, c: char) -> bool {
+        let c = c as u32;
+        self.ranges.iter().any(|&(lo, hi)| (lo..=hi).contains(&c))
+    }
+
+    fn unicode_range(&self) -> String {
+        self.ranges
+            .iter()
+            .map(|&(lo, hi)| format!("U+{:X}-{:X}", lo, hi))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+pub static SCRIPTS: [Script; 9] = [
+    Script {
+        name: "Han",
+        ranges: &[
+            (0x2E80, 0x2FDF),
+            (0x3000, 0x303F),
+            (0x3400, 0x4DBF),
+            (0x4E00, 0x9FFF),
+            (0xF900, 0xFAFF),
+            (0xFF00, 0xFFEF),
+            (0x20000, 0x2FA1F),
+        ],
+        fonts: &[
+            "PingFang SC",
+            "Hiragino Sans GB",
+            "Microsoft YaHei",
+            "Noto Sans CJK SC",
+            "Source Han Sans SC",
+            "WenQuanYi Micro Hei",
+        ],
+    },
+    Script {
+        name: "Kana",
+        ranges: &[(0x3040, 0x30FF), (0x31F0, 0x31FF)],
+        fonts: &["Hiragino Sans", "Yu Gothic", "Meiryo", "Noto Sans CJK JP"],
+    },
+    Script {
+        name: "Hangul",
+        ranges: &[(0x1100, 0x11FF), (0x3130, 0x318F), (0xAC00, 0xD7AF)],
+        fonts: &["Apple SD Gothic Neo", "Malgun Gothic", "Noto Sans CJK KR"],
+    },
+    Script {
+        name: "Arabic",
+        ranges: &[
+            (0x0600, 0x06FF),
+            (0x0750, 0x077F),
+            (0x08A0, 0x08FF),
+            (0xFB50, 0xFDFF),
+            (0xFE70, 0xFEFF),
+        ],
+        fonts: &["Geeza Pro", "Segoe UI", "Noto Sans Arabic", "Tahoma"],
+    },
+    Script {
+        name: "Hebrew",
+        ranges: &[(0x0590, 0x05FF), (0xFB1D, 0xFB4F)],
+        fonts: &["Arial Hebrew", "Segoe UI", "Noto Sans Hebrew"],
+    },
+    Script {
+        name: "Cyrillic",
+        ranges: &[(0x0400, 0x052F)],
+        fonts: &["Helvetica Neue", "Segoe UI", "Roboto", "Noto Sans", "Arial"],
+    },
+    Script {
+        name: "Greek",
+        ranges: &[(0x0370, 0x03FF), (0x1F00, 0x1FFF)],
+        fonts: &["Helvetica Neue", "Segoe UI", "Roboto", "Noto Sans", "Arial"],
+    },
+    Script {
+        name: "Thai",
+        ranges: &[(0x0E00, 0x0E7F)],
+        fonts: &["Thonburi", "Leelawadee UI", "Noto Sans Thai"],
+    },
+    Script {
+        name: "Devanagari",
+        ranges: &[(0x0900, 0x097F)],
+        fonts: &["Kohinoor Devanagari", "Nirmala UI", "Noto Sans Devanagari"],
+    },
+];
+
+/// A script used by the rendered text that none of the embedded fonts covers.
+pub struct ScriptGap {
+    pub script: &'static Script,
+    /// Distinct characters not covered.
+    pub missing: usize,
+    /// A few of them, for the report.
+    pub sample: String,
+}
+
+pub struct GlyphAudit {
+    /// `font-family` names of the embedded `@font-face` rules, in order.
+    pub families: Vec<String>,
+    /// Embedded fonts that could be read.
+    pub fonts: usize,
+    pub gaps: Vec<ScriptGap>,
+}
+
+/// Distinct characters of the text content of `fragments`.
+pub fn text_chars<'a>(fragments: impl IntoIterator<Item = &'a str>) -> BTreeSet<char> {
+    let mut chars = BTreeSet::new();
+    for fragment in fragments {
+        let text = kuchiki::parse_html().one(fragment).text_contents();
+        chars.extend(text.chars().filter(|c| !c.is_whitespace()));
+    }
+    chars
+}
+
+/// Compare `chars` with the cmaps of the fonts embedded by `css`. Relative font URLs are files of `css_dir` (dir
+/// mode), resolved like any local stylesheet's against `site`; `data:` URIs are decoded in place.
+pub fn audit(css: &str, site: &Url, css_dir: Option<&Path>, chars: &BTreeSet<char>) -> GlyphAudit {
+    let mut families = Vec::new();
+    let mut covered = HashSet::new();
+    let mut fonts = 0;
+    for face in FONT_FACE_RE.captures_iter(css) {
+        let block = &face[1];
+        if let Some(family) = FAMILY_RE.captures(block) {
+            let family = family[1].trim().trim_matches(['"', '\'']).to_string();
+            if !family.is_empty() && !families.contains(&family) {
+                families.push(family);
+            }
+        }
+        for src in URL_RE.captures_iter(block) {
+            let Some(bytes) = load_font(url_arg(&src), site, css_dir) else {
+                continue;
+            };
+            match font_coverage(&bytes) {
+                Some(cmap) => {
+                    fonts += 1;
+                    covered.extend(cmap);
+                }
+                None => tracing::debug!(url = url_arg(&src), "could not read the font's cmap"),
+            }
+        }
+    }
+
+    let gaps = SCRIPTS
+        .iter()
+        .filter_map(|script| {
+            let missing: Vec<char> = chars
+                .iter()
+                .copied()
+                .filter(|&c| script.contains(c) && !covered.contains(&(c as u32)))
+                .collect();
+            (!missing.is_empty()).then(|| ScriptGap {
+                script,
+                missing: missing.len(),
+                sample: missing.iter().take(8).collect(),
+            })
+        })
+        .collect();
+    GlyphAudit {
+        families,
+        fonts,
+        gaps,
+    }
+}
+
+/// `@font-face` rules that make each family in `families` fall back to local system fonts for the uncovered
+/// scripts. Browsers merge faces of one family by `unicode-range`, so the site's own stacks pick them up.
+pub fn fallback_css(gaps: &[ScriptGap], families: &[String]) -> String {
+    let mut out = String::new();
+    if gaps.is_empty() || families.is_empty() {
+        return out;
+    }
+    out.push_str("\n/* system font fallbacks for scripts the embedded fonts do not cover */\n");
+    for family in families {
+        for gap in gaps {
+            let src = gap
+                .script
+                .fonts
+                .iter()
+                .map(|f| format!("local(\"{}\")", f))
+                .collect::<Vec<_>>()
+                .join(", ");
+            out.push_str(&format!(
+                "@font-face {{ font-family: \"{}\"; src: {}; unicode-range: {}; }}\n",
+                family.replace(['"', '\\'], ""),
+                src,
+                gap.script.unicode_range()
+            ));
+        }
+    }
+    out
+}
+
+static FONT_FACE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)@font-face\s*\{([^}]*)\}").expect("font-face regex"));
+static FAMILY_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)font-family\s*:\s*([^;]+)").expect("font-family regex"));
+
+fn load_font(url: &str, site: &Url, css_dir: Option<&Path>) -> Option<Vec<u8>> {
+    if let Some(data) = url.trim().strip_prefix("data:") {
+        let (_, payload) = data.split_once(";base64,")?;
+        return base64::engine::general_purpose::STANDARD
+            .decode(payload)
+            .ok();
+    }
+    // Every stylesheet of the css dir resolves relative URLs alike; the name is only there to have a file.
+    let stylesheet = css_dir?.join("site.css");
+    match urlres::resolve(site, Origin::Local(&stylesheet), url, &[]).ok()? {
+        Resolved::Local(path) => std::fs::read(path).ok(),
+        // Not embedded: nothing to audit.
+        Resolved::Remote(_) => None,
+    }
+}
+
+/// Code points mapped by the font's Unicode cmap subtables. Reads TrueType/OpenType, WOFF and WOFF2.
+pub fn font_coverage(bytes: &[u8]) -> Option<HashSet<u32>> {
+    let cmap = match bytes.get(..4)? {
+        b"wOFF" => woff_table(bytes, b"cmap")?,
+        b"wOF2" => woff2_table(bytes, b"cmap")?,
+        _ => ttf_parser::RawFace::parse(bytes, 0)
+            .ok()?
+            .table(ttf_parser::Tag::from_bytes(b"cmap"))?
+            .to_vec(),
+    };
+    let table = ttf_parser::cmap::Table::parse(&cmap)?;
+    let mut out = HashSet::new();
+    for subtable in table.subtables {
+        if subtable.is_unicode() {
+            subtable.codepoints(|c| {
+                out.insert(c);
+            });
+        }
+    }
+    Some(out)
+}
+
+fn be_u16(data: &[u8], at: usize) -> Option<usize> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?) as usize)
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<usize> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?) as usize)
+}
+
+/// A table of a WOFF 1.0 font, inflated if it was compressed.
+fn woff_table(data: &[u8], tag: &[u8; 4]) -> Option<Vec<u8>> {
+    let tables = be_u16(data, 12)?;
+    for i in 0..tables {
+        let entry = 44 + i * 20;
+        if data.get(entry..entry + 4)? != tag {
+            continue;
+        }
+        let offset = be_u32(data, entry + 4)?;
+        let comp_length = be_u32(data, entry + 8)?;
+        let orig_length = be_u32(data, entry + 12)?;
+        let raw = data.get(offset..offset.checked_add(comp_length)?)?;
+        if comp_length >= orig_length {
+            return Some(raw.to_vec());
+        }
+        let mut out = Vec::with_capacity(orig_length);
+        flate2::read::ZlibDecoder::new(raw)
+            .take(orig_length as u64)
+            .read_to_end(&mut out)
+            .ok()?;
+        return Some(out);
+    }
+    None
+}
+
+/// Tags of the WOFF2 known-table list, by index.
+const WOFF2_KNOWN_TAGS: [&[u8; 4]; 12] = [
+    b"cmap", b"head", b"hhea", b"hmtx", b"maxp", b"name", b"OS/2", b"post", b"cvt ", b"fpgm",
+    b"glyf", b"loca",
+];
+
+/// A table of a WOFF2 font. Only tables without a transform (everything but `glyf`/`loca`/`hmtx`) come out
+/// usable; `cmap` never has one.
+fn woff2_table(data: &[u8], tag: &[u8; 4]) -> Option<Vec<u8>> {
+    let flavor = data.get(4..8)?;
+    if flavor == b"ttcf" {
+        return None;
+    }
+    let tables = be_u16(data, 12)?;
+    let compressed_size = be_u32(data, 20)?;
+
+    let mut pos = 48;
+    let mut offset = 0usize;
+    let mut found = None;
+    for _ in 0..tables {
+        let flags = *data.get(pos)?;
+        pos += 1;
+        let index = (flags & 0x3f) as usize;
+        let table_tag: [u8; 4] = if index == 0x3f {
+            let t = data.get(pos..pos + 4)?.try_into().ok()?;
+            pos += 4;
+            t
+        } else {
+            // Tags past the first dozen are not needed here; treat them as unknown.
+            WOFF2_KNOWN_TAGS.get(index).map_or(*b"????", |t| **t)
+        };
+        let version = flags >> 6;
+        let orig_length = read_base128(data, &mut pos)?;
+        let transformed = if matches!(&table_tag, b"glyf" | b"loca") {
+            version != 3
+        } else {
+            version != 0
+        };
+        let length = if transformed {
+            read_base128(data, &mut pos)?
+        } else {
+            orig_length
+        };
+        if &table_tag == tag && !transformed {
+            found = Some((offset, length));
+        }
+        offset = offset.checked_add(length)?;
+    }
+
+    let (start, length) = found?;
+    let compressed = data.get(pos..pos.checked_add(compressed_size)?)?;
+    let mut out = Vec::with_capacity(offset);
+    brotli_decompressor::Decompressor::new(compressed, 4096)
+        .take(offset as u64)
+        .read_to_end(&mut out)
+        .ok()?;
+    out.get(start..start + length).map(<[u8]>::to_vec)
+}
+
+/// WOFF2 `UIntBase128`.
+fn read_base128(data: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut value: u32 = 0;
+    for i in 0..5 {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        if i == 0 && byte == 0x80 {
+            return None;
+        }
+        if value & 0xFE00_0000 != 0 {
+            return None;
+        }
+        value = (value << 7) | (byte & 0x7f) as u32;
+        if byte & 0x80 == 0 {
+            return Some(value as usize);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A font with only a cmap (format 4) mapping printable ASCII; `tests/fixtures/fonts/latin-only.ttf` holds
+    /// the same bytes.
+    fn latin_only_ttf() -> Vec<u8> {
+        let cmap = latin_cmap();
+        let mut font = Vec::new();
+        font.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+        font.extend_from_slice(&1u16.to_be_bytes());
+        font.extend_from_slice(&[0, 16, 0, 0, 0, 0]);
+        font.extend_from_slice(b"cmap");
+        font.extend_from_slice(&0u32.to_be_bytes());
+        font.extend_from_slice(&28u32.to_be_bytes());
+        font.extend_from_slice(&(cmap.len() as u32).to_be_bytes());
+        font.extend_from_slice(&cmap);
+        font
+    }
+
+    fn latin_cmap() -> Vec<u8> {
+        let mut cmap = Vec::new();
+        // version, numTables, then one Windows Unicode BMP record at offset 12.
+        for w in [0u16, 1, 3, 1] {
+            cmap.extend_from_slice(&w.to_be_bytes());
+        }
+        cmap.extend_from_slice(&12u32.to_be_bytes());
+        // Format 4 with segments 0x20-0x7E (mapped from glyph 1 on) and the closing 0xFFFF.
+        let subtable: [u16; 16] = [
+            4, 32, 0, 4, 4, 1, 0, 0x7E, 0xFFFF, 0, 0x20, 0xFFFF, 0xFFE1, 1, 0, 0,
+        ];
+        for w in subtable {
+            cmap.extend_from_slice(&w.to_be_bytes());
+        }
+        cmap
+    }
+
+    fn base128(mut n: usize) -> Vec<u8> {
+        let mut out = vec![(n & 0x7f) as u8];
+        n >>= 7;
+        while n > 0 {
+            out.insert(0, 0x80 | (n & 0x7f) as u8);
+            n >>= 7;
+        }
+        out
+    }
+
+    #[test]
+    fn cmap_coverage_from_every_container() {
+        let ttf = latin_only_ttf();
+        assert_eq!(
+            ttf,
+            include_bytes!("../tests/fixtures/fonts/latin-only.ttf")
+        );
+        let cmap = latin_cmap();
+
+        let mut woff = b"wOFF".to_vec();
+        woff.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+        woff.extend_from_slice(&0u32.to_be_bytes());
+        woff.extend_from_slice(&1u16.to_be_bytes());
+        woff.resize(44, 0);
+        let mut deflated = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+        std::io::Write::write_all(&mut deflated, &[cmap.clone(), cmap.clone()].concat()).unwrap();
+        let deflated = deflated.finish().unwrap();
+        woff.extend_from_slice(b"cmap");
+        woff.extend_from_slice(&64u32.to_be_bytes());
+        woff.extend_from_slice(&(deflated.len() as u32).to_be_bytes());
+        woff.extend_from_slice(&(2 * cmap.len() as u32).to_be_bytes());
+        woff.extend_from_slice(&0u32.to_be_bytes());
+        woff.extend_from_slice(&deflated);
+
+        let mut woff2 = b"wOF2".to_vec();
+        woff2.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+        woff2.extend_from_slice(&0u32.to_be_bytes());
+        woff2.extend_from_slice(&2u16.to_be_bytes());
+        woff2.resize(48, 0);
+        // A transformed glyf table first, so the cmap sits at an offset in the decompressed stream.
+        woff2.push(10);
+        woff2.extend(base128(400));
+        woff2.extend(base128(5));
+        woff2.push(0);
+        woff2.extend(base128(cmap.len()));
+        let stream = [vec![0u8; 5], cmap.clone()].concat();
+        let mut compressed = Vec::new();
+        brotli::BrotliCompress(&mut &stream[..], &mut compressed, &Default::default()).unwrap();
+        woff2[20..24].copy_from_slice(&(compressed.len() as u32).to_be_bytes());
+        woff2.extend_from_slice(&compressed);
+
+        for font in [&ttf, &woff, &woff2] {
+            let coverage = font_coverage(font).expect("cmap");
+            assert_eq!(coverage.len(), 0x7E - 0x20 + 1);
+            assert!(coverage.contains(&('A' as u32)) && !coverage.contains(&('中' as u32)));
+        }
+        assert!(font_coverage(b"wOF2 truncated").is_none());
+    }
+
+    #[test]
+    fn audit_flags_scripts_the_fonts_miss() {
+        let b64 = base64::engine::general_purpose::STANDARD.encode(latin_only_ttf());
+        let css = format!(
+            "@font-face {{ font-family: 'Site Sans'; src: url(data:font/ttf;base64,{b64}) format('truetype'); }}"
+        );
+        let chars = text_chars(["<p>Hello 世界, <b>Привет</b></p>"]);
+        let site = Url::parse("https://forum.example.com/").unwrap();
+        let audit = audit(&css, &site, None, &chars);
+        assert_eq!(audit.families, ["Site Sans"]);
+        assert_eq!(audit.fonts, 1);
+        let gaps: Vec<(&str, usize, &str)> = audit
+            .gaps
+            .iter()
+            .map(|g| (g.script.name, g.missing, g.sample.as_str()))
+            .collect();
+        assert_eq!(gaps, [("Han", 2, "世界"), ("Cyrillic", 6, "Пвеирт")]);
+
+        let fallback = fallback_css(&audit.gaps, &audit.families);
+        assert!(
+            fallback
+                .contains("@font-face { font-family: \"Site Sans\"; src: local(\"PingFang SC\"), ")
+        );
+        assert!(
+            fallback.contains("unicode-range: U+2E80-2FDF, U+3000-303F, U+3400-4DBF, U+4E00-9FFF")
+        );
+        assert!(fallback_css(&[], &audit.families).is_empty());
+    }
+
+    #[test]
+    fn audit_reads_fonts_relative_to_the_css_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let css_dir = dir.path().join("css");
+        std::fs::create_dir_all(dir.path().join("fonts")).unwrap();
+        std::fs::create_dir_all(&css_dir).unwrap();
+        std::fs::write(dir.path().join("fonts/Latin Only.ttf"), latin_only_ttf()).unwrap();
+        let site = Url::parse("https://forum.example.com/").unwrap();
+        let chars = text_chars(["<p>Hello</p>"]);
+
+        for src in [
+            "../fonts/Latin%20Only.ttf?v=2#iefix",
+            "'../fonts/Latin Only.ttf'",
+        ] {
+            let css = format!("@font-face {{ font-family: 'Site Sans'; src: url({src}); }}");
+            assert_eq!(audit(&css, &site, Some(&css_dir), &chars).fonts, 1, "{src}");
+        }
+        // Fonts left on the forum are not embedded.
+        let css = "@font-face { font-family: 'Site Sans'; src: url(/fonts/Latin%20Only.ttf); }";
+        assert_eq!(audit(css, &site, Some(&css_dir), &chars).fonts, 0);
+    }
+}
@@ -1,37 +1,573 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
 use anyhow::Context as _;
+use base64::Engine as _;
+use futures_util::{StreamExt as _, TryStreamExt as _, stream};
+use kuchiki::iter::NodeIterator as _;
 use kuchiki::traits::TendrilSink as _;
 use maud::{DOCTYPE, Markup, PreEscaped, html};
 use url::Url;
 
-use crate::assets::{AssetKind, AssetRequest, AssetSource, AssetStore};
+use crate::assets::{
+    AssetKind, AssetRequest, AssetSource, AssetStore, OutputMode, with_download_time,
+};
 use crate::builtin;
+use crate::cli::{FetchOrder, PostRange, SmallActionsMode};
+use crate::css::{URL_RE, rewrite_embedded_css, url_arg};
+use crate::fetcher::AssetTooLarge;
+use crate::progress::PostTiming;
+use crate::qr;
+use crate::strict;
 use crate::topic::{Post, TopicJson};
+use crate::urlres::{forum_route, resolve_url};
 
 pub struct RenderedPost {
     pub post_number: u64,
     pub username: String,
     pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub version: Option<u64>,
     pub avatar_src: String,
+    /// `srcset` of the larger `--avatar-size`s by pixel density, the `src` being 1x; empty with a single size.
+    pub avatar_srcset: String,
+    /// Forum URL of the avatar `src`, kept with `--keep-original-urls`.
+    pub avatar_original_src: Option<String>,
     pub cooked_html: String,
+    pub reply_to: Option<ReplyTo>,
+    /// Label for small-action posts, which render as a compact one-liner.
+    pub small_action: Option<String>,
+    /// Headings of the cooked HTML down to `--toc-max-depth`, collected when `--toc` is set.
+    pub headings: Vec<Heading>,
+    /// `span.dtr-qr-permalink` with an inline SVG QR code of the post's forum URL (`--qr-permalinks`).
+    pub qr_svg: Option<String>,
+}
+
+pub struct Heading {
+    pub level: u8,
+    pub id: String,
+    pub text: String,
+}
+
+pub struct RewrittenCooked {
+    pub html: String,
+    pub headings: Vec<Heading>,
+}
+
+pub struct ReplyTo {
+    pub post_number: u64,
+    pub username: Option<String>,
+    /// `#post_N` when the target is part of this render, otherwise an absolute forum URL.
+    pub href: String,
+}
+
+/// Where each rendered post ends up. Links to posts missing from here go back to the forum.
+pub struct PostLocations {
+    topic_id: u64,
+    /// Post number → 1-based page number.
+    pages: HashMap<u64, usize>,
+    paginated: bool,
+}
+
+impl PostLocations {
+    /// `page_size` splits the posts into pages of that many posts, in order.
+    pub fn new(topic_id: u64, post_numbers: &[u64], page_size: Option<usize>) -> Self {
+        let per_page = page_size.unwrap_or(usize::MAX).max(1);
+        Self {
+            topic_id,
+            pages: post_numbers
+                .iter()
+                .enumerate()
+                .map(|(i, n)| (*n, i / per_page + 1))
+                .collect(),
+            paginated: page_size.is_some(),
+        }
+    }
+
+    pub fn page_of(&self, post_number: u64) -> Option<usize> {
+        self.pages.get(&post_number).copied()
+    }
+
+    /// Link to a rendered post from a post on `from_page`: `#post_N` on the same page, otherwise
+    /// `topic-{id}-pK.html#post_N`.
+    fn href(&self, post_number: u64, from_page: usize) -> Option<String> {
+        let page = self.page_of(post_number)?;
+        Some(if !self.paginated || page == from_page {
+            format!("#post_{}", post_number)
+        } else {
+            format!(
+                "{}#post_{}",
+                page_file_name(self.topic_id, page),
+                post_number
+            )
+        })
+    }
+}
+
+/// File name of a page written by `--paginate`.
+pub fn page_file_name(topic_id: u64, page: usize) -> String {
+    format!("topic-{}-p{}.html", topic_id, page)
 }
 
 pub struct RenderContext<'a> {
     pub base_url: &'a Url,
     pub topic_id: u64,
+    pub locations: &'a PostLocations,
+    /// Page of the post being rewritten.
+    pub page: usize,
+    /// Collect the headings down to this level (`<h1>` is 1) for the table of contents.
+    pub collect_headings: Option<u8>,
+    /// Replace assets that fail to download with links instead of failing the render.
+    pub keep_going: bool,
+    /// Elements removed before anything else is rewritten (see [`noise_selectors`]).
+    pub strip_selectors: &'a [String],
+    /// Wrap each line of `<pre><code>` blocks for numbering.
+    pub line_numbers: bool,
+    /// Put a copy button before each `<pre><code>`; ids are made unique with the post number.
+    pub code_copy_buttons: bool,
+    pub post_number: u64,
+    pub max_css_bytes: usize,
+    pub lenient_css: bool,
+    /// Fail on `<img>` elements without an `alt` attribute instead of only warning.
+    pub require_alt_text: bool,
+    /// Record the remote URL of each localized image and link in `data-dtr-original-src`/`-href`.
+    pub keep_original_urls: bool,
+}
+
+pub struct RenderOptions<'a> {
+    pub base_url: &'a Url,
+    /// Sizes for `{size}` in `avatar_template`; the smallest is the `src`.
+    pub avatar_sizes: &'a [u32],
+    pub small_actions: SmallActionsMode,
+    pub include_whispers: bool,
+    pub posts: Option<PostRange>,
+    /// Collect every post's headings for the table of contents, down to `toc_max_depth`.
+    pub toc: bool,
+    pub toc_max_depth: u8,
+    pub fetch_order: FetchOrder,
+    /// Split the output into pages of this many posts.
+    pub paginate: Option<usize>,
+    /// When non-empty, only posts by these users (case-insensitive) are rendered.
+    pub only_users: &'a [String],
+    /// Posts by these users (case-insensitive) are left out.
+    pub exclude_users: &'a [String],
+    pub keep_going: bool,
+    /// Posts rendered (and prefetch downloads started) at once.
+    pub concurrency: usize,
+    /// Remove reaction and like summaries.
+    pub strip_reactions: bool,
+    /// Extra CSS selectors of elements to remove.
+    pub strip_selectors: &'a [String],
+    /// Number the lines of code blocks.
+    pub line_numbers: bool,
+    pub code_copy_buttons: bool,
+    /// Add a QR code of the forum URL to each post header.
+    pub qr_permalinks: bool,
+    /// Limit and leniency for stylesheets pulled in by `@import`s in cooked `<style>` elements.
+    pub max_css_bytes: usize,
+    pub lenient_css: bool,
+    pub require_alt_text: bool,
+    pub keep_original_urls: bool,
+    /// Warn about posts that take longer than this to render, naming them.
+    pub slow_post: Option<Duration>,
+}
+
+/// Reaction and "N people liked this" summaries some exports bake into cooked HTML.
+const REACTION_SELECTORS: [&str; 6] = [
+    ".post-likes",
+    ".discourse-reactions-counter",
+    ".discourse-reactions-list",
+    ".discourse-reactions-actions",
+    ".discourse-reactions-reaction-button",
+    ".reactions-summary",
+];
+
+/// Selectors of "noise" removed from cooked HTML: the reaction summaries for `--strip-reactions`, then each
+/// `--strip-selector`.
+pub fn noise_selectors(opts: &RenderOptions<'_>) -> Vec<String> {
+    let builtin = REACTION_SELECTORS
+        .iter()
+        .filter(|_| opts.strip_reactions)
+        .map(|s| s.to_string());
+    builtin
+        .chain(opts.strip_selectors.iter().cloned())
+        .collect()
+}
+
+/// Fail early on a selector kuchiki can't parse.
+pub fn validate_selectors(selectors: &[String]) -> anyhow::Result<()> {
+    for selector in selectors {
+        if kuchiki::Selectors::compile(selector).is_err() {
+            anyhow::bail!("invalid --strip-selector {:?}", selector);
+        }
+    }
+    Ok(())
+}
+
+/// Detach every element matching one of `selectors`; returns how many were removed.
+fn strip_noise(document: &kuchiki::NodeRef, selectors: &[String]) -> usize {
+    let mut removed = 0;
+    for selector in selectors {
+        let Ok(nodes) = document.select(selector) else {
+            continue;
+        };
+        for node in nodes.collect::<Vec<_>>() {
+            node.as_node().detach();
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// Rebuild each `<pre><code>` as one `span.dtr-line[data-line]` per line, under `pre.dtr-line-numbered`. The
+/// numbers themselves come from CSS, so copying the code doesn't copy them. Highlighting markup inside the
+/// block is flattened to text.
+fn number_code_lines(document: &kuchiki::NodeRef) {
+    let Ok(codes) = document.select("pre > code") else {
+        return;
+    };
+    for code in codes.collect::<Vec<_>>() {
+        let text = code.text_contents();
+        let text = text.strip_suffix('\n').unwrap_or(&text);
+        let node = code.as_node();
+        for child in node.children().collect::<Vec<_>>() {
+            child.detach();
+        }
+        let lines: Vec<String> = text
+            .split('\n')
+            .enumerate()
+            .map(|(i, line)| {
+                format!(
+                    "<span class=\"dtr-line\" data-line=\"{}\">{}</span>",
+                    i + 1,
+                    html_escape_text(line)
+                )
+            })
+            .collect();
+        let frag = format!("<pre><code>{}</code></pre>", lines.join("\n"));
+        let doc = kuchiki::parse_html().one(frag);
+        if let Ok(numbered) = doc.select_first("code") {
+            for child in numbered.as_node().children().collect::<Vec<_>>() {
+                node.append(child);
+            }
+        }
+        if let Some(pre) = node.parent()
+            && let Some(el) = pre.as_element()
+        {
+            let mut attrs = el.attributes.borrow_mut();
+            let class = match attrs.get("class") {
+                Some(c) if !c.trim().is_empty() => format!("{} dtr-line-numbered", c.trim()),
+                _ => "dtr-line-numbered".to_string(),
+            };
+            attrs.insert("class", class);
+        }
+    }
+}
+
+/// Turn `div.spoiler` (revealed by a click handler on the forum) into a native `details.dtr-spoiler`, keeping its
+/// children.
+fn convert_spoilers(document: &kuchiki::NodeRef) {
+    let Ok(spoilers) = document.select("div.spoiler") else {
+        return;
+    };
+    for spoiler in spoilers.collect::<Vec<_>>() {
+        let doc = kuchiki::parse_html().one(
+            "<details class=\"dtr-spoiler\"><summary class=\"dtr-spoiler-label\">Spoiler (click to reveal)</summary></details>",
+        );
+        let Ok(details) = doc.select_first("details") else {
+            continue;
+        };
+        let details = details.as_node().clone();
+        let node = spoiler.as_node();
+        for child in node.children().collect::<Vec<_>>() {
+            details.append(child);
+        }
+        node.insert_before(details);
+        node.detach();
+    }
+}
+
+/// Append `a.dtr-quote-jump` to the title of each `aside.quote` whose quoted post (`data-post`) is rendered from
+/// this topic (`data-topic`, when present). The title's own link points at the quoted user, not the post.
+fn add_quote_jump_links(
+    document: &kuchiki::NodeRef,
+    topic_id: u64,
+    locations: &PostLocations,
+    page: usize,
+) {
+    let Ok(asides) = document.select("aside.quote[data-post]") else {
+        return;
+    };
+    for aside in asides.collect::<Vec<_>>() {
+        let (post, topic) = {
+            let attrs = aside.attributes.borrow();
+            (
+                attrs.get("data-post").and_then(|p| p.parse::<u64>().ok()),
+                attrs.get("data-topic").map(|t| t.parse::<u64>().ok()),
+            )
+        };
+        let Some(post) = post else { continue };
+        if topic.is_some_and(|t| t != Some(topic_id)) {
+            continue;
+        }
+        let Some(href) = locations.href(post, page) else {
+            continue;
+        };
+        let node = aside.as_node();
+        if node.select_first(".dtr-quote-jump").is_ok() {
+            continue;
+        }
+        let frag = format!(
+            "<a class=\"dtr-quote-jump\" href=\"{}\">Jump to original</a>",
+            html_escape_attr(&href)
+        );
+        let doc = kuchiki::parse_html().one(frag);
+        let Ok(link) = doc.select_first("a") else {
+            continue;
+        };
+        match node.select_first(".title") {
+            Ok(title) => title.as_node().append(link.as_node().clone()),
+            Err(()) => node.prepend(link.as_node().clone()),
+        }
+    }
+}
+
+/// Point the links of each onebox of a post rendered from this topic (`aside.onebox` or a quote-style
+/// `aside.quote`, identified by `data-onebox-src`) at that post, and mark it with `span.dtr-onebox-local`.
+/// Oneboxes of other topics, and of posts left out of the output, keep their forum links.
+fn localize_same_topic_oneboxes(
+    document: &kuchiki::NodeRef,
+    base_url: &Url,
+    topic_id: u64,
+    locations: &PostLocations,
+    page: usize,
+) {
+    let Ok(asides) = document.select("aside.onebox[data-onebox-src], aside.quote[data-onebox-src]")
+    else {
+        return;
+    };
+    for aside in asides.collect::<Vec<_>>() {
+        let src = aside
+            .attributes
+            .borrow()
+            .get("data-onebox-src")
+            .map(|s| s.to_string());
+        let Some(src) = src else { continue };
+        let Some(target) = topic_local_anchor(base_url, topic_id, locations, page, &src) else {
+            continue;
+        };
+        // Posts that are not rendered map onto their absolute forum URL; local targets are relative.
+        if Url::parse(&target).is_ok() {
+            continue;
+        }
+        let node = aside.as_node();
+        if let Ok(links) = node.select("a[href]") {
+            for link in links {
+                let href = link.attributes.borrow().get("href").map(|s| s.to_string());
+                let Some(href) = href else { continue };
+                let same_post = href == src
+                    || topic_local_anchor(base_url, topic_id, locations, page, &href).as_ref()
+                        == Some(&target);
+                if same_post {
+                    link.attributes.borrow_mut().insert("href", target.clone());
+                }
+            }
+        }
+        if node.select_first(".dtr-onebox-local").is_ok() {
+            continue;
+        }
+        let doc =
+            kuchiki::parse_html().one("<span class=\"dtr-onebox-local\">(in this archive)</span>");
+        let Ok(marker) = doc.select_first("span") else {
+            continue;
+        };
+        match node.select_first("header.source, .title") {
+            Ok(header) => header.as_node().append(marker.as_node().clone()),
+            Err(()) => node.prepend(marker.as_node().clone()),
+        }
+    }
+}
+
+/// Give each `<pre><code>` an id (`dtr-code-{post}-{n}`) and a `button.dtr-copy-btn` targeting it, placed
+/// before the `<pre>`; [`builtin::CODE_COPY_JS`] wires the buttons up.
+fn add_code_copy_buttons(document: &kuchiki::NodeRef, post_number: u64) {
+    let Ok(codes) = document.select("pre > code") else {
+        return;
+    };
+    for (i, code) in codes.collect::<Vec<_>>().into_iter().enumerate() {
+        let id = format!("dtr-code-{}-{}", post_number, i + 1);
+        code.attributes.borrow_mut().insert("id", id.clone());
+        let Some(pre) = code.as_node().parent() else {
+            continue;
+        };
+        let frag = format!(
+            "<button type=\"button\" class=\"dtr-copy-btn\" aria-label=\"Copy code\" data-target=\"{}\">Copy</button>",
+            id
+        );
+        let doc = kuchiki::parse_html().one(frag);
+        if let Ok(button) = doc.select_first("button") {
+            pre.insert_before(button.as_node().clone());
+        }
+    }
+}
+
+/// Posts from the topic that will appear in the output, in stream order.
+pub fn select_posts<'t>(topic: &'t TopicJson, opts: &RenderOptions<'_>) -> Vec<&'t Post> {
+    topic
+        .post_stream
+        .posts
+        .iter()
+        .filter(|p| {
+            if let Some(range) = &opts.posts
+                && !range.contains(p.post_number)
+            {
+                return false;
+            }
+            if p.is_whisper() && !opts.include_whispers {
+                return false;
+            }
+            if !opts.only_users.is_empty() && !post_by_any(p, opts.only_users) {
+                return false;
+            }
+            if post_by_any(p, opts.exclude_users) {
+                return false;
+            }
+            if p.is_small_action() {
+                return matches!(opts.small_actions, SmallActionsMode::Render);
+            }
+            !p.cooked.as_deref().unwrap_or("").trim().is_empty()
+        })
+        .collect()
+}
+
+fn post_by_any(post: &Post, names: &[String]) -> bool {
+    [post.username.as_deref(), post.display_username.as_deref()]
+        .into_iter()
+        .flatten()
+        .any(|u| names.iter().any(|n| n.eq_ignore_ascii_case(u)))
 }
 
 pub async fn render_posts(
     topic: &TopicJson,
-    base_url: &Url,
-    avatar_size: u32,
+    opts: &RenderOptions<'_>,
     store: &AssetStore,
 ) -> anyhow::Result<Vec<RenderedPost>> {
-    let mut rendered = Vec::with_capacity(topic.post_stream.posts.len());
-    for post in &topic.post_stream.posts {
-        let cooked = post.cooked.as_deref().unwrap_or("").trim().to_string();
-        if cooked.is_empty() {
-            continue;
+    let selected = select_posts(topic, opts);
+    let post_numbers: Vec<u64> = selected.iter().map(|p| p.post_number).collect();
+    let concurrency = opts.concurrency.max(1);
+
+    // Per post: the time spent finding its assets, then downloading them, in the pass below.
+    let mut prefetched: HashMap<u64, (Duration, Duration)> = HashMap::new();
+    if matches!(opts.fetch_order, FetchOrder::Size) {
+        // Results (including failures) are cached by the store; the render pass below reports them per post.
+        // Downloads start in plan order, and the fetcher's permits are handed out first come, first served.
+        let mut plan = prefetch_plan(&selected, opts);
+        order_by_expected_size(&mut plan.requests, store, concurrency).await;
+        let downloads = std::sync::Mutex::new(HashMap::<u64, Duration>::new());
+        stream::iter(plan.requests)
+            .for_each_concurrent(concurrency, |(post_number, req)| {
+                let downloads = &downloads;
+                async move {
+                    let started = Instant::now();
+                    let _ = store.get(req).await;
+                    *downloads
+                        .lock()
+                        .expect("prefetch timings")
+                        .entry(post_number)
+                        .or_default() += started.elapsed();
+                }
+            })
+            .await;
+        let downloads = downloads.into_inner().expect("prefetch timings");
+        for (post_number, scan) in plan.scan {
+            let download = downloads.get(&post_number).copied().unwrap_or_default();
+            prefetched.insert(post_number, (scan, download));
         }
+    }
+
+    let renderer = PostRenderer {
+        topic,
+        opts,
+        locations: PostLocations::new(topic.id, &post_numbers, opts.paginate),
+        strip_selectors: noise_selectors(opts),
+        prefetched,
+    };
+    // Several posts in flight at once; `buffered` keeps the output in stream order.
+    stream::iter(selected)
+        .map(|post| renderer.render(post, store))
+        .buffered(concurrency)
+        .try_collect()
+        .await
+}
+
+/// What every post of one `render_posts` call shares.
+struct PostRenderer<'a> {
+    topic: &'a TopicJson,
+    opts: &'a RenderOptions<'a>,
+    locations: PostLocations,
+    strip_selectors: Vec<String>,
+    /// Scan and download time of each post in the prefetch pass.
+    prefetched: HashMap<u64, (Duration, Duration)>,
+}
+
+impl PostRenderer<'_> {
+    /// [`render_post`](Self::render_post), timed. With `--slow-post-secs`, a post still rendering past the limit
+    /// is named while it runs, and again with where its time went once it is done.
+    async fn render(&self, post: &Post, store: &AssetStore) -> anyhow::Result<RenderedPost> {
+        let started = Instant::now();
+        let (scan, prefetched) = self
+            .prefetched
+            .get(&post.post_number)
+            .copied()
+            .unwrap_or_default();
+        let mut rendering = std::pin::pin!(with_download_time(self.render_post(post, store)));
+        let (rendered, waited) = match self.opts.slow_post {
+            Some(limit) => {
+                let left = limit.saturating_sub(scan + prefetched);
+                match tokio::time::timeout(left, &mut rendering).await {
+                    Ok(done) => done,
+                    Err(_) => {
+                        tracing::warn!(
+                            post = post.post_number,
+                            secs = limit.as_secs(),
+                            "post is still rendering"
+                        );
+                        rendering.await
+                    }
+                }
+            }
+            None => rendering.await,
+        };
+
+        let elapsed = started.elapsed();
+        let timing = PostTiming::new(
+            post.post_number,
+            scan,
+            prefetched + waited.min(elapsed),
+            elapsed.saturating_sub(waited),
+        );
+        if let Some(limit) = self.opts.slow_post
+            && timing.total_secs >= limit.as_secs_f64()
+        {
+            tracing::warn!(
+                post = post.post_number,
+                secs = format!("{:.1}", timing.total_secs),
+                downloads = format!("{:.1}", timing.download_secs),
+                dominant = timing.dominant,
+                "slow post"
+            );
+        }
+        if let Some(p) = store.progress() {
+            p.post_timed(timing);
+        }
+        rendered
+    }
+
+    async fn render_post(&self, post: &Post, store: &AssetStore) -> anyhow::Result<RenderedPost> {
+        let (topic, opts, locations) = (self.topic, self.opts, &self.locations);
+        let base_url = opts.base_url;
+        let page = locations.page_of(post.post_number).unwrap_or(1);
+        let cooked = post.cooked.as_deref().unwrap_or("").trim().to_string();
 
         let username = post
             .display_username
@@ -39,43 +575,101 @@ pub async fn render_posts(
             .or_else(|| post.username.clone())
             .unwrap_or_else(|| "unknown".to_string());
 
-        let avatar_src = resolve_and_fetch_avatar(post, base_url, avatar_size, store).await?;
+        let small_action = if post.is_small_action() {
+            post.action_code.as_deref().map(small_action_label)
+        } else {
+            None
+        };
 
-        let cooked_html = rewrite_cooked_html(
-            &cooked,
-            &RenderContext {
-                base_url,
-                topic_id: topic.id,
-            },
-            store,
-        )
-        .await
-        .with_context(|| format!("rewrite cooked html for post {}", post.post_number))?;
+        let (avatar_src, avatar_srcset, avatar_url) = if small_action.is_some() {
+            Default::default()
+        } else {
+            match resolve_and_fetch_avatar(post, base_url, opts.avatar_sizes, store).await {
+                Ok(avatar) => avatar,
+                Err(e) if opts.keep_going => {
+                    tracing::warn!(error = %format!("{:#}", e), post = post.post_number, "avatar download failed; omitting it");
+                    Default::default()
+                }
+                Err(e) => return Err(e),
+            }
+        };
 
-        rendered.push(RenderedPost {
-            post_number: post.post_number,
-            username,
-            created_at: post.created_at.clone(),
-            avatar_src,
-            cooked_html,
+        let RewrittenCooked {
+            html: cooked_html,
+            headings,
+        } = if cooked.is_empty() {
+            RewrittenCooked {
+                html: String::new(),
+                headings: Vec::new(),
+            }
+        } else {
+            rewrite_cooked_html(
+                &cooked,
+                &RenderContext {
+                    base_url,
+                    topic_id: topic.id,
+                    locations,
+                    page,
+                    collect_headings: opts.toc.then_some(opts.toc_max_depth),
+                    keep_going: opts.keep_going,
+                    strip_selectors: &self.strip_selectors,
+                    line_numbers: opts.line_numbers,
+                    code_copy_buttons: opts.code_copy_buttons,
+                    post_number: post.post_number,
+                    max_css_bytes: opts.max_css_bytes,
+                    lenient_css: opts.lenient_css,
+                    require_alt_text: opts.require_alt_text,
+                    keep_original_urls: opts.keep_original_urls,
+                },
+                store,
+            )
+            .await
+            .with_context(|| format!("rewrite cooked html for post {}", post.post_number))?
+        };
+
+        let reply_to = post.reply_to_post_number.map(|n| ReplyTo {
+            post_number: n,
+            username: post.reply_to_user.as_ref().and_then(|u| u.username.clone()),
+            href: locations
+                .href(n, page)
+                .unwrap_or_else(|| remote_post_url(base_url, topic.id, n)),
         });
 
+        let qr_svg = (opts.qr_permalinks && small_action.is_none())
+            .then(|| qr::permalink_svg(&remote_post_url(base_url, topic.id, post.post_number)))
+            .flatten();
+
         if let Some(p) = store.progress() {
             p.post_done(post.post_number);
         }
+        Ok(RenderedPost {
+            post_number: post.post_number,
+            username,
+            created_at: post.created_at.clone(),
+            updated_at: post.updated_at.clone(),
+            version: post.version,
+            avatar_src,
+            avatar_srcset,
+            avatar_original_src: avatar_url
+                .filter(|_| opts.keep_original_urls)
+                .map(String::from),
+            cooked_html,
+            reply_to,
+            small_action,
+            headings,
+            qr_svg,
+        })
     }
-    Ok(rendered)
 }
 
-async fn resolve_and_fetch_avatar(
-    post: &Post,
-    base_url: &Url,
-    avatar_size: u32,
-    store: &AssetStore,
-) -> anyhow::Result<String> {
+fn avatar_srcset(p: &RenderedPost) -> Option<&str> {
+    (!p.avatar_srcset.is_empty()).then_some(p.avatar_srcset.as_str())
+}
+
+fn avatar_url(post: &Post, base_url: &Url, avatar_size: u32) -> anyhow::Result<Option<Url>> {
     let template = post.avatar_template.as_deref().unwrap_or("");
     if template.is_empty() {
-        return Ok(String::new());
+        return Ok(None);
     }
 
     let mut t = template.to_string();
@@ -83,20 +677,192 @@ async fn resolve_and_fetch_avatar(
         t = t.replace("{size}", &avatar_size.to_string());
     }
 
-    let url = resolve_any_url(base_url, &t)
+    let url = resolve_url(base_url, &t)
         .with_context(|| format!("resolve avatar_template {}", template))?;
-    let req = AssetRequest {
-        kind: AssetKind::Avatar,
+    Ok(Some(url))
+}
+
+/// The downloads of the prefetch pass, each with the post that first refers to it, and the time spent finding each
+/// post's assets.
+struct PrefetchPlan {
+    requests: Vec<(u64, AssetRequest)>,
+    scan: HashMap<u64, Duration>,
+}
+
+/// Assets of the selected posts, smallest expected download first: avatars, emoji, inline images, then
+/// full-size originals behind image links, found as the render pass finds them. Unresolvable URLs are left for the
+/// render pass to report.
+fn prefetch_plan(posts: &[&Post], opts: &RenderOptions<'_>) -> PrefetchPlan {
+    let strip_selectors = noise_selectors(opts);
+    let mut planned: Vec<(u8, u64, AssetRequest)> = Vec::new();
+    let mut scan = HashMap::new();
+    let remote = |kind, url| AssetRequest {
+        kind,
         source: AssetSource::Remote(url),
     };
-    store.get(req).await
+    for post in posts {
+        let started = Instant::now();
+        let n = post.post_number;
+        if !post.is_small_action() {
+            // The same requests `resolve_and_fetch_avatar` makes, so the render pass finds them cached.
+            let smallest = opts.avatar_sizes.iter().copied().min();
+            for &size in opts.avatar_sizes {
+                let request = match Some(size) == smallest {
+                    true => avatar_request(post, opts.base_url, size),
+                    false => avatar_url(post, opts.base_url, size)
+                        .map(|url| url.map(|url| remote(AssetKind::Avatar, url))),
+                };
+                if let Ok(Some(request)) = request {
+                    planned.push((0, n, request));
+                }
+            }
+        }
+
+        let cooked = post.cooked.as_deref().unwrap_or("");
+        if cooked.trim().is_empty() {
+            scan.insert(n, started.elapsed());
+            continue;
+        }
+        let doc = kuchiki::parse_html().one(cooked);
+        strip_noise(&doc, &strip_selectors);
+        if let Ok(nodes) = doc.select("img") {
+            for node in nodes {
+                let attrs = node.attributes.borrow();
+                let Some((raw, _)) = img_download_src(attrs.get("srcset"), attrs.get("src")) else {
+                    continue;
+                };
+                let Ok(url) = resolve_url(opts.base_url, &raw) else {
+                    continue;
+                };
+                let is_emoji = attrs
+                    .get("class")
+                    .is_some_and(|c| c.split_whitespace().any(|c| c == "emoji"))
+                    || url.path().contains("/emoji/");
+                planned.push((
+                    if is_emoji { 1 } else { 2 },
+                    n,
+                    remote(AssetKind::Image, url),
+                ));
+            }
+        }
+        if let Ok(nodes) = doc.select("a[href]") {
+            for node in nodes {
+                if let Some(href) = image_link_href(&node, opts.base_url)
+                    && let Ok(url) = resolve_url(opts.base_url, &href)
+                {
+                    planned.push((3, n, remote(AssetKind::Image, url)));
+                }
+            }
+        }
+        scan.insert(n, started.elapsed());
+    }
+
+    planned.sort_by_key(|(rank, _, _)| *rank);
+    let mut seen = std::collections::HashSet::new();
+    let requests = planned
+        .into_iter()
+        .filter(|(_, _, request)| seen.insert(request.key()))
+        .map(|(_, post_number, request)| (post_number, request))
+        .collect();
+    PrefetchPlan { requests, scan }
+}
+
+/// Put the planned downloads whose preflight found a `Content-Length` first, smallest first; the rest keep the
+/// plan's order by kind. Without `--preflight-above-bytes` nothing is known and the plan is left as it is.
+async fn order_by_expected_size(
+    requests: &mut Vec<(u64, AssetRequest)>,
+    store: &AssetStore,
+    concurrency: usize,
+) {
+    let sizes: Vec<Option<u64>> = stream::iter(requests.iter())
+        .map(|(_, request)| store.expected_size(request))
+        .buffered(concurrency)
+        .collect()
+        .await;
+    let mut sized: Vec<_> = sizes.into_iter().zip(requests.drain(..)).collect();
+    // Stable, so assets of unknown size stay in kind order.
+    sized.sort_by_key(|(size, _)| (size.is_none(), size.unwrap_or(0)));
+    requests.extend(sized.into_iter().map(|(_, request)| request));
+}
+
+/// Avatar sizes Discourse generates; a CDN that only keeps these answers 404 for any other `{size}`.
+const AVATAR_SIZE_LADDER: [u32; 5] = [240, 120, 96, 48, 25];
+
+/// The avatar at `size`, falling back on a 404 to each smaller size of [`AVATAR_SIZE_LADDER`] in turn.
+fn avatar_request(post: &Post, base_url: &Url, size: u32) -> anyhow::Result<Option<AssetRequest>> {
+    let mut urls: Vec<Url> = Vec::new();
+    let smaller = AVATAR_SIZE_LADDER.into_iter().filter(|&s| s < size);
+    for size in std::iter::once(size).chain(smaller) {
+        let Some(url) = avatar_url(post, base_url, size)? else {
+            return Ok(None);
+        };
+        // Without `{size}` in the template every size is the same URL.
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    Ok(Some(AssetRequest {
+        kind: AssetKind::Avatar,
+        source: AssetSource::FirstFound(urls),
+    }))
+}
+
+/// The avatar `src` at the smallest of `sizes` (or a smaller one when the server doesn't have it) and, when there
+/// are several, a `srcset` with each larger size at its density relative to the smallest (`b.png 2x`); the `src`
+/// stands in for 1x, so a data URI is not embedded twice. A larger size that fails to download is left out of the
+/// `srcset` with a warning. Also returns the requested URL of the
+/// `src`.
+async fn resolve_and_fetch_avatar(
+    post: &Post,
+    base_url: &Url,
+    sizes: &[u32],
+    store: &AssetStore,
+) -> anyhow::Result<(String, String, Option<Url>)> {
+    let mut sizes = sizes.to_vec();
+    sizes.sort_unstable();
+    sizes.dedup();
+    let Some((&smallest, larger)) = sizes.split_first() else {
+        return Ok(Default::default());
+    };
+    let (Some(url), Some(request)) = (
+        avatar_url(post, base_url, smallest)?,
+        avatar_request(post, base_url, smallest)?,
+    ) else {
+        return Ok(Default::default());
+    };
+    let src = store.get(request).await?;
+
+    let mut candidates = Vec::new();
+    for &size in larger {
+        let Some(sized) = avatar_url(post, base_url, size)? else {
+            continue;
+        };
+        // A template without `{size}` names one image whatever the size.
+        if sized == url {
+            continue;
+        }
+        let req = AssetRequest {
+            kind: AssetKind::Avatar,
+            source: AssetSource::Remote(sized.clone()),
+        };
+        match store.get(req).await {
+            Ok(local) => {
+                let density = (f64::from(size) / f64::from(smallest) * 100.0).round() / 100.0;
+                candidates.push(format!("{local} {density}x"));
+            }
+            Err(e) => {
+                tracing::warn!(error = %format!("{:#}", e), url = %sized, "avatar download failed; leaving the size out of the srcset");
+            }
+        }
+    }
+    Ok((src, candidates.join(", "), Some(url)))
 }
 
 pub async fn rewrite_cooked_html(
     cooked: &str,
     ctx: &RenderContext<'_>,
     store: &AssetStore,
-) -> anyhow::Result<String> {
+) -> anyhow::Result<RewrittenCooked> {
     let document = kuchiki::parse_html().one(cooked);
 
     // Remove scripts entirely.
@@ -106,18 +872,54 @@ pub async fn rewrite_cooked_html(
         }
     }
 
-    // Replace iframes with plain links.
-    if let Ok(nodes) = document.select("iframe") {
-        for node in nodes {
-            let href = node
-                .attributes
-                .borrow()
-                .get("src")
-                .map(|s| s.to_string())
-                .unwrap_or_default();
-            let link = make_link_node(&href);
-            node.as_node().insert_before(link);
-            node.as_node().detach();
+    // Remove noise (reaction summaries, `--strip-selector`) before downloading anything inside it.
+    let stripped = strip_noise(&document, ctx.strip_selectors);
+    if stripped > 0
+        && let Some(p) = store.progress()
+    {
+        p.noise_stripped(stripped);
+    }
+
+    // Drop event handlers and neutralize `javascript:` URLs.
+    for node in document.descendants().elements() {
+        let mut attrs = node.attributes.borrow_mut();
+        let handlers: Vec<_> = attrs
+            .map
+            .keys()
+            .filter(|name| strict::is_event_handler_attr(name.local.as_ref()))
+            .cloned()
+            .collect();
+        for name in handlers {
+            attrs.map.remove(&name);
+        }
+        // By local name, so namespaced attributes in inline SVG (`xlink:href`) are covered too.
+        for (name, attr) in attrs.map.iter_mut() {
+            if strict::URL_ATTRS.contains(&name.local.as_ref())
+                && strict::is_javascript_url(&attr.value)
+            {
+                attr.value = "#".to_string();
+            }
+        }
+    }
+
+    // Replace iframes and plugin content (<object data>, <embed src>) with plain links.
+    for (selector, attr) in [("iframe", "src"), ("object", "data"), ("embed", "src")] {
+        if let Ok(nodes) = document.select(selector) {
+            for node in nodes.collect::<Vec<_>>() {
+                let href = node
+                    .attributes
+                    .borrow()
+                    .get(attr)
+                    .map(|s| s.to_string())
+                    .unwrap_or_default();
+                if selector == "iframe" && youtube_video_id(ctx.base_url, &href).is_some() {
+                    // Replaced with a thumbnail after the <img> pass, which would try to refetch a local src.
+                    continue;
+                }
+                let link = make_link_node(&href);
+                node.as_node().insert_before(link);
+                node.as_node().detach();
+            }
         }
     }
 
@@ -138,37 +940,125 @@ pub async fn rewrite_cooked_html(
         }
     }
 
+    // Single mode inlines one image per <picture>, see `reduce_picture`; dir mode keeps the sources.
+    if matches!(store.output_mode(), OutputMode::Single)
+        && let Ok(nodes) = document.select("picture")
+    {
+        for picture in nodes.collect::<Vec<_>>() {
+            reduce_picture(picture.as_node());
+        }
+    }
+
     // Rewrite <img>.
+    let mut missing_alt = Vec::new();
     if let Ok(nodes) = document.select("img") {
-        for node in nodes {
-            rewrite_img_like(node, ctx.base_url, store).await?;
+        // Collected up front: failed downloads may detach the node under `--keep-going`.
+        for node in nodes.collect::<Vec<_>>() {
+            rewrite_img_like(node, ctx, store, &mut missing_alt).await?;
+        }
+    }
+    if ctx.require_alt_text && !missing_alt.is_empty() {
+        anyhow::bail!(
+            "{} image(s) without an alt attribute (--require-alt-text):\n  {}",
+            missing_alt.len(),
+            missing_alt.join("\n  ")
+        );
+    }
+
+    // YouTube embeds become a static thumbnail linking to the video.
+    if let Ok(nodes) = document.select("iframe") {
+        for node in nodes.collect::<Vec<_>>() {
+            let src = node
+                .attributes
+                .borrow()
+                .get("src")
+                .map(|s| s.to_string())
+                .unwrap_or_default();
+            let Some(id) = youtube_video_id(ctx.base_url, &src) else {
+                continue;
+            };
+            let watch = format!("https://www.youtube.com/watch?v={}", id);
+            let thumbnail =
+                Url::parse(&format!("https://img.youtube.com/vi/{}/hqdefault.jpg", id))?;
+            let req = AssetRequest {
+                kind: AssetKind::Image,
+                source: AssetSource::Remote(thumbnail.clone()),
+            };
+            let replacement = match store.get(req).await {
+                Ok(local) => video_embed_node(&watch, &local),
+                Err(e) => {
+                    tracing::warn!(error = %format!("{:#}", e), url = %thumbnail, "video thumbnail download failed; linking to the video");
+                    make_link_node(&watch)
+                }
+            };
+            node.as_node().insert_before(replacement);
+            node.as_node().detach();
+        }
+    }
+
+    // Inline SVG: `<image>` and external `<use>` references load resources too.
+    if let Ok(nodes) = document.select("svg image, svg use") {
+        for node in nodes.collect::<Vec<_>>() {
+            rewrite_svg_href(&node, ctx, store).await?;
         }
     }
 
     // Rewrite <source> inside picture/video/audio.
     if let Ok(nodes) = document.select("source") {
-        for node in nodes {
-            let mut attrs = node.attributes.borrow_mut();
-            if let Some(srcset) = attrs.get("srcset").map(|s| s.to_string()) {
-                if let Some(best) = choose_best_src_from_srcset(&srcset) {
-                    let url = resolve_any_url(ctx.base_url, &best)?;
+        for node in nodes.collect::<Vec<_>>() {
+            let (srcset, src) = {
+                let attrs = node.attributes.borrow();
+                (
+                    attrs.get("srcset").map(|s| s.to_string()),
+                    attrs.get("src").map(|s| s.to_string()),
+                )
+            };
+            // Dir mode keeps every candidate of a <picture> source, and its `type`, `media` and `sizes`, so the
+            // browser still picks the format and resolution. Otherwise the best candidate becomes the `src`.
+            let rewritten = match srcset {
+                Some(srcset)
+                    if matches!(store.output_mode(), OutputMode::Dir)
+                        && is_picture_source(&node) =>
+                {
+                    rewrite_srcset(&srcset, ctx.base_url, store)
+                        .await
+                        .map(|local| ("srcset", local))
+                }
+                _ => {
+                    let raw = match srcset {
+                        Some(srcset) => choose_best_src_from_srcset(&srcset),
+                        None => {
+                            src.filter(|s| !s.trim().starts_with("data:") && !s.trim().is_empty())
+                        }
+                    };
+                    let Some(raw) = raw else { continue };
+                    let url = resolve_url(ctx.base_url, &raw)?;
                     let req = AssetRequest {
                         kind: AssetKind::Image,
                         source: AssetSource::Remote(url),
                     };
-                    let new_src = store.get(req).await?;
-                    attrs.insert("src", new_src);
-                    attrs.remove("srcset");
+                    store.get(req).await.map(|local| ("src", local))
                 }
-            } else if let Some(src) = attrs.get("src").map(|s| s.to_string()) {
-                if !src.trim().starts_with("data:") && !src.trim().is_empty() {
-                    let url = resolve_any_url(ctx.base_url, &src)?;
-                    let req = AssetRequest {
-                        kind: AssetKind::Image,
-                        source: AssetSource::Remote(url),
+            };
+            match rewritten {
+                Ok((attr, local)) => {
+                    let mut attrs = node.attributes.borrow_mut();
+                    attrs.insert(attr, local);
+                    if attr == "src" {
+                        attrs.remove("srcset");
+                        attrs.remove("sizes");
+                    }
+                }
+                Err(e) => {
+                    let Some(link) = oversized_asset_link(&e) else {
+                        return Err(e);
                     };
-                    let new_src = store.get(req).await?;
-                    attrs.insert("src", new_src);
+                    // Link after the <video>/<picture> rather than inside it, where it would not show.
+                    match node.as_node().parent() {
+                        Some(media) => media.insert_after(link),
+                        None => node.as_node().insert_before(link),
+                    }
+                    node.as_node().detach();
                 }
             }
         }
@@ -184,41 +1074,139 @@ pub async fn rewrite_cooked_html(
         }
     }
 
-    // Rewrite lightbox links if they look like image hrefs.
-    if let Ok(nodes) = document.select("a.lightbox") {
-        for node in nodes {
-            let href = node.attributes.borrow().get("href").map(|s| s.to_string());
-            let Some(href) = href else { continue };
-            if !looks_like_image_url(&href) {
+    // Legacy `background` attributes (`<td background>`, `<table background>`) load images like `<img>` does.
+    if let Ok(nodes) = document.select("[background]") {
+        for node in nodes.collect::<Vec<_>>() {
+            let raw = node
+                .attributes
+                .borrow()
+                .get("background")
+                .map(|s| s.trim().to_string())
+                .unwrap_or_default();
+            if raw.is_empty() || raw.starts_with("data:") {
                 continue;
             }
-            let url = resolve_any_url(ctx.base_url, &href)?;
+            let url = resolve_url(ctx.base_url, &raw)?;
+            let req = AssetRequest {
+                kind: AssetKind::Image,
+                source: AssetSource::Remote(url.clone()),
+            };
+            match store.get(req).await {
+                Ok(local) => {
+                    node.attributes.borrow_mut().insert("background", local);
+                }
+                // A background is decoration; drop it rather than link to it.
+                Err(e) if e.is::<AssetTooLarge>() || ctx.keep_going => {
+                    tracing::warn!(error = %format!("{:#}", e), %url, "background image not downloaded; dropping it");
+                    node.attributes.borrow_mut().remove("background");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Rewrite <style> elements the same way as the page's stylesheets.
+    if let Ok(nodes) = document.select("style") {
+        for node in nodes.collect::<Vec<_>>() {
+            let css = node.text_contents();
+            let rewritten = rewrite_embedded_css(
+                ctx.base_url,
+                &css,
+                store,
+                ctx.max_css_bytes,
+                ctx.lenient_css,
+            )
+            .await
+            .context("rewrite <style> element")?;
+            let node = node.as_node();
+            for child in node.children().collect::<Vec<_>>() {
+                child.detach();
+            }
+            node.append(kuchiki::NodeRef::new_text(rewritten));
+        }
+    }
+
+    // Rewrite lightbox links, and plain links wrapping a single image, if they point at the full-size image.
+    if let Ok(nodes) = document.select("a[href]") {
+        for node in nodes {
+            let Some(href) = image_link_href(&node, ctx.base_url) else {
+                continue;
+            };
+            let url = resolve_url(ctx.base_url, &href)?;
             let req = AssetRequest {
                 kind: AssetKind::Image,
-                source: AssetSource::Remote(url),
+                source: AssetSource::Remote(url.clone()),
+            };
+            let new_href = match store.get(req).await {
+                Ok(v) => v,
+                Err(e) if e.is::<AssetTooLarge>() => url.to_string(),
+                Err(e) if ctx.keep_going => {
+                    tracing::warn!(error = %format!("{:#}", e), %url, "image download failed; linking to the original");
+                    url.to_string()
+                }
+                Err(e) => return Err(e),
             };
-            let new_href = store.get(req).await?;
-            node.attributes.borrow_mut().insert("href", new_href);
+            let mut attrs = node.attributes.borrow_mut();
+            if ctx.keep_original_urls && new_href != url.as_str() {
+                attrs.insert("data-dtr-original-href", url.to_string());
+            }
+            attrs.insert("href", new_href);
         }
     }
 
-    // Rewrite in-topic links to anchors.
+    // Rewrite in-topic links to anchors, starting with oneboxes of this topic's posts.
+    localize_same_topic_oneboxes(
+        &document,
+        ctx.base_url,
+        ctx.topic_id,
+        ctx.locations,
+        ctx.page,
+    );
+    let local_asset_prefix = format!("{}/", store.assets_dir_name());
     if let Ok(nodes) = document.select("a[href]") {
         for node in nodes {
             let href = node.attributes.borrow().get("href").map(|s| s.to_string());
             let Some(href) = href else { continue };
-            if let Some(anchor) = topic_local_anchor(ctx.base_url, ctx.topic_id, &href) {
+            if href.starts_with(&local_asset_prefix) {
+                // Already localized above.
+                continue;
+            }
+            if let Some(url) = mention_href(ctx.base_url, &node, &href) {
+                node.attributes.borrow_mut().insert("href", url);
+                continue;
+            }
+            if let Some(anchor) =
+                topic_local_anchor(ctx.base_url, ctx.topic_id, ctx.locations, ctx.page, &href)
+            {
                 node.attributes.borrow_mut().insert("href", anchor);
                 continue;
             }
-            if should_absolutize_href(&href) {
-                if let Ok(url) = resolve_any_url(ctx.base_url, &href) {
-                    node.attributes.borrow_mut().insert("href", url.to_string());
-                }
+            if should_absolutize_href(&href)
+                && let Ok(url) = resolve_url(ctx.base_url, &href)
+            {
+                node.attributes.borrow_mut().insert("href", url.to_string());
             }
         }
     }
 
+    add_quote_jump_links(&document, ctx.topic_id, ctx.locations, ctx.page);
+    convert_spoilers(&document);
+
+    if ctx.line_numbers {
+        number_code_lines(&document);
+    }
+    if ctx.code_copy_buttons {
+        add_code_copy_buttons(&document, ctx.post_number);
+    }
+
+    add_heading_anchors(&document, ctx.post_number);
+
+    let headings = if let Some(max_depth) = ctx.collect_headings {
+        collect_headings(&document, max_depth)
+    } else {
+        Vec::new()
+    };
+
     // Serialize body children only (avoid wrapping <html><body> around cooked).
     let body = document
         .select_first("body")
@@ -235,63 +1223,683 @@ pub async fn rewrite_cooked_html(
     } else {
         document.serialize(&mut out).context("serialize cooked")?;
     }
-    Ok(String::from_utf8(out).context("cooked html not utf-8")?)
+    Ok(RewrittenCooked {
+        html: String::from_utf8(out).context("cooked html not utf-8")?,
+        headings,
+    })
+}
+
+/// Give every heading without an `id` one of `{slug}_post{n}` (suffixed on clashes within the post), so sections
+/// can be deep-linked, and end each heading with a `¶` link to itself.
+fn add_heading_anchors(document: &kuchiki::NodeRef, post_number: u64) {
+    let Ok(nodes) = document.select("h1, h2, h3, h4, h5, h6") else {
+        return;
+    };
+    let nodes: Vec<_> = nodes.collect();
+    let mut used: std::collections::HashSet<String> = nodes
+        .iter()
+        .filter_map(|n| n.attributes.borrow().get("id").map(str::to_string))
+        .collect();
+    for node in nodes {
+        if node.as_node().select_first(".dtr-heading-anchor").is_ok() {
+            continue;
+        }
+        let existing = node.attributes.borrow().get("id").map(str::to_string);
+        let id = match existing {
+            Some(id) if !id.is_empty() => id,
+            _ => {
+                let slug = slugify(&node.text_contents());
+                let mut id = format!("{}_post{}", slug, post_number);
+                let mut n = 2;
+                while used.contains(&id) {
+                    id = format!("{}-{}_post{}", slug, n, post_number);
+                    n += 1;
+                }
+                node.attributes.borrow_mut().insert("id", id.clone());
+                used.insert(id.clone());
+                id
+            }
+        };
+        let frag = format!(
+            "<a class=\"dtr-heading-anchor\" href=\"#{}\" aria-label=\"Link to heading\">¶</a>",
+            html_escape_attr(&id)
+        );
+        if let Ok(anchor) = kuchiki::parse_html().one(frag).select_first("a") {
+            node.as_node().append(anchor.as_node().clone());
+        }
+    }
+}
+
+/// Headings from `<h1>` down to `<h{max_depth}>` in document order, after [`add_heading_anchors`] gave them ids;
+/// the `¶` link is left out of their text.
+fn collect_headings(document: &kuchiki::NodeRef, max_depth: u8) -> Vec<Heading> {
+    let mut out = Vec::new();
+    let Ok(nodes) = document.select("h1, h2, h3, h4, h5, h6") else {
+        return out;
+    };
+    for node in nodes {
+        let text: String = node
+            .as_node()
+            .children()
+            .filter(|c| {
+                c.as_element().is_none_or(|e| {
+                    e.attributes.borrow().get("class") != Some("dtr-heading-anchor")
+                })
+            })
+            .map(|c| c.text_contents())
+            .collect();
+        let text = text.trim().to_string();
+        let Some(id) = node.attributes.borrow().get("id").map(str::to_string) else {
+            continue;
+        };
+        if text.is_empty() {
+            continue;
+        }
+        let level = node.name.local.as_bytes()[1] - b'0';
+        if level > max_depth {
+            continue;
+        }
+        out.push(Heading { level, id, text });
+    }
+    out
+}
+
+/// Lowercase alphanumerics (any script) joined by `-`.
+fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for ch in text.chars() {
+        if ch.is_alphanumeric() {
+            slug.extend(ch.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        "section".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+/// What an `<img>` downloads: the best `srcset` candidate (`true`), else the `src` (`false`). `None` when there is
+/// nothing to download, as for a `data:` URI. Shared by the prefetch plan and the render pass.
+fn img_download_src(srcset: Option<&str>, src: Option<&str>) -> Option<(String, bool)> {
+    if let Some(best) = srcset.and_then(choose_best_src_from_srcset) {
+        return Some((best, true));
+    }
+    let src = src?.trim();
+    (!src.is_empty() && !src.starts_with("data:")).then(|| (src.to_string(), false))
 }
 
+/// Localize an `<img>`. Images left in the page without an `alt` attribute are logged and their original
+/// source added to `missing_alt`.
 async fn rewrite_img_like(
     node: kuchiki::NodeDataRef<kuchiki::ElementData>,
-    base_url: &Url,
+    ctx: &RenderContext<'_>,
     store: &AssetStore,
+    missing_alt: &mut Vec<String>,
 ) -> anyhow::Result<()> {
-    let mut attrs = node.attributes.borrow_mut();
+    let (srcset, src, has_alt) = {
+        let attrs = node.attributes.borrow();
+        (
+            attrs.get("srcset").map(|s| s.to_string()),
+            attrs.get("src").map(|s| s.to_string()),
+            attrs.contains("alt"),
+        )
+    };
+    let mut note_missing_alt = |original_src: &str| {
+        if !has_alt {
+            // Inline images have no name worth listing.
+            let original_src = if original_src.starts_with("data:") {
+                "(inline data: image)"
+            } else {
+                original_src
+            };
+            tracing::warn!(url = %original_src, "img element has no alt attribute; screen reader users will hear the filename");
+            missing_alt.push(original_src.to_string());
+        }
+    };
 
-    if let Some(srcset) = attrs.get("srcset").map(|s| s.to_string()) {
-        if let Some(best) = choose_best_src_from_srcset(&srcset) {
-            let url = resolve_any_url(base_url, &best)?;
-            let req = AssetRequest {
-                kind: AssetKind::Image,
-                source: AssetSource::Remote(url),
+    let Some((raw, from_srcset)) = img_download_src(srcset.as_deref(), src.as_deref()) else {
+        note_missing_alt(src.as_deref().unwrap_or_default().trim());
+        return Ok(());
+    };
+
+    let url = resolve_url(ctx.base_url, &raw)?;
+    let req = AssetRequest {
+        kind: AssetKind::Image,
+        source: AssetSource::Remote(url.clone()),
+    };
+    match store.get(req).await {
+        Ok(new_src) => {
+            // Dir mode keeps every candidate so the browser can still pick by density or width; single mode keeps
+            // only the best one rather than inlining them all.
+            let local_srcset = match srcset.as_deref() {
+                Some(srcset) if from_srcset && matches!(store.output_mode(), OutputMode::Dir) => {
+                    match rewrite_srcset(srcset, ctx.base_url, store).await {
+                        Ok(local) => Some(local),
+                        Err(e) => {
+                            tracing::warn!(error = %format!("{:#}", e), %url, "srcset candidate download failed; keeping only the best image");
+                            None
+                        }
+                    }
+                }
+                _ => None,
             };
-            let new_src = store.get(req).await?;
+            let mut attrs = node.attributes.borrow_mut();
+            if ctx.keep_original_urls {
+                attrs.insert("data-dtr-original-src", url.to_string());
+            }
             attrs.insert("src", new_src);
-            attrs.remove("srcset");
-            return Ok(());
+            match local_srcset {
+                Some(local) => {
+                    attrs.insert("srcset", local);
+                }
+                // `sizes` only picks among `srcset` candidates.
+                None if from_srcset => {
+                    attrs.remove("srcset");
+                    attrs.remove("sizes");
+                }
+                None => {}
+            }
+            // Only matters when the archive is served over HTTP; an explicit `loading="eager"` is kept.
+            if !attrs.contains("loading") {
+                attrs.insert("loading", "lazy".to_string());
+            }
+            if !attrs.contains("decoding") {
+                attrs.insert("decoding", "async".to_string());
+            }
+            note_missing_alt(url.as_str());
+        }
+        Err(e) => {
+            let link = match oversized_asset_link(&e) {
+                Some(link) => link,
+                None if ctx.keep_going => {
+                    tracing::warn!(error = %format!("{:#}", e), %url, "image download failed; linking to the original");
+                    make_link_node(url.as_str())
+                }
+                None => return Err(e),
+            };
+            node.as_node().insert_before(link);
+            node.as_node().detach();
         }
     }
 
-    if let Some(src) = attrs.get("src").map(|s| s.to_string()) {
-        let s = src.trim();
-        if s.is_empty() || s.starts_with("data:") {
-            return Ok(());
+    Ok(())
+}
+
+/// Replace `picture` with its `<img>`, pointed at the best candidate of the first `<source>` a browser would use
+/// whatever the viewport (no `media` condition, an image `type` or none). The `<img>` pass then downloads it. A
+/// picture without an `<img>` is left for the `<source>` pass.
+fn reduce_picture(picture: &kuchiki::NodeRef) {
+    let Ok(img) = picture.select_first("img") else {
+        return;
+    };
+    let preferred = picture.select("source").ok().and_then(|mut sources| {
+        sources.find_map(|source| {
+            let attrs = source.attributes.borrow();
+            let unconditional = attrs
+                .get("media")
+                .is_none_or(|m| m.trim().is_empty() || m.trim().eq_ignore_ascii_case("all"));
+            let image_type = attrs
+                .get("type")
+                .is_none_or(|t| t.trim().to_ascii_lowercase().starts_with("image/"));
+            (unconditional && image_type)
+                .then(|| attrs.get("srcset").and_then(choose_best_src_from_srcset))
+                .flatten()
+        })
+    });
+    if let Some(src) = preferred {
+        let mut attrs = img.attributes.borrow_mut();
+        attrs.insert("src", src);
+        attrs.remove("srcset");
+        attrs.remove("sizes");
+    }
+    picture.insert_before(img.as_node().clone());
+    picture.detach();
+}
+
+fn is_picture_source(source: &kuchiki::NodeDataRef<kuchiki::ElementData>) -> bool {
+    source
+        .as_node()
+        .parent()
+        .and_then(|parent| parent.into_element_ref())
+        .is_some_and(|parent| parent.name.local.as_ref() == "picture")
+}
+
+/// `srcset` with every candidate URL localized and its descriptor kept. `data:` candidates stay as they are.
+async fn rewrite_srcset(
+    srcset: &str,
+    base_url: &Url,
+    store: &AssetStore,
+) -> anyhow::Result<String> {
+    let mut candidates = Vec::new();
+    for (raw, descriptor) in srcset_candidates(srcset) {
+        let local = if raw.starts_with("data:") {
+            raw.to_string()
+        } else {
+            let req = AssetRequest {
+                kind: AssetKind::Image,
+                source: AssetSource::Remote(resolve_url(base_url, raw)?),
+            };
+            store.get(req).await?
+        };
+        candidates.push(if descriptor.is_empty() {
+            local
+        } else {
+            format!("{local} {descriptor}")
+        });
+    }
+    Ok(candidates.join(", "))
+}
+
+/// Localize `href`/`xlink:href` of an inline SVG `<image>` or `<use>`. Same-document references (`#id`) are
+/// left alone; an external `<use>` keeps its fragment.
+async fn rewrite_svg_href(
+    node: &kuchiki::NodeDataRef<kuchiki::ElementData>,
+    ctx: &RenderContext<'_>,
+    store: &AssetStore,
+) -> anyhow::Result<()> {
+    let hrefs: Vec<(kuchiki::ExpandedName, String)> = node
+        .attributes
+        .borrow()
+        .map
+        .iter()
+        .filter(|(name, _)| name.local.as_ref() == "href")
+        .map(|(name, attr)| (name.clone(), attr.value.trim().to_string()))
+        .collect();
+
+    for (name, value) in hrefs {
+        if value.is_empty() || value.starts_with('#') || value.starts_with("data:") {
+            continue;
         }
-        let url = resolve_any_url(base_url, s)?;
+        let (target, fragment) = match value.split_once('#') {
+            Some((target, fragment)) => (target, Some(fragment)),
+            None => (value.as_str(), None),
+        };
+        let url = resolve_url(ctx.base_url, target)?;
         let req = AssetRequest {
             kind: AssetKind::Image,
-            source: AssetSource::Remote(url),
+            source: AssetSource::Remote(url.clone()),
         };
-        let new_src = store.get(req).await?;
-        attrs.insert("src", new_src);
+        let new_value = match store.get(req).await {
+            Ok(local) => match fragment {
+                Some(fragment) => format!("{}#{}", local, fragment),
+                None => local,
+            },
+            Err(e) if ctx.keep_going || e.is::<AssetTooLarge>() => {
+                tracing::warn!(error = %format!("{:#}", e), %url, "svg reference download failed; dropping it");
+                node.attributes.borrow_mut().map.remove(&name);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(attr) = node.attributes.borrow_mut().map.get_mut(&name) {
+            attr.value = new_value;
+        }
     }
-
     Ok(())
 }
 
+/// For an asset refused by `--max-asset-size`: a link to the original, labelled with its size.
+fn oversized_asset_link(e: &anyhow::Error) -> Option<kuchiki::NodeRef> {
+    let too_large = e.downcast_ref::<AssetTooLarge>()?;
+    let href = too_large.url.as_str();
+    let name = too_large
+        .url
+        .path_segments()
+        .and_then(|mut s| s.next_back())
+        .filter(|s| !s.is_empty())
+        .unwrap_or(href);
+    Some(make_link_node_with_text(
+        href,
+        &format!("{} ({})", name, too_large.size_label()),
+    ))
+}
+
+/// A `<script>` appended to the end of `<body>`.
+pub enum PageScript {
+    /// Local script file, referenced relative to the HTML file.
+    Src(String),
+    /// Script source embedded in the page.
+    Inline(String),
+}
+
+/// Page-level options shared by `build_html` and `build_html_minimal`.
+pub struct PageOptions<'a> {
+    pub scripts: &'a [PageScript],
+    /// Embed the builtin theme toggle script (minimal template only).
+    pub builtin_js: bool,
+    /// Show the theme toggle button (minimal template only); off for `--no-js`.
+    pub theme_toggle: bool,
+    /// Short note under the topic title, e.g. "showing posts 10–20 of 300".
+    pub subtitle: Option<String>,
+    pub pagination: Option<Pagination>,
+    /// Table of contents sidebar (minimal template only).
+    pub toc: bool,
+    /// Embed the post text and the client-side search script (minimal template only).
+    pub search: bool,
+    /// Embed the script behind the code blocks' copy buttons (minimal template only).
+    pub code_copy_buttons: bool,
+    /// `<title>` override; defaults to the topic title.
+    pub title: Option<String>,
+    /// `<meta name="description">` content.
+    pub description: Option<String>,
+    /// Tool/version/time note, written as a comment right after the doctype.
+    pub stamp: Option<String>,
+    /// Say how many times an edited post was edited (minimal template only).
+    pub show_revision_count: bool,
+    /// Participants of a private message, shown in a banner under the title.
+    pub private_message: Option<Vec<String>>,
+    /// `<meta name="robots" content="noindex">`.
+    pub noindex: bool,
+    /// Input fingerprint for `--skip-unchanged`, written as `<meta name="dtr-fingerprint">`.
+    pub fingerprint: Option<String>,
+    /// `--emit-preload-hints`: preload the linked stylesheet and the first N local images and avatars.
+    pub preload_images: Option<usize>,
+}
+
+/// Position of a page among the files written by `--paginate`.
+#[derive(Clone, Copy)]
+pub struct Pagination {
+    pub topic_id: u64,
+    /// 1-based.
+    pub current: usize,
+    pub total: usize,
+}
+
+/// `<title>`, description and, for paginated output, `<link rel="prev/next">` to the sibling pages.
+fn render_page_meta(topic_title: &str, page: &PageOptions<'_>) -> Markup {
+    html! {
+        title { (page.title.as_deref().unwrap_or(topic_title)) }
+        @if let Some(description) = &page.description {
+            meta name="description" content=(description);
+        }
+        @if page.noindex {
+            meta name="robots" content="noindex";
+        }
+        @if let Some(fingerprint) = &page.fingerprint {
+            meta name=(crate::fingerprint::META_NAME) content=(fingerprint);
+        }
+        @if let Some(p) = &page.pagination {
+            @if p.current > 1 {
+                link rel="prev" href=(page_file_name(p.topic_id, p.current - 1));
+            }
+            @if p.current < p.total {
+                link rel="next" href=(page_file_name(p.topic_id, p.current + 1));
+            }
+        }
+    }
+}
+
+/// At most `max_chars` characters of `text`, cut at a word boundary and marked with `…` when shortened.
+pub(crate) fn excerpt(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(max_chars).collect();
+    let cut = match cut.rfind(' ') {
+        Some(idx) if idx > 0 => &cut[..idx],
+        _ => cut.as_str(),
+    };
+    format!("{}…", cut.trim_end())
+}
+
+/// "Private message between alice, bob" (or just "Private message" when the export lists nobody).
+fn private_message_label(participants: &[String]) -> String {
+    if participants.is_empty() {
+        "Private message".to_string()
+    } else {
+        format!("Private message between {}", participants.join(", "))
+    }
+}
+
+fn render_pagination(p: &Pagination) -> Markup {
+    html! {
+        nav class="dtr-pages" aria-label="Pages" {
+            @if p.current > 1 {
+                a class="dtr-page-prev" rel="prev" href=(page_file_name(p.topic_id, p.current - 1)) { "← Prev" }
+            }
+            @for n in 1..=p.total {
+                @if n == p.current {
+                    span class="dtr-page dtr-page-current" aria-current="page" { (n) }
+                } @else {
+                    a class="dtr-page" href=(page_file_name(p.topic_id, n)) { (n) }
+                }
+            }
+            @if p.current < p.total {
+                a class="dtr-page-next" rel="next" href=(page_file_name(p.topic_id, p.current + 1)) { "Next →" }
+            }
+        }
+    }
+}
+
+/// Base64 SHA-384 digest of a stylesheet, for the `integrity` attribute of its link.
+pub fn css_sha384(css: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA384, css.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(digest.as_ref())
+}
+
+/// Split rendered posts into pages of `page_size` posts (the same chunking as `PostLocations`), returning
+/// `(file name, html)` per page. All pages share the same stylesheet link.
+pub fn build_paginated(
+    topic: &TopicJson,
+    posts: &[RenderedPost],
+    page_size: usize,
+    css_link_href: &str,
+    css_hash: Option<&str>,
+    page: &PageOptions<'_>,
+    minimal: bool,
+) -> Vec<(String, String)> {
+    let chunks: Vec<&[RenderedPost]> = posts.chunks(page_size.max(1)).collect();
+    let total = chunks.len();
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let (first, last) = (chunk[0].post_number, chunk[chunk.len() - 1].post_number);
+            let page = PageOptions {
+                title: Some(format!("{} — posts {}–{}", topic.title, first, last)),
+                description: chunk
+                    .iter()
+                    .find(|p| p.small_action.is_none())
+                    .map(|p| excerpt(&plain_text(&p.cooked_html), 160))
+                    .filter(|d| !d.is_empty()),
+                scripts: page.scripts,
+                builtin_js: page.builtin_js,
+                theme_toggle: page.theme_toggle,
+                subtitle: page.subtitle.clone(),
+                pagination: Some(Pagination {
+                    topic_id: topic.id,
+                    current: i + 1,
+                    total,
+                }),
+                toc: page.toc,
+                search: page.search,
+                code_copy_buttons: page.code_copy_buttons,
+                stamp: page.stamp.clone(),
+                show_revision_count: page.show_revision_count,
+                private_message: page.private_message.clone(),
+                noindex: page.noindex,
+                fingerprint: page.fingerprint.clone(),
+                preload_images: page.preload_images,
+            };
+            let html = if minimal {
+                build_html_minimal(topic, chunk, "", Some(css_link_href), css_hash, &page)
+            } else {
+                build_html(topic, chunk, "", Some(css_link_href), css_hash, &page)
+            };
+            (page_file_name(topic.id, i + 1), html)
+        })
+        .collect()
+}
+
+/// One chapter of `--mode epub`: the posts under the chapter title, without scripts or page chrome. HTML; the
+/// caller serializes it as XHTML.
+pub fn build_epub_chapter(
+    topic: &TopicJson,
+    posts: &[RenderedPost],
+    chapter_title: &str,
+    css_href: &str,
+    minimal: bool,
+    show_revision_count: bool,
+) -> String {
+    let lang = topic.language_tag().unwrap_or_else(|| "en".to_string());
+    let dir = is_rtl_locale(&lang).then_some("rtl");
+    let markup: Markup = html! {
+        html lang=(lang) dir=[dir] {
+            head {
+                title { (chapter_title) }
+                link rel="stylesheet" href=(css_href);
+            }
+            @if minimal {
+                body class="dtr" {
+                    main class="dtr-container dtr-main" {
+                        @for p in posts {
+                            (render_post_minimal(p, show_revision_count))
+                        }
+                    }
+                }
+            } @else {
+                body class="crawler" {
+                    div id="main-outlet" class="wrap" {
+                        main class="topic-posts" {
+                            @for p in posts {
+                                (render_post(p))
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    };
+    markup.into_string()
+}
+
+/// `<link rel="stylesheet">`, with an SRI `integrity` when the SHA-384 of the stylesheet is given.
+fn stylesheet_link(href: &str, css_hash: Option<&str>) -> Markup {
+    html! {
+        @if let Some(hash) = css_hash {
+            link rel="stylesheet" href=(href) integrity=(format!("sha384-{hash}")) crossorigin="anonymous";
+        } @else {
+            link rel="stylesheet" href=(href);
+        }
+    }
+}
+
+/// `<link rel="preload">` for the stylesheet and the first `images` local avatars and post images of `posts`, in
+/// document order. Nothing without `--emit-preload-hints`. The stylesheet's is fetched in the same mode as its
+/// [`stylesheet_link`], so the browser can reuse the response.
+fn preload_links(
+    css_href: &str,
+    css_hash: Option<&str>,
+    posts: &[RenderedPost],
+    images: Option<usize>,
+) -> Markup {
+    let Some(images) = images else {
+        return html! {};
+    };
+    html! {
+        @if let Some(hash) = css_hash {
+            link rel="preload" href=(css_href) as="style" integrity=(format!("sha384-{hash}")) crossorigin="anonymous";
+        } @else {
+            link rel="preload" href=(css_href) as="style";
+        }
+        @for href in preload_image_hrefs(posts, images) {
+            link rel="preload" href=(href) as="image";
+        }
+    }
+}
+
+/// The first `limit` distinct local image URLs of the posts: each post's avatar, then the `<img src>`s of its
+/// cooked HTML. Remote and `data:` URLs are skipped; a preload of either would not help an offline page.
+fn preload_image_hrefs(posts: &[RenderedPost], limit: usize) -> Vec<String> {
+    let mut seen = std::collections::HashSet::new();
+    let mut out = Vec::new();
+    for p in posts {
+        if out.len() >= limit {
+            break;
+        }
+        let mut candidates = vec![p.avatar_src.clone()];
+        if p.small_action.is_none() {
+            candidates.extend(img_srcs(&p.cooked_html, "img[src]"));
+        }
+        for src in candidates {
+            if out.len() < limit && is_local_href(&src) && seen.insert(src.clone()) {
+                out.push(src);
+            }
+        }
+    }
+    out
+}
+
+/// The first local `<img>` of a post's cooked HTML other than an emoji: the cover of `--mode epub`.
+pub fn first_local_image(cooked_html: &str) -> Option<String> {
+    img_srcs(cooked_html, "img[src]:not(.emoji)")
+        .into_iter()
+        .find(|src| is_local_href(src))
+}
+
+fn img_srcs(cooked_html: &str, selector: &str) -> Vec<String> {
+    let fragment = kuchiki::parse_html().one(cooked_html);
+    let Ok(imgs) = fragment.select(selector) else {
+        return Vec::new();
+    };
+    imgs.filter_map(|img| img.attributes.borrow().get("src").map(str::to_string))
+        .collect()
+}
+
+/// A relative reference into the output directory: no scheme, not host- or root-relative.
+fn is_local_href(href: &str) -> bool {
+    let href = href.trim();
+    !href.is_empty()
+        && !href.starts_with(['/', '\\', '#'])
+        && matches!(
+            Url::parse(href),
+            Err(url::ParseError::RelativeUrlWithoutBase)
+        )
+}
+
+fn render_scripts(scripts: &[PageScript]) -> Markup {
+    html! {
+        @for s in scripts {
+            @match s {
+                PageScript::Src(src) => script src=(src) {},
+                PageScript::Inline(code) => script { (PreEscaped(escape_inline_script(code))) },
+            }
+        }
+    }
+}
+
+/// Keep inline script text from closing its own `<script>` element early.
+fn escape_inline_script(code: &str) -> String {
+    code.replace("</script", "<\\/script")
+        .replace("</SCRIPT", "<\\/SCRIPT")
+}
+
 pub fn build_html(
     topic: &TopicJson,
     posts: &[RenderedPost],
     css: &str,
     css_link_href: Option<&str>,
+    css_hash: Option<&str>,
+    page: &PageOptions<'_>,
 ) -> String {
     let title = topic.title.as_str();
     let markup: Markup = html! {
         (DOCTYPE)
+        @if let Some(stamp) = &page.stamp {
+            (PreEscaped(crate::stamp::html_comment(stamp)))
+        }
         html lang="en" {
             head {
                 meta charset="utf-8";
                 meta name="viewport" content="width=device-width, initial-scale=1";
-                title { (title) }
+                (render_page_meta(title, page))
                 @if let Some(href) = css_link_href {
-                    link rel="stylesheet" href=(href);
+                    (preload_links(href, css_hash, posts, page.preload_images))
+                    (stylesheet_link(href, css_hash))
                 } @else {
                     style { (PreEscaped(css)) }
                 }
@@ -300,6 +1908,15 @@ pub fn build_html(
                 div id="main-outlet" class="wrap" {
                     header class="topic-header" {
                         h1 class="topic-title" { (title) }
+                        @if let Some(subtitle) = &page.subtitle {
+                            p class="topic-subtitle" { (subtitle) }
+                        }
+                        @if let Some(participants) = &page.private_message {
+                            p class="topic-pm-banner" { (private_message_label(participants)) }
+                        }
+                        @if let Some(p) = &page.pagination {
+                            (render_pagination(p))
+                        }
                     }
                     main class="topic-posts" {
                         @for p in posts {
@@ -307,6 +1924,7 @@ pub fn build_html(
                         }
                     }
                 }
+                (render_scripts(page.scripts))
             }
         }
     };
@@ -318,20 +1936,28 @@ pub fn build_html_minimal(
     posts: &[RenderedPost],
     css: &str,
     css_link_href: Option<&str>,
+    css_hash: Option<&str>,
+    page: &PageOptions<'_>,
 ) -> String {
     let title = topic.title.as_str();
     let post_count = posts.len();
+    let lang = topic.language_tag().unwrap_or_else(|| "en".to_string());
+    let dir = is_rtl_locale(&lang).then_some("rtl");
 
     let markup: Markup = html! {
         (DOCTYPE)
-        html lang="en" {
+        @if let Some(stamp) = &page.stamp {
+            (PreEscaped(crate::stamp::html_comment(stamp)))
+        }
+        html lang=(lang) dir=[dir] {
             head {
                 meta charset="utf-8";
                 meta name="viewport" content="width=device-width, initial-scale=1";
                 meta name="color-scheme" content="light dark";
-                title { (title) }
+                (render_page_meta(title, page))
                 @if let Some(href) = css_link_href {
-                    link rel="stylesheet" href=(href);
+                    (preload_links(href, css_hash, posts, page.preload_images))
+                    (stylesheet_link(href, css_hash))
                 } @else {
                     style { (PreEscaped(css)) }
                 }
@@ -341,13 +1967,29 @@ pub fn build_html_minimal(
                     div class="dtr-container dtr-topbar-inner" {
                         div class="dtr-title" {
                             h1 { (title) }
+                            @if let Some(subtitle) = &page.subtitle {
+                                div class="dtr-subtitle" { (subtitle) }
+                            }
+                            @if let Some(participants) = &page.private_message {
+                                div class="dtr-pm-banner" { (private_message_label(participants)) }
+                            }
                         }
-                        button type="button" id="dtr-theme-toggle" class="dtr-btn" { "Theme" }
+                        @if page.theme_toggle {
+                            button type="button" id="dtr-theme-toggle" class="dtr-btn" { "Theme" }
+                        }
+                    }
+                    @if let Some(p) = &page.pagination {
+                        div class="dtr-container" { (render_pagination(p)) }
+                    }
+                }
+                @if page.toc {
+                    @if let Some(toc) = render_toc(posts) {
+                        (toc)
                     }
                 }
                 main class="dtr-container dtr-main" {
                     @for p in posts {
-                        (render_post_minimal(p))
+                        (render_post_minimal(p, page.show_revision_count))
                     }
                 }
                 footer class="dtr-footer" {
@@ -355,7 +1997,17 @@ pub fn build_html_minimal(
                         "Posts: " (post_count)
                     }
                 }
-                script { (PreEscaped(builtin::THEME_TOGGLE_JS)) }
+                @if page.builtin_js {
+                    script { (PreEscaped(builtin::THEME_TOGGLE_JS)) }
+                }
+                @if page.search {
+                    script type="application/json" id="dtr-search-data" { (PreEscaped(search_index_json(posts))) }
+                    script { (PreEscaped(builtin::SEARCH_JS)) }
+                }
+                @if page.code_copy_buttons {
+                    script { (PreEscaped(builtin::CODE_COPY_JS)) }
+                }
+                (render_scripts(page.scripts))
             }
         }
     };
@@ -367,12 +2019,16 @@ fn render_post(p: &RenderedPost) -> Markup {
     let post_number = p.post_number;
     let created_at = p.created_at.as_deref().unwrap_or("");
 
+    if p.small_action.is_some() {
+        return render_small_action(p);
+    }
+
     html! {
         article id=(post_id) class="topic-post" {
             div class="post-wrapper" {
                 aside class="topic-avatar" {
                     @if !p.avatar_src.is_empty() {
-                        img class="avatar" width="45" height="45" src=(p.avatar_src) alt="avatar";
+                        img class="avatar" width="45" height="45" src=(p.avatar_src) srcset=[avatar_srcset(p)] data-dtr-original-src=[p.avatar_original_src.as_deref()] alt="avatar";
                     }
                 }
                 section class="topic-body" {
@@ -380,12 +2036,19 @@ fn render_post(p: &RenderedPost) -> Markup {
                         div class="names" {
                             span class="username" { (p.username) }
                         }
+                        @if let Some(qr) = &p.qr_svg {
+                            (PreEscaped(qr))
+                        }
                         div class="post-info" {
                             span class="post-number" { "#" (post_number) }
                             @if !created_at.is_empty() {
                                 " "
                                 time datetime=(created_at) { (created_at) }
                             }
+                            @if let Some(updated_at) = edited_at(p) {
+                                " "
+                                (render_edited(updated_at))
+                            }
                         }
                     }
                     div class="cooked" {
@@ -397,17 +2060,180 @@ fn render_post(p: &RenderedPost) -> Markup {
     }
 }
 
-fn render_post_minimal(p: &RenderedPost) -> Markup {
+/// `[{"n": post_number, "u": username, "t": plain text}]`, safe to embed in a `<script>` element.
+fn search_index_json(posts: &[RenderedPost]) -> String {
+    let entries: Vec<serde_json::Value> = posts
+        .iter()
+        .map(|p| {
+            let text = match &p.small_action {
+                Some(label) => label.clone(),
+                None => plain_text(&p.cooked_html),
+            };
+            serde_json::json!({ "n": p.post_number, "u": p.username, "t": text })
+        })
+        .collect();
+    serde_json::to_string(&entries)
+        .unwrap_or_else(|_| "[]".to_string())
+        .replace('<', "\\u003c")
+}
+
+/// Text content of an HTML fragment with whitespace runs collapsed.
+pub(crate) fn plain_text(html: &str) -> String {
+    let document = kuchiki::parse_html().one(html);
+    if let Ok(anchors) = document.select(".dtr-heading-anchor") {
+        for anchor in anchors.collect::<Vec<_>>() {
+            anchor.as_node().detach();
+        }
+    }
+    let text = document.text_contents();
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// `cooked` as published on the forum, with every link and media URL made absolute against `base_url`, for readers
+/// outside the archive.
+pub(crate) fn absolute_cooked(cooked: &str, base_url: &Url) -> String {
+    let document = kuchiki::parse_html().one(cooked);
+    let absolute = |raw: &str| {
+        resolve_url(base_url, raw)
+            .map(|u| u.to_string())
+            .unwrap_or_else(|_| raw.to_string())
+    };
+    if let Ok(nodes) = document.select("[href], [src], [srcset], [poster]") {
+        for node in nodes {
+            let mut attrs = node.attributes.borrow_mut();
+            for name in ["href", "src", "poster"] {
+                if let Some(value) = attrs.get_mut(name) {
+                    *value = absolute(value);
+                }
+            }
+            if let Some(srcset) = attrs.get_mut("srcset") {
+                *srcset = srcset_candidates(srcset)
+                    .into_iter()
+                    .map(|(url, descriptor)| match descriptor {
+                        "" => absolute(url),
+                        _ => format!("{} {}", absolute(url), descriptor),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+            }
+        }
+    }
+    let mut out = Vec::new();
+    if let Ok(body) = document.select_first("body") {
+        for child in body.as_node().children() {
+            let _ = child.serialize(&mut out);
+        }
+    }
+    String::from_utf8(out).unwrap_or_default()
+}
+
+/// A heading and the lower-level headings after it, up to the next heading of its level or higher.
+struct TocEntry<'a> {
+    heading: &'a Heading,
+    children: Vec<TocEntry<'a>>,
+}
+
+/// Each post's headings nested by level; a post's first heading starts a new top-level entry whatever its level.
+fn toc_tree(posts: &[RenderedPost]) -> Vec<TocEntry<'_>> {
+    fn insert<'a>(siblings: &mut Vec<TocEntry<'a>>, heading: &'a Heading) {
+        match siblings.last_mut() {
+            Some(last) if heading.level > last.heading.level => insert(&mut last.children, heading),
+            _ => siblings.push(TocEntry {
+                heading,
+                children: Vec::new(),
+            }),
+        }
+    }
+    let mut roots = Vec::new();
+    for p in posts {
+        let mut tree = Vec::new();
+        for heading in &p.headings {
+            insert(&mut tree, heading);
+        }
+        roots.append(&mut tree);
+    }
+    roots
+}
+
+/// `None` when no post has a heading.
+fn render_toc(posts: &[RenderedPost]) -> Option<Markup> {
+    let tree = toc_tree(posts);
+    if tree.is_empty() {
+        return None;
+    }
+    Some(html! {
+        nav class="dtr-toc" aria-label="Table of contents" {
+            details open {
+                summary { "Contents" }
+                (render_toc_entries(&tree))
+            }
+        }
+    })
+}
+
+fn render_toc_entries(entries: &[TocEntry<'_>]) -> Markup {
+    html! {
+        ol {
+            @for e in entries {
+                li class=(format!("dtr-toc-h{}", e.heading.level)) {
+                    a href=(format!("#{}", e.heading.id)) { (e.heading.text) }
+                    @if !e.children.is_empty() {
+                        (render_toc_entries(&e.children))
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `updated_at` when the post was edited more than a minute after it was created.
+fn edited_at(p: &RenderedPost) -> Option<&str> {
+    let created = p.created_at.as_deref()?;
+    let updated = p.updated_at.as_deref()?;
+    let created = chrono::DateTime::parse_from_rfc3339(created).ok()?;
+    let parsed = chrono::DateTime::parse_from_rfc3339(updated).ok()?;
+    ((parsed - created).num_seconds() > 60).then_some(updated)
+}
+
+fn render_edited(updated_at: &str) -> Markup {
+    html! {
+        span class="dtr-edited" title=(format!("Last edited: {}", updated_at)) { "✎" }
+    }
+}
+
+/// "(edited)" for posts with more than one revision.
+fn render_version(p: &RenderedPost, show_count: bool) -> Option<Markup> {
+    let version = p.version.filter(|v| *v > 1)?;
+    let label = if show_count {
+        let edits = version - 1;
+        format!(
+            "(edited {} {})",
+            edits,
+            if edits == 1 { "time" } else { "times" }
+        )
+    } else {
+        "(edited)".to_string()
+    };
+    Some(html! {
+        span class="dtr-version" title=(format!("{} revisions", version)) { (label) }
+    })
+}
+
+fn render_post_minimal(p: &RenderedPost, show_revision_count: bool) -> Markup {
     let post_id = format!("post_{}", p.post_number);
     let post_number = p.post_number;
     let created_at = p.created_at.as_deref().unwrap_or("");
 
+    if p.small_action.is_some() {
+        return render_small_action(p);
+    }
+
     html! {
         article id=(post_id) class="dtr-post" {
             header class="dtr-post-header" {
                 @if !p.avatar_src.is_empty() {
                     div class="dtr-post-avatar" {
-                        img class="dtr-avatar" width="40" height="40" src=(p.avatar_src) alt="avatar";
+                        img class="dtr-avatar" width="40" height="40" src=(p.avatar_src) srcset=[avatar_srcset(p)] data-dtr-original-src=[p.avatar_original_src.as_deref()] alt="avatar";
                     }
                 }
                 div class="dtr-post-meta" {
@@ -419,19 +2245,135 @@ fn render_post_minimal(p: &RenderedPost) -> Markup {
                         @if !created_at.is_empty() {
                             time datetime=(created_at) { (created_at) }
                         }
+                        @if let Some(updated_at) = edited_at(p) {
+                            (render_edited(updated_at))
+                        }
+                        @if let Some(version) = render_version(p, show_revision_count) {
+                            (version)
+                        }
+                    }
+                    @if let Some(r) = &p.reply_to {
+                        div class="dtr-reply-to" {
+                            a href=(r.href) {
+                                "↪ in reply to "
+                                @if let Some(u) = &r.username {
+                                    "@" (u) " "
+                                }
+                                "(#" (r.post_number) ")"
+                            }
+                        }
                     }
                 }
+                @if let Some(qr) = &p.qr_svg {
+                    (PreEscaped(qr))
+                }
             }
-            div class="cooked dtr-cooked" {
+            div class="cooked dtr-cooked" dir="auto" {
                 (PreEscaped(&p.cooked_html))
             }
         }
     }
 }
 
+/// Whether `locale` (e.g. `ar`, `he_IL`, `fa-IR`) names a right-to-left language.
+fn is_rtl_locale(locale: &str) -> bool {
+    let primary = locale
+        .split(['-', '_'])
+        .next()
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    matches!(primary.as_str(), "ar" | "he" | "fa" | "ur" | "yi")
+}
+
+fn render_small_action(p: &RenderedPost) -> Markup {
+    let post_id = format!("post_{}", p.post_number);
+    let label = p.small_action.as_deref().unwrap_or("");
+    let created_at = p.created_at.as_deref().unwrap_or("");
+
+    html! {
+        div id=(post_id) class="dtr-small-action" {
+            span class="dtr-username" { (p.username) }
+            " " (label)
+            @if !created_at.is_empty() {
+                " "
+                time datetime=(created_at) { (created_at) }
+            }
+            @if !p.cooked_html.is_empty() {
+                div class="cooked dtr-cooked" dir="auto" {
+                    (PreEscaped(&p.cooked_html))
+                }
+            }
+        }
+    }
+}
+
+/// Human-readable label for a Discourse `action_code` (e.g. `closed.enabled` -> "closed this topic").
+fn small_action_label(code: &str) -> String {
+    let label = match code {
+        "closed.enabled" => "closed this topic",
+        "closed.disabled" => "opened this topic",
+        "autoclosed.enabled" => "automatically closed this topic",
+        "autoclosed.disabled" => "automatically opened this topic",
+        "archived.enabled" => "archived this topic",
+        "archived.disabled" => "unarchived this topic",
+        "pinned.enabled" => "pinned this topic",
+        "pinned.disabled" => "unpinned this topic",
+        "pinned_globally.enabled" => "pinned this topic globally",
+        "pinned_globally.disabled" => "unpinned this topic",
+        "banner.enabled" => "made this a banner",
+        "banner.disabled" => "removed this banner",
+        "visible.enabled" => "listed this topic",
+        "visible.disabled" => "unlisted this topic",
+        "split_topic" => "split this topic",
+        "invited_user" => "invited a user",
+        "invited_group" => "invited a group",
+        "removed_user" => "removed a user",
+        "removed_group" => "removed a group",
+        "user_left" => "left this conversation",
+        _ => return code.replace(['.', '_'], " "),
+    };
+    label.to_string()
+}
+
+/// Video id of a YouTube embed URL (`https://www.youtube.com/embed/{id}`, also `youtube-nocookie.com`).
+fn youtube_video_id(base_url: &Url, src: &str) -> Option<String> {
+    let url = resolve_url(base_url, src.trim()).ok()?;
+    let host = url.host_str()?.to_ascii_lowercase();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+    if !matches!(
+        host,
+        "youtube.com" | "m.youtube.com" | "youtube-nocookie.com"
+    ) {
+        return None;
+    }
+    let id = url.path().strip_prefix("/embed/")?.trim_end_matches('/');
+    // Playlists (`/embed/videoseries?list=`) have no single thumbnail.
+    let valid = !id.is_empty()
+        && id != "videoseries"
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| id.to_string())
+}
+
+/// `div.dtr-video-embed`: the video's thumbnail under a play icon, linking to the video.
+fn video_embed_node(watch_url: &str, thumbnail_src: &str) -> kuchiki::NodeRef {
+    let frag = format!(
+        "<div class=\"dtr-video-embed\"><a href=\"{}\"><img src=\"{}\" class=\"dtr-video-thumbnail\" alt=\"YouTube video\"><span class=\"dtr-play-icon\">▶</span></a></div>",
+        html_escape_attr(watch_url),
+        html_escape_attr(thumbnail_src)
+    );
+    let doc = kuchiki::parse_html().one(frag);
+    doc.select_first("div").unwrap().as_node().clone()
+}
+
 fn make_link_node(href: &str) -> kuchiki::NodeRef {
     let safe = href.trim();
-    let display = if safe.is_empty() { "link" } else { safe };
+    make_link_node_with_text(safe, if safe.is_empty() { "link" } else { safe })
+}
+
+fn make_link_node_with_text(href: &str, display: &str) -> kuchiki::NodeRef {
+    let safe = href.trim();
     let frag = format!(
         "<p><a href=\"{}\" rel=\"noreferrer noopener\">{}</a></p>",
         html_escape_attr(safe),
@@ -466,15 +2408,71 @@ fn looks_like_image_url(href: &str) -> bool {
         })
 }
 
-fn resolve_any_url(base_url: &Url, raw: &str) -> anyhow::Result<Url> {
-    let r = raw.trim();
-    if r.starts_with("http://") || r.starts_with("https://") {
-        return Ok(Url::parse(r)?);
+/// The `href` of a link to a full-size image that gets downloaded: a lightbox to an image, or a plain link wrapping
+/// a single image that points at an image or an upload. Shared by the prefetch plan and the render pass.
+fn image_link_href(
+    node: &kuchiki::NodeDataRef<kuchiki::ElementData>,
+    base_url: &Url,
+) -> Option<String> {
+    let attrs = node.attributes.borrow();
+    let href = attrs.get("href")?;
+    let is_lightbox = attrs
+        .get("class")
+        .is_some_and(|c| c.split_whitespace().any(|c| c == "lightbox"));
+    let localize = if is_lightbox {
+        looks_like_image_url(href)
+    } else {
+        wraps_single_img(node.as_node())
+            && (looks_like_image_url(href) || is_upload_url(base_url, href))
+    };
+    localize.then(|| href.to_string())
+}
+
+/// Whether `node` contains exactly one `<img>` and nothing else but whitespace.
+fn wraps_single_img(node: &kuchiki::NodeRef) -> bool {
+    let mut imgs = 0;
+    for child in node.children() {
+        if let Some(el) = child.as_element() {
+            if el.name.local.as_ref() != "img" {
+                return false;
+            }
+            imgs += 1;
+        } else if let Some(text) = child.as_text()
+            && !text.borrow().trim().is_empty()
+        {
+            return false;
+        }
     }
-    if r.starts_with("//") {
-        return Ok(Url::parse(&format!("{}:{}", base_url.scheme(), r))?);
+    imgs == 1
+}
+
+/// Whether `href` points into the site's `/uploads/` tree.
+fn is_upload_url(base_url: &Url, href: &str) -> bool {
+    resolve_url(base_url, href).is_ok_and(|u| u.path().contains("/uploads/"))
+}
+
+/// The forum profile URL for `@user` (`a.mention`) and `@group` (`a.mention-group`) links: the link's own
+/// `href` made absolute, or one built from the `@name` text when the link has none.
+fn mention_href(
+    base_url: &Url,
+    node: &kuchiki::NodeDataRef<kuchiki::ElementData>,
+    href: &str,
+) -> Option<String> {
+    let class = node.attributes.borrow().get("class")?.to_string();
+    let route = class.split_whitespace().find_map(|c| match c {
+        "mention" => Some("u"),
+        "mention-group" => Some("groups"),
+        _ => None,
+    })?;
+    let href = href.trim();
+    if !href.is_empty() && !href.starts_with('#') {
+        return resolve_url(base_url, href).ok().map(|u| u.to_string());
     }
-    Ok(base_url.join(r)?)
+    let text = node.text_contents();
+    let name = text.trim().strip_prefix('@').filter(|n| !n.is_empty())?;
+    forum_route(base_url, &format!("{}/{}", route, name))
+        .ok()
+        .map(|u| u.to_string())
 }
 
 fn should_absolutize_href(href: &str) -> bool {
@@ -491,16 +2489,40 @@ fn should_absolutize_href(href: &str) -> bool {
     !(h.starts_with("http://") || h.starts_with("https://"))
 }
 
+/// `(url, descriptors)` of each `srcset` candidate. URLs end at whitespace, not at commas, so `data:` URLs
+/// survive; a comma right after a URL ends its candidate.
+pub(crate) fn srcset_candidates(srcset: &str) -> Vec<(&str, &str)> {
+    let mut out = Vec::new();
+    let mut rest = srcset;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == ',');
+        if rest.is_empty() {
+            return out;
+        }
+        let url_end = rest
+            .find(|c: char| c.is_ascii_whitespace())
+            .unwrap_or(rest.len());
+        let url = &rest[..url_end];
+        if let Some(url) = url.strip_suffix(',') {
+            out.push((url.trim_end_matches(','), ""));
+            rest = &rest[url_end..];
+            continue;
+        }
+        let after = &rest[url_end..];
+        let descriptor_end = after.find(',').unwrap_or(after.len());
+        out.push((url, after[..descriptor_end].trim()));
+        rest = &after[descriptor_end..];
+    }
+}
+
+/// The candidate with the highest density or width; `data:` candidates are not downloads and are skipped.
 fn choose_best_src_from_srcset(srcset: &str) -> Option<String> {
     let mut best: Option<(f64, String)> = None;
-    for part in srcset.split(',') {
-        let part = part.trim();
-        if part.is_empty() {
+    for (url, descriptor) in srcset_candidates(srcset) {
+        if url.starts_with("data:") {
             continue;
         }
-        let mut pieces = part.split_whitespace();
-        let url = pieces.next()?.to_string();
-        let descriptor = pieces.next().unwrap_or("");
+        let url = url.to_string();
         let score = if descriptor.ends_with('w') || descriptor.ends_with('x') {
             descriptor[..descriptor.len().saturating_sub(1)]
                 .parse::<f64>()
@@ -516,33 +2538,53 @@ fn choose_best_src_from_srcset(srcset: &str) -> Option<String> {
     best.map(|(_, url)| url)
 }
 
-fn topic_local_anchor(base_url: &Url, topic_id: u64, href: &str) -> Option<String> {
-    // Accept absolute or relative URLs.
-    let resolved = if href.starts_with("http://") || href.starts_with("https://") {
-        Url::parse(href).ok()?
-    } else if href.starts_with("//") {
-        Url::parse(&format!("{}:{}", base_url.scheme(), href)).ok()?
-    } else {
-        base_url.join(href).ok()?
+/// Absolute URL of a post on the live forum.
+fn remote_post_url(base_url: &Url, topic_id: u64, post_number: u64) -> String {
+    forum_route(base_url, &format!("t/{}/{}", topic_id, post_number))
+        .map(|u| u.to_string())
+        .unwrap_or_else(|_| format!("#post_{}", post_number))
+}
+
+/// Map a link to a post of this topic onto its in-page `#post_N` anchor, or onto the absolute forum URL when
+/// that post is not part of the rendered output. Returns `None` for links that don't target this topic.
+fn topic_local_anchor(
+    base_url: &Url,
+    topic_id: u64,
+    locations: &PostLocations,
+    from_page: usize,
+    href: &str,
+) -> Option<String> {
+    let local_or_remote = |post: u64| {
+        locations
+            .href(post, from_page)
+            .unwrap_or_else(|| remote_post_url(base_url, topic_id, post))
     };
 
+    let resolved = resolve_url(base_url, href).ok()?;
+
     // Must be same host and /t/... structure.
     if resolved.host_str() != base_url.host_str() {
         return None;
     }
 
     // Fast path: already a post anchor.
-    if let Some(fragment) = resolved.fragment() {
-        if fragment.starts_with("post_") {
-            return Some(format!("#{}", fragment));
-        }
+    if let Some(fragment) = resolved.fragment()
+        && let Some(post) = fragment.strip_prefix("post_")
+    {
+        return Some(match post.parse::<u64>() {
+            Ok(post) => local_or_remote(post),
+            Err(_) => format!("#{}", fragment),
+        });
     }
 
+    // Under the forum's root, which may be a subfolder.
+    let root = forum_route(base_url, "").ok()?;
     let segs: Vec<_> = resolved
-        .path_segments()
-        .map(|s| s.collect::<Vec<_>>())
-        .unwrap_or_default();
-    if segs.is_empty() || segs[0] != "t" {
+        .path()
+        .strip_prefix(root.path())?
+        .split('/')
+        .collect();
+    if segs[0] != "t" {
         return None;
     }
 
@@ -558,7 +2600,7 @@ fn topic_local_anchor(base_url: &Url, topic_id: u64, href: &str) -> Option<Strin
     }
 
     let post = post_seg?.parse::<u64>().ok()?;
-    Some(format!("#post_{}", post))
+    Some(local_or_remote(post))
 }
 
 async fn rewrite_inline_style(
@@ -566,27 +2608,18 @@ async fn rewrite_inline_style(
     base_url: &Url,
     store: &AssetStore,
 ) -> anyhow::Result<String> {
-    let re = regex::Regex::new(
-        r#"url\(\s*(?:(?:"(?P<u_d>[^"]+)"|'(?P<u_s>[^']+)'|(?P<u2>[^)]+)))\s*\)"#,
-    )
-    .expect("inline style url regex");
     let mut out = String::with_capacity(style.len());
     let mut last = 0usize;
-    for caps in re.captures_iter(style) {
+    for caps in URL_RE.captures_iter(style) {
         let m = caps.get(0).expect("match");
         out.push_str(&style[last..m.start()]);
-        let url_raw = caps
-            .name("u_d")
-            .or_else(|| caps.name("u_s"))
-            .or_else(|| caps.name("u2"))
-            .map(|m| m.as_str().trim().trim_matches('"').trim_matches('\''))
-            .unwrap_or_default();
+        let url_raw = url_arg(&caps);
         if url_raw.starts_with("data:") || url_raw.starts_with('#') || url_raw.is_empty() {
             out.push_str(m.as_str());
             last = m.end();
             continue;
         }
-        let url = resolve_any_url(base_url, url_raw)?;
+        let url = resolve_url(base_url, url_raw)?;
         let req = AssetRequest {
             kind: AssetKind::Image,
             source: AssetSource::Remote(url),
@@ -606,6 +2639,23 @@ mod tests {
     use super::*;
     use url::Url;
 
+    #[test]
+    fn srcset_candidates_keep_commas_inside_urls() {
+        assert_eq!(
+            srcset_candidates(" a.png, b.png 2x ,data:image/gif;base64,R0lG 1.5x,, c.png 300w"),
+            [
+                ("a.png", ""),
+                ("b.png", "2x"),
+                ("data:image/gif;base64,R0lG", "1.5x"),
+                ("c.png", "300w"),
+            ]
+        );
+        assert_eq!(
+            choose_best_src_from_srcset("a.png 1x, data:image/png;base64,AAAA 3x").as_deref(),
+            Some("a.png")
+        );
+    }
+
     #[test]
     fn srcset_choose_best() {
         assert_eq!(
@@ -621,14 +2671,495 @@ mod tests {
     #[test]
     fn topic_anchor_rewrite() {
         let base = Url::parse("https://forum.example.com/").unwrap();
+        let rendered = PostLocations::new(123, &[1, 5], None);
+        assert_eq!(
+            topic_local_anchor(&base, 123, &rendered, 1, "/t/slug/123/5").as_deref(),
+            Some("#post_5")
+        );
         assert_eq!(
-            topic_local_anchor(&base, 123, "/t/slug/123/5").as_deref(),
+            topic_local_anchor(
+                &base,
+                123,
+                &rendered,
+                1,
+                "https://forum.example.com/t/slug/123/5"
+            )
+            .as_deref(),
             Some("#post_5")
         );
+        assert!(topic_local_anchor(&base, 999, &rendered, 1, "/t/slug/123/5").is_none());
+        assert_eq!(
+            topic_local_anchor(&base, 123, &rendered, 1, "/t/slug/123/7").as_deref(),
+            Some("https://forum.example.com/t/123/7")
+        );
+
+        let paged = PostLocations::new(123, &[1, 2, 3, 57], Some(2));
+        assert_eq!(
+            topic_local_anchor(&base, 123, &paged, 1, "/t/slug/123/57").as_deref(),
+            Some("topic-123-p2.html#post_57")
+        );
+        assert_eq!(
+            topic_local_anchor(&base, 123, &paged, 2, "#post_57").as_deref(),
+            Some("#post_57")
+        );
+
+        let subfolder = Url::parse("https://example.com/forum").unwrap();
         assert_eq!(
-            topic_local_anchor(&base, 123, "https://forum.example.com/t/slug/123/5").as_deref(),
+            topic_local_anchor(&subfolder, 123, &rendered, 1, "/forum/t/slug/123/5").as_deref(),
             Some("#post_5")
         );
-        assert!(topic_local_anchor(&base, 999, "/t/slug/123/5").is_none());
+        assert_eq!(
+            topic_local_anchor(&subfolder, 123, &rendered, 1, "/forum/t/slug/123/7").as_deref(),
+            Some("https://example.com/forum/t/123/7")
+        );
+        assert!(topic_local_anchor(&subfolder, 123, &rendered, 1, "/t/slug/123/5").is_none());
+    }
+
+    #[test]
+    fn same_topic_quotes_get_a_jump_link() {
+        let cooked = r##"<aside class="quote" data-post="5" data-topic="123"><div class="title"><a href="#post_5">@alice</a> said:</div><blockquote>a</blockquote></aside><aside class="quote" data-post="5" data-topic="999"><div class="title">bob:</div></aside><aside class="quote" data-post="7" data-topic="123"><div class="title">carol:</div></aside>"##;
+        let jumps = |locations: &PostLocations, page: usize| {
+            let doc = kuchiki::parse_html().one(cooked);
+            add_quote_jump_links(&doc, 123, locations, page);
+            add_quote_jump_links(&doc, 123, locations, page);
+            doc.select("aside")
+                .unwrap()
+                .map(|aside| {
+                    let links: Vec<String> = aside
+                        .as_node()
+                        .select(".title > a.dtr-quote-jump")
+                        .unwrap()
+                        .map(|a| a.attributes.borrow().get("href").unwrap().to_string())
+                        .collect();
+                    links.join(" ")
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Cross-topic quotes and quotes of posts that were not rendered are left alone.
+        let whole = PostLocations::new(123, &[1, 5], None);
+        assert_eq!(jumps(&whole, 1), ["#post_5", "", ""]);
+        let paged = PostLocations::new(123, &[1, 5], Some(1));
+        assert_eq!(jumps(&paged, 1), ["topic-123-p2.html#post_5", "", ""]);
+    }
+
+    #[test]
+    fn same_topic_oneboxes_link_locally() {
+        let base = Url::parse("https://forum.example.com/").unwrap();
+        let cooked = r#"<aside class="onebox discoursetopic" data-onebox-src="https://forum.example.com/t/slug/123/5"><header class="source"><a href="https://forum.example.com/t/slug/123/5">forum.example.com</a></header><article class="onebox-body"><h3><a href="https://forum.example.com/t/slug/123/5?u=alice">Slug</a></h3><a href="/u/bob">bob</a></article></aside><aside class="onebox" data-onebox-src="https://forum.example.com/t/other/999/5"><header class="source"><a href="https://forum.example.com/t/other/999/5">forum.example.com</a></header></aside><aside class="onebox" data-onebox-src="https://forum.example.com/t/slug/123/7"><header class="source"><a href="https://forum.example.com/t/slug/123/7">forum.example.com</a></header></aside>"#;
+        let doc = kuchiki::parse_html().one(cooked);
+        let locations = PostLocations::new(123, &[1, 5], None);
+        localize_same_topic_oneboxes(&doc, &base, 123, &locations, 1);
+        localize_same_topic_oneboxes(&doc, &base, 123, &locations, 1);
+        let asides: Vec<(Vec<String>, usize)> = doc
+            .select("aside")
+            .unwrap()
+            .map(|aside| {
+                let links = aside
+                    .as_node()
+                    .select("a")
+                    .unwrap()
+                    .map(|a| a.attributes.borrow().get("href").unwrap().to_string())
+                    .collect();
+                let markers = aside
+                    .as_node()
+                    .select("header .dtr-onebox-local")
+                    .unwrap()
+                    .count();
+                (links, markers)
+            })
+            .collect();
+
+        // The user link stays; other topics and posts that were not rendered keep their forum links.
+        assert_eq!(
+            asides,
+            [
+                (vec!["#post_5".into(), "#post_5".into(), "/u/bob".into()], 1),
+                (vec!["https://forum.example.com/t/other/999/5".into()], 0),
+                (vec!["https://forum.example.com/t/slug/123/7".into()], 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn stylesheet_links_carry_the_sri_hash_when_given() {
+        assert_eq!(
+            css_sha384(""),
+            "OLBgp1GsljhM2TJ+sbHjaiH9txEUvgdDTAzHv2P24donTt6/529l+9Ua0vFImLlb"
+        );
+        assert_eq!(
+            css_sha384("alert('Hello, world.');"),
+            "H8BRh8j48O9oYatfu5AZzq6A9RINhZO5H16dQZngK7T62em8MUt1FLm52t+eX6xO"
+        );
+        assert_eq!(
+            stylesheet_link("assets/css/site.css", Some("abc")).into_string(),
+            "<link rel=\"stylesheet\" href=\"assets/css/site.css\" integrity=\"sha384-abc\" crossorigin=\"anonymous\">"
+        );
+        assert_eq!(
+            stylesheet_link("assets/css/site.css", None).into_string(),
+            "<link rel=\"stylesheet\" href=\"assets/css/site.css\">"
+        );
+    }
+
+    #[test]
+    fn the_stylesheet_preload_matches_its_link() {
+        assert_eq!(
+            preload_links("assets/css/site.css", Some("abc"), &[], Some(0)).into_string(),
+            "<link rel=\"preload\" href=\"assets/css/site.css\" as=\"style\" integrity=\"sha384-abc\" crossorigin=\"anonymous\">"
+        );
+        assert_eq!(
+            preload_links("assets/css/site.css", None, &[], Some(0)).into_string(),
+            "<link rel=\"preload\" href=\"assets/css/site.css\" as=\"style\">"
+        );
+        assert_eq!(
+            preload_links("assets/css/site.css", Some("abc"), &[], None).into_string(),
+            ""
+        );
+    }
+
+    #[test]
+    fn youtube_embeds_are_recognized() {
+        let base = Url::parse("https://forum.example.com/").unwrap();
+        for (src, id) in [
+            (
+                "https://www.youtube.com/embed/dQw4w9WgXcQ",
+                Some("dQw4w9WgXcQ"),
+            ),
+            (
+                "//www.youtube.com/embed/dQw4w9WgXcQ?feature=oembed",
+                Some("dQw4w9WgXcQ"),
+            ),
+            (
+                "https://www.youtube-nocookie.com/embed/a_b-c/",
+                Some("a_b-c"),
+            ),
+            ("https://youtube.com/embed/", None),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ", None),
+            ("https://www.youtube.com/embed/videoseries?list=PL1", None),
+            ("https://player.vimeo.com/video/1", None),
+            ("https://evil.example.com/embed/x", None),
+            ("/embed/x", None),
+        ] {
+            assert_eq!(youtube_video_id(&base, src).as_deref(), id, "{src}");
+        }
+
+        let node = video_embed_node("https://www.youtube.com/watch?v=x", "assets/img/t.jpg");
+        let mut out = Vec::new();
+        node.serialize(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "<div class=\"dtr-video-embed\"><a href=\"https://www.youtube.com/watch?v=x\"><img alt=\"YouTube video\" class=\"dtr-video-thumbnail\" src=\"assets/img/t.jpg\"><span class=\"dtr-play-icon\">▶</span></a></div>"
+        );
+    }
+
+    #[test]
+    fn spoilers_become_details() {
+        let doc = kuchiki::parse_html().one(
+            r#"<div class="spoiler"><p>hidden <b>text</b></p><div class="spoiler"><p>nested</p></div></div><span class="spoiler">inline</span>"#,
+        );
+        convert_spoilers(&doc);
+        let body = doc.select_first("body").unwrap();
+        let mut out = Vec::new();
+        for child in body.as_node().children() {
+            child.serialize(&mut out).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"<details class="dtr-spoiler"><summary class="dtr-spoiler-label">Spoiler (click to reveal)</summary>"#,
+                r#"<p>hidden <b>text</b></p>"#,
+                r#"<details class="dtr-spoiler"><summary class="dtr-spoiler-label">Spoiler (click to reveal)</summary><p>nested</p></details>"#,
+                r#"</details><span class="spoiler">inline</span>"#,
+            )
+        );
+    }
+
+    #[test]
+    fn mentions_link_to_forum_profiles() {
+        let base = Url::parse("https://forum.example.com/").unwrap();
+        let doc = kuchiki::parse_html().one(
+            r##"<p><a class="mention" href="/u/alice">@Alice</a> <a class="mention-group" href="/groups/staff">@staff</a> <a class="mention" href="/u/bob/"></a> <a href="/u/carol">carol</a> <a class="mention" href="">@dave</a> <a class="mention" href="#">@</a></p>"##,
+        );
+        let hrefs: Vec<Option<String>> = doc
+            .select("a")
+            .unwrap()
+            .map(|a| {
+                let href = a.attributes.borrow().get("href").unwrap().to_string();
+                mention_href(&base, &a, &href)
+            })
+            .collect();
+        assert_eq!(
+            hrefs,
+            [
+                Some("https://forum.example.com/u/alice".to_string()),
+                Some("https://forum.example.com/groups/staff".to_string()),
+                Some("https://forum.example.com/u/bob/".to_string()),
+                None,
+                Some("https://forum.example.com/u/dave".to_string()),
+                None,
+            ]
+        );
+
+        // A subfolder install's links carry its prefix already.
+        let subfolder = Url::parse("https://example.com/forum/").unwrap();
+        let a = doc.select_first("a").unwrap();
+        assert_eq!(
+            mention_href(&subfolder, &a, "/forum/u/alice").as_deref(),
+            Some("https://example.com/forum/u/alice")
+        );
+    }
+
+    #[test]
+    fn code_blocks_get_one_span_per_line() {
+        let doc = kuchiki::parse_html().one(
+            "<pre class=\"lang-rust\"><code>fn main() {\n    x &lt; y;\n}\n</code></pre><p><code>inline</code></p>",
+        );
+        number_code_lines(&doc);
+        let pre = doc.select_first("pre").unwrap();
+        assert_eq!(
+            pre.attributes.borrow().get("class"),
+            Some("lang-rust dtr-line-numbered")
+        );
+        let mut out = Vec::new();
+        pre.as_node().serialize(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "<pre class=\"lang-rust dtr-line-numbered\"><code>\
+             <span class=\"dtr-line\" data-line=\"1\">fn main() {</span>\n\
+             <span class=\"dtr-line\" data-line=\"2\">    x &lt; y;</span>\n\
+             <span class=\"dtr-line\" data-line=\"3\">}</span></code></pre>"
+        );
+        assert!(doc.select_first("p code span").is_err());
+    }
+
+    #[test]
+    fn heading_ids_are_assigned_and_deduplicated() {
+        let doc = kuchiki::parse_html()
+            .one(r#"<h1>Intro</h1><h2 id="keep">Kept</h2><h3>Intro</h3><h4>Skipped</h4>"#);
+        add_heading_anchors(&doc, 7);
+        add_heading_anchors(&doc, 7);
+        let headings = collect_headings(&doc, 3);
+        let ids: Vec<_> = headings.iter().map(|h| h.id.as_str()).collect();
+        assert_eq!(ids, ["intro_post7", "keep", "intro-2_post7"]);
+        assert_eq!(headings[2].level, 3);
+        assert_eq!(headings[0].text, "Intro");
+        let h4 = doc.select_first("h4").unwrap();
+        assert_eq!(h4.attributes.borrow().get("id"), Some("skipped_post7"));
+        assert_eq!(
+            h4.as_node().to_string(),
+            r##"<h4 id="skipped_post7">Skipped<a aria-label="Link to heading" class="dtr-heading-anchor" href="#skipped_post7">¶</a></h4>"##
+        );
+        assert_eq!(slugify("Crème Brûlée: part 2!"), "crème-brûlée-part-2");
+    }
+
+    #[test]
+    fn edits_within_a_minute_are_not_flagged() {
+        let post = |updated_at: &str| RenderedPost {
+            post_number: 1,
+            username: "alice".to_string(),
+            created_at: Some("2025-01-01T10:00:00.000Z".to_string()),
+            updated_at: Some(updated_at.to_string()),
+            version: None,
+            avatar_src: String::new(),
+            avatar_srcset: String::new(),
+            avatar_original_src: None,
+            cooked_html: String::new(),
+            reply_to: None,
+            small_action: None,
+            headings: Vec::new(),
+            qr_svg: None,
+        };
+        assert_eq!(edited_at(&post("2025-01-01T10:00:45.000Z")), None);
+        assert_eq!(
+            edited_at(&post("2025-01-01T10:05:00.000Z")),
+            Some("2025-01-01T10:05:00.000Z")
+        );
+        assert_eq!(edited_at(&post("not a date")), None);
+
+        let html = render_post_minimal(&post("2025-01-02T08:00:00.000Z"), false).into_string();
+        assert!(html.contains(
+            r#"<span class="dtr-edited" title="Last edited: 2025-01-02T08:00:00.000Z">✎</span>"#
+        ));
+    }
+
+    #[test]
+    fn revision_count_is_shown_for_edited_posts_only() {
+        let post = |version: u64| RenderedPost {
+            post_number: 1,
+            username: "alice".to_string(),
+            created_at: None,
+            updated_at: None,
+            version: Some(version),
+            avatar_src: String::new(),
+            avatar_srcset: String::new(),
+            avatar_original_src: None,
+            cooked_html: String::new(),
+            reply_to: None,
+            small_action: None,
+            headings: Vec::new(),
+            qr_svg: None,
+        };
+        let html = render_post_minimal(&post(1), true).into_string();
+        assert!(!html.contains("dtr-version"));
+
+        let html = render_post_minimal(&post(3), false).into_string();
+        assert!(html.contains(r#"<span class="dtr-version" title="3 revisions">(edited)</span>"#));
+        let html = render_post_minimal(&post(3), true).into_string();
+        assert!(html.contains(">(edited 2 times)</span>"));
+    }
+
+    #[test]
+    fn search_index_cannot_close_its_script_element() {
+        let post = RenderedPost {
+            post_number: 3,
+            username: "alice".to_string(),
+            created_at: None,
+            updated_at: None,
+            version: None,
+            avatar_src: String::new(),
+            avatar_srcset: String::new(),
+            avatar_original_src: None,
+            cooked_html: "<p>see <code>&lt;/script&gt;</code>\n  here</p>".to_string(),
+            reply_to: None,
+            small_action: None,
+            headings: Vec::new(),
+            qr_svg: None,
+        };
+        let json = search_index_json(&[post]);
+        assert!(!json.contains('<'));
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed[0]["t"], "see </script> here");
+        assert_eq!(parsed[0]["n"], 3);
+    }
+
+    #[test]
+    fn prefetch_plan_orders_small_assets_first() {
+        let base = Url::parse("https://forum.example.com/").unwrap();
+        let topic: TopicJson = serde_json::from_str(
+            r#"{"id": 1, "title": "T", "post_stream": {"posts": [{
+                "post_number": 1,
+                "avatar_template": "/user_avatar/forum.example.com/a/{size}/1.png",
+                "cooked": "<a class=\"lightbox\" href=\"/uploads/original/big.png\"><img src=\"/uploads/optimized/big_600.png\"></a><img class=\"emoji\" src=\"/images/emoji/smile.png\"><img src=\"/images/emoji/smile.png\">"
+            }]}}"#,
+        )
+        .unwrap();
+        let opts = RenderOptions {
+            base_url: &base,
+            avatar_sizes: &[120],
+            small_actions: SmallActionsMode::Render,
+            include_whispers: false,
+            posts: None,
+            toc: false,
+            toc_max_depth: 3,
+            fetch_order: FetchOrder::Size,
+            paginate: None,
+            only_users: &[],
+            exclude_users: &[],
+            keep_going: false,
+            concurrency: 1,
+            strip_reactions: false,
+            strip_selectors: &[],
+            line_numbers: false,
+            code_copy_buttons: false,
+            qr_permalinks: false,
+            max_css_bytes: 1024,
+            lenient_css: false,
+            require_alt_text: false,
+            keep_original_urls: false,
+            slow_post: None,
+        };
+        let plan = prefetch_plan(&select_posts(&topic, &opts), &opts);
+        assert!(plan.requests.iter().all(|(n, _)| *n == 1));
+        assert_eq!(plan.scan.len(), 1);
+        let paths: Vec<String> = plan
+            .requests
+            .iter()
+            .map(|(_, r)| match &r.source {
+                AssetSource::Remote(u) => u.path().to_string(),
+                AssetSource::FirstFound(urls) => {
+                    urls.iter().map(Url::path).collect::<Vec<_>>().join(" ")
+                }
+                AssetSource::Local(p) => p.display().to_string(),
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "/user_avatar/forum.example.com/a/120/1.png /user_avatar/forum.example.com/a/96/1.png \
+                 /user_avatar/forum.example.com/a/48/1.png /user_avatar/forum.example.com/a/25/1.png",
+                "/images/emoji/smile.png",
+                "/uploads/optimized/big_600.png",
+                "/uploads/original/big.png",
+            ]
+        );
+    }
+
+    #[test]
+    fn rtl_locale_detection() {
+        assert!(is_rtl_locale("ar"));
+        assert!(is_rtl_locale("he_IL"));
+        assert!(is_rtl_locale("fa-IR"));
+        assert!(!is_rtl_locale("en"));
+        assert!(!is_rtl_locale("zh_CN"));
+    }
+
+    /// Representative cooked HTML under `tests/fixtures/cooked` rendered with the builtin theme. Regenerate the
+    /// goldens with `DTR_UPDATE_GOLDENS=1 cargo test builtin_theme_goldens` after an intended change.
+    #[test]
+    fn builtin_theme_goldens() {
+        let fixtures =
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/cooked");
+        for (name, locale) in [
+            ("code-blocks", "en"),
+            ("tables", "en"),
+            ("quotes", "en"),
+            ("polls", "en"),
+            ("images", "en"),
+            ("rtl", "ar"),
+        ] {
+            let cooked = std::fs::read_to_string(fixtures.join(format!("{name}.html"))).unwrap();
+            let topic: TopicJson = serde_json::from_value(serde_json::json!({
+                "id": 123,
+                "title": format!("Fixture: {name}"),
+                "locale": locale,
+                "post_stream": {"posts": []},
+            }))
+            .unwrap();
+            let post = RenderedPost {
+                post_number: 1,
+                username: "alice".to_string(),
+                created_at: Some("2024-01-02T03:04:05.000Z".to_string()),
+                updated_at: None,
+                version: None,
+                avatar_src: "assets/avatar-alice.png".to_string(),
+                avatar_srcset: String::new(),
+                avatar_original_src: None,
+                cooked_html: cooked,
+                reply_to: None,
+                small_action: None,
+                headings: Vec::new(),
+                qr_svg: None,
+            };
+            let page = PageOptions {
+                scripts: &[],
+                builtin_js: true,
+                theme_toggle: true,
+                subtitle: None,
+                pagination: None,
+                toc: false,
+                search: false,
+                code_copy_buttons: false,
+                title: None,
+                description: None,
+                stamp: None,
+                show_revision_count: false,
+                private_message: None,
+                noindex: false,
+                fingerprint: None,
+                preload_images: None,
+            };
+            let html = build_html_minimal(&topic, &[post], builtin::BUILTIN_CSS, None, None, &page);
+            crate::testing::assert_golden(&format!("builtin-theme/{name}"), &html);
+        }
     }
 }
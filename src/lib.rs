@@ -1,12 +1,31 @@
 mod assets;
 mod builtin;
 mod cli;
+mod config;
 mod css;
+mod dedupe;
+mod epub;
+mod feed;
 mod fetcher;
+mod fingerprint;
+mod gc;
+mod glyphs;
 mod html;
+mod meta;
+mod output;
 mod progress;
+mod purge;
+mod qr;
+mod report;
+mod serve;
+mod stamp;
 mod strict;
+#[cfg(test)]
+mod testing;
 mod topic;
+mod urlres;
+mod verify;
+mod xml;
 
 use std::path::{Path, PathBuf};
 
@@ -15,8 +34,16 @@ use assets::AssetStore;
 use cli::Args;
 use fetcher::Fetcher;
 
-pub use cli::ProgressMode;
-pub use cli::{Args as CliArgs, Mode, OfflineMode};
+pub use cli::{Args as CliArgs, GcArgs, Mode, OfflineMode, ServeArgs, VerifyArgs};
+pub use cli::{FetchOrder, PostRange, ProgressMode, SmallActionsMode, SummaryFormat};
+pub use config::effective_config;
+pub use gc::{find_orphans, gc};
+pub use output::OutputNotWritable;
+pub use progress::JsonLogLayer;
+pub use serve::{Server, serve};
+pub use strict::{StrictReport, Violation, check_no_js, check_strict_offline};
+pub use topic::{IncompleteExport, InputTooLarge};
+pub use verify::{verify, verify_files};
 
 pub async fn run(args: Args) -> anyhow::Result<()> {
     use std::io::IsTerminal as _;
@@ -24,51 +51,389 @@ pub async fn run(args: Args) -> anyhow::Result<()> {
     if !matches!(args.offline, OfflineMode::Strict) {
         anyhow::bail!("only --offline strict is supported in v1");
     }
+    if args.paginate.is_some() && !matches!(args.mode, Mode::Dir) {
+        anyhow::bail!("--paginate requires --mode dir");
+    }
+    html::validate_selectors(&args.strip_selector)?;
+    if let Some(url) = args
+        .css_url
+        .iter()
+        .find(|u| !matches!(u.scheme(), "http" | "https"))
+    {
+        anyhow::bail!("--css-url {url} is not an http(s) URL; pass local files with --css");
+    }
+    if args.toc && !args.builtin_css {
+        tracing::warn!("--toc only applies to the --builtin-css theme; ignoring it");
+    }
+    if args.search && !args.builtin_css {
+        tracing::warn!("--search only applies to the --builtin-css theme; ignoring it");
+    }
+    if args.code_copy_button && !args.builtin_css {
+        tracing::warn!("--code-copy-button only applies to the --builtin-css theme; ignoring it");
+    }
+
+    if args.css_integrity && !matches!(args.mode, Mode::Dir) {
+        tracing::warn!(
+            "--css-integrity only applies to --mode dir, which links the stylesheet; ignoring it"
+        );
+    }
+    if args.emit_preload_hints.is_some() && !matches!(args.mode, Mode::Dir) {
+        tracing::warn!(
+            "--emit-preload-hints only applies to --mode dir, whose assets are separate files; ignoring it"
+        );
+    }
+    if matches!(args.mode, Mode::Epub) {
+        if !args.inject_js.is_empty() || args.search || args.code_copy_button {
+            tracing::warn!(
+                "--mode epub leaves scripts out; ignoring --inject-js, --search and --code-copy-button"
+            );
+        }
+        if args.skip_unchanged {
+            tracing::warn!(
+                "--skip-unchanged cannot read the fingerprint of an EPUB; rendering anyway"
+            );
+        }
+    }
 
     let progress_enabled = match args.progress {
         ProgressMode::Always => true,
-        ProgressMode::Never => false,
+        ProgressMode::Never | ProgressMode::Json => false,
         ProgressMode::Auto => std::io::stderr().is_terminal(),
     };
-    let progress = progress::Progress::new(progress_enabled, args.max_concurrency);
+    let progress = match args.progress {
+        ProgressMode::Json => progress::Progress::json_lines(args.max_concurrency),
+        _ => progress::Progress::new(progress_enabled, args.max_concurrency),
+    };
     progress.set_stage("读取 topic.json");
 
-    let topic: topic::TopicJson = {
-        let bytes =
-            std::fs::read(&args.input).with_context(|| format!("read {}", args.input.display()))?;
-        serde_json::from_slice(&bytes).context("parse topic.json")?
+    let (topic, topic_hash) =
+        topic::read_topic(&args.input, args.max_input_mb.saturating_mul(1024 * 1024))?;
+
+    let incomplete = topic.incomplete_export(args.min_completeness);
+    if let Some(gap) = incomplete {
+        if args.require_complete {
+            return Err(gap.into());
+        }
+        tracing::warn!("{gap}");
+    }
+
+    let fingerprint = fingerprint::compute(&args, &topic_hash)?;
+    if args.skip_unchanged {
+        let page = primary_output_path(&args, topic.id);
+        if fingerprint::previous(&page).as_deref() == Some(fingerprint.as_str()) {
+            progress.finish();
+            tracing::info!(
+                topic = topic.id,
+                page = %page.display(),
+                "unchanged since the last render; skipping"
+            );
+            if matches!(args.summary, SummaryFormat::Json) {
+                print_json_summary(&args, topic.id, &progress, None, true)?;
+            }
+            return Ok(());
+        }
+    }
+
+    let selected = html::select_posts(&topic, &render_options(&args)).len();
+    if selected == 0 && (!args.only_user.is_empty() || !args.exclude_user.is_empty()) {
+        anyhow::bail!("no posts left to render after applying --only-user/--exclude-user");
+    }
+    progress.set_posts_total(selected);
+    let skipped_whispers = if args.include_whispers {
+        0
+    } else {
+        topic
+            .post_stream
+            .posts
+            .iter()
+            .filter(|p| p.is_whisper())
+            .count()
     };
 
-    let total_posts = topic
-        .post_stream
-        .posts
-        .iter()
-        .filter(|p| p.cooked.as_deref().unwrap_or("").trim().len() > 0)
-        .count();
-    progress.set_posts_total(total_posts);
+    // Fail before any download when the output location cannot be written.
+    output::probe_writable(&output_dir(&args, topic.id))?;
 
+    let mut cookies = args.cookie.clone();
+    if let Some(path) = &args.cookie_file {
+        let host = args.base_url.host_str().unwrap_or("");
+        cookies.extend(fetcher::read_netscape_cookie_file(path, host)?);
+    }
+    let user_agent = fetcher::user_agent(args.user_agent.as_deref(), args.ua_contact.as_deref());
     let fetcher = Fetcher::new(
-        &args.user_agent,
-        args.max_concurrency,
+        fetcher::FetcherConfig {
+            user_agent: &user_agent,
+            max_concurrency: args.max_concurrency,
+            per_domain_concurrency: args.per_domain_concurrency,
+            base_url: &args.base_url,
+            cookies,
+            timeouts: timeouts(&args),
+            proxy: args.proxy.as_deref(),
+            no_proxy: &args.no_proxy,
+            max_retry_total: std::time::Duration::from_secs(args.max_retry_total_secs),
+            max_connect_retries: args.max_connect_retries,
+            max_response_bytes: args.max_response_bytes,
+            max_asset_bytes: args.max_asset_size,
+            preflight_above_bytes: args.preflight_above_bytes,
+            skip_large_assets: args.skip_large_assets,
+            max_buffered_bytes: args.max_memory_mb.map(|mb| mb.saturating_mul(1024 * 1024)),
+            max_total_bytes: args.max_total_bytes,
+            fetch_deadline: args.fetch_deadline_secs.map(std::time::Duration::from_secs),
+        },
         Some(progress.clone()),
     )?;
 
     let res = match args.mode {
-        Mode::Dir => render_dir(&topic, &args, fetcher, progress.clone()).await,
-        Mode::Single => render_single(&topic, &args, fetcher, progress.clone()).await,
+        Mode::Dir => render_dir(&topic, &args, fingerprint, fetcher, progress.clone()).await,
+        Mode::Single => render_single(&topic, &args, fingerprint, fetcher, progress.clone()).await,
+        Mode::Epub => render_epub(&topic, &args, fetcher, progress.clone()).await,
     };
     progress.finish();
+    if skipped_whispers > 0 {
+        tracing::info!(
+            count = skipped_whispers,
+            "skipped whisper posts (use --include-whispers to keep them)"
+        );
+    }
+    if matches!(args.summary, SummaryFormat::Json) {
+        let error = res.as_ref().err().map(|e| format!("{:#}", e));
+        print_json_summary(&args, topic.id, &progress, error, false)?;
+    } else if !matches!(args.progress, ProgressMode::Never | ProgressMode::Json) {
+        let mut summary = progress.summary();
+        if skipped_whispers > 0 {
+            summary.note(
+                report::Tone::Warning,
+                format!(
+                    "skipped {} whisper posts (use --include-whispers to keep them)",
+                    report::thousands(skipped_whispers as u64)
+                ),
+            );
+        }
+        if let Some(declared) = topic.posts_count.filter(|&n| n > 0) {
+            let exported = topic.post_stream.posts.len() as u64;
+            summary.note(
+                match incomplete {
+                    Some(_) => report::Tone::Warning,
+                    None => report::Tone::Plain,
+                },
+                format!(
+                    "topic.json holds {} of {} declared posts ({:.1}%)",
+                    report::thousands(exported),
+                    report::thousands(declared),
+                    exported as f64 * 100.0 / declared as f64
+                ),
+            );
+        }
+        if let Some(bytes) = output_bytes(&args, topic.id) {
+            summary.note(
+                report::Tone::Plain,
+                format!("output: {} on disk", report::human_bytes(bytes)),
+            );
+        }
+        summary.note(
+            report::Tone::Plain,
+            format!("requests sent as User-Agent: {user_agent}"),
+        );
+        if let Err(e) = &res {
+            summary.note(report::Tone::Failure, format!("failed: {:#}", e));
+        }
+        eprint!("{}", summary.render(report::color_enabled(args.no_color)));
+    }
     res
 }
 
+/// `--summary json`: the summary as one line on stdout.
+fn print_json_summary(
+    args: &Args,
+    topic_id: u64,
+    progress: &progress::Progress,
+    error: Option<String>,
+    skipped: bool,
+) -> anyhow::Result<()> {
+    let summary = progress::Summary {
+        output_bytes: output_bytes(args, topic_id),
+        error,
+        skipped,
+        ..progress.totals()
+    };
+    println!(
+        "{}",
+        serde_json::to_string(&summary).context("serialize summary")?
+    );
+    Ok(())
+}
+
+fn timeouts(args: &Args) -> fetcher::Timeouts {
+    use std::time::Duration;
+
+    let global = args.timeout_secs.max(1);
+    fetcher::Timeouts {
+        global: Duration::from_secs(global),
+        avatar: Duration::from_secs(args.timeout_avatar.unwrap_or((global / 4).max(1))),
+        image: Duration::from_secs(args.timeout_image.unwrap_or(global)),
+        font: Duration::from_secs(args.timeout_font.unwrap_or((global / 2).max(1))),
+    }
+}
+
+fn render_options(args: &Args) -> html::RenderOptions<'_> {
+    html::RenderOptions {
+        base_url: &args.base_url,
+        avatar_sizes: &args.avatar_size,
+        small_actions: args.small_actions,
+        include_whispers: args.include_whispers,
+        posts: args.posts,
+        toc: args.toc,
+        toc_max_depth: args.toc_max_depth,
+        fetch_order: args.fetch_order,
+        // Epub chapters are named like `--paginate` pages, so links between posts resolve the same way.
+        paginate: match args.mode {
+            Mode::Epub => Some(args.epub_posts_per_chapter as usize),
+            _ => args.paginate.map(|n| n as usize),
+        },
+        only_users: &args.only_user,
+        exclude_users: &args.exclude_user,
+        keep_going: args.keep_going,
+        concurrency: args.max_concurrency,
+        strip_reactions: args.strip_reactions,
+        strip_selectors: &args.strip_selector,
+        line_numbers: args.line_numbers,
+        code_copy_buttons: args.code_copy_button && args.builtin_css,
+        qr_permalinks: args.qr_permalinks,
+        max_css_bytes: args.max_css_bytes,
+        lenient_css: args.lenient_css,
+        require_alt_text: args.require_alt_text,
+        keep_original_urls: args.keep_original_urls,
+        slow_post: (args.slow_post_secs > 0)
+            .then(|| std::time::Duration::from_secs(args.slow_post_secs)),
+    }
+}
+
+fn page_options<'a>(
+    topic: &topic::TopicJson,
+    posts: &[html::RenderedPost],
+    args: &Args,
+    scripts: &'a [html::PageScript],
+    stamp: Option<String>,
+    fingerprint: String,
+) -> html::PageOptions<'a> {
+    let filtered_by_user = !args.only_user.is_empty() || !args.exclude_user.is_empty();
+    let subtitle = match (posts.first(), posts.last()) {
+        (Some(first), Some(last)) if args.posts.is_some() => Some(format!(
+            "showing posts {}–{} of {}",
+            first.post_number,
+            last.post_number,
+            topic.total_posts()
+        )),
+        (Some(_), Some(_)) if filtered_by_user => Some(format!(
+            "showing {} of {} posts",
+            posts.len(),
+            topic.total_posts()
+        )),
+        _ => None,
+    };
+    html::PageOptions {
+        scripts,
+        builtin_js: !args.no_builtin_js && !args.no_js,
+        theme_toggle: !args.no_js,
+        subtitle,
+        pagination: None,
+        toc: args.toc,
+        search: args.search,
+        code_copy_buttons: args.code_copy_button,
+        title: None,
+        description: None,
+        stamp,
+        show_revision_count: args.show_revision_count,
+        private_message: topic.is_private_message().then(|| topic.participants()),
+        noindex: topic.is_private_message() && !args.include_private,
+        fingerprint: Some(fingerprint),
+        preload_images: args.emit_preload_hints,
+    }
+}
+
+/// Directory the pages and assets are written to.
+fn output_dir(args: &Args, topic_id: u64) -> PathBuf {
+    match args.mode {
+        Mode::Dir => args.out.clone().unwrap_or_else(|| PathBuf::from("out")),
+        Mode::Single | Mode::Epub => primary_output_path(args, topic_id)
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .map(|p| p.to_path_buf())
+            .unwrap_or_else(|| PathBuf::from(".")),
+    }
+}
+
+/// Bytes on disk of the output: in dir mode the topic's pages and the assets its manifest lists (the directory
+/// may hold other topics), the page or book otherwise.
+fn output_bytes(args: &Args, topic_id: u64) -> Option<u64> {
+    let file_bytes = |path: &Path| std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    match args.mode {
+        Mode::Dir => {
+            let dir = output_dir(args, topic_id);
+            let pages: Vec<PathBuf> = match args.paginate {
+                Some(_) => (1..)
+                    .map(|n| dir.join(html::page_file_name(topic_id, n)))
+                    .take_while(|page| page.is_file())
+                    .collect(),
+                None => Some(dir.join(format!("topic-{}.html", topic_id)))
+                    .filter(|page| page.is_file())
+                    .into_iter()
+                    .collect(),
+            };
+            if pages.is_empty() {
+                return None;
+            }
+            let manifest =
+                std::fs::read(dir.join(format!("topic-{}.assets-manifest.json", topic_id)))
+                    .ok()
+                    .and_then(|json| serde_json::from_slice::<Vec<serde_json::Value>>(&json).ok())
+                    .unwrap_or_default();
+            let assets: std::collections::BTreeSet<&str> =
+                manifest.iter().filter_map(|e| e["path"].as_str()).collect();
+            Some(
+                pages.iter().map(|page| file_bytes(page)).sum::<u64>()
+                    + assets
+                        .iter()
+                        .map(|rel| file_bytes(&dir.join(rel)))
+                        .sum::<u64>(),
+            )
+        }
+        Mode::Single | Mode::Epub => std::fs::metadata(primary_output_path(args, topic_id))
+            .ok()
+            .map(|m| m.len()),
+    }
+}
+
+/// The page `--skip-unchanged` reads the previous fingerprint from: the HTML file, or the first page when paginated.
+fn primary_output_path(args: &Args, topic_id: u64) -> PathBuf {
+    match args.mode {
+        Mode::Dir => {
+            let out_dir = args.out.clone().unwrap_or_else(|| PathBuf::from("out"));
+            let name = match args.paginate {
+                Some(_) => html::page_file_name(topic_id, 1),
+                None => format!("topic-{}.html", topic_id),
+            };
+            out_dir.join(name)
+        }
+        Mode::Single => args
+            .out
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(format!("topic-{}.html", topic_id))),
+        Mode::Epub => args
+            .out
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(format!("topic-{}.epub", topic_id))),
+    }
+}
+
 async fn render_dir(
     topic: &topic::TopicJson,
     args: &Args,
+    fingerprint: String,
     fetcher: Fetcher,
     progress: std::sync::Arc<progress::Progress>,
 ) -> anyhow::Result<()> {
     let out_dir = args.out.clone().unwrap_or_else(|| PathBuf::from("out"));
-    std::fs::create_dir_all(&out_dir).with_context(|| format!("create {}", out_dir.display()))?;
+    output::create_dir_all(&out_dir)?;
 
     let store = AssetStore::new_dir(
         out_dir.clone(),
@@ -77,31 +442,98 @@ async fn render_dir(
         Some(progress.clone()),
     );
 
+    // `--purge-css` matches the bundle against the rendered posts, so they come first.
+    let rendered = match args.purge_css {
+        true => {
+            progress.set_stage("渲染帖子");
+            Some(html::render_posts(topic, &render_options(args), &store).await?)
+        }
+        false => None,
+    };
+    let purger = rendered
+        .as_deref()
+        .filter(|_| !args.builtin_css)
+        .map(|posts| purge::Purger::new(purge_pages(topic, posts, args)));
+
     progress.set_stage("打包 CSS");
-    let css_text = bundle_css_for_args(args, &store).await?;
-    let css_rel = write_css_file(&out_dir, &args.assets_dir_name, &css_text)?;
+    let mut css_text = bundle_css_for_args(topic, args, &store, purger.as_ref()).await?;
+    if args.qr_permalinks {
+        css_text.push_str(qr::css(args.qr_always));
+    }
+    append_injected_css(&mut css_text, &args.inject_css)?;
+    let stamp = (!args.no_stamp).then(|| stamp::stamp(topic, args.reproducible));
+    if let Some(stamp) = &stamp {
+        css_text.insert_str(0, &stamp::css_comment(stamp));
+    }
 
-    progress.set_stage("渲染帖子");
-    let posts = html::render_posts(topic, &args.base_url, args.avatar_size, &store).await?;
+    let posts = match rendered {
+        Some(posts) => posts,
+        None => {
+            progress.set_stage("渲染帖子");
+            html::render_posts(topic, &render_options(args), &store).await?
+        }
+    };
+
+    progress.set_stage("检查字形覆盖");
+    let css_dir = out_dir.join(&args.assets_dir_name).join("css");
+    audit_glyphs(args, topic, &posts, &mut css_text, Some(&css_dir));
+    let css_rel = write_css_file(&out_dir, &args.assets_dir_name, &css_text)?;
+    let css_hash = args.css_integrity.then(|| html::css_sha384(&css_text));
 
     progress.set_stage("生成 HTML");
-    let html = if args.builtin_css {
-        html::build_html_minimal(topic, &posts, "", Some(&css_rel))
-    } else {
-        html::build_html(topic, &posts, "", Some(&css_rel))
+    let scripts = copy_injected_js(&out_dir, &args.assets_dir_name, &args.inject_js)?;
+    let page = page_options(topic, &posts, args, &scripts, stamp, fingerprint);
+    let pages = match args.paginate {
+        Some(page_size) => html::build_paginated(
+            topic,
+            &posts,
+            page_size as usize,
+            &css_rel,
+            css_hash.as_deref(),
+            &page,
+            args.builtin_css,
+        ),
+        None => {
+            let html = if args.builtin_css {
+                html::build_html_minimal(
+                    topic,
+                    &posts,
+                    "",
+                    Some(&css_rel),
+                    css_hash.as_deref(),
+                    &page,
+                )
+            } else {
+                html::build_html(
+                    topic,
+                    &posts,
+                    "",
+                    Some(&css_rel),
+                    css_hash.as_deref(),
+                    &page,
+                )
+            };
+            vec![(format!("topic-{}.html", topic.id), html)]
+        }
     };
-    strict::assert_strict_offline(&html, &css_text)?;
+    let htmls: Vec<&str> = pages.iter().map(|(_, html)| html.as_str()).collect();
+    check_strict(args, &htmls, &css_text, &progress)?;
 
     progress.set_stage("写入输出");
-    let html_path = out_dir.join(format!("topic-{}.html", topic.id));
-    std::fs::write(&html_path, html).with_context(|| format!("write {}", html_path.display()))?;
-
+    for (name, html) in &pages {
+        output::write(&out_dir.join(name), html)?;
+    }
+    write_feeds(args, topic, &posts)?;
+    write_meta_sidecar(args, topic, &store).await?;
+    let written = written_assets(args, &css_rel, &css_text, &scripts)?;
+    write_asset_manifest(&out_dir, topic.id, &store, written).await?;
     Ok(())
 }
 
 async fn render_single(
     topic: &topic::TopicJson,
     args: &Args,
+    fingerprint: String,
     fetcher: Fetcher,
     progress: std::sync::Arc<progress::Progress>,
 ) -> anyhow::Result<()> {
@@ -110,11 +542,10 @@ async fn render_single(
         .clone()
         .unwrap_or_else(|| PathBuf::from(format!("topic-{}.html", topic.id)));
 
-    if let Some(parent) = out_path.parent() {
-        if !parent.as_os_str().is_empty() {
-            std::fs::create_dir_all(parent)
-                .with_context(|| format!("create {}", parent.display()))?;
-        }
+    if let Some(parent) = out_path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        output::create_dir_all(parent)?;
     }
 
     let out_dir = out_path
@@ -123,54 +554,508 @@ async fn render_single(
         .unwrap_or_else(|| PathBuf::from("."));
     let store = AssetStore::new_single(out_dir, fetcher.clone(), Some(progress.clone()));
 
+    // `--purge-css` matches the bundle against the rendered posts, so they come first.
+    let rendered = match args.purge_css {
+        true => {
+            progress.set_stage("渲染帖子");
+            Some(html::render_posts(topic, &render_options(args), &store).await?)
+        }
+        false => None,
+    };
+    let purger = rendered
+        .as_deref()
+        .filter(|_| !args.builtin_css)
+        .map(|posts| purge::Purger::new(purge_pages(topic, posts, args)));
+
     progress.set_stage("打包 CSS");
-    let css_text = bundle_css_for_args(args, &store).await?;
-    progress.set_stage("渲染帖子");
-    let posts = html::render_posts(topic, &args.base_url, args.avatar_size, &store).await?;
+    let mut css_text = bundle_css_for_args(topic, args, &store, purger.as_ref()).await?;
+    if args.qr_permalinks {
+        css_text.push_str(qr::css(args.qr_always));
+    }
+    append_injected_css(&mut css_text, &args.inject_css)?;
+    let stamp = (!args.no_stamp).then(|| stamp::stamp(topic, args.reproducible));
+    if let Some(stamp) = &stamp {
+        css_text.insert_str(0, &stamp::css_comment(stamp));
+    }
+    let posts = match rendered {
+        Some(posts) => posts,
+        None => {
+            progress.set_stage("渲染帖子");
+            html::render_posts(topic, &render_options(args), &store).await?
+        }
+    };
+
+    progress.set_stage("检查字形覆盖");
+    audit_glyphs(args, topic, &posts, &mut css_text, None);
 
     progress.set_stage("生成 HTML");
+    let scripts = read_injected_js(&args.inject_js)?;
+    let page = page_options(topic, &posts, args, &scripts, stamp, fingerprint);
     let html = if args.builtin_css {
-        html::build_html_minimal(topic, &posts, &css_text, None)
+        html::build_html_minimal(topic, &posts, &css_text, None, None, &page)
     } else {
-        html::build_html(topic, &posts, &css_text, None)
+        html::build_html(topic, &posts, &css_text, None, None, &page)
     };
-    strict::assert_strict_offline(&html, &css_text)?;
+    check_strict(args, &[&html], &css_text, &progress)?;
 
     progress.set_stage("写入输出");
-    std::fs::write(&out_path, html).with_context(|| format!("write {}", out_path.display()))?;
+    output::write(&out_path, html)?;
+    write_feeds(args, topic, &posts)?;
+    write_meta_sidecar(args, topic, &store).await?;
     Ok(())
 }
 
-async fn bundle_css_for_args(args: &Args, store: &AssetStore) -> anyhow::Result<String> {
+async fn render_epub(
+    topic: &topic::TopicJson,
+    args: &Args,
+    fetcher: Fetcher,
+    progress: std::sync::Arc<progress::Progress>,
+) -> anyhow::Result<()> {
+    let out_path = primary_output_path(args, topic.id);
+    if let Some(parent) = out_path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        output::create_dir_all(parent)?;
+    }
+
+    // Assets are downloaded as for dir mode, into a scratch directory that is packed into the book and removed
+    // when dropped.
+    let staging = tempfile::Builder::new()
+        .prefix(&format!("discourse-topic-render-{}-", topic.id))
+        .tempdir()
+        .context("create EPUB staging directory")?;
+    let store = AssetStore::new_dir(
+        staging.path().to_path_buf(),
+        args.assets_dir_name.clone(),
+        fetcher.clone(),
+        Some(progress.clone()),
+    );
+
+    // `--purge-css` matches the bundle against the rendered posts, so they come first.
+    let rendered = match args.purge_css {
+        true => {
+            progress.set_stage("渲染帖子");
+            Some(html::render_posts(topic, &render_options(args), &store).await?)
+        }
+        false => None,
+    };
+    let purger = rendered
+        .as_deref()
+        .filter(|_| !args.builtin_css)
+        .map(|posts| purge::Purger::new(purge_pages(topic, posts, args)));
+
+    progress.set_stage("打包 CSS");
+    let mut css_text = bundle_css_for_args(topic, args, &store, purger.as_ref()).await?;
+    if args.qr_permalinks {
+        css_text.push_str(qr::css(args.qr_always));
+    }
+    append_injected_css(&mut css_text, &args.inject_css)?;
+    if !args.no_stamp {
+        css_text.insert_str(
+            0,
+            &stamp::css_comment(&stamp::stamp(topic, args.reproducible)),
+        );
+    }
+
+    let posts = match rendered {
+        Some(posts) => posts,
+        None => {
+            progress.set_stage("渲染帖子");
+            html::render_posts(topic, &render_options(args), &store).await?
+        }
+    };
+
+    progress.set_stage("检查字形覆盖");
+    let css_dir = staging.path().join(&args.assets_dir_name).join("css");
+    audit_glyphs(args, topic, &posts, &mut css_text, Some(&css_dir));
+    let css_rel = write_css_file(staging.path(), &args.assets_dir_name, &css_text)?;
+
+    progress.set_stage("生成 HTML");
+    let chapters: Vec<epub::Chapter> = posts
+        .chunks(args.epub_posts_per_chapter as usize)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let (first, last) = (chunk[0].post_number, chunk[chunk.len() - 1].post_number);
+            let title = match (first == last, chunk[0].username.is_empty()) {
+                (true, false) => format!("#{} {}", first, chunk[0].username),
+                (true, true) => format!("#{}", first),
+                (false, _) => format!("Posts {}–{}", first, last),
+            };
+            let html = html::build_epub_chapter(
+                topic,
+                chunk,
+                &title,
+                &css_rel,
+                args.builtin_css,
+                args.show_revision_count,
+            );
+            epub::Chapter {
+                file_name: html::page_file_name(topic.id, i + 1),
+                title,
+                content: epub::to_xhtml(&html),
+            }
+        })
+        .collect();
+    let pages: Vec<&str> = chapters.iter().map(|c| c.content.as_str()).collect();
+    check_strict(args, &pages, &css_text, &progress)?;
+
+    progress.set_stage("写入输出");
+    let cover = posts
+        .iter()
+        .find(|p| p.small_action.is_none())
+        .and_then(|p| html::first_local_image(&p.cooked_html))
+        .filter(|src| src.starts_with(&format!("{}/", args.assets_dir_name)));
+    let book = epub::Book {
+        identifier: urlres::forum_route(&args.base_url, &format!("t/{}", topic.id))
+            .map(|u| u.to_string())
+            .unwrap_or_else(|_| format!("urn:discourse-topic:{}", topic.id)),
+        title: &topic.title,
+        language: topic.language_tag().unwrap_or_else(|| "en".to_string()),
+        modified: render_time(topic, args.reproducible),
+        chapters,
+        assets_root: staging.path(),
+        assets_dir_name: &args.assets_dir_name,
+        cover,
+    };
+    epub::write(&book, &out_path)?;
+    write_feeds(args, topic, &posts)?;
+    write_meta_sidecar(args, topic, &store).await
+}
+
+/// When the output was made, as the book's `dcterms:modified` and the sidecar's `rendered_at`: the topic's last
+/// activity under `--reproducible`, otherwise now.
+fn render_time(topic: &topic::TopicJson, reproducible: bool) -> String {
+    let last_activity = topic
+        .last_activity()
+        .filter(|_| reproducible)
+        .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok());
+    match last_activity {
+        Some(t) => t
+            .with_timezone(&chrono::Utc)
+            .format("%Y-%m-%dT%H:%M:%SZ")
+            .to_string(),
+        None => stamp::utc_timestamp(std::time::SystemTime::now()),
+    }
+}
+
+async fn bundle_css_for_args(
+    topic: &topic::TopicJson,
+    args: &Args,
+    store: &AssetStore,
+    purger: Option<&purge::Purger>,
+) -> anyhow::Result<String> {
     if args.builtin_css {
-        if !args.css.is_empty() {
-            tracing::warn!("--builtin-css is set; ignoring --css");
+        if !args.css.is_empty() || !args.css_url.is_empty() {
+            tracing::warn!("--builtin-css is set; ignoring --css and --css-url");
+        }
+        if args.purge_css {
+            tracing::warn!("--builtin-css is set; --purge-css only applies to the site's CSS");
         }
         return Ok(builtin::BUILTIN_CSS.to_string());
     }
 
-    if !args.css.is_empty() {
-        return css::bundle_css(&args.base_url, &args.css, store).await;
+    let fonts_used = match args.prune_fonts {
+        true => {
+            let mut used: std::collections::HashSet<_> =
+                html::select_posts(topic, &render_options(args))
+                    .iter()
+                    .flat_map(|post| {
+                        css::font_families_in_html(post.cooked.as_deref().unwrap_or(""))
+                    })
+                    .collect();
+            // `--inject-css` is appended after the bundle is pruned, so its families count as used up front.
+            for path in &args.inject_css {
+                used.extend(css::font_families_in_css(&read_injected_css(path)?));
+            }
+            Some(used)
+        }
+        false => None,
+    };
+    let pruning = css::Pruning {
+        fonts: fonts_used.as_ref(),
+        rules: purger,
+        duplicates: !args.no_dedupe_css,
+    };
+
+    if !args.css.is_empty() || !args.css_url.is_empty() {
+        return css::bundle_css(
+            &args.base_url,
+            &args.css,
+            &args.css_url,
+            store,
+            args.max_css_bytes,
+            args.lenient_css,
+            pruning,
+        )
+        .await;
     }
 
     let origins = css::discover_css_origins_from_base_url(&args.base_url, store).await?;
     if origins.is_empty() {
         anyhow::bail!(
-            "no CSS discovered from {}; pass one or more --css <file> paths",
+            "no CSS discovered from {}; pass one or more --css <file> paths or --css-url <url>s",
             args.base_url
         );
     }
 
     tracing::info!(count = origins.len(), "auto-discovered css stylesheets");
-    css::bundle_css_origins(&args.base_url, &origins, store).await
+    css::bundle_css_origins(
+        &args.base_url,
+        &origins,
+        store,
+        args.max_css_bytes,
+        args.lenient_css,
+        pruning,
+    )
+    .await
+}
+
+/// The pages as they will be written, without their CSS, for `--purge-css` to match selectors against.
+fn purge_pages(topic: &topic::TopicJson, posts: &[html::RenderedPost], args: &Args) -> Vec<String> {
+    let page = page_options(topic, posts, args, &[], None, String::new());
+    match (args.mode, args.paginate) {
+        (Mode::Dir, Some(page_size)) => {
+            html::build_paginated(topic, posts, page_size as usize, "", None, &page, false)
+                .into_iter()
+                .map(|(_, html)| html)
+                .collect()
+        }
+        _ => vec![html::build_html(topic, posts, "", None, None, &page)],
+    }
+}
+
+/// Warn about scripts in the rendered text that no embedded font covers, and unless `--no-font-fallback-injection`
+/// append `@font-face` fallbacks to local system fonts for them.
+fn audit_glyphs(
+    args: &Args,
+    topic: &topic::TopicJson,
+    posts: &[html::RenderedPost],
+    css: &mut String,
+    css_dir: Option<&Path>,
+) {
+    let text = std::iter::once(topic.title.as_str()).chain(
+        posts
+            .iter()
+            .flat_map(|p| [p.username.as_str(), p.cooked_html.as_str()]),
+    );
+    let audit = glyphs::audit(css, &args.base_url, css_dir, &glyphs::text_chars(text));
+    for gap in &audit.gaps {
+        tracing::warn!(
+            script = gap.script.name,
+            characters = gap.missing,
+            sample = %gap.sample,
+            embedded_fonts = audit.fonts,
+            "no embedded font covers this script"
+        );
+    }
+    if audit.gaps.is_empty() || args.no_font_fallback_injection {
+        return;
+    }
+    let mut families = audit.families;
+    if args.builtin_css {
+        families.push(glyphs::BUILTIN_FALLBACK_FAMILY.to_string());
+    }
+    css.push_str(&glyphs::fallback_css(&audit.gaps, &families));
+}
+
+/// `topic-{id}.assets-manifest.json`: every asset the topic's pages use from the assets dir, where it came from and
+/// what it is. One per topic, as topics rendered into the same directory share the assets dir.
+async fn write_asset_manifest(
+    out_dir: &Path,
+    topic_id: u64,
+    store: &AssetStore,
+    written: Vec<assets::ManifestEntry>,
+) -> anyhow::Result<()> {
+    let mut manifest = store.manifest().await;
+    manifest.extend(written);
+    manifest.sort_by(|a, b| (&a.path, &a.source).cmp(&(&b.path, &b.source)));
+    let json = serde_json::to_vec_pretty(&manifest).context("serialize asset manifest")?;
+    output::write(
+        &out_dir.join(format!("topic-{}.assets-manifest.json", topic_id)),
+        json,
+    )
+}
+
+/// Manifest entries for the files dir mode writes to the assets dir besides the store's: the bundled stylesheet
+/// and the copied `--inject-js` scripts.
+fn written_assets(
+    args: &Args,
+    css_rel: &str,
+    css_text: &str,
+    scripts: &[html::PageScript],
+) -> anyhow::Result<Vec<assets::ManifestEntry>> {
+    let mut written = vec![assets::ManifestEntry::written(
+        args.base_url.to_string(),
+        css_rel.to_string(),
+        "text/css",
+        css_text.as_bytes(),
+        true,
+    )];
+    for (path, script) in args.inject_js.iter().zip(scripts) {
+        let html::PageScript::Src(rel) = script else {
+            continue;
+        };
+        let content =
+            std::fs::read(path).with_context(|| format!("read injected js {}", path.display()))?;
+        written.push(assets::ManifestEntry::written(
+            path.display().to_string(),
+            rel.clone(),
+            "text/javascript",
+            &content,
+            false,
+        ));
+    }
+    Ok(written)
+}
+
+/// `--meta-sidecar`: `topic-{id}.meta.json` in the output directory.
+async fn write_meta_sidecar(
+    args: &Args,
+    topic: &topic::TopicJson,
+    store: &AssetStore,
+) -> anyhow::Result<()> {
+    if !args.meta_sidecar {
+        return Ok(());
+    }
+    let meta = meta::TopicMeta::new(
+        topic,
+        args.mode,
+        render_time(topic, args.reproducible),
+        fetcher::user_agent(args.user_agent.as_deref(), args.ua_contact.as_deref()),
+        store.stats().await,
+    );
+    let json = serde_json::to_vec_pretty(&meta).context("serialize topic metadata")?;
+    output::write(
+        &output_dir(args, topic.id).join(meta::file_name(topic.id)),
+        json,
+    )
+}
+
+/// `--rss` and `--atom`, written next to the pages.
+fn write_feeds(
+    args: &Args,
+    topic: &topic::TopicJson,
+    posts: &[html::RenderedPost],
+) -> anyhow::Result<()> {
+    for (path, atom) in [(&args.rss, false), (&args.atom, true)] {
+        let Some(path) = path else { continue };
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            output::create_dir_all(parent)?;
+        }
+        let xml = match atom {
+            // Next to the feed, wherever the two end up being published.
+            true => {
+                let self_href = path
+                    .file_name()
+                    .map(|n| n.to_string_lossy())
+                    .unwrap_or_default();
+                feed::atom(topic, posts, &args.base_url, &self_href)
+            }
+            false => feed::rss(topic, posts, &args.base_url),
+        };
+        output::write(path, xml)?;
+    }
+    Ok(())
+}
+
+fn check_strict(
+    args: &Args,
+    pages: &[&str],
+    css: &str,
+    progress: &progress::Progress,
+) -> anyhow::Result<()> {
+    // The user chose to link these rather than download them.
+    let mut report = strict::check_strict_offline_linking(pages, css, &progress.linked_urls());
+    if args.no_js {
+        strict::check_no_js(pages, &mut report);
+    }
+    if args.allow_dangling_anchors {
+        report.allow_dangling_anchors();
+    }
+    for w in &report.warnings {
+        tracing::warn!("{}", w.detail);
+    }
+    if let Some(path) = &args.emit_strict_report {
+        let json = serde_json::to_vec_pretty(&report).context("serialize strict report")?;
+        std::fs::write(path, json).with_context(|| format!("write {}", path.display()))?;
+    }
+    report.into_result()
+}
+
+fn append_injected_css(css: &mut String, files: &[PathBuf]) -> anyhow::Result<()> {
+    for path in files {
+        css.push_str("\n/* injected */\n");
+        css.push_str(&read_injected_css(path)?);
+    }
+    Ok(())
+}
+
+fn read_injected_css(path: &Path) -> anyhow::Result<String> {
+    std::fs::read_to_string(path).with_context(|| format!("read injected css {}", path.display()))
+}
+
+/// Copy the `--inject-js` files to `{assets_dir_name}/js/`, under their own names. A file whose name an earlier
+/// one already took (from another directory) gets a content-hash suffix, so neither overwrites the other.
+fn copy_injected_js(
+    out_dir: &Path,
+    assets_dir_name: &str,
+    files: &[PathBuf],
+) -> anyhow::Result<Vec<html::PageScript>> {
+    let mut scripts = Vec::with_capacity(files.len());
+    let mut taken = std::collections::HashSet::new();
+    for path in files {
+        let file_name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .with_context(|| format!("injected js {} has no file name", path.display()))?;
+        let content =
+            std::fs::read(path).with_context(|| format!("read injected js {}", path.display()))?;
+        let file_name = if taken.insert(file_name.to_string()) {
+            file_name.to_string()
+        } else {
+            let hash = blake3::hash(&content).to_hex();
+            let stem = Path::new(file_name)
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or(file_name);
+            match Path::new(file_name).extension().and_then(|e| e.to_str()) {
+                Some(ext) => format!("{}-{}.{}", stem, &hash[..16], ext),
+                None => format!("{}-{}", stem, &hash[..16]),
+            }
+        };
+        let rel = format!("{}/js/{}", assets_dir_name, file_name);
+        let abs = out_dir.join(&rel);
+        if let Some(parent) = abs.parent() {
+            output::create_dir_all(parent)?;
+        }
+        output::write(&abs, content)?;
+        scripts.push(html::PageScript::Src(rel));
+    }
+    Ok(scripts)
+}
+
+fn read_injected_js(files: &[PathBuf]) -> anyhow::Result<Vec<html::PageScript>> {
+    files
+        .iter()
+        .map(|path| {
+            std::fs::read_to_string(path)
+                .map(html::PageScript::Inline)
+                .with_context(|| format!("read injected js {}", path.display()))
+        })
+        .collect()
 }
 
+/// Write the bundled stylesheet as `{assets_dir_name}/css/site-{hash}.css`. Named by its content, so a cached
+/// stylesheet is never paired with a re-rendered page.
 fn write_css_file(out_dir: &Path, assets_dir_name: &str, css: &str) -> anyhow::Result<String> {
-    let rel = format!("{}/css/site.css", assets_dir_name);
+    let hash = blake3::hash(css.as_bytes()).to_hex();
+    let rel = format!("{}/css/site-{}.css", assets_dir_name, &hash[..16]);
     let abs = out_dir.join(&rel);
     if let Some(parent) = abs.parent() {
-        std::fs::create_dir_all(parent).with_context(|| format!("create {}", parent.display()))?;
+        output::create_dir_all(parent)?;
     }
-    std::fs::write(&abs, css).with_context(|| format!("write {}", abs.display()))?;
+    output::write(&abs, css)?;
     Ok(rel)
 }
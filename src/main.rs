@@ -1,14 +1,61 @@
-use clap::Parser as _;
+use clap::{CommandFactory as _, FromArgMatches as _, Parser as _};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
-        )
-        .init();
-
-    let args = discourse_topic_render::CliArgs::parse();
+    // Subcommands are dispatched before the main parser, whose `--input`/`--base-url` are required.
+    let subcommand = std::env::args_os().nth(1);
+    if subcommand.as_deref().is_some_and(|a| a == "serve") {
+        init_tracing(false);
+        let args = discourse_topic_render::ServeArgs::parse_from(std::env::args_os().skip(1));
+        return discourse_topic_render::serve(args).await;
+    }
+    if subcommand.as_deref().is_some_and(|a| a == "verify") {
+        init_tracing(false);
+        let args = discourse_topic_render::VerifyArgs::parse_from(std::env::args_os().skip(1));
+        return discourse_topic_render::verify(args);
+    }
+
+    if subcommand.as_deref().is_some_and(|a| a == "gc") {
+        init_tracing(false);
+        let args = discourse_topic_render::GcArgs::parse_from(std::env::args_os().skip(1));
+        return discourse_topic_render::gc(args);
+    }
+
+    // Parsed in two steps so `--print-config` can tell which values were given and which are defaults.
+    let matches = discourse_topic_render::CliArgs::command().get_matches();
+    let args =
+        discourse_topic_render::CliArgs::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if matches!(args.progress, discourse_topic_render::ProgressMode::Json) {
+        // Log lines join the progress events on stderr, as events themselves.
+        use tracing_subscriber::prelude::*;
+        tracing_subscriber::registry()
+            .with(env_filter())
+            .with(discourse_topic_render::JsonLogLayer)
+            .init();
+    } else {
+        // `--summary json` keeps stdout for the summary.
+        init_tracing(matches!(
+            args.summary,
+            discourse_topic_render::SummaryFormat::Json
+        ));
+    }
+    if args.print_config {
+        let config = discourse_topic_render::effective_config(&matches);
+        println!("{}", serde_json::to_string_pretty(&config)?);
+        return Ok(());
+    }
     discourse_topic_render::run(args).await
 }
+
+fn env_filter() -> tracing_subscriber::EnvFilter {
+    tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"))
+}
+
+fn init_tracing(to_stderr: bool) {
+    let builder = tracing_subscriber::fmt().with_env_filter(env_filter());
+    match to_stderr {
+        true => builder.with_writer(std::io::stderr).init(),
+        false => builder.init(),
+    }
+}
@@ -0,0 +1,80 @@
+//! `--meta-sidecar`: `topic-{id}.meta.json` next to the output, so inventory tools can list archived topics without
+//! parsing the pages.
+
+use serde::Serialize;
+
+use crate::assets::AssetStats;
+use crate::cli::Mode;
+use crate::topic::TopicJson;
+
+/// What the sidecar records about a topic and its render.
+#[derive(Debug, Serialize)]
+pub struct TopicMeta<'a> {
+    pub id: u64,
+    pub title: &'a str,
+    pub slug: Option<&'a str>,
+    /// The category id.
+    pub category: Option<u64>,
+    pub tags: Vec<&'a str>,
+    /// As declared by the topic, falling back to the exported post count.
+    pub posts_count: u64,
+    pub views: Option<u64>,
+    /// The topic's, or its first exported post's.
+    pub created_at: Option<&'a str>,
+    pub last_posted_at: Option<&'a str>,
+    pub closed: bool,
+    pub archived: bool,
+    pub rendered_at: String,
+    pub tool_version: &'static str,
+    /// The `User-Agent` the assets were requested with.
+    pub user_agent: String,
+    /// `dir`, `single` or `epub`.
+    pub output_mode: &'static str,
+    pub asset_count: u64,
+    pub asset_bytes: u64,
+}
+
+impl<'a> TopicMeta<'a> {
+    pub fn new(
+        topic: &'a TopicJson,
+        mode: Mode,
+        rendered_at: String,
+        user_agent: String,
+        assets: AssetStats,
+    ) -> Self {
+        Self {
+            id: topic.id,
+            title: &topic.title,
+            slug: topic.slug.as_deref(),
+            category: topic.category_id,
+            tags: topic.tags.iter().map(|t| t.name()).collect(),
+            posts_count: topic.total_posts(),
+            views: topic.views,
+            created_at: topic.created_at.as_deref().or_else(|| {
+                topic
+                    .post_stream
+                    .posts
+                    .iter()
+                    .find(|p| p.post_number == 1)
+                    .and_then(|p| p.created_at.as_deref())
+            }),
+            last_posted_at: topic.last_posted_at.as_deref(),
+            closed: topic.closed,
+            archived: topic.archived,
+            rendered_at,
+            tool_version: env!("CARGO_PKG_VERSION"),
+            user_agent,
+            output_mode: match mode {
+                Mode::Dir => "dir",
+                Mode::Single => "single",
+                Mode::Epub => "epub",
+            },
+            asset_count: assets.count,
+            asset_bytes: assets.bytes,
+        }
+    }
+}
+
+pub fn file_name(topic_id: u64) -> String {
+    format!("topic-{}.meta.json", topic_id)
+}
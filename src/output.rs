@@ -0,0 +1,101 @@
+//! Writing the rendered output: an upfront writability probe, and permission/read-only failures surfaced as
+//! [`OutputNotWritable`] with a hint instead of a bare OS error.
+
+use std::path::{Path, PathBuf};
+
+/// The output location rejected a write: no permission, or a read-only file system.
+#[derive(Debug)]
+pub struct OutputNotWritable {
+    pub path: PathBuf,
+    pub source: std::io::Error,
+}
+
+impl std::fmt::Display for OutputNotWritable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self.source.kind() {
+            std::io::ErrorKind::ReadOnlyFilesystem => "the file system is read-only",
+            _ => "permission denied",
+        };
+        write!(
+            f,
+            "cannot write to {}: {}; pass --out with a writable location",
+            self.path.display(),
+            reason
+        )
+    }
+}
+
+impl std::error::Error for OutputNotWritable {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// `verb path` context for an I/O error, or [`OutputNotWritable`] when the location refuses writes.
+pub fn classify(verb: &str, path: &Path, e: std::io::Error) -> anyhow::Error {
+    match e.kind() {
+        std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::ReadOnlyFilesystem => {
+            OutputNotWritable {
+                path: path.to_path_buf(),
+                source: e,
+            }
+            .into()
+        }
+        _ => anyhow::Error::new(e).context(format!("{} {}", verb, path.display())),
+    }
+}
+
+pub fn create_dir_all(dir: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir).map_err(|e| classify("create", dir, e))
+}
+
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+    std::fs::write(path, contents).map_err(|e| classify("write", path, e))
+}
+
+/// Create `dir` and a scratch file in it, so an unwritable output fails before any download.
+pub fn probe_writable(dir: &Path) -> anyhow::Result<()> {
+    create_dir_all(dir)?;
+    let probe = dir.join(format!(".dtr-write-probe-{}", std::process::id()));
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .map_err(|e| classify("create", &probe, e))?;
+    std::fs::remove_file(&probe).map_err(|e| classify("remove", &probe, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permission_and_read_only_errors_get_the_out_hint() {
+        let path = Path::new("/srv/archive");
+        for (kind, reason) in [
+            (std::io::ErrorKind::PermissionDenied, "permission denied"),
+            (
+                std::io::ErrorKind::ReadOnlyFilesystem,
+                "the file system is read-only",
+            ),
+        ] {
+            let e = classify("write", path, std::io::Error::from(kind));
+            let typed = e.downcast_ref::<OutputNotWritable>().unwrap();
+            assert_eq!(typed.path, path);
+            assert_eq!(
+                e.to_string(),
+                format!(
+                    "cannot write to /srv/archive: {reason}; pass --out with a writable location"
+                )
+            );
+        }
+
+        let other = classify(
+            "write",
+            path,
+            std::io::Error::from(std::io::ErrorKind::StorageFull),
+        );
+        assert!(other.downcast_ref::<OutputNotWritable>().is_none());
+        assert_eq!(other.to_string(), "write /srv/archive");
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::atomic::{AtomicU64, Ordering};
@@ -6,9 +7,12 @@ use std::time::{Duration, Instant};
 use indicatif::{
     HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
 };
+use serde::Serialize;
+use serde_json::json;
 use url::Url;
 
 use crate::assets::AssetKind;
+use crate::report::{self, Cell, Tone};
 
 #[derive(Debug, Clone, Copy)]
 pub enum DownloadKind {
@@ -30,6 +34,78 @@ impl DownloadKind {
     }
 }
 
+/// Downloads listed as the largest in the summary.
+const LARGEST_DOWNLOADS: usize = 10;
+/// Posts listed as the slowest in the summary.
+const SLOWEST_POSTS: usize = 5;
+
+/// The end-of-run numbers, as `--summary json` prints them.
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub posts: u64,
+    /// Responses received, and requests that failed.
+    pub http_requests: u64,
+    pub http_failed: u64,
+    /// Attempts that ran out of time, retried or not, per kind and per host, leaving out those with none.
+    pub timeouts_by_kind: BTreeMap<&'static str, u64>,
+    pub timeouts_by_host: BTreeMap<String, u64>,
+    pub bytes: u64,
+    /// Bytes downloaded per kind (`html`, `css`, `avatar`, …), leaving out kinds with none.
+    pub bytes_by_kind: BTreeMap<&'static str, u64>,
+    pub asset_references: u64,
+    pub unique_assets: u64,
+    pub cache_hits: u64,
+    pub elapsed_secs: f64,
+    /// Size of the output on disk: the topic's pages and their assets in dir mode, the file otherwise.
+    pub output_bytes: Option<u64>,
+    pub largest_downloads: Vec<Download>,
+    pub slowest_posts: Vec<PostTiming>,
+    /// Assets left out because `--max-total-bytes` or `--fetch-deadline-secs` ran out.
+    pub over_budget: Vec<String>,
+    pub error: Option<String>,
+    /// `--skip-unchanged` found the output up to date, so nothing was rendered.
+    pub skipped: bool,
+}
+
+/// Where the time of one post went.
+#[derive(Debug, Clone, Serialize)]
+pub struct PostTiming {
+    pub post_number: u64,
+    pub total_secs: f64,
+    /// Finding its assets before the download pass.
+    pub scan_secs: f64,
+    /// Waiting for its assets, in the download pass and while rewriting.
+    pub download_secs: f64,
+    /// Rewriting its HTML, not counting the downloads.
+    pub rewrite_secs: f64,
+    /// `downloads` or `dom`, whichever took longer.
+    pub dominant: &'static str,
+}
+
+impl PostTiming {
+    pub fn new(post_number: u64, scan: Duration, download: Duration, rewrite: Duration) -> Self {
+        let dom = scan + rewrite;
+        Self {
+            post_number,
+            total_secs: (dom + download).as_secs_f64(),
+            scan_secs: scan.as_secs_f64(),
+            download_secs: download.as_secs_f64(),
+            rewrite_secs: rewrite.as_secs_f64(),
+            dominant: match download > dom {
+                true => "downloads",
+                false => "dom",
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Download {
+    pub url: String,
+    pub kind: &'static str,
+    pub bytes: u64,
+}
+
 #[derive(Debug, Default)]
 struct DownloadCounters {
     html: AtomicU64,
@@ -42,26 +118,19 @@ struct DownloadCounters {
 
 impl DownloadCounters {
     fn inc(&self, kind: DownloadKind) {
-        match kind {
-            DownloadKind::Html => {
-                self.html.fetch_add(1, Ordering::Relaxed);
-            }
-            DownloadKind::Css => {
-                self.css.fetch_add(1, Ordering::Relaxed);
-            }
-            DownloadKind::Asset(AssetKind::Avatar) => {
-                self.avatar.fetch_add(1, Ordering::Relaxed);
-            }
-            DownloadKind::Asset(AssetKind::Image) => {
-                self.image.fetch_add(1, Ordering::Relaxed);
-            }
-            DownloadKind::Asset(AssetKind::Font) => {
-                self.font.fetch_add(1, Ordering::Relaxed);
-            }
-            DownloadKind::Asset(AssetKind::Other) => {
-                self.other.fetch_add(1, Ordering::Relaxed);
-            }
-        }
+        self.add(kind, 1);
+    }
+
+    fn add(&self, kind: DownloadKind, n: u64) {
+        let counter = match kind {
+            DownloadKind::Html => &self.html,
+            DownloadKind::Css => &self.css,
+            DownloadKind::Asset(AssetKind::Avatar) => &self.avatar,
+            DownloadKind::Asset(AssetKind::Image) => &self.image,
+            DownloadKind::Asset(AssetKind::Font) => &self.font,
+            DownloadKind::Asset(AssetKind::Other) => &self.other,
+        };
+        counter.fetch_add(n, Ordering::Relaxed);
     }
 
     fn snapshot(&self) -> (u64, u64, u64, u64, u64, u64) {
@@ -78,6 +147,8 @@ impl DownloadCounters {
 
 pub struct Progress {
     enabled: bool,
+    /// `--progress json`: each change is written to stderr as a JSON line instead of drawn.
+    json_lines: bool,
     start: Instant,
     max_concurrency: usize,
 
@@ -94,39 +165,47 @@ pub struct Progress {
     asset_requests_total: AtomicU64,
     asset_requests_unique: AtomicU64,
     asset_requests_cache_hit: AtomicU64,
+    /// Page and stylesheet fetches answered by an earlier download of the same URL.
+    text_cache_hits: AtomicU64,
 
     http_in_flight: AtomicU64,
     http_done: AtomicU64,
     http_bytes: AtomicU64,
 
+    avatar_fallbacks: AtomicU64,
+    noise_stripped: AtomicU64,
+    /// `--prune-fonts`: `@font-face` rules dropped and the font URLs they would have downloaded.
+    fonts_pruned: AtomicU64,
+    font_urls_pruned: AtomicU64,
+    /// `--purge-css`: style rules dropped, and the stylesheet bytes before and after.
+    css_rules_purged: AtomicU64,
+    css_bytes_before_purge: AtomicU64,
+    css_bytes_after_purge: AtomicU64,
+    /// Most response-body bytes held in memory at any one time.
+    buffered_peak: AtomicU64,
+    /// Assets refused by `--max-asset-size`, with their size when known.
+    oversized: Mutex<Vec<(Url, Option<u64>)>>,
+    /// Non-image assets skipped by `--skip-large-assets`, with their `Content-Length`.
+    large_linked: Mutex<Vec<(Url, u64)>>,
+    /// Assets not downloaded because `--max-total-bytes` or `--fetch-deadline-secs` ran out.
+    over_budget: Mutex<Vec<Url>>,
+    /// The [`LARGEST_DOWNLOADS`] largest responses, largest first.
+    largest: Mutex<Vec<Download>>,
+    /// The [`SLOWEST_POSTS`] slowest posts, slowest first.
+    slowest_posts: Mutex<Vec<PostTiming>>,
+
     done_by_kind: DownloadCounters,
+    failed_by_kind: DownloadCounters,
+    timeouts_by_kind: DownloadCounters,
+    timeouts_by_host: Mutex<BTreeMap<String, u64>>,
+    bytes_by_kind: DownloadCounters,
     last_http_label: Mutex<String>,
 }
 
 impl Progress {
     pub fn new(enabled: bool, max_concurrency: usize) -> Arc<Self> {
-        let start = Instant::now();
-
         if !enabled {
-            return Arc::new(Self {
-                enabled: false,
-                start,
-                max_concurrency: max_concurrency.max(1),
-                mp: None,
-                stage: ProgressBar::hidden(),
-                posts: ProgressBar::hidden(),
-                downloads: ProgressBar::hidden(),
-                posts_total: AtomicU64::new(0),
-                posts_done: AtomicU64::new(0),
-                asset_requests_total: AtomicU64::new(0),
-                asset_requests_unique: AtomicU64::new(0),
-                asset_requests_cache_hit: AtomicU64::new(0),
-                http_in_flight: AtomicU64::new(0),
-                http_done: AtomicU64::new(0),
-                http_bytes: AtomicU64::new(0),
-                done_by_kind: DownloadCounters::default(),
-                last_http_label: Mutex::new(String::new()),
-            });
+            return Arc::new(Self::hidden(max_concurrency));
         }
 
         let mp = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
@@ -155,26 +234,77 @@ impl Progress {
 
         Arc::new(Self {
             enabled: true,
-            start,
-            max_concurrency: max_concurrency.max(1),
             mp: Some(mp),
             stage,
             posts,
             downloads,
+            ..Self::hidden(max_concurrency)
+        })
+    }
+
+    /// `--progress json`: nothing is drawn; every change is written to stderr as one JSON object per line.
+    pub fn json_lines(max_concurrency: usize) -> Arc<Self> {
+        Arc::new(Self {
+            json_lines: true,
+            ..Self::hidden(max_concurrency)
+        })
+    }
+
+    fn hidden(max_concurrency: usize) -> Self {
+        Self {
+            enabled: false,
+            json_lines: false,
+            start: Instant::now(),
+            max_concurrency: max_concurrency.max(1),
+            mp: None,
+            stage: ProgressBar::hidden(),
+            posts: ProgressBar::hidden(),
+            downloads: ProgressBar::hidden(),
             posts_total: AtomicU64::new(0),
             posts_done: AtomicU64::new(0),
             asset_requests_total: AtomicU64::new(0),
             asset_requests_unique: AtomicU64::new(0),
             asset_requests_cache_hit: AtomicU64::new(0),
+            text_cache_hits: AtomicU64::new(0),
             http_in_flight: AtomicU64::new(0),
             http_done: AtomicU64::new(0),
             http_bytes: AtomicU64::new(0),
+            avatar_fallbacks: AtomicU64::new(0),
+            noise_stripped: AtomicU64::new(0),
+            fonts_pruned: AtomicU64::new(0),
+            font_urls_pruned: AtomicU64::new(0),
+            css_rules_purged: AtomicU64::new(0),
+            css_bytes_before_purge: AtomicU64::new(0),
+            css_bytes_after_purge: AtomicU64::new(0),
+            buffered_peak: AtomicU64::new(0),
+            oversized: Mutex::new(Vec::new()),
+            large_linked: Mutex::new(Vec::new()),
+            over_budget: Mutex::new(Vec::new()),
+            largest: Mutex::new(Vec::new()),
+            slowest_posts: Mutex::new(Vec::new()),
             done_by_kind: DownloadCounters::default(),
+            failed_by_kind: DownloadCounters::default(),
+            timeouts_by_kind: DownloadCounters::default(),
+            timeouts_by_host: Mutex::new(BTreeMap::new()),
+            bytes_by_kind: DownloadCounters::default(),
             last_http_label: Mutex::new(String::new()),
-        })
+        }
+    }
+
+    /// One `--progress json` event. Written straight to stderr, which is unbuffered, so a reader sees it at once.
+    /// Write the event `event` builds, under `--progress json`; otherwise it is never built.
+    fn emit(&self, event: impl FnOnce() -> serde_json::Value) {
+        if self.json_lines {
+            write_event(&event());
+        }
     }
 
     pub fn set_stage(&self, msg: impl Into<String>) {
+        if self.json_lines {
+            let msg = msg.into();
+            self.emit(|| json!({"event": "stage", "msg": msg}));
+            return;
+        }
         if !self.enabled {
             return;
         }
@@ -183,13 +313,32 @@ impl Progress {
 
     pub fn set_posts_total(&self, total: usize) {
         self.posts_total.store(total as u64, Ordering::Relaxed);
+        self.emit(|| json!({"event": "posts_total", "total": total}));
         if self.enabled {
             self.posts.set_length(total as u64);
         }
     }
 
+    pub fn post_timed(&self, timing: PostTiming) {
+        if let Ok(mut slowest) = self.slowest_posts.lock() {
+            let at = slowest.partition_point(|t| t.total_secs >= timing.total_secs);
+            if at < SLOWEST_POSTS {
+                slowest.insert(at, timing);
+                slowest.truncate(SLOWEST_POSTS);
+            }
+        }
+    }
+
     pub fn post_done(&self, post_number: u64) {
-        self.posts_done.fetch_add(1, Ordering::Relaxed);
+        let done = self.posts_done.fetch_add(1, Ordering::Relaxed) + 1;
+        self.emit(|| {
+            json!({
+                "event": "post_done",
+                "post": post_number,
+                "done": done,
+                "total": self.posts_total.load(Ordering::Relaxed),
+            })
+        });
         if self.enabled {
             self.posts.inc(1);
             self.posts.set_message(format!("post #{post_number}"));
@@ -205,7 +354,12 @@ impl Progress {
                 .fetch_add(1, Ordering::Relaxed);
         }
 
-        if self.enabled && (self.asset_requests_total.load(Ordering::Relaxed) % 8) == 0 {
+        if self.enabled
+            && self
+                .asset_requests_total
+                .load(Ordering::Relaxed)
+                .is_multiple_of(8)
+        {
             // Keep the UI reasonably fresh without over-allocating.
             self.refresh_downloads();
         }
@@ -213,6 +367,7 @@ impl Progress {
 
     pub fn http_start(&self, kind: DownloadKind, url: &Url) {
         self.http_in_flight.fetch_add(1, Ordering::Relaxed);
+        self.emit(|| json!({"event": "http_start", "url": url.as_str(), "kind": kind.label()}));
         if self.enabled {
             if let Ok(mut last) = self.last_http_label.lock() {
                 *last = format!("GET {} ({})", url, kind.label());
@@ -223,6 +378,15 @@ impl Progress {
     }
 
     pub fn http_throttled(&self, kind: DownloadKind, url: &Url, status: u16, wait: Duration) {
+        self.emit(|| {
+            json!({
+                "event": "http_throttled",
+                "url": url.as_str(),
+                "kind": kind.label(),
+                "status": status,
+                "wait_ms": wait.as_millis() as u64,
+            })
+        });
         if !self.enabled {
             return;
         }
@@ -238,12 +402,62 @@ impl Progress {
         self.refresh_downloads();
     }
 
-    pub fn http_ok(&self, kind: DownloadKind, url: &Url, bytes: usize) {
+    /// Requests to `host` are held back until its throttling circuit closes.
+    pub fn circuit_open(&self, host: &str, remaining: Duration) {
+        self.emit(|| {
+            json!({
+                "event": "circuit_open",
+                "host": host,
+                "remaining_secs": remaining.as_secs_f64().ceil() as u64,
+            })
+        });
+        if !self.enabled {
+            return;
+        }
+        if let Ok(mut last) = self.last_http_label.lock() {
+            *last = format!(
+                "circuit open for host {} ({}s remaining)",
+                host,
+                remaining.as_secs_f64().ceil() as u64
+            );
+        }
+        self.refresh_downloads();
+    }
+
+    /// Part of a response body arrived; counted as it streams so large downloads show progress.
+    pub fn http_chunk(&self, kind: DownloadKind, bytes: usize) {
+        self.http_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.bytes_by_kind.add(kind, bytes as u64);
+        if self.enabled {
+            self.refresh_downloads();
+        }
+    }
+
+    /// A download finished; its bytes were already counted by `http_chunk`.
+    pub fn http_ok(&self, kind: DownloadKind, url: &Url, bytes: u64) {
         self.http_in_flight.fetch_sub(1, Ordering::Relaxed);
         self.http_done.fetch_add(1, Ordering::Relaxed);
-        self.http_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
         self.done_by_kind.inc(kind);
+        if bytes > 0
+            && let Ok(mut largest) = self.largest.lock()
+        {
+            let at = largest.partition_point(|d| d.bytes >= bytes);
+            if at < LARGEST_DOWNLOADS {
+                largest.insert(
+                    at,
+                    Download {
+                        url: url.to_string(),
+                        kind: kind.label(),
+                        bytes,
+                    },
+                );
+                largest.truncate(LARGEST_DOWNLOADS);
+            }
+        }
 
+        self.emit(
+            || json!({"event": "http_ok", "url": url.as_str(), "bytes": bytes, "kind": kind.label()}),
+        );
         if self.enabled {
             if let Ok(mut last) = self.last_http_label.lock() {
                 *last = format!("GET {} ({}) ok {}B", url, kind.label(), bytes);
@@ -254,6 +468,8 @@ impl Progress {
 
     pub fn http_err(&self, kind: DownloadKind, url: &Url) {
         self.http_in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.failed_by_kind.inc(kind);
+        self.emit(|| json!({"event": "http_err", "url": url.as_str(), "kind": kind.label()}));
         if self.enabled {
             if let Ok(mut last) = self.last_http_label.lock() {
                 *last = format!("GET {} ({}) failed", url, kind.label());
@@ -262,7 +478,99 @@ impl Progress {
         }
     }
 
+    /// An attempt at `url` ran out of time. Counted on its own, whether it is retried or the download then fails.
+    pub fn http_timeout(&self, kind: DownloadKind, url: &Url) {
+        self.timeouts_by_kind.inc(kind);
+        if let Ok(mut hosts) = self.timeouts_by_host.lock() {
+            *hosts
+                .entry(url.host_str().unwrap_or("").to_string())
+                .or_default() += 1;
+        }
+        self.emit(|| json!({"event": "http_timeout", "url": url.as_str(), "kind": kind.label()}));
+    }
+
+    /// A page or stylesheet fetch answered by an earlier download of the same URL.
+    pub fn text_cache_hit(&self) {
+        self.text_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An avatar could not be downloaded (or was not an image) and was replaced by a letter avatar.
+    pub fn avatar_fallback(&self) {
+        self.avatar_fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Elements removed by `--strip-reactions` / `--strip-selector`.
+    pub fn noise_stripped(&self, n: usize) {
+        self.noise_stripped.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// `@font-face` rules dropped by `--prune-fonts`, and the font downloads skipped with them.
+    pub fn fonts_pruned(&self, rules: usize, urls: usize) {
+        self.fonts_pruned.fetch_add(rules as u64, Ordering::Relaxed);
+        self.font_urls_pruned
+            .fetch_add(urls as u64, Ordering::Relaxed);
+    }
+
+    pub fn css_purged(&self, stats: crate::purge::PurgeStats) {
+        self.css_rules_purged
+            .fetch_add(stats.rules_dropped as u64, Ordering::Relaxed);
+        self.css_bytes_before_purge
+            .fetch_add(stats.bytes_before as u64, Ordering::Relaxed);
+        self.css_bytes_after_purge
+            .fetch_add(stats.bytes_after as u64, Ordering::Relaxed);
+    }
+
+    /// Response bodies currently buffered in memory, in bytes.
+    pub fn buffered(&self, bytes: u64) {
+        self.buffered_peak.fetch_max(bytes, Ordering::Relaxed);
+    }
+
+    pub fn asset_oversized(&self, url: &Url, bytes: Option<u64>) {
+        if let Ok(mut oversized) = self.oversized.lock() {
+            oversized.push((url.clone(), bytes));
+        }
+    }
+
+    pub fn large_asset_linked(&self, url: &Url, bytes: u64) {
+        if let Ok(mut linked) = self.large_linked.lock() {
+            linked.push((url.clone(), bytes));
+        }
+    }
+
+    pub fn asset_over_budget(&self, url: &Url) {
+        if let Ok(mut over_budget) = self.over_budget.lock() {
+            over_budget.push(url.clone());
+        }
+    }
+
+    /// Assets left as links to the original: over `--max-asset-size`, non-images skipped by
+    /// `--skip-large-assets`, or left out by the download budget.
+    pub fn linked_urls(&self) -> Vec<String> {
+        let oversized = self.oversized.lock().map(|o| o.clone()).unwrap_or_default();
+        let large = self
+            .large_linked
+            .lock()
+            .map(|l| l.clone())
+            .unwrap_or_default();
+        oversized
+            .into_iter()
+            .map(|(url, _)| url.to_string())
+            .chain(large.into_iter().map(|(url, _)| url.to_string()))
+            .chain(self.over_budget_urls())
+            .collect()
+    }
+
+    fn over_budget_urls(&self) -> Vec<String> {
+        self.over_budget
+            .lock()
+            .map(|o| o.iter().map(Url::to_string).collect())
+            .unwrap_or_default()
+    }
+
     pub fn finish(&self) {
+        self.emit(
+            || json!({"event": "finish", "elapsed_secs": self.start.elapsed().as_secs_f64()}),
+        );
         if !self.enabled {
             return;
         }
@@ -276,6 +584,267 @@ impl Progress {
         }
     }
 
+    /// The counters so far; `output_bytes` and `error` are left for the caller.
+    pub fn totals(&self) -> Summary {
+        let (b_html, b_css, b_avatar, b_image, b_font, b_other) = self.bytes_by_kind.snapshot();
+        let (f_html, f_css, f_avatar, f_image, f_font, f_other) = self.failed_by_kind.snapshot();
+        let (t_html, t_css, t_avatar, t_image, t_font, t_other) = self.timeouts_by_kind.snapshot();
+        let bytes_by_kind = [
+            ("html", b_html),
+            ("css", b_css),
+            ("avatar", b_avatar),
+            ("image", b_image),
+            ("font", b_font),
+            ("other", b_other),
+        ]
+        .into_iter()
+        .filter(|&(_, bytes)| bytes > 0)
+        .collect();
+        let timeouts_by_kind = [
+            ("html", t_html),
+            ("css", t_css),
+            ("avatar", t_avatar),
+            ("image", t_image),
+            ("font", t_font),
+            ("other", t_other),
+        ]
+        .into_iter()
+        .filter(|&(_, timeouts)| timeouts > 0)
+        .collect();
+        let http_failed = f_html + f_css + f_avatar + f_image + f_font + f_other;
+        Summary {
+            posts: self.posts_done.load(Ordering::Relaxed),
+            http_requests: self.http_done.load(Ordering::Relaxed) + http_failed,
+            http_failed,
+            timeouts_by_kind,
+            timeouts_by_host: self
+                .timeouts_by_host
+                .lock()
+                .map(|h| h.clone())
+                .unwrap_or_default(),
+            bytes: self.http_bytes.load(Ordering::Relaxed),
+            bytes_by_kind,
+            asset_references: self.asset_requests_total.load(Ordering::Relaxed),
+            unique_assets: self.asset_requests_unique.load(Ordering::Relaxed),
+            cache_hits: self.asset_requests_cache_hit.load(Ordering::Relaxed),
+            elapsed_secs: self.start.elapsed().as_secs_f64(),
+            output_bytes: None,
+            largest_downloads: self.largest.lock().map(|l| l.clone()).unwrap_or_default(),
+            slowest_posts: self
+                .slowest_posts
+                .lock()
+                .map(|s| s.clone())
+                .unwrap_or_default(),
+            over_budget: self.over_budget_urls(),
+            error: None,
+            skipped: false,
+        }
+    }
+
+    /// End-of-run summary: downloads and failures per kind, then totals.
+    pub fn summary(&self) -> report::Table {
+        let totals = self.totals();
+        let (html, css, avatar, image, font, other) = self.done_by_kind.snapshot();
+        let (f_html, f_css, f_avatar, f_image, f_font, f_other) = self.failed_by_kind.snapshot();
+
+        let (b_html, b_css, b_avatar, b_image, b_font, b_other) = self.bytes_by_kind.snapshot();
+
+        let mut table = report::Table::new(["kind", "downloaded", "failed", "timed out", "bytes"]);
+        for (label, done, failed, bytes) in [
+            ("html", html, f_html, b_html),
+            ("css", css, f_css, b_css),
+            ("avatar", avatar, f_avatar, b_avatar),
+            ("image", image, f_image, b_image),
+            ("font", font, f_font, b_font),
+            ("other", other, f_other, b_other),
+        ] {
+            let timeouts = totals.timeouts_by_kind.get(label).copied().unwrap_or(0);
+            if done == 0 && failed == 0 && timeouts == 0 {
+                continue;
+            }
+            table.row(vec![
+                Cell::text(label),
+                Cell::count(done),
+                Cell::failures(failed),
+                Cell::failures(timeouts),
+                Cell::bytes(bytes),
+            ]);
+        }
+        table.row(vec![
+            Cell::text("total"),
+            Cell::count(self.http_done.load(Ordering::Relaxed)),
+            Cell::failures(totals.http_failed),
+            Cell::failures(totals.timeouts_by_kind.values().sum()),
+            Cell::bytes(self.http_bytes.load(Ordering::Relaxed)),
+        ]);
+
+        table.note(
+            Tone::Plain,
+            format!(
+                "{} posts, {} asset references ({} unique, {} cache hits), {} HTTP requests in {}",
+                report::thousands(totals.posts),
+                report::thousands(totals.asset_references),
+                report::thousands(totals.unique_assets),
+                report::thousands(totals.cache_hits),
+                report::thousands(totals.http_requests),
+                HumanDuration(self.start.elapsed()),
+            ),
+        );
+        if !totals.timeouts_by_host.is_empty() {
+            let hosts: Vec<String> = totals
+                .timeouts_by_host
+                .iter()
+                .map(|(host, n)| format!("{} {}", host, report::thousands(*n)))
+                .collect();
+            table.note(
+                Tone::Warning,
+                format!("timed-out attempts per host: {}", hosts.join(", ")),
+            );
+        }
+        let text_cache_hits = self.text_cache_hits.load(Ordering::Relaxed);
+        if text_cache_hits > 0 {
+            table.note(
+                Tone::Plain,
+                format!(
+                    "{} repeated page/stylesheet fetches served from the cache",
+                    report::thousands(text_cache_hits)
+                ),
+            );
+        }
+        let avatar_fallbacks = self.avatar_fallbacks.load(Ordering::Relaxed);
+        if avatar_fallbacks > 0 {
+            table.note(
+                Tone::Warning,
+                format!(
+                    "{} avatars replaced by letter avatars",
+                    report::thousands(avatar_fallbacks)
+                ),
+            );
+        }
+        let noise_stripped = self.noise_stripped.load(Ordering::Relaxed);
+        if noise_stripped > 0 {
+            table.note(
+                Tone::Plain,
+                format!(
+                    "removed {} elements matching --strip-reactions/--strip-selector",
+                    report::thousands(noise_stripped)
+                ),
+            );
+        }
+        let fonts_pruned = self.fonts_pruned.load(Ordering::Relaxed);
+        if fonts_pruned > 0 {
+            table.note(
+                Tone::Plain,
+                format!(
+                    "pruned {} unused @font-face rules ({} font downloads skipped)",
+                    report::thousands(fonts_pruned),
+                    report::thousands(self.font_urls_pruned.load(Ordering::Relaxed))
+                ),
+            );
+        }
+        let css_bytes_before_purge = self.css_bytes_before_purge.load(Ordering::Relaxed);
+        if css_bytes_before_purge > 0 {
+            table.note(
+                Tone::Plain,
+                format!(
+                    "--purge-css dropped {} unused rules: stylesheets {} → {}",
+                    report::thousands(self.css_rules_purged.load(Ordering::Relaxed)),
+                    report::human_bytes(css_bytes_before_purge),
+                    report::human_bytes(self.css_bytes_after_purge.load(Ordering::Relaxed))
+                ),
+            );
+        }
+        let buffered_peak = self.buffered_peak.load(Ordering::Relaxed);
+        if buffered_peak > 0 {
+            table.note(
+                Tone::Plain,
+                format!(
+                    "at most {} of response bodies buffered in memory at once",
+                    report::human_bytes(buffered_peak)
+                ),
+            );
+        }
+        let oversized = self.oversized.lock().map(|o| o.clone()).unwrap_or_default();
+        if !oversized.is_empty() {
+            table.note(
+                Tone::Warning,
+                format!(
+                    "{} assets over --max-asset-size linked instead of downloaded:",
+                    report::thousands(oversized.len() as u64)
+                ),
+            );
+            for (url, bytes) in oversized {
+                let size = bytes.map_or_else(|| "size unknown".to_string(), report::human_bytes);
+                table.note(Tone::Warning, format!("  {} ({})", url, size));
+            }
+        }
+        let large_linked = self
+            .large_linked
+            .lock()
+            .map(|l| l.clone())
+            .unwrap_or_default();
+        if !large_linked.is_empty() {
+            table.note(
+                Tone::Warning,
+                format!(
+                    "{} non-image assets over --preflight-above-bytes skipped and linked to the original:",
+                    report::thousands(large_linked.len() as u64)
+                ),
+            );
+            for (url, bytes) in large_linked {
+                table.note(
+                    Tone::Warning,
+                    format!("  {} ({})", url, report::human_bytes(bytes)),
+                );
+            }
+        }
+        if !totals.over_budget.is_empty() {
+            table.note(
+                Tone::Warning,
+                format!(
+                    "{} assets not downloaded once the download budget ran out:",
+                    report::thousands(totals.over_budget.len() as u64)
+                ),
+            );
+            for url in &totals.over_budget {
+                table.note(Tone::Warning, format!("  {url}"));
+            }
+        }
+        if !totals.largest_downloads.is_empty() {
+            table.note(Tone::Plain, "largest downloads:");
+            for download in totals.largest_downloads {
+                table.note(
+                    Tone::Plain,
+                    format!(
+                        "  {} {} ({})",
+                        report::human_bytes(download.bytes),
+                        download.url,
+                        download.kind
+                    ),
+                );
+            }
+        }
+        // Posts that render in well under a second are not worth listing.
+        let slow: Vec<_> = totals
+            .slowest_posts
+            .iter()
+            .filter(|t| t.total_secs >= 1.0)
+            .collect();
+        if !slow.is_empty() {
+            table.note(Tone::Plain, "slowest posts:");
+            for t in slow {
+                table.note(
+                    Tone::Plain,
+                    format!(
+                        "  #{} {:.1}s: downloads {:.1}s, scan {:.1}s, rewrite {:.1}s",
+                        t.post_number, t.total_secs, t.download_secs, t.scan_secs, t.rewrite_secs
+                    ),
+                );
+            }
+        }
+        table
+    }
+
     fn refresh_downloads(&self) {
         if !self.enabled {
             return;
@@ -307,3 +876,72 @@ impl Progress {
         ));
     }
 }
+
+/// One `--progress json` event as a line on stderr.
+fn write_event(event: &serde_json::Value) {
+    use std::io::Write as _;
+    let _ = writeln!(std::io::stderr().lock(), "{event}");
+}
+
+/// Under `--progress json`, log lines as `{"event":"log","level":...,"target":...,"msg":...,"fields":{...}}` among
+/// the other events, so stderr holds nothing else.
+pub struct JsonLogLayer;
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for JsonLogLayer {
+    fn on_event(
+        &self,
+        event: &tracing::Event<'_>,
+        _ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let mut fields = LogFields::default();
+        event.record(&mut fields);
+        let meta = event.metadata();
+        write_event(&json!({
+            "event": "log",
+            "level": meta.level().as_str().to_ascii_lowercase(),
+            "target": meta.target(),
+            "msg": fields.message,
+            "fields": fields.fields,
+        }));
+    }
+}
+
+/// The message and the other fields of a log event.
+#[derive(Default)]
+struct LogFields {
+    message: String,
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl LogFields {
+    fn record(&mut self, field: &tracing::field::Field, value: serde_json::Value) {
+        match (field.name(), value) {
+            ("message", serde_json::Value::String(message)) => self.message = message,
+            (name, value) => {
+                self.fields.insert(name.to_string(), value);
+            }
+        }
+    }
+}
+
+impl tracing::field::Visit for LogFields {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.record(field, format!("{value:?}").into());
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.record(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.record(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.record(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.record(field, value.into());
+    }
+}
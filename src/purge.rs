@@ -0,0 +1,371 @@
+//! `--purge-css`: drop the style rules of the bundle whose selectors match nothing in the rendered pages.
+//!
+//! Conservative by construction: each selector is widened before matching — pseudo-classes, pseudo-elements and
+//! attribute conditions are removed, so state the page only reaches at runtime (`:hover`, `[open]`, `::before`)
+//! never drops a rule. At-rules other than `@media`, `@supports`, `@layer` and `@container` (`@font-face`,
+//! `@keyframes`, `@page`, …) are kept whole, and anything the parser can't make sense of is kept too.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use cssparser::{ParseError, Parser, ParserInput, Token};
+use kuchiki::iter::NodeIterator as _;
+use kuchiki::traits::TendrilSink as _;
+
+/// The rendered pages a bundle is purged against. Holds their HTML rather than parsed trees, so a render stays
+/// `Send`; the pages are parsed only when some selector passes the cheap name checks.
+pub struct Purger {
+    pages: Vec<String>,
+    tags: HashSet<String>,
+    classes: HashSet<String>,
+    ids: HashSet<String>,
+    /// Widened selector → whether it matches some page.
+    matched: Mutex<HashMap<String, bool>>,
+}
+
+/// Rules and bytes before and after purging.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PurgeStats {
+    pub rules_dropped: usize,
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+}
+
+/// One selector of a rule's selector list, widened to what a static document can show.
+struct Widened {
+    selector: String,
+    tags: Vec<String>,
+    classes: Vec<String>,
+    ids: Vec<String>,
+}
+
+/// At-rules whose block holds style rules that are purged like top-level ones.
+pub(crate) const GROUPING_AT_RULES: &[&str] = &[
+    "media",
+    "supports",
+    "layer",
+    "container",
+    "document",
+    "-moz-document",
+];
+
+impl Purger {
+    pub fn new(pages: Vec<String>) -> Self {
+        let (mut tags, mut classes, mut ids) = (HashSet::new(), HashSet::new(), HashSet::new());
+        for page in &pages {
+            let document = kuchiki::parse_html().one(page.as_str());
+            for element in document.inclusive_descendants().elements() {
+                tags.insert(element.name.local.to_ascii_lowercase().to_string());
+                let attributes = element.attributes.borrow();
+                if let Some(class) = attributes.get("class") {
+                    classes.extend(class.split_ascii_whitespace().map(str::to_string));
+                }
+                if let Some(id) = attributes.get("id") {
+                    ids.insert(id.to_string());
+                }
+            }
+        }
+        Self {
+            pages,
+            tags,
+            classes,
+            ids,
+            matched: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `css` without the style rules that can't match any page, adding to `stats`. Everything else, comments
+    /// included, is copied through byte for byte.
+    pub fn purge(&self, css: &str, stats: &mut PurgeStats) -> String {
+        let mut input = ParserInput::new(css);
+        let mut parser = Parser::new(&mut input);
+        let mut rules = Vec::new();
+        collect_style_rules(&mut parser, &mut rules);
+
+        let unknown: Vec<&str> = {
+            let matched = self.matched.lock().expect("purge cache");
+            rules
+                .iter()
+                .flat_map(|rule| rule.selectors.iter().flatten())
+                .filter(|w| self.names_present(w) && !matched.contains_key(&w.selector))
+                .map(|w| w.selector.as_str())
+                .collect()
+        };
+        if !unknown.is_empty() {
+            self.match_selectors(&unknown);
+        }
+
+        let matched = self.matched.lock().expect("purge cache");
+        let mut out = String::with_capacity(css.len());
+        let mut last = 0usize;
+        for rule in &rules {
+            let keep = rule.selectors.iter().any(|widened| match widened {
+                None => true,
+                Some(w) => self.names_present(w) && matched.get(&w.selector) != Some(&false),
+            });
+            if !keep {
+                out.push_str(&css[last..rule.start]);
+                last = rule.end;
+                stats.rules_dropped += 1;
+            }
+        }
+        out.push_str(&css[last..]);
+        stats.bytes_before += css.len();
+        stats.bytes_after += out.len();
+        out
+    }
+
+    /// Whether every element name, class and id the selector requires occurs somewhere in the pages.
+    fn names_present(&self, w: &Widened) -> bool {
+        w.tags.iter().all(|t| self.tags.contains(t))
+            && w.classes.iter().all(|c| self.classes.contains(c))
+            && w.ids.iter().all(|i| self.ids.contains(i))
+    }
+
+    fn match_selectors(&self, selectors: &[&str]) {
+        let documents: Vec<_> = self
+            .pages
+            .iter()
+            .map(|page| kuchiki::parse_html().one(page.as_str()))
+            .collect();
+        let mut matched = self.matched.lock().expect("purge cache");
+        for &selector in selectors {
+            // A selector kuchiki doesn't support counts as matching.
+            let hit = kuchiki::Selectors::compile(selector).map_or(true, |compiled| {
+                documents.iter().any(|document| {
+                    compiled
+                        .filter(document.inclusive_descendants().elements())
+                        .next()
+                        .is_some()
+                })
+            });
+            matched.insert(selector.to_string(), hit);
+        }
+    }
+}
+
+/// A complete style rule: its byte range in the stylesheet and its widened selectors (`None` where a selector
+/// couldn't be analyzed).
+struct StyleRule {
+    start: usize,
+    end: usize,
+    selectors: Vec<Option<Widened>>,
+}
+
+/// The style rules of a rule list, descending into grouping at-rules.
+fn collect_style_rules(parser: &mut Parser<'_, '_>, rules: &mut Vec<StyleRule>) {
+    loop {
+        let start = parser.position();
+        let (at_rule, mut has_block) = match parser.next_including_whitespace_and_comments() {
+            Err(_) => return,
+            Ok(Token::WhiteSpace(_) | Token::Comment(_) | Token::Semicolon) => continue,
+            Ok(Token::AtKeyword(name)) => (Some(name.to_ascii_lowercase()), false),
+            Ok(Token::CurlyBracketBlock) => (None, true),
+            Ok(_) => (None, false),
+        };
+        // The prelude runs to the block (or, for statements such as `@import`, the semicolon).
+        while !has_block {
+            match parser.next_including_whitespace_and_comments() {
+                Err(_) | Ok(Token::Semicolon) => break,
+                Ok(Token::CurlyBracketBlock) => {
+                    has_block = true;
+                    break;
+                }
+                Ok(_) => {}
+            }
+        }
+        let prelude_end = parser.position();
+        let prelude = parser.slice(start..prelude_end);
+        if !has_block {
+            continue;
+        }
+        match at_rule {
+            Some(name) if GROUPING_AT_RULES.contains(&name.as_str()) => {
+                let _ = parser.parse_nested_block(|nested| {
+                    collect_style_rules(nested, rules);
+                    Ok::<_, ParseError<'_, ()>>(())
+                });
+            }
+            Some(_) => skip_block(parser),
+            None => {
+                skip_block(parser);
+                let end = parser.position();
+                // A block cut off by the end of the input is kept; the stylesheet continues past it.
+                if parser.slice(start..end).ends_with('}') {
+                    rules.push(StyleRule {
+                        start: start.byte_index(),
+                        end: end.byte_index(),
+                        selectors: widen_selector_list(&prelude[..prelude.len() - 1]),
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn skip_block(parser: &mut Parser<'_, '_>) {
+    let _ = parser.parse_nested_block(|nested| {
+        while nested.next_including_whitespace_and_comments().is_ok() {}
+        Ok::<_, ParseError<'_, ()>>(())
+    });
+}
+
+/// Each selector of `list`, widened: pseudo-classes, pseudo-elements and attribute selectors removed (a compound
+/// left empty becomes `*`), comments dropped.
+fn widen_selector_list(list: &str) -> Vec<Option<Widened>> {
+    let mut input = ParserInput::new(list);
+    let mut parser = Parser::new(&mut input);
+    let mut selectors = Vec::new();
+    loop {
+        let (widened, more) = widen_selector(&mut parser);
+        selectors.push(widened.filter(|w| !w.selector.trim().is_empty()));
+        if !more {
+            return selectors;
+        }
+    }
+}
+
+/// The next selector of a list, and whether a comma follows it.
+fn widen_selector(parser: &mut Parser<'_, '_>) -> (Option<Widened>, bool) {
+    let mut w = Widened {
+        selector: String::new(),
+        tags: Vec::new(),
+        classes: Vec::new(),
+        ids: Vec::new(),
+    };
+    let mut analyzable = true;
+    let mut compound_empty = true;
+    loop {
+        let before = parser.position();
+        let token = match parser.next_including_whitespace_and_comments() {
+            Err(_) => return (analyzable.then_some(w), false),
+            Ok(token) => token.clone(),
+        };
+        match token {
+            Token::Comma => return (analyzable.then_some(w), true),
+            Token::Comment(_) => {}
+            Token::WhiteSpace(_) => {
+                w.selector.push(' ');
+                compound_empty = true;
+            }
+            Token::Delim(c @ ('>' | '+' | '~')) => {
+                w.selector.push(c);
+                compound_empty = true;
+            }
+            Token::Delim('*') => {
+                w.selector.push('*');
+                compound_empty = false;
+            }
+            Token::Ident(name) if compound_empty => {
+                w.selector.push_str(parser.slice_from(before));
+                w.tags.push(name.to_ascii_lowercase());
+                compound_empty = false;
+            }
+            Token::Delim('.') => match parser.next_including_whitespace_and_comments() {
+                Ok(Token::Ident(name)) => {
+                    w.classes.push(name.to_string());
+                    w.selector.push_str(parser.slice_from(before));
+                    compound_empty = false;
+                }
+                _ => analyzable = false,
+            },
+            Token::IDHash(name) => {
+                w.ids.push(name.to_string());
+                w.selector.push_str(parser.slice_from(before));
+                compound_empty = false;
+            }
+            Token::SquareBracketBlock => fill_empty_compound(&mut w, &mut compound_empty),
+            Token::Colon => {
+                // `:name`, `::name`, `:name(…)`.
+                let mut state = parser.state();
+                if let Ok(Token::Colon) = parser.next_including_whitespace_and_comments() {
+                    state = parser.state();
+                }
+                parser.reset(&state);
+                match parser.next_including_whitespace_and_comments() {
+                    Ok(Token::Ident(_) | Token::Function(_)) => {
+                        fill_empty_compound(&mut w, &mut compound_empty)
+                    }
+                    _ => analyzable = false,
+                }
+            }
+            // Namespaces, nesting selectors and anything else this doesn't model.
+            _ => analyzable = false,
+        }
+    }
+}
+
+/// A removed condition that leaves its compound empty is replaced by `*`, so the combinators keep their operands.
+fn fill_empty_compound(w: &mut Widened, compound_empty: &mut bool) {
+    if *compound_empty {
+        w.selector.push('*');
+        *compound_empty = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_are_dropped_only_when_no_widened_selector_matches() {
+        let page = r#"<html><body><main class="topic"><article id="post_1" class="post"><p>Hi <a href="/u">x</a></p></article></main></body></html>"#;
+        let purger = Purger::new(vec![page.to_string()]);
+        let css = "@charset \"utf-8\";\n\
+            .post p { color: red }\n\
+            .composer { display: none }\n\
+            .topic > .post:hover a::after, .sidebar { content: \"\" }\n\
+            details[open] summary { color: blue }\n\
+            :root { --x: 1 }\n\
+            #post_1, #post_2 { margin: 0 }\n\
+            .post .topic { color: green }\n\
+            /* kept */\n\
+            @media (min-width: 40em) { .sidebar { width: 10em } .post { width: 30em } }\n\
+            @font-face { font-family: X; src: url(x.woff2) }\n\
+            @keyframes spin { from { opacity: 0 } to { opacity: 1 } }\n\
+            svg|rect { fill: red }\n\
+            .unclosed { color: red";
+        let mut stats = PurgeStats::default();
+        let purged = purger.purge(css, &mut stats);
+        assert_eq!(
+            purged,
+            "@charset \"utf-8\";\n\
+            .post p { color: red }\n\
+            \n\
+            .topic > .post:hover a::after, .sidebar { content: \"\" }\n\
+            \n\
+            :root { --x: 1 }\n\
+            #post_1, #post_2 { margin: 0 }\n\
+            \n\
+            /* kept */\n\
+            @media (min-width: 40em) {  .post { width: 30em } }\n\
+            @font-face { font-family: X; src: url(x.woff2) }\n\
+            @keyframes spin { from { opacity: 0 } to { opacity: 1 } }\n\
+            svg|rect { fill: red }\n\
+            .unclosed { color: red"
+        );
+        assert_eq!(stats.rules_dropped, 4);
+        assert_eq!(stats.bytes_before, css.len());
+        assert_eq!(stats.bytes_after, purged.len());
+    }
+
+    #[test]
+    fn selectors_are_widened_before_matching() {
+        let widened: Vec<Option<String>> = widen_selector_list(
+            "a:not(.x)::before, .a > :hover, input[type=\"checkbox\"]:checked + label, .md\\:flex, ns|a",
+        )
+        .into_iter()
+        .map(|w| w.map(|w| w.selector))
+        .collect();
+        assert_eq!(
+            widened,
+            [
+                Some("a".to_string()),
+                Some(" .a > *".to_string()),
+                Some(" input + label".to_string()),
+                Some(" .md\\:flex".to_string()),
+                None,
+            ]
+        );
+    }
+}
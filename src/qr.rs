@@ -0,0 +1,113 @@
+//! `--qr-permalinks`: a QR code of each post's forum URL, for readers of printed archives.
+
+use qrcode::QrCode;
+use qrcode::render::svg;
+
+const SHOWN_CSS: &str = "\n/* qr permalinks */\n\
+.dtr-qr-permalink { display: inline-block; margin-inline-start: auto; line-height: 0; }\n\
+.dtr-qr-permalink svg { width: 72px; height: 72px; }\n";
+
+const PRINT_ONLY_CSS: &str = "\n/* qr permalinks */\n\
+.dtr-qr-permalink { display: none; }\n\
+@media print {\n  .dtr-qr-permalink { display: inline-block; margin-inline-start: auto; line-height: 0; }\n}\n\
+.dtr-qr-permalink svg { width: 72px; height: 72px; }\n";
+
+/// Hidden on screen and shown in print, unless `always`. Appended to the page CSS after the theme.
+pub fn css(always: bool) -> &'static str {
+    if always { SHOWN_CSS } else { PRINT_ONLY_CSS }
+}
+
+/// Inline `<svg>` (one unit per module, quiet zone included) encoding `url`, wrapped in a
+/// `span.dtr-qr-permalink`. `None` when the URL is too long for a QR code.
+pub fn permalink_svg(url: &str) -> Option<String> {
+    let code = QrCode::new(url.as_bytes()).ok()?;
+    let image = code
+        .render::<svg::Color>()
+        .module_dimensions(1, 1)
+        .quiet_zone(true)
+        .build();
+    // The renderer writes a standalone document; the prologue is not allowed inside HTML.
+    let start = image.find("<svg")?;
+    let label = html_escape(&format!("QR code for {url}"));
+    Some(format!(
+        "<span class=\"dtr-qr-permalink\" title=\"{}\"><svg role=\"img\" aria-label=\"{}\"{}</span>",
+        html_escape(url),
+        label,
+        &image[start + "<svg".len()..]
+    ))
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qrcode::Color;
+
+    /// Dark modules drawn by the SVG path, as a square matrix without the quiet zone.
+    fn decode_modules(svg: &str) -> Vec<Vec<bool>> {
+        let size: usize = svg
+            .split("width=\"")
+            .nth(1)
+            .unwrap()
+            .split('"')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let path = svg
+            .split(" d=\"")
+            .nth(1)
+            .unwrap()
+            .split('"')
+            .next()
+            .unwrap();
+        let mut grid = vec![vec![false; size]; size];
+        // Each rectangle is `M{left} {top}h{width}v{height}H{left}V{top}`.
+        for rect in path.split('M').filter(|r| !r.is_empty()) {
+            let nums: Vec<usize> = rect
+                .split(|c: char| !c.is_ascii_digit())
+                .filter(|n| !n.is_empty())
+                .map(|n| n.parse().unwrap())
+                .collect();
+            let (left, top, width, height) = (nums[0], nums[1], nums[2], nums[3]);
+            for row in grid.iter_mut().skip(top).take(height) {
+                for module in row.iter_mut().skip(left).take(width) {
+                    *module = true;
+                }
+            }
+        }
+        let quiet = 4;
+        grid[quiet..size - quiet]
+            .iter()
+            .map(|row| row[quiet..size - quiet].to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn svg_draws_the_code_for_the_url() {
+        let url = "https://forum.example.com/t/123/4";
+        let svg = permalink_svg(url).unwrap();
+        assert!(svg.starts_with(
+            "<span class=\"dtr-qr-permalink\" title=\"https://forum.example.com/t/123/4\"><svg role=\"img\""
+        ));
+        assert!(!svg.contains("<?xml"));
+
+        let code = QrCode::new(url).unwrap();
+        let expected: Vec<Vec<bool>> = code
+            .to_colors()
+            .chunks(code.width())
+            .map(|row| row.iter().map(|&c| c == Color::Dark).collect())
+            .collect();
+        assert_eq!(decode_modules(&svg), expected);
+        assert_ne!(
+            decode_modules(&permalink_svg("https://forum.example.com/t/123/5").unwrap()),
+            expected
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use std::fmt::Write as _;
+
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tone {
+    Plain,
+    Warning,
+    Failure,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cell {
+    text: String,
+    tone: Tone,
+    align: Align,
+}
+
+impl Cell {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            tone: Tone::Plain,
+            align: Align::Left,
+        }
+    }
+
+    /// A right-aligned count with thousands separators.
+    pub fn count(n: u64) -> Self {
+        Self {
+            text: thousands(n),
+            tone: Tone::Plain,
+            align: Align::Right,
+        }
+    }
+
+    /// A right-aligned, humanized byte size.
+    pub fn bytes(n: u64) -> Self {
+        Self {
+            text: human_bytes(n),
+            tone: Tone::Plain,
+            align: Align::Right,
+        }
+    }
+
+    pub fn tone(mut self, tone: Tone) -> Self {
+        self.tone = tone;
+        self
+    }
+
+    /// Red when `failures > 0`.
+    pub fn failures(n: u64) -> Self {
+        let tone = if n > 0 { Tone::Failure } else { Tone::Plain };
+        Self::count(n).tone(tone)
+    }
+}
+
+/// A plain-text table with aligned columns, optionally colored with ANSI escapes.
+#[derive(Debug, Default)]
+pub struct Table {
+    headers: Vec<String>,
+    rows: Vec<Vec<Cell>>,
+    notes: Vec<(Tone, String)>,
+}
+
+impl Table {
+    pub fn new<I, S>(headers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            headers: headers.into_iter().map(Into::into).collect(),
+            ..Self::default()
+        }
+    }
+
+    pub fn row(&mut self, cells: Vec<Cell>) {
+        self.rows.push(cells);
+    }
+
+    /// A line printed under the table, e.g. a warning.
+    pub fn note(&mut self, tone: Tone, text: impl Into<String>) {
+        self.notes.push((tone, text.into()));
+    }
+
+    pub fn render(&self, color: bool) -> String {
+        let columns = self
+            .rows
+            .iter()
+            .map(Vec::len)
+            .chain([self.headers.len()])
+            .max()
+            .unwrap_or(0);
+        let mut widths = vec![0usize; columns];
+        for (i, h) in self.headers.iter().enumerate() {
+            widths[i] = widths[i].max(h.chars().count());
+        }
+        for row in &self.rows {
+            for (i, cell) in row.iter().enumerate() {
+                widths[i] = widths[i].max(cell.text.chars().count());
+            }
+        }
+
+        let mut out = String::new();
+        if !self.headers.is_empty() {
+            let line = self
+                .headers
+                .iter()
+                .enumerate()
+                .map(|(i, h)| pad(h, widths[i], Align::Left))
+                .collect::<Vec<_>>()
+                .join("  ");
+            push_line(&mut out, line.trim_end(), color.then_some(BOLD));
+        }
+        for row in &self.rows {
+            let mut line = String::new();
+            for (i, cell) in row.iter().enumerate() {
+                if i > 0 {
+                    line.push_str("  ");
+                }
+                let padded = pad(&cell.text, widths[i], cell.align);
+                match tone_code(cell.tone).filter(|_| color) {
+                    Some(code) => {
+                        let _ = write!(line, "{code}{padded}{RESET}");
+                    }
+                    None => line.push_str(&padded),
+                }
+            }
+            push_line(&mut out, line.trim_end(), None);
+        }
+        for (tone, text) in &self.notes {
+            push_line(&mut out, text, tone_code(*tone).filter(|_| color));
+        }
+        out
+    }
+}
+
+fn push_line(out: &mut String, line: &str, code: Option<&str>) {
+    match code {
+        Some(code) => {
+            let _ = writeln!(out, "{code}{line}{RESET}");
+        }
+        None => {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+}
+
+fn tone_code(tone: Tone) -> Option<&'static str> {
+    match tone {
+        Tone::Plain => None,
+        Tone::Warning => Some(YELLOW),
+        Tone::Failure => Some(RED),
+    }
+}
+
+fn pad(text: &str, width: usize, align: Align) -> String {
+    let fill = " ".repeat(width.saturating_sub(text.chars().count()));
+    match align {
+        Align::Left => format!("{text}{fill}"),
+        Align::Right => format!("{fill}{text}"),
+    }
+}
+
+/// Whether to color output on stderr: off with `--no-color`, a non-empty `NO_COLOR`, or when stderr is not a terminal.
+pub fn color_enabled(no_color_flag: bool) -> bool {
+    use std::io::IsTerminal as _;
+
+    color_allowed(
+        no_color_flag,
+        std::env::var_os("NO_COLOR").as_deref(),
+        std::io::stderr().is_terminal(),
+    )
+}
+
+fn color_allowed(no_color_flag: bool, no_color_env: Option<&std::ffi::OsStr>, tty: bool) -> bool {
+    let env_disables = no_color_env.is_some_and(|v| !v.is_empty());
+    tty && !no_color_flag && !env_disables
+}
+
+pub fn thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, ch) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(ch);
+    }
+    out
+}
+
+pub fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if n < 1024 {
+        return format!("{n} B");
+    }
+    let mut value = n as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Table {
+        let mut table = Table::new(["kind", "downloads", "failed", "bytes"]);
+        table.row(vec![
+            Cell::text("image"),
+            Cell::count(1234),
+            Cell::failures(2),
+            Cell::bytes(5 * 1024 * 1024),
+        ]);
+        table.row(vec![
+            Cell::text("font"),
+            Cell::count(7),
+            Cell::failures(0),
+            Cell::bytes(512),
+        ]);
+        table.note(Tone::Warning, "skipped 3 whisper posts");
+        table
+    }
+
+    #[test]
+    fn plain_rendering_is_aligned() {
+        let expected = "\
+kind   downloads  failed  bytes
+image      1,234       2  5.0 MiB
+font           7       0    512 B
+skipped 3 whisper posts
+";
+        assert_eq!(snapshot().render(false), expected);
+    }
+
+    #[test]
+    fn no_color_disables_ansi() {
+        assert!(!color_allowed(false, Some("1".as_ref()), true));
+        assert!(!color_allowed(true, None, true));
+        assert!(!color_allowed(false, None, false));
+        assert!(color_allowed(false, Some("".as_ref()), true));
+
+        let colored = snapshot().render(true);
+        assert!(colored.contains(RED) && colored.contains(YELLOW));
+        assert!(!snapshot().render(false).contains('\x1b'));
+    }
+
+    #[test]
+    fn number_formatting() {
+        assert_eq!(thousands(0), "0");
+        assert_eq!(thousands(999), "999");
+        assert_eq!(thousands(1000), "1,000");
+        assert_eq!(thousands(1234567), "1,234,567");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+    }
+}
@@ -0,0 +1,211 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::cli::ServeArgs;
+
+/// Static file server for previewing dir-mode output. Binds to localhost only.
+pub struct Server {
+    listener: TcpListener,
+    root: PathBuf,
+}
+
+impl Server {
+    /// Bind `127.0.0.1:port` (`0` picks a free port) serving files under `root`.
+    pub async fn bind(root: &Path, port: u16) -> anyhow::Result<Self> {
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("open {}", root.display()))?;
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .await
+            .with_context(|| format!("bind 127.0.0.1:{}", port))?;
+        Ok(Self { listener, root })
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        self.listener.local_addr().context("read local address")
+    }
+
+    /// Serve until the task is dropped.
+    pub async fn run(self) -> anyhow::Result<()> {
+        let root = std::sync::Arc::new(self.root);
+        loop {
+            let (stream, _) = self.listener.accept().await.context("accept")?;
+            let root = root.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle(stream, &root).await {
+                    tracing::debug!(error = %format!("{:#}", e), "serve: connection failed");
+                }
+            });
+        }
+    }
+}
+
+pub async fn serve(args: ServeArgs) -> anyhow::Result<()> {
+    let server = Server::bind(&args.dir, args.port).await?;
+    let url = format!("http://{}/", server.local_addr()?);
+    eprintln!("Serving {} at {}", args.dir.display(), url);
+    if args.open {
+        open_browser(&url);
+    }
+    server.run().await
+}
+
+fn open_browser(url: &str) {
+    let mut cmd = if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else if cfg!(windows) {
+        let mut c = std::process::Command::new("cmd");
+        c.args(["/C", "start", ""]);
+        c
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+    if let Err(e) = cmd.arg(url).spawn() {
+        tracing::warn!(error = %e, "could not open a browser");
+    }
+}
+
+async fn handle(mut stream: TcpStream, root: &Path) -> anyhow::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await.context("read request")?;
+        if n == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.len() > 16 * 1024 {
+            return respond(&mut stream, "431 Request Header Fields Too Large").await;
+        }
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or("/"));
+    if method != "GET" && method != "HEAD" {
+        return respond(&mut stream, "405 Method Not Allowed").await;
+    }
+
+    let Some(path) = resolve(root, target) else {
+        return respond(&mut stream, "404 Not Found").await;
+    };
+    match tokio::fs::read(&path).await {
+        Ok(body) => {
+            let content_type = content_type(&path);
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                content_type,
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).await?;
+            if method == "GET" {
+                stream.write_all(&body).await?;
+            }
+            stream.shutdown().await.ok();
+            Ok(())
+        }
+        Err(_) => respond(&mut stream, "404 Not Found").await,
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str) -> anyhow::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        status.len(),
+        status
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await.ok();
+    Ok(())
+}
+
+/// Map a request target to a file under `root`; `None` for anything outside it or missing.
+fn resolve(root: &Path, target: &str) -> Option<PathBuf> {
+    let path = target.split(['?', '#']).next().unwrap_or("/");
+    let decoded = percent_decode(path)?;
+
+    let mut rel = PathBuf::new();
+    for seg in decoded.split('/') {
+        if seg.is_empty() || seg == "." {
+            continue;
+        }
+        if seg == ".." || seg.contains('\\') || seg.contains(':') {
+            return None;
+        }
+        rel.push(seg);
+    }
+
+    let candidate = if rel.as_os_str().is_empty() {
+        index_file(root)?
+    } else {
+        root.join(rel)
+    };
+    // Symlinks could still point outside; compare the real paths.
+    let real = candidate.canonicalize().ok()?;
+    (real.starts_with(root) && real.is_file()).then_some(real)
+}
+
+/// The topic page served at `/`: `index.html`, else `topic-{id}.html`, else the first page of a paginated render.
+fn index_file(root: &Path) -> Option<PathBuf> {
+    let index = root.join("index.html");
+    if index.is_file() {
+        return Some(index);
+    }
+    let mut names: Vec<String> = std::fs::read_dir(root)
+        .ok()?
+        .filter_map(|e| e.ok()?.file_name().into_string().ok())
+        .filter(|n| n.starts_with("topic-") && n.ends_with(".html"))
+        .collect();
+    names.sort();
+    let whole = names.iter().find(|n| !n.contains("-p"));
+    let first_page = names.iter().find(|n| n.ends_with("-p1.html"));
+    whole.or(first_page).map(|n| root.join(n))
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "woff2" => "font/woff2",
+        "woff" => "font/woff",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,96 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::topic::TopicJson;
+
+/// One-line "generated by" note for the HTML and CSS headers.
+///
+/// `reproducible` swaps the wall-clock time for the topic's last activity so re-renders stay byte-identical;
+/// the time is dropped when the export has no activity date.
+pub fn stamp(topic: &TopicJson, reproducible: bool) -> String {
+    let tool = format!(
+        "Generated by {} {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+    let when = if reproducible {
+        topic
+            .last_activity()
+            .map(|t| format!("{} (topic last activity)", sanitize(t)))
+    } else {
+        Some(utc_timestamp(SystemTime::now()))
+    };
+    match when {
+        Some(when) => format!("{} on {}", tool, when),
+        None => tool,
+    }
+}
+
+pub fn html_comment(stamp: &str) -> String {
+    format!("<!-- {} -->", stamp)
+}
+
+pub fn css_comment(stamp: &str) -> String {
+    format!("/* {} */\n", stamp)
+}
+
+/// Dates come from the topic JSON; keep only timestamp characters so they can't close the comment.
+fn sanitize(timestamp: &str) -> String {
+    timestamp
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, ':' | '.' | '+' | '-'))
+        .collect()
+}
+
+/// `YYYY-MM-DDTHH:MM:SSZ` in UTC.
+pub fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Proleptic Gregorian date for a day count since 1970-01-01 (Howard Hinnant's algorithm).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn utc_timestamps() {
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let t = UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(utc_timestamp(t), "2024-02-29T12:34:56Z");
+    }
+
+    #[test]
+    fn sanitized_dates_cannot_close_comments() {
+        assert_eq!(
+            sanitize("2025-01-01T00:00:00Z --> */"),
+            "2025-01-01T00:00:00Z--"
+        );
+    }
+}
@@ -1,90 +1,672 @@
+use std::collections::HashSet;
+
+use kuchiki::iter::NodeIterator as _;
 use kuchiki::traits::TendrilSink as _;
+use serde::Serialize;
+
+/// Outcome of the strict-offline checks: every rule that was evaluated, how many elements/tokens it looked at,
+/// and any violations found.
+#[derive(Debug, Serialize)]
+pub struct StrictReport {
+    pub tool_version: &'static str,
+    pub rules: Vec<RuleReport>,
+    pub violations: Vec<Violation>,
+    /// Violations that [`StrictReport::allow_dangling_anchors`] let through, so they only get logged.
+    pub warnings: Vec<Violation>,
+    /// Page being checked, recorded on its violations.
+    #[serde(skip)]
+    page: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuleReport {
+    pub rule: &'static str,
+    pub inspected: usize,
+    pub violations: usize,
+    pub warnings: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Violation {
+    pub rule: &'static str,
+    /// Index into the checked pages; `None` for the stylesheet.
+    pub page: Option<usize>,
+    /// Offending element, e.g. `img`; `None` in the stylesheet.
+    pub element: Option<String>,
+    pub attribute: Option<String>,
+    /// The offending URL or attribute value.
+    pub value: String,
+    /// Byte offset of `value` in the page or stylesheet, when it can be found there verbatim.
+    pub offset: Option<usize>,
+    pub detail: String,
+}
+
+/// Where a violation is, before it becomes a [`Violation`].
+struct Found<'a> {
+    element: Option<&'a str>,
+    attribute: Option<&'a str>,
+    value: &'a str,
+    offset: Option<usize>,
+}
+
+impl<'a> Found<'a> {
+    /// In a page; the offset is that of the first occurrence of `value`, since the parser keeps no positions.
+    fn in_page(page: &str, element: &'a str, attribute: Option<&'a str>, value: &'a str) -> Self {
+        Self {
+            element: Some(element),
+            attribute,
+            value,
+            offset: (!value.is_empty()).then(|| page.find(value)).flatten(),
+        }
+    }
+}
+
+impl StrictReport {
+    fn new() -> Self {
+        Self {
+            tool_version: env!("CARGO_PKG_VERSION"),
+            rules: Vec::new(),
+            violations: Vec::new(),
+            warnings: Vec::new(),
+            page: None,
+        }
+    }
+
+    fn rule(&mut self, rule: &'static str) -> usize {
+        if let Some(idx) = self.rules.iter().position(|r| r.rule == rule) {
+            return idx;
+        }
+        self.rules.push(RuleReport {
+            rule,
+            inspected: 0,
+            violations: 0,
+            warnings: 0,
+        });
+        self.rules.len() - 1
+    }
+
+    fn inspect(&mut self, idx: usize) {
+        self.rules[idx].inspected += 1;
+    }
+
+    fn violation(&mut self, idx: usize, found: Found<'_>, detail: String) {
+        self.rules[idx].violations += 1;
+        let v = self.found(idx, found, detail);
+        self.violations.push(v);
+    }
+
+    fn found(&self, idx: usize, found: Found<'_>, detail: String) -> Violation {
+        Violation {
+            rule: self.rules[idx].rule,
+            page: self.page,
+            element: found.element.map(str::to_string),
+            attribute: found.attribute.map(str::to_string),
+            value: found.value.to_string(),
+            offset: found.offset,
+            detail,
+        }
+    }
+
+    /// Turn dangling same-page links from violations into warnings (`--allow-dangling-anchors`).
+    pub fn allow_dangling_anchors(&mut self) {
+        let (allowed, violations) = std::mem::take(&mut self.violations)
+            .into_iter()
+            .partition(|v| v.rule == ANCHOR_RULE);
+        self.violations = violations;
+        self.warnings = allowed;
+        if let Some(rule) = self.rules.iter_mut().find(|r| r.rule == ANCHOR_RULE) {
+            rule.warnings += std::mem::take(&mut rule.violations);
+        }
+    }
+
+    /// Turn the report into an error listing every violation, if there are any.
+    pub fn into_result(self) -> anyhow::Result<()> {
+        if self.violations.is_empty() {
+            return Ok(());
+        }
+        let mut msg = format!(
+            "strict offline check failed with {} violation(s):",
+            self.violations.len()
+        );
+        for v in &self.violations {
+            msg.push_str("\n  ");
+            msg.push_str(&v.detail);
+        }
+        anyhow::bail!(msg)
+    }
+}
+
+/// Check the bundled CSS and every HTML page of one render.
+pub fn check_strict_offline(pages: &[&str], css: &str) -> StrictReport {
+    check_strict_offline_linking(pages, css, &[])
+}
+
+/// [`check_strict_offline`], except that stylesheets may still refer to `linked`: assets over `--max-asset-size`,
+/// or skipped by `--skip-large-assets`, that are linked to on purpose.
+pub fn check_strict_offline_linking(pages: &[&str], css: &str, linked: &[String]) -> StrictReport {
+    let mut report = StrictReport::new();
+    check_css_strict(css, linked, &mut report);
+    for (i, html) in pages.iter().enumerate() {
+        report.page = Some(i);
+        check_html_strict(html, linked, &mut report);
+    }
+    report.page = None;
+    report
+}
+
+/// Extra `--no-js` rule: no `<script>` element in any page (event handlers are always rejected).
+pub fn check_no_js(pages: &[&str], report: &mut StrictReport) {
+    let scripts = report.rule("script (--no-js)");
+    for (i, html) in pages.iter().enumerate() {
+        report.page = Some(i);
+        let doc = kuchiki::parse_html().one(*html);
+        if let Ok(nodes) = doc.select("script") {
+            for node in nodes {
+                report.inspect(scripts);
+                let src = node.attributes.borrow().get("src").map(str::to_string);
+                let found = match &src {
+                    Some(src) => Found::in_page(html, "script", Some("src"), src),
+                    None => Found::in_page(html, "script", None, ""),
+                };
+                report.violation(
+                    scripts,
+                    found,
+                    "<script> element in --no-js output".to_string(),
+                );
+            }
+        }
+    }
+    report.page = None;
+}
+
+fn check_css_strict(css: &str, linked: &[String], report: &mut StrictReport) {
+    let urls = report.rule("css url()");
+    let imports = report.rule("css @import");
+    for r in css_references(css) {
+        let rule = if r.import { imports } else { urls };
+        report.inspect(rule);
+        if !is_local_css_url(&r.url) && !linked.contains(&r.url) {
+            let what = if r.import {
+                "css still imports a non-local stylesheet"
+            } else {
+                "css still references non-local url"
+            };
+            let found = Found {
+                element: None,
+                attribute: None,
+                value: &r.url,
+                offset: Some(r.offset),
+            };
+            report.violation(rule, found, format!("{}: {}", what, snippet(css, r.offset)));
+        }
+    }
+}
+
+/// A `url()` or `@import` target found by [`css_references`].
+#[derive(Debug, PartialEq, Eq)]
+struct CssReference {
+    /// Unquoted and unescaped.
+    url: String,
+    import: bool,
+    /// Byte offset of the `url(` or `@import`.
+    offset: usize,
+}
+
+/// Every `url()` token and `@import` target in `css`, plus the string candidates of `image-set()` (which load
+/// like `url()`). Other comments and strings are skipped, so text that only looks like a URL inside them isn't
+/// reported; `url(`, `image-set(` and `@import` match in any case.
+fn css_references(css: &str) -> Vec<CssReference> {
+    let mut out = Vec::new();
+    let mut scan = CssScanner { css, pos: 0 };
+    // Parenthesis depth, and the depth just inside the innermost open `image-set(`.
+    let mut depth = 0usize;
+    let mut image_set: Option<usize> = None;
+    while let Some(c) = scan.peek() {
+        let start = scan.pos;
+        if scan.rest().starts_with("/*") {
+            scan.skip_comment();
+        } else if c == '"' || c == '\'' {
+            let url = scan.string();
+            if image_set == Some(depth) {
+                out.push(CssReference {
+                    url,
+                    import: false,
+                    offset: start,
+                });
+            }
+        } else if c == '\\' {
+            scan.escape();
+        } else if c == '@' {
+            scan.bump();
+            if scan.ident().eq_ignore_ascii_case("import") {
+                scan.skip_trivia();
+                let url = if scan.peek().is_some_and(|c| c == '"' || c == '\'') {
+                    Some(scan.string())
+                } else {
+                    scan.url_function()
+                };
+                if let Some(url) = url {
+                    out.push(CssReference {
+                        url,
+                        import: true,
+                        offset: start,
+                    });
+                }
+            }
+        } else if c.is_alphabetic() || c == '-' || c == '_' {
+            if let Some(url) = scan.url_function() {
+                out.push(CssReference {
+                    url,
+                    import: false,
+                    offset: start,
+                });
+            } else {
+                let ident = scan.ident().to_ascii_lowercase();
+                let is_image_set = ident == "image-set" || ident == "-webkit-image-set";
+                if is_image_set && scan.peek() == Some('(') {
+                    scan.bump();
+                    depth += 1;
+                    image_set = Some(depth);
+                }
+            }
+        } else {
+            match scan.bump() {
+                Some('(') => depth += 1,
+                Some(')') => {
+                    if image_set == Some(depth) {
+                        image_set = None;
+                    }
+                    depth = depth.saturating_sub(1);
+                }
+                _ => {}
+            }
+        }
+    }
+    out
+}
+
+struct CssScanner<'a> {
+    css: &'a str,
+    pos: usize,
+}
+
+impl CssScanner<'_> {
+    fn rest(&self) -> &str {
+        &self.css[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_comment(&mut self) {
+        self.pos = match self.css[self.pos + 2..].find("*/") {
+            Some(end) => self.pos + 2 + end + 2,
+            None => self.css.len(),
+        };
+    }
+
+    /// Whitespace and comments.
+    fn skip_trivia(&mut self) {
+        loop {
+            if self.rest().starts_with("/*") {
+                self.skip_comment();
+            } else if self.peek().is_some_and(|c| c.is_whitespace()) {
+                self.bump();
+            } else {
+                return;
+            }
+        }
+    }
+
+    /// The character after a backslash: a hex code point (plus one optional space) or the literal character.
+    fn escape(&mut self) -> Option<char> {
+        self.bump();
+        let hex: String = self
+            .rest()
+            .chars()
+            .take_while(|c| c.is_ascii_hexdigit())
+            .take(6)
+            .collect();
+        if hex.is_empty() {
+            return self.bump();
+        }
+        self.pos += hex.len();
+        if self.peek().is_some_and(|c| c.is_whitespace()) {
+            self.bump();
+        }
+        u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+    }
+
+    fn ident(&mut self) -> String {
+        let mut out = String::new();
+        while let Some(c) = self.peek() {
+            if c == '\\' {
+                out.extend(self.escape());
+            } else if c.is_alphanumeric() || c == '-' || c == '_' || !c.is_ascii() {
+                out.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        out
+    }
 
-pub fn assert_strict_offline(html: &str, css: &str) -> anyhow::Result<()> {
-    assert_css_strict(css)?;
-    assert_html_strict(html)?;
-    Ok(())
-}
-
-fn assert_css_strict(css: &str) -> anyhow::Result<()> {
-    let lowered = css.to_ascii_lowercase();
-    if lowered.contains("url(http://")
-        || lowered.contains("url(https://")
-        || lowered.contains("url(\"http://")
-        || lowered.contains("url(\"https://")
-        || lowered.contains("url('//")
-        || lowered.contains("url(\"//")
-        || lowered.contains("url(/")
-        || lowered.contains("url(\"/")
-        || lowered.contains("url('/")
-        || lowered.contains("@import \"http")
-        || lowered.contains("@import url(http")
-        || lowered.contains("@import url(\"http")
+    /// A quoted string starting at the quote; returns its value.
+    fn string(&mut self) -> String {
+        let Some(quote) = self.bump() else {
+            return String::new();
+        };
+        let mut out = String::new();
+        while let Some(c) = self.peek() {
+            match c {
+                '\\' => out.extend(self.escape()),
+                '\n' => break,
+                c if c == quote => {
+                    self.bump();
+                    break;
+                }
+                c => {
+                    out.push(c);
+                    self.bump();
+                }
+            }
+        }
+        out
+    }
+
+    /// If a `url(` starts here (any case), consume the whole token and return its target.
+    fn url_function(&mut self) -> Option<String> {
+        let head = self.rest().get(..4)?;
+        if !head.eq_ignore_ascii_case("url(") {
+            return None;
+        }
+        self.pos += 4;
+        while self.peek().is_some_and(|c| c.is_whitespace()) {
+            self.bump();
+        }
+        let url = if self.peek().is_some_and(|c| c == '"' || c == '\'') {
+            let url = self.string();
+            // Skip to the closing paren, like a browser would after a quoted argument.
+            while let Some(c) = self.bump() {
+                if c == ')' {
+                    break;
+                }
+            }
+            url
+        } else {
+            let mut url = String::new();
+            while let Some(c) = self.peek() {
+                match c {
+                    ')' => {
+                        self.bump();
+                        break;
+                    }
+                    '\\' => url.extend(self.escape()),
+                    c => {
+                        url.push(c);
+                        self.bump();
+                    }
+                }
+            }
+            url.trim_end().to_string()
+        };
+        Some(url)
+    }
+}
+
+/// `data:` URIs, fragments and relative paths stay inside the output; anything with another scheme, `//host`
+/// or a root-relative `/path` would go to the network (or nowhere) when opened from disk.
+fn is_local_css_url(url: &str) -> bool {
+    let url = url.trim();
+    if url.is_empty() || url.starts_with('#') {
+        return true;
+    }
+    if url
+        .get(..5)
+        .is_some_and(|s| s.eq_ignore_ascii_case("data:"))
     {
-        anyhow::bail!("strict offline check failed: css still references non-local urls");
+        return true;
     }
-    Ok(())
+    if url.starts_with('/') || url.starts_with('\\') {
+        return false;
+    }
+    let scheme_len = url
+        .find(|c: char| !(c.is_ascii_alphanumeric() || "+-.".contains(c)))
+        .unwrap_or(url.len());
+    let has_scheme = scheme_len > 0
+        && url.as_bytes()[0].is_ascii_alphabetic()
+        && url[scheme_len..].starts_with(':');
+    !has_scheme
 }
 
-fn assert_html_strict(html: &str) -> anyhow::Result<()> {
+fn check_html_strict(html: &str, linked: &[String], report: &mut StrictReport) {
     let doc = kuchiki::parse_html().one(html);
 
-    for selector in [
-        "img[src]",
-        "img[srcset]",
-        "source[src]",
-        "source[srcset]",
-        "script[src]",
-        "link[href]",
-        "iframe[src]",
-        "audio[src]",
-        "video[src]",
+    for (selector, attr) in [
+        ("img[src]", "src"),
+        ("img[srcset]", "srcset"),
+        ("source[src]", "src"),
+        ("source[srcset]", "srcset"),
+        ("script[src]", "src"),
+        ("link[href]", "href"),
+        ("iframe[src]", "src"),
+        ("audio[src]", "src"),
+        ("video[src]", "src"),
+        ("video[poster]", "poster"),
+        ("object[data]", "data"),
+        ("embed[src]", "src"),
+        ("[background]", "background"),
     ] {
+        let rule = report.rule(selector);
         if let Ok(nodes) = doc.select(selector) {
             for node in nodes {
+                report.inspect(rule);
                 let attrs = node.attributes.borrow();
-                for attr in ["src", "srcset", "href"] {
-                    if let Some(v) = attrs.get(attr) {
-                        if is_disallowed_autoload(v) {
-                            anyhow::bail!(
-                                "strict offline check failed: <{} {}=\"{}\"> is not local",
-                                node.name.local.as_ref(),
-                                attr,
-                                v
-                            );
-                        }
-                    }
+                let Some(value) = attrs.get(attr) else {
+                    continue;
+                };
+                // Every `srcset` candidate may be loaded, not just the first.
+                let urls = match attr {
+                    "srcset" => crate::html::srcset_candidates(value)
+                        .into_iter()
+                        .map(|(url, _)| url)
+                        .collect(),
+                    _ => vec![value],
+                };
+                for v in urls.into_iter().filter(|v| is_disallowed_autoload(v)) {
+                    report.violation(
+                        rule,
+                        Found::in_page(html, node.name.local.as_ref(), Some(attr), v),
+                        format!(
+                            "<{} {}=\"{}\"> is not local",
+                            node.name.local.as_ref(),
+                            attr,
+                            v
+                        ),
+                    );
                 }
             }
         }
     }
 
-    // Inline styles (attrs + <style>) should not have remote `url(http...)`.
-    if let Ok(nodes) = doc.select("[style]") {
+    // Inline SVG references, in either `href` or `xlink:href`.
+    let rule = report.rule("svg image/use href");
+    if let Ok(nodes) = doc.select("svg image, svg use") {
         for node in nodes {
-            if let Some(style) = node.attributes.borrow().get("style") {
-                if style.to_ascii_lowercase().contains("url(http") || style.contains("url(//") {
-                    anyhow::bail!(
-                        "strict offline check failed: style attribute contains remote url()"
+            report.inspect(rule);
+            let attrs = node.attributes.borrow();
+            for (name, attr) in attrs.map.iter() {
+                if name.local.as_ref() == "href" && is_disallowed_autoload(&attr.value) {
+                    let prefix = attr.prefix.as_ref().map(|p| format!("{}:", p));
+                    let attribute = format!("{}href", prefix.clone().unwrap_or_default());
+                    report.violation(
+                        rule,
+                        Found::in_page(
+                            html,
+                            node.name.local.as_ref(),
+                            Some(&attribute),
+                            &attr.value,
+                        ),
+                        format!(
+                            "<{} {}href=\"{}\"> is not local",
+                            node.name.local.as_ref(),
+                            prefix.unwrap_or_default(),
+                            attr.value
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    // Event handlers and `javascript:` URLs never survive sanitization of cooked HTML. `data-*` attributes are
+    // never loaded or followed, so the remote URLs `--keep-original-urls` records there pass every rule.
+    let handlers = report.rule("on* attribute");
+    let js_urls = report.rule("javascript: url");
+    for node in doc.descendants().elements() {
+        let attrs = node.attributes.borrow();
+        for (name, attr) in attrs.map.iter() {
+            let name = name.local.as_ref();
+            if is_event_handler_attr(name) {
+                report.inspect(handlers);
+                report.violation(
+                    handlers,
+                    Found::in_page(html, node.name.local.as_ref(), Some(name), &attr.value),
+                    format!(
+                        "<{} {}> event handler attribute",
+                        node.name.local.as_ref(),
+                        name
+                    ),
+                );
+            }
+            if URL_ATTRS.contains(&name) {
+                report.inspect(js_urls);
+                if is_javascript_url(&attr.value) {
+                    report.violation(
+                        js_urls,
+                        Found::in_page(html, node.name.local.as_ref(), Some(name), &attr.value),
+                        format!("<{} {}=\"{}\">", node.name.local.as_ref(), name, attr.value),
                     );
                 }
             }
         }
     }
+
+    // Inline styles (attrs + <style>) get the same url()/@import check as the bundle.
+    let rule = report.rule("[style] url()");
+    if let Ok(nodes) = doc.select("[style]") {
+        for node in nodes {
+            report.inspect(rule);
+            if let Some(style) = node.attributes.borrow().get("style")
+                && let Some(r) = css_references(style)
+                    .into_iter()
+                    .find(|r| !is_local_css_url(&r.url) && !linked.contains(&r.url))
+            {
+                report.violation(
+                    rule,
+                    Found::in_page(html, node.name.local.as_ref(), Some("style"), &r.url),
+                    format!(
+                        "style attribute on <{}> contains remote url()",
+                        node.name.local.as_ref()
+                    ),
+                );
+            }
+        }
+    }
+    let rule = report.rule("<style> url() / @import");
     if let Ok(nodes) = doc.select("style") {
         for node in nodes {
+            report.inspect(rule);
             let text = node.text_contents();
-            let lowered = text.to_ascii_lowercase();
-            if lowered.contains("url(http")
-                || lowered.contains("url(//")
-                || lowered.contains("@import")
+            if let Some(r) = css_references(&text)
+                .into_iter()
+                .find(|r| !is_local_css_url(&r.url) && !linked.contains(&r.url))
             {
-                anyhow::bail!("strict offline check failed: <style> contains remote url()");
+                report.violation(
+                    rule,
+                    Found::in_page(html, "style", None, &r.url),
+                    "<style> contains remote url()".to_string(),
+                );
             }
         }
     }
 
-    Ok(())
+    check_anchors(html, &doc, report);
+}
+
+const ANCHOR_RULE: &str = "a[href^=\"#\"] target";
+
+/// Every same-page link (`href="#…"`) lands on an element of the page: an `id`, or the `name` of an `<a>`. An
+/// empty fragment and `#top` scroll to the top of any page. Links into other pages of a paginated render name the
+/// file and are not checked.
+fn check_anchors(html: &str, doc: &kuchiki::NodeRef, report: &mut StrictReport) {
+    let rule = report.rule(ANCHOR_RULE);
+    let mut targets = HashSet::new();
+    for node in doc.descendants().elements() {
+        let attrs = node.attributes.borrow();
+        targets.extend(attrs.get("id").map(str::to_string));
+        if node.name.local.as_ref() == "a" {
+            targets.extend(attrs.get("name").map(str::to_string));
+        }
+    }
+    let Ok(links) = doc.select("a[href^='#']") else {
+        return;
+    };
+    for link in links {
+        report.inspect(rule);
+        let href = link
+            .attributes
+            .borrow()
+            .get("href")
+            .unwrap_or("")
+            .to_string();
+        let fragment = &href[1..];
+        if fragment.is_empty() || fragment.eq_ignore_ascii_case("top") || targets.contains(fragment)
+        {
+            continue;
+        }
+        report.violation(
+            rule,
+            Found::in_page(html, "a", Some("href"), &href),
+            format!("<a href=\"{}\"> points at no element of the page", href),
+        );
+    }
+}
+
+/// Attributes holding a URL that a `javascript:` scheme would turn into script.
+pub const URL_ATTRS: [&str; 5] = ["href", "src", "action", "formaction", "xlink:href"];
+
+pub fn is_event_handler_attr(name: &str) -> bool {
+    name.len() > 2 && name[..2].eq_ignore_ascii_case("on")
+}
+
+/// `javascript:` URL, ignoring case and the whitespace/control characters browsers strip from schemes.
+pub fn is_javascript_url(v: &str) -> bool {
+    let scheme: String = v
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace() && !c.is_control())
+        .take("javascript:".len())
+        .collect();
+    scheme.eq_ignore_ascii_case("javascript:")
+}
+
+/// Up to 80 characters from `start`, on one line.
+fn snippet(s: &str, start: usize) -> String {
+    s[start..]
+        .lines()
+        .next()
+        .unwrap_or("")
+        .chars()
+        .take(80)
+        .collect()
 }
 
 fn is_remote_auto_load(v: &str) -> bool {
@@ -107,3 +689,210 @@ fn is_disallowed_autoload(v: &str) -> bool {
     }
     is_remote_auto_load(s) || s.starts_with('/')
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_js_flags_scripts_and_handlers() {
+        let html = r##"<html><body><p id="a" onclick="x()">hi</p><script type="application/json">[]</script><a href="#a">ok</a></body></html>"##;
+        let mut report = check_strict_offline(&[html], "");
+        check_no_js(&[html], &mut report);
+        assert_eq!(report.violations.len(), 2);
+        assert!(report.into_result().is_err());
+
+        let mut report = check_strict_offline(&["<p>clean</p>"], "");
+        check_no_js(&["<p>clean</p>"], &mut report);
+        assert!(report.into_result().is_ok());
+    }
+
+    #[test]
+    fn same_page_links_must_have_a_target() {
+        let html = r##"<html><body><article id="post_1"><a href="#post_1">1</a><a href="#post_7">quote of #7</a><a href="#">top</a><a href="#Top">top</a><a name="legacy"></a><a href="#legacy">named</a><a href="topic-1-p2.html#post_9">next page</a><img src="https://cdn.example.com/a.png"></article></body></html>"##;
+        let mut report = check_strict_offline(&[html], "");
+        let dangling: Vec<&str> = report
+            .violations
+            .iter()
+            .filter(|v| v.rule == ANCHOR_RULE)
+            .map(|v| v.value.as_str())
+            .collect();
+        assert_eq!(dangling, ["#post_7"]);
+        assert_eq!(report.violations[1].offset, html.find("#post_7"));
+        let rule = |report: &StrictReport| {
+            let r = report.rules.iter().find(|r| r.rule == ANCHOR_RULE).unwrap();
+            (r.inspected, r.violations, r.warnings)
+        };
+        assert_eq!(rule(&report), (5, 1, 0));
+
+        // Only the anchor is let through; the remote image still fails the check.
+        report.allow_dangling_anchors();
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.warnings[0].value, "#post_7");
+        assert_eq!(rule(&report), (5, 0, 1));
+        assert_eq!(report.violations.len(), 1);
+        assert!(report.into_result().is_err());
+    }
+
+    #[test]
+    fn event_handlers_and_javascript_urls_are_violations() {
+        let html =
+            "<p><img src=\"a.png\" onerror=\"x()\"><a href=\" java\tscript:alert(1)\">x</a></p>";
+        let report = check_strict_offline(&[html], "");
+        let rules: Vec<_> = report.violations.iter().map(|v| v.rule).collect();
+        assert_eq!(rules, ["on* attribute", "javascript: url"]);
+    }
+
+    #[test]
+    fn inline_svg_references_must_be_local() {
+        let html = r##"<p><svg viewBox="0 0 10 10"><use href="#dot"/><use xlink:href="https://cdn.example.com/sprite.svg#i"/><image href="assets/img/a.png"/><image xlink:href="https://cdn.example.com/b.png"/></svg></p>"##;
+        let report = check_strict_offline(&[html], "");
+        let details: Vec<_> = report
+            .violations
+            .iter()
+            .map(|v| v.detail.as_str())
+            .collect();
+        assert_eq!(
+            details,
+            [
+                r#"<use xlink:href="https://cdn.example.com/sprite.svg#i"> is not local"#,
+                r#"<image xlink:href="https://cdn.example.com/b.png"> is not local"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn every_srcset_candidate_must_be_local() {
+        let html = r#"<p><img class="avatar" src="assets/avatar/a.png" srcset="assets/avatar/a.png 1x, assets/avatar/b.png 2x"><img src="assets/img/c.png" srcset="assets/img/c.png 1x, data:image/png;base64,AAAA 1.5x, https://cdn.example.com/d.png 2x"></p>"#;
+        let report = check_strict_offline(&[html], "");
+        let values: Vec<_> = report.violations.iter().map(|v| v.value.as_str()).collect();
+        assert_eq!(values, ["https://cdn.example.com/d.png"]);
+    }
+
+    #[test]
+    fn data_attributes_may_hold_remote_urls() {
+        let html = r#"<p><img src="assets/img/a.png" data-dtr-original-src="https://cdn.example.com/a.png"><a href="assets/img/b.png" data-dtr-original-href="https://cdn.example.com/b.png" data-href="javascript:x()">b</a></p>"#;
+        let report = check_strict_offline(&[html], "");
+        assert!(report.violations.is_empty(), "{:?}", report.violations);
+    }
+
+    #[test]
+    fn legacy_and_plugin_loads_must_be_local() {
+        for (html, rule) in [
+            (
+                r#"<table><tr><td background="https://x/bg.png">a</td></tr></table>"#,
+                "[background]",
+            ),
+            (
+                r#"<table background="//x/bg.png"><tr><td>a</td></tr></table>"#,
+                "[background]",
+            ),
+            (
+                r#"<object data="https://x/movie.swf"></object>"#,
+                "object[data]",
+            ),
+            (r#"<embed src="https://x/movie.swf">"#, "embed[src]"),
+            (
+                r#"<video poster="https://x/poster.jpg"></video>"#,
+                "video[poster]",
+            ),
+        ] {
+            let report = check_strict_offline(&[html], "");
+            let rules: Vec<_> = report.violations.iter().map(|v| v.rule).collect();
+            assert_eq!(rules, [rule], "{html}");
+            let local = html
+                .replace("https://x/", "assets/img/")
+                .replace("//x/", "assets/img/");
+            assert!(
+                check_strict_offline(&[&local], "").violations.is_empty(),
+                "{local}"
+            );
+        }
+    }
+
+    #[test]
+    fn css_references_skip_comments_and_strings() {
+        let refs = |css: &str| -> Vec<(String, bool)> {
+            css_references(css)
+                .into_iter()
+                .map(|r| (r.url, r.import))
+                .collect()
+        };
+        let url = |u: &str| (u.to_string(), false);
+        let import = |u: &str| (u.to_string(), true);
+
+        assert_eq!(refs("/* url(/a.png) */ a { content: \"url(/b.png)\" }"), []);
+        assert_eq!(
+            refs("a { b: url(  'https://x/a.png'  ) }"),
+            [url("https://x/a.png")]
+        );
+        assert_eq!(refs("a { b: URL(//x/a.png) }"), [url("//x/a.png")]);
+        assert_eq!(refs("a { b: url( c.png ) }"), [url("c.png")]);
+        assert_eq!(refs("a { b: url(\"c\\\".png\") }"), [url("c\".png")]);
+        assert_eq!(
+            refs("a { b: url(\\68 ttp://x/a.png) }"),
+            [url("http://x/a.png")]
+        );
+        assert_eq!(refs("a { my-url(x) curl(y) }"), []);
+        assert_eq!(
+            refs("@IMPORT /* c */ 'https://x/a.css' screen; @import url(\"b.css\");"),
+            [import("https://x/a.css"), import("b.css")]
+        );
+        assert_eq!(
+            refs(
+                "a { b: -webkit-image-set(\"https://x/a.png\" 1x, url(b.png) 2x type(\"image/png\")) }"
+            ),
+            [url("https://x/a.png"), url("b.png")]
+        );
+        assert_eq!(
+            refs("a { b: Image-Set('/a.png' 1x); c: \"d\" }"),
+            [url("/a.png")]
+        );
+    }
+
+    #[test]
+    fn css_strict_table() {
+        for (css, violations) in [
+            // Previously flagged: looks like a url() but sits in a comment or string.
+            ("/* background: url(/a.png) */", 0),
+            ("a::before { content: \"url(/a.png)\" }", 0),
+            // Previously missed: whitespace inside the quotes, uppercase, escapes, protocol-relative imports.
+            ("a { b: url(\" https://x/a.png\") }", 1),
+            ("a { b: URL(https://x/a.png) }", 1),
+            ("a { b: url(\\68 ttps://x/a.png) }", 1),
+            ("@import '//x/a.css';", 1),
+            ("@import url(https://x/a.css);", 1),
+            ("a { b: url(ftp://x/a.png) }", 1),
+            // Fine either way.
+            ("a { b: url(data:image/png;base64,AAAA) }", 0),
+            ("a { b: url(\"DATA:image/svg+xml,<svg/>\") }", 0),
+            ("a { filter: url(#blur) }", 0),
+            ("a { b: url(../img/a.png) }", 0),
+            ("a { b: url() }", 0),
+            ("@import 'local.css';", 0),
+            ("a { b: url(/root.png) }", 1),
+        ] {
+            let report = check_strict_offline(&[], css);
+            assert_eq!(report.violations.len(), violations, "{css}");
+        }
+    }
+
+    #[test]
+    fn report_lists_violation_details() {
+        let html = r#"<html><body><img src="https://cdn.example.com/a.png"><img src="assets/img/b.png"></body></html>"#;
+        let css = r#"body { background: url("https://cdn.example.com/bg.png"); }"#;
+        let report = check_strict_offline(&[html], css);
+
+        let img = report.rules.iter().find(|r| r.rule == "img[src]").unwrap();
+        assert_eq!(img.inspected, 2);
+        assert_eq!(img.violations, 1);
+        assert_eq!(report.violations.len(), 2);
+        assert!(
+            report
+                .violations
+                .iter()
+                .any(|v| v.detail.contains("https://cdn.example.com/a.png"))
+        );
+        assert!(report.into_result().is_err());
+    }
+}
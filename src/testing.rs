@@ -0,0 +1,230 @@
+//! Golden-file support for tests: pages are compared by structure rather than byte for byte.
+
+use std::fmt::Write as _;
+use std::path::PathBuf;
+
+use kuchiki::NodeRef;
+use kuchiki::traits::TendrilSink as _;
+
+/// Set to a non-empty value to rewrite the goldens from the current output instead of comparing.
+const UPDATE_ENV: &str = "DTR_UPDATE_GOLDENS";
+
+/// Compare `html` against `tests/goldens/{name}.html`, or rewrite that file when `DTR_UPDATE_GOLDENS` is set.
+pub fn assert_golden(name: &str, html: &str) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(format!("tests/goldens/{name}.html"));
+    if std::env::var_os(UPDATE_ENV).is_some_and(|v| !v.is_empty()) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, html).unwrap();
+        return;
+    }
+    let golden = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "read {}: {e}; run with {UPDATE_ENV}=1 to create it",
+            path.display()
+        )
+    });
+    let (expected, actual) = (normalize_html(&golden), normalize_html(html));
+    if expected != actual {
+        panic!(
+            "{} is out of date; rerun with {UPDATE_ENV}=1 if the change is intended\n{}",
+            path.display(),
+            first_difference(&expected, &actual)
+        );
+    }
+}
+
+/// One line per element and text run. Attributes are sorted, class lists sorted and deduplicated, whitespace
+/// outside `<pre>` collapsed, and comments dropped. A `<style>` element is reduced to the selectors of its rules,
+/// so recoloring the theme is not a change but renaming a selector is. Script bodies are dropped.
+pub fn normalize_html(html: &str) -> String {
+    let document = kuchiki::parse_html().one(html);
+    let mut out = String::new();
+    for child in document.children() {
+        write_node(&mut out, &child, 0, false);
+    }
+    out
+}
+
+fn write_node(out: &mut String, node: &NodeRef, depth: usize, in_pre: bool) {
+    let indent = "  ".repeat(depth);
+    if let Some(text) = node.as_text() {
+        let text = text.borrow();
+        if in_pre {
+            let _ = writeln!(out, "{indent}{:?}", text.as_str());
+        } else {
+            let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+            if !collapsed.is_empty() {
+                let _ = writeln!(out, "{indent}{collapsed:?}");
+            }
+        }
+        return;
+    }
+    let Some(element) = node.as_element() else {
+        return;
+    };
+
+    let tag = element.name.local.as_ref();
+    let _ = write!(out, "{indent}<{tag}");
+    let attributes = element.attributes.borrow();
+    let mut attrs: Vec<(&str, String)> = attributes
+        .map
+        .iter()
+        .map(|(name, attr)| {
+            let value = if name.local.as_ref() == "class" {
+                let mut classes: Vec<&str> = attr.value.split_whitespace().collect();
+                classes.sort_unstable();
+                classes.dedup();
+                classes.join(" ")
+            } else {
+                attr.value.clone()
+            };
+            (name.local.as_ref(), value)
+        })
+        .collect();
+    attrs.sort();
+    for (name, value) in attrs {
+        let _ = write!(out, " {name}={value:?}");
+    }
+    out.push_str(">\n");
+
+    match tag {
+        "style" => {
+            for selector in css_selectors(&node.text_contents()) {
+                let _ = writeln!(out, "{indent}  {selector}");
+            }
+        }
+        "script" => {}
+        _ => {
+            let in_pre = in_pre || tag == "pre";
+            for child in node.children() {
+                write_node(out, &child, depth + 1, in_pre);
+            }
+        }
+    }
+}
+
+/// Selector of every style rule, prefixed by the at-rules it is nested in, e.g.
+/// `@media (max-width: 600px) > .dtr-cooked`. Sorted and deduplicated.
+fn css_selectors(css: &str) -> Vec<String> {
+    let mut selectors = Vec::new();
+    let mut groups: Vec<String> = Vec::new();
+    let mut prelude = String::new();
+    let mut chars = css.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut prev = '\0';
+                for c in chars.by_ref() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            }
+            '{' => {
+                let head = prelude.split_whitespace().collect::<Vec<_>>().join(" ");
+                prelude.clear();
+                if head.starts_with('@') {
+                    groups.push(head);
+                    continue;
+                }
+                let mut path = groups.clone();
+                path.push(head);
+                selectors.push(path.join(" > "));
+                skip_block(&mut chars);
+            }
+            '}' => {
+                groups.pop();
+                prelude.clear();
+            }
+            // Statement at-rules and the declarations of `@font-face`-style blocks.
+            ';' => prelude.clear(),
+            _ => prelude.push(c),
+        }
+    }
+    selectors.sort();
+    selectors.dedup();
+    selectors
+}
+
+/// Consume a declaration block up to its closing brace, minding strings.
+fn skip_block(chars: &mut impl Iterator<Item = char>) {
+    let mut depth = 1;
+    let mut quote = None;
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '{') => depth += 1,
+            (None, '}') => {
+                depth -= 1;
+                if depth == 0 {
+                    return;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn first_difference(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let at = expected
+        .iter()
+        .zip(&actual)
+        .position(|(e, a)| e != a)
+        .unwrap_or(expected.len().min(actual.len()));
+    let start = at.saturating_sub(3);
+    let mut out = format!("first difference at normalized line {}:\n", at + 1);
+    for (label, lines) in [("golden", &expected), ("actual", &actual)] {
+        let _ = writeln!(out, "--- {label}");
+        for line in lines.iter().skip(start).take(at - start + 4) {
+            let _ = writeln!(out, "{line}");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalization_ignores_formatting_and_class_order() {
+        let a = r#"<!DOCTYPE html><!-- stamp --><div class="b a"  id="x">
+            <p>hello   world</p></div><pre>  keep
+  this</pre>"#;
+        let b = r#"<!DOCTYPE html><div id="x" class="a b a"><p>hello world</p></div><pre>  keep
+  this</pre>"#;
+        assert_eq!(normalize_html(a), normalize_html(b));
+        assert_ne!(
+            normalize_html("<pre> a</pre>"),
+            normalize_html("<pre>a</pre>")
+        );
+    }
+
+    #[test]
+    fn stylesheets_compare_by_selector() {
+        let page = |css: &str| normalize_html(&format!("<style>{css}</style>"));
+        let theme = "/* theme */ :root { --bg: #fff; }\n@media (prefers-color-scheme: dark) { :root { --bg: #000; } }\n.dtr-cooked pre { content: \"}\"; }";
+        assert_eq!(page(theme), page(&theme.replace("#000", "#111")));
+        assert_ne!(
+            page(theme),
+            page(&theme.replace(".dtr-cooked pre", ".dtr-post pre"))
+        );
+        assert_eq!(
+            css_selectors(theme),
+            [
+                ".dtr-cooked pre",
+                ":root",
+                "@media (prefers-color-scheme: dark) > :root"
+            ]
+        );
+    }
+}
@@ -1,12 +1,253 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
 use serde::Deserialize;
 
+/// The topic file is bigger than `--max-input-mb`.
+#[derive(Debug)]
+pub struct InputTooLarge {
+    pub path: PathBuf,
+    /// From the file's metadata; `None` when the limit was hit while reading (e.g. from a pipe).
+    pub bytes: Option<u64>,
+    pub limit: u64,
+}
+
+impl std::fmt::Display for InputTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.bytes {
+            Some(bytes) => write!(f, "{} is {} bytes", self.path.display(), bytes)?,
+            None => write!(
+                f,
+                "{} is larger than {} bytes",
+                self.path.display(),
+                self.limit
+            )?,
+        }
+        write!(
+            f,
+            ", over --max-input-mb; is it a single topic? raise the limit if it is"
+        )
+    }
+}
+
+impl std::error::Error for InputTooLarge {}
+
+/// The export holds noticeably fewer posts than the topic's declared `posts_count`, usually because only the first
+/// chunk of the post stream was saved. A warning, or an error under `--require-complete`.
+#[derive(Debug, Clone, Copy)]
+pub struct IncompleteExport {
+    pub topic_id: u64,
+    pub exported: u64,
+    pub declared: u64,
+}
+
+impl IncompleteExport {
+    /// Exported posts as a percentage of the declared count.
+    pub fn percent(&self) -> f64 {
+        self.exported as f64 * 100.0 / self.declared as f64
+    }
+}
+
+impl std::fmt::Display for IncompleteExport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "topic.json holds {} of the {} posts topic {} declares ({:.1}%); /t/{}.json only includes the first \
+             posts of a long topic, so export the whole stream with tools/discourse_topic_json_exporter.user.js or \
+             merge the page exports with tools/merge_topic_json_pages.py",
+            self.exported,
+            self.declared,
+            self.topic_id,
+            self.percent(),
+            self.topic_id
+        )
+    }
+}
+
+impl std::error::Error for IncompleteExport {}
+
+/// Parse the topic file at `path` straight from disk, refusing files over `limit` bytes, and hash it for the
+/// fingerprint on the way. Hostile nesting can't overflow the stack: unknown fields are skipped without
+/// recursion, and serde_json gives up past 128 levels.
+pub fn read_topic(path: &Path, limit: u64) -> anyhow::Result<(TopicJson, blake3::Hash)> {
+    let file = std::fs::File::open(path).with_context(|| format!("read {}", path.display()))?;
+    let bytes = file
+        .metadata()
+        .with_context(|| format!("read {}", path.display()))?
+        .len();
+    if bytes > limit {
+        return Err(InputTooLarge {
+            path: path.to_path_buf(),
+            bytes: Some(bytes),
+            limit,
+        }
+        .into());
+    }
+    let mut reader = HashingReader {
+        inner: std::io::BufReader::new(file).take(limit + 1),
+        hasher: blake3::Hasher::new(),
+        read: 0,
+    };
+    let parsed = serde_json::from_reader(&mut reader);
+    if reader.read > limit {
+        return Err(InputTooLarge {
+            path: path.to_path_buf(),
+            bytes: None,
+            limit,
+        }
+        .into());
+    }
+    let topic = parsed.context("parse topic.json")?;
+    Ok((topic, reader.hasher.finalize()))
+}
+
+/// Hashes and counts what passes through.
+struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+    read: u64,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct TopicJson {
     pub id: u64,
     pub title: String,
+    #[serde(default)]
+    pub slug: Option<String>,
+    #[serde(default)]
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub posts_count: Option<u64>,
+    #[serde(default)]
+    pub views: Option<u64>,
+    #[serde(default)]
+    pub category_id: Option<u64>,
+    #[serde(default)]
+    pub tags: Vec<Tag>,
+    #[serde(default)]
+    pub closed: bool,
+    #[serde(default)]
+    pub archived: bool,
+    #[serde(default)]
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub last_posted_at: Option<String>,
+    #[serde(default)]
+    pub bumped_at: Option<String>,
+    /// `regular`, or `private_message` for a PM thread.
+    #[serde(default)]
+    pub archetype: Option<String>,
+    #[serde(default)]
+    pub details: Option<TopicDetails>,
     pub post_stream: PostStream,
 }
 
+impl TopicJson {
+    /// `locale` as a BCP 47 language tag for `lang` attributes (`zh_CN` → `zh-CN`), if the topic has one.
+    pub fn language_tag(&self) -> Option<String> {
+        self.locale
+            .as_deref()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(|l| l.replace('_', "-"))
+    }
+
+    /// Number of posts in the topic as declared by Discourse, falling back to the exported stream length.
+    pub fn total_posts(&self) -> u64 {
+        self.posts_count
+            .unwrap_or(self.post_stream.posts.len() as u64)
+    }
+
+    /// The exported and declared post counts, when the export holds less than `min_percent` of the posts the
+    /// topic declares.
+    pub fn incomplete_export(&self, min_percent: u8) -> Option<IncompleteExport> {
+        let declared = self.posts_count.filter(|&n| n > 0)?;
+        let gap = IncompleteExport {
+            topic_id: self.id,
+            exported: self.post_stream.posts.len() as u64,
+            declared,
+        };
+        (gap.percent() < f64::from(min_percent)).then_some(gap)
+    }
+
+    /// When the topic last changed: `last_posted_at`, else `bumped_at`, else the newest exported post.
+    pub fn last_activity(&self) -> Option<&str> {
+        self.last_posted_at
+            .as_deref()
+            .or(self.bumped_at.as_deref())
+            .or_else(|| {
+                self.post_stream
+                    .posts
+                    .iter()
+                    .filter_map(|p| p.created_at.as_deref())
+                    .max()
+            })
+    }
+
+    pub fn is_private_message(&self) -> bool {
+        self.archetype.as_deref() == Some("private_message")
+    }
+
+    /// Users, then groups, a private message was sent to.
+    pub fn participants(&self) -> Vec<String> {
+        let Some(details) = &self.details else {
+            return Vec::new();
+        };
+        let users = details
+            .allowed_users
+            .iter()
+            .filter_map(|u| u.username.clone());
+        let groups = details.allowed_groups.iter().filter_map(|g| g.name.clone());
+        users.chain(groups).collect()
+    }
+}
+
+/// A topic tag: a bare name, or an object with one on newer Discourse versions.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Tag {
+    Name(String),
+    Object { name: String },
+}
+
+impl Tag {
+    pub fn name(&self) -> &str {
+        match self {
+            Tag::Name(name) | Tag::Object { name } => name,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct TopicDetails {
+    #[serde(default)]
+    pub allowed_users: Vec<AllowedUser>,
+    #[serde(default)]
+    pub allowed_groups: Vec<AllowedGroup>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AllowedUser {
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AllowedGroup {
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct PostStream {
     pub posts: Vec<Post>,
@@ -24,5 +265,93 @@ pub struct Post {
     #[serde(default)]
     pub created_at: Option<String>,
     #[serde(default)]
+    pub updated_at: Option<String>,
+    /// Revision number; 1 for a post that was never edited.
+    #[serde(default)]
+    pub version: Option<u64>,
+    #[serde(default)]
     pub cooked: Option<String>,
+    /// 1 = regular, 2 = moderator action, 3 = small action, 4 = whisper.
+    #[serde(default)]
+    pub post_type: Option<u64>,
+    #[serde(default)]
+    pub action_code: Option<String>,
+    #[serde(default)]
+    pub reply_to_post_number: Option<u64>,
+    #[serde(default)]
+    pub reply_to_user: Option<ReplyToUser>,
+}
+
+pub const POST_TYPE_SMALL_ACTION: u64 = 3;
+pub const POST_TYPE_WHISPER: u64 = 4;
+
+impl Post {
+    pub fn is_small_action(&self) -> bool {
+        self.post_type == Some(POST_TYPE_SMALL_ACTION) && self.action_code.is_some()
+    }
+
+    pub fn is_whisper(&self) -> bool {
+        self.post_type == Some(POST_TYPE_WHISPER)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplyToUser {
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exports_below_the_threshold_are_reported_with_both_counts() {
+        let topic = |posts_count: Option<u64>, exported: usize| -> TopicJson {
+            let posts: Vec<_> = (1..=exported)
+                .map(|n| serde_json::json!({"post_number": n, "cooked": "<p>x</p>"}))
+                .collect();
+            serde_json::from_value(serde_json::json!({
+                "id": 7,
+                "title": "T",
+                "posts_count": posts_count,
+                "post_stream": {"posts": posts},
+            }))
+            .unwrap()
+        };
+
+        let gap = topic(Some(800), 20).incomplete_export(90).unwrap();
+        assert_eq!((gap.exported, gap.declared), (20, 800));
+        assert_eq!(
+            gap.to_string(),
+            "topic.json holds 20 of the 800 posts topic 7 declares (2.5%); /t/7.json only includes the first \
+             posts of a long topic, so export the whole stream with tools/discourse_topic_json_exporter.user.js or \
+             merge the page exports with tools/merge_topic_json_pages.py"
+        );
+        assert!(topic(Some(20), 18).incomplete_export(90).is_none());
+        assert!(topic(Some(20), 17).incomplete_export(90).is_some());
+        assert!(topic(Some(20), 17).incomplete_export(0).is_none());
+        assert!(topic(None, 1).incomplete_export(90).is_none());
+        assert!(topic(Some(0), 0).incomplete_export(90).is_none());
+    }
+
+    #[test]
+    fn locales_become_language_tags() {
+        let topic = |locale: Option<&str>| -> TopicJson {
+            serde_json::from_value(serde_json::json!({
+                "id": 7,
+                "title": "T",
+                "locale": locale,
+                "post_stream": {"posts": []},
+            }))
+            .unwrap()
+        };
+        assert_eq!(
+            topic(Some("zh_CN")).language_tag().as_deref(),
+            Some("zh-CN")
+        );
+        assert_eq!(topic(Some("ar")).language_tag().as_deref(), Some("ar"));
+        assert_eq!(topic(Some(" ")).language_tag(), None);
+        assert_eq!(topic(None).language_tag(), None);
+    }
 }
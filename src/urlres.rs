@@ -0,0 +1,260 @@
+//! Resolving the references found in cooked HTML and stylesheets; every call site goes through [`resolve`].
+//!
+//! A reference resolves against the document it appears in, by the URL standard. A local `--css` file has no
+//! host of its own: its relative references stay files, and root-relative, scheme-relative and absolute ones
+//! go to the forum.
+
+use std::path::{Path, PathBuf};
+
+use url::Url;
+
+/// The document a reference appears in.
+#[derive(Debug, Clone, Copy)]
+pub enum Origin<'a> {
+    /// A page or stylesheet on the web. Cooked HTML and the `<style>`s of the forum page count as the forum's.
+    Remote(&'a Url),
+    /// A file on disk.
+    Local(&'a Path),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolved {
+    Remote(Url),
+    /// Query and fragment dropped, percent-escapes decoded.
+    Local(PathBuf),
+}
+
+/// Schemes the fetcher can download.
+pub const FETCHABLE: &[&str] = &["http", "https"];
+
+/// Resolve `raw` (surrounding whitespace ignored) as found in `origin`, with `site` standing in for the host of
+/// local files. When `schemes` is not empty, a remote result must use one of them.
+pub fn resolve(
+    site: &Url,
+    origin: Origin<'_>,
+    raw: &str,
+    schemes: &[&str],
+) -> anyhow::Result<Resolved> {
+    let raw = raw.trim();
+    let resolved = match origin {
+        Origin::Remote(document) => Resolved::Remote(document.join(raw)?),
+        Origin::Local(_) if raw.starts_with('/') || Url::parse(raw).is_ok() => {
+            Resolved::Remote(site.join(raw)?)
+        }
+        Origin::Local(path) => {
+            let file = Url::from_file_path(std::path::absolute(path)?)
+                .map_err(|()| anyhow::anyhow!("{} is not a file path", path.display()))?;
+            let url = file.join(raw)?;
+            Resolved::Local(
+                url.to_file_path()
+                    .map_err(|()| anyhow::anyhow!("{url} is not a file path"))?,
+            )
+        }
+    };
+    if let Resolved::Remote(url) = &resolved
+        && !schemes.is_empty()
+        && !schemes.contains(&url.scheme())
+    {
+        anyhow::bail!("{raw}: {}: URLs can't be downloaded", url.scheme());
+    }
+    Ok(resolved)
+}
+
+/// [`resolve`] for a reference on the forum itself (cooked HTML, the forum page), any scheme.
+pub fn resolve_url(base_url: &Url, raw: &str) -> anyhow::Result<Url> {
+    match resolve(base_url, Origin::Remote(base_url), raw, &[])? {
+        Resolved::Remote(url) => Ok(url),
+        Resolved::Local(_) => unreachable!("a remote origin resolves to URLs"),
+    }
+}
+
+/// A route of the forum app (`t/{slug}/{id}`, `u/{username}`, …) under `base_url`, taken as the forum's root even
+/// without the trailing slash of a subfolder install.
+pub fn forum_route(base_url: &Url, route: &str) -> anyhow::Result<Url> {
+    let mut root = base_url.clone();
+    if !root.path().ends_with('/') {
+        root.set_path(&format!("{}/", root.path()));
+    }
+    Ok(root.join(route.trim_start_matches('/'))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forum_routes_stay_under_a_subfolder() {
+        for base in [
+            "https://forum.example.com/sub",
+            "https://forum.example.com/sub/",
+        ] {
+            let base = Url::parse(base).unwrap();
+            assert_eq!(
+                forum_route(&base, "t/a-topic/7/3").unwrap().as_str(),
+                "https://forum.example.com/sub/t/a-topic/7/3"
+            );
+        }
+        let root = Url::parse("https://forum.example.com").unwrap();
+        assert_eq!(
+            forum_route(&root, "/t/7").unwrap().as_str(),
+            "https://forum.example.com/t/7"
+        );
+    }
+
+    #[test]
+    fn references_resolve_against_their_document() {
+        let site = Url::parse("https://forum.example.com/sub/").unwrap();
+        let cdn_css = Url::parse("http://cdn.example.net/assets/css/site.css?v=3").unwrap();
+        let local_css = Path::new("/work/theme/site.css");
+        let remote = |url: &str| Some(Resolved::Remote(Url::parse(url).unwrap()));
+        let local = |path: &str| Some(Resolved::Local(PathBuf::from(path)));
+
+        let cases = [
+            // Cooked HTML, resolved against a forum in a subfolder.
+            (
+                Origin::Remote(&site),
+                "https://cdn.example.com/a.png",
+                remote("https://cdn.example.com/a.png"),
+            ),
+            (
+                Origin::Remote(&site),
+                "HTTPS://CDN.example.com/a.png",
+                remote("https://cdn.example.com/a.png"),
+            ),
+            (
+                Origin::Remote(&site),
+                "//cdn.example.com/a.png",
+                remote("https://cdn.example.com/a.png"),
+            ),
+            (
+                Origin::Remote(&site),
+                "/uploads/a.png",
+                remote("https://forum.example.com/uploads/a.png"),
+            ),
+            (
+                Origin::Remote(&site),
+                " img/a.png ",
+                remote("https://forum.example.com/sub/img/a.png"),
+            ),
+            (
+                Origin::Remote(&site),
+                "./img/../a.png",
+                remote("https://forum.example.com/sub/a.png"),
+            ),
+            (
+                Origin::Remote(&site),
+                "../a.png",
+                remote("https://forum.example.com/a.png"),
+            ),
+            (
+                Origin::Remote(&site),
+                "#post_2",
+                remote("https://forum.example.com/sub/#post_2"),
+            ),
+            (
+                Origin::Remote(&site),
+                "data:image/gif;base64,R0lG",
+                remote("data:image/gif;base64,R0lG"),
+            ),
+            (
+                Origin::Remote(&site),
+                "mailto:a@example.com",
+                remote("mailto:a@example.com"),
+            ),
+            // A stylesheet on another host and scheme.
+            (
+                Origin::Remote(&cdn_css),
+                "//fonts.example.com/f.woff2",
+                remote("http://fonts.example.com/f.woff2"),
+            ),
+            (
+                Origin::Remote(&cdn_css),
+                "/img/a.png",
+                remote("http://cdn.example.net/img/a.png"),
+            ),
+            (
+                Origin::Remote(&cdn_css),
+                "../font/f.woff2?#iefix",
+                remote("http://cdn.example.net/assets/font/f.woff2?#iefix"),
+            ),
+            (
+                Origin::Remote(&cdn_css),
+                "a.png",
+                remote("http://cdn.example.net/assets/css/a.png"),
+            ),
+            // A local file: relative stays local, everything else goes to the forum.
+            (
+                Origin::Local(local_css),
+                "fonts/a%20b.woff2?v=1#x",
+                local("/work/theme/fonts/a b.woff2"),
+            ),
+            (
+                Origin::Local(local_css),
+                "../img/a.png",
+                local("/work/img/a.png"),
+            ),
+            (
+                Origin::Local(local_css),
+                "#frag",
+                local("/work/theme/site.css"),
+            ),
+            (
+                Origin::Local(local_css),
+                "/uploads/a.png",
+                remote("https://forum.example.com/uploads/a.png"),
+            ),
+            (
+                Origin::Local(local_css),
+                "//cdn.example.com/a.png",
+                remote("https://cdn.example.com/a.png"),
+            ),
+            (
+                Origin::Local(local_css),
+                "http://cdn.example.com/a.png",
+                remote("http://cdn.example.com/a.png"),
+            ),
+            (
+                Origin::Local(local_css),
+                "data:font/woff2;base64,AA",
+                remote("data:font/woff2;base64,AA"),
+            ),
+            // Not URLs at all.
+            (Origin::Remote(&site), "http://[::1", None),
+            (Origin::Local(local_css), "http://[::1", None),
+        ];
+        for (origin, raw, expected) in cases {
+            assert_eq!(
+                resolve(&site, origin, raw, &[]).ok(),
+                expected,
+                "{raw} in {origin:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn schemes_limit_remote_results_only() {
+        let site = Url::parse("https://forum.example.com/").unwrap();
+        for raw in [
+            "data:image/png;base64,AA",
+            "mailto:a@example.com",
+            "file:///etc/passwd",
+        ] {
+            let e = resolve(&site, Origin::Remote(&site), raw, FETCHABLE).unwrap_err();
+            assert!(e.to_string().contains("can't be downloaded"), "{e}");
+        }
+        assert!(resolve(&site, Origin::Remote(&site), "a.png", FETCHABLE).is_ok());
+        assert!(
+            resolve(
+                &site,
+                Origin::Local(Path::new("/w/site.css")),
+                "a.png",
+                FETCHABLE
+            )
+            .is_ok()
+        );
+        assert_eq!(
+            resolve_url(&site, "mailto:a@example.com").unwrap().as_str(),
+            "mailto:a@example.com"
+        );
+    }
+}
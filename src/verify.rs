@@ -0,0 +1,119 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use kuchiki::traits::TendrilSink as _;
+
+use crate::cli::VerifyArgs;
+use crate::report::{self, Cell, Tone};
+use crate::strict::{self, StrictReport};
+
+/// `verify`: check rendered pages without re-rendering, print every violation, and fail if there are any.
+pub fn verify(args: VerifyArgs) -> anyhow::Result<()> {
+    let (mut report, stylesheets) = verify_files(&args.files, args.no_js)?;
+    if args.allow_dangling_anchors {
+        report.allow_dangling_anchors();
+    }
+    for w in &report.warnings {
+        let file = w.page.map(|i| args.files[i].display().to_string());
+        eprintln!("warning: {}: {}", file.unwrap_or_default(), w.detail);
+    }
+    if report.violations.is_empty() {
+        eprintln!(
+            "{} page(s) and {} stylesheet(s) are offline-clean",
+            args.files.len(),
+            stylesheets.len()
+        );
+        return Ok(());
+    }
+
+    let css_label = match stylesheets.as_slice() {
+        [one] => one.display().to_string(),
+        _ => "(stylesheets)".to_string(),
+    };
+    let mut table = report::Table::new(["file", "offset", "rule", "where", "value"]);
+    for v in &report.violations {
+        let file = match v.page {
+            Some(i) => args.files[i].display().to_string(),
+            None => css_label.clone(),
+        };
+        let place = match (&v.element, &v.attribute) {
+            (Some(el), Some(attr)) => format!("<{} {}>", el, attr),
+            (Some(el), None) => format!("<{}>", el),
+            _ => String::new(),
+        };
+        table.row(vec![
+            Cell::text(file),
+            v.offset
+                .map_or_else(|| Cell::text(""), |o| Cell::count(o as u64)),
+            Cell::text(v.rule),
+            Cell::text(place),
+            Cell::text(truncate(&v.value, 80)).tone(Tone::Failure),
+        ]);
+    }
+    print!("{}", table.render(report::color_enabled(args.no_color)));
+    anyhow::bail!(
+        "{} strict-offline violation(s) found",
+        report.violations.len()
+    )
+}
+
+/// Run the strict-offline checks over `pages` and the local stylesheets they link to. Returns the report and
+/// the stylesheets that were read; violations with no `page` come from those (concatenated, in that order).
+pub fn verify_files(
+    pages: &[PathBuf],
+    no_js: bool,
+) -> anyhow::Result<(StrictReport, Vec<PathBuf>)> {
+    let mut htmls = Vec::with_capacity(pages.len());
+    let mut stylesheets: Vec<PathBuf> = Vec::new();
+    for path in pages {
+        let html =
+            std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        for sheet in linked_stylesheets(path, &html) {
+            if !stylesheets.contains(&sheet) {
+                stylesheets.push(sheet);
+            }
+        }
+        htmls.push(html);
+    }
+
+    let mut css = String::new();
+    for sheet in &stylesheets {
+        let text =
+            std::fs::read_to_string(sheet).with_context(|| format!("read {}", sheet.display()))?;
+        css.push_str(&text);
+        css.push('\n');
+    }
+
+    let pages: Vec<&str> = htmls.iter().map(String::as_str).collect();
+    let mut report = strict::check_strict_offline(&pages, &css);
+    if no_js {
+        strict::check_no_js(&pages, &mut report);
+    }
+    Ok((report, stylesheets))
+}
+
+/// Local files named by `<link rel="stylesheet" href>`, relative to the page. Remote ones are left to the
+/// `link[href]` rule.
+fn linked_stylesheets(page: &Path, html: &str) -> Vec<PathBuf> {
+    let base = page.parent().unwrap_or(Path::new(""));
+    let doc = kuchiki::parse_html().one(html);
+    let Ok(links) = doc.select("link[rel~=stylesheet][href]") else {
+        return Vec::new();
+    };
+    links
+        .filter_map(|link| {
+            let href = link.attributes.borrow().get("href")?.to_string();
+            let path = href.split(['?', '#']).next()?.to_string();
+            let local = !path.is_empty() && !path.starts_with('/') && !path.contains(':');
+            local.then(|| base.join(path))
+        })
+        .collect()
+}
+
+fn truncate(s: &str, max_chars: usize) -> String {
+    if s.chars().count() <= max_chars {
+        return s.to_string();
+    }
+    let cut: String = s.chars().take(max_chars).collect();
+    format!("{}…", cut)
+}
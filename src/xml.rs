@@ -0,0 +1,35 @@
+//! Escaping for the XML the tool writes: EPUB documents and feeds.
+
+use std::borrow::Cow;
+
+/// `s` escaped for XML text or a double-quoted attribute.
+pub(crate) fn escape_xml(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    escape_xml_into(s, true, &mut out);
+    out
+}
+
+/// Escapes markup characters and drops the control characters XML 1.0 does not allow.
+pub(crate) fn escape_xml_into(s: &str, attribute: bool, out: &mut String) {
+    for c in s.chars().filter(|&c| is_xml_char(c)) {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' if attribute => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// `s` without the control characters XML 1.0 does not allow, for a writer that escapes the rest.
+pub(crate) fn xml_chars(s: &str) -> Cow<'_, str> {
+    match s.chars().all(is_xml_char) {
+        true => Cow::Borrowed(s),
+        false => Cow::Owned(s.chars().filter(|&c| is_xml_char(c)).collect()),
+    }
+}
+
+fn is_xml_char(c: char) -> bool {
+    c >= ' ' || matches!(c, '\t' | '\n' | '\r')
+}
@@ -0,0 +1,16 @@
+#[allow(dead_code)]
+#[path = "../examples/render_from_memory.rs"]
+mod render_from_memory;
+
+#[tokio::test]
+async fn render_from_memory_prints_a_self_contained_page() {
+    let html = render_from_memory::render(render_from_memory::TOPIC_JSON)
+        .await
+        .unwrap();
+    assert!(html.starts_with("<!DOCTYPE html>"), "{html}");
+    assert!(html.contains("<title>Rendering from memory</title>"));
+    assert!(html.contains("The whole topic fits in a string."));
+    assert!(html.contains("<style>"));
+    let report = discourse_topic_render::check_strict_offline(&[&html], "");
+    assert!(report.violations.is_empty(), "{:?}", report.violations);
+}
@@ -0,0 +1,94 @@
+use std::path::Path;
+
+use tempfile::tempdir;
+
+fn write(path: &Path, contents: &str) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, contents).unwrap();
+}
+
+fn gc_args(dir: &Path, dry_run: bool, force: bool) -> discourse_topic_render::GcArgs {
+    discourse_topic_render::GcArgs {
+        dir: dir.to_path_buf(),
+        assets_dir_name: "assets".to_string(),
+        dry_run,
+        force,
+    }
+}
+
+#[test]
+fn gc_removes_only_assets_nothing_refers_to() {
+    let tmp = tempdir().unwrap();
+    let out = tmp.path().join("out");
+    write(
+        &out.join("topic-123.html"),
+        r#"<!DOCTYPE html><html><head><meta name="dtr-fingerprint" content="abc"><link rel="stylesheet" href="assets/css/site.css"></head><body><img src="assets/avatar/a.png"><img srcset="assets/img/b.png 2x"></body></html>"#,
+    );
+    write(
+        &out.join("assets/css/site.css"),
+        "@font-face { font-family: A; src: url(\"../font/a.woff2\"); }",
+    );
+    let kept = [
+        "assets/css/site.css",
+        "assets/font/a.woff2",
+        "assets/avatar/a.png",
+        "assets/img/b.png",
+    ];
+    let orphans = ["assets/font/old.woff2", "assets/img/old.png"];
+    for path in kept.iter().chain(&orphans).skip(1) {
+        write(&out.join(path), "x");
+    }
+    let manifest = out.join("topic-123.assets-manifest.json");
+    write(
+        &manifest,
+        r#"[{"path": "assets/img/b.png", "source": "https://forum.example.com/b.png"}, {"path": "assets/img/old.png", "source": "https://forum.example.com/old.png"}]"#,
+    );
+
+    let canonical = std::fs::canonicalize(&out).unwrap();
+    let expected: Vec<_> = orphans.iter().map(|p| canonical.join(p)).collect();
+    assert_eq!(
+        discourse_topic_render::find_orphans(&out, "assets").unwrap(),
+        expected
+    );
+
+    discourse_topic_render::gc(gc_args(&out, true, false)).unwrap();
+    assert!(orphans.iter().all(|p| out.join(p).exists()));
+    assert!(
+        std::fs::read_to_string(&manifest)
+            .unwrap()
+            .contains("old.png")
+    );
+
+    discourse_topic_render::gc(gc_args(&out, false, false)).unwrap();
+    assert!(orphans.iter().all(|p| !out.join(p).exists()));
+    assert!(kept.iter().all(|p| out.join(p).exists()));
+    // The manifest no longer lists what was removed.
+    let entries: Vec<serde_json::Value> =
+        serde_json::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["path"], "assets/img/b.png");
+    assert!(
+        discourse_topic_render::find_orphans(&out, "assets")
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn gc_refuses_directories_without_an_asset_manifest() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().join("site");
+    // A fingerprinted page alone doesn't make the directory a render.
+    write(
+        &dir.join("index.html"),
+        r#"<meta name="dtr-fingerprint" content="abc"><p>not a render</p>"#,
+    );
+    write(&dir.join("assets/img/a.png"), "x");
+
+    let err = discourse_topic_render::gc(gc_args(&dir, false, false)).unwrap_err();
+    assert!(err.to_string().contains("--force"));
+    assert!(dir.join("assets/img/a.png").exists());
+
+    discourse_topic_render::gc(gc_args(&dir, false, true)).unwrap();
+    assert!(!dir.join("assets/img/a.png").exists());
+}
@@ -1,7 +1,10 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use httpmock::Method::GET;
+use base64::Engine as _;
+use clap::Parser as _;
+use httpmock::Method::{GET, HEAD};
 use httpmock::MockServer;
+use kuchiki::traits::TendrilSink as _;
 use tempfile::tempdir;
 use url::Url;
 
@@ -18,10 +21,51 @@ fn woff2_bytes() -> Vec<u8> {
     vec![b'w', b'O', b'F', b'2', 0, 0, 0, 0]
 }
 
+fn base_args(input: &Path, base_url: &Url) -> discourse_topic_render::CliArgs {
+    discourse_topic_render::CliArgs::parse_from([
+        "discourse-topic-render",
+        "--input",
+        input.to_str().unwrap(),
+        "--base-url",
+        base_url.as_str(),
+        "--max-concurrency",
+        "4",
+        "--user-agent",
+        "test-agent",
+        "--progress",
+        "never",
+    ])
+}
+
+/// Write `topic.json` into `dir` for topic 123, titled "T", with `posts` as its post stream, and return its path.
+fn write_topic(dir: &Path, posts: serde_json::Value) -> PathBuf {
+    let input = dir.join("topic.json");
+    let topic = serde_json::json!({"id": 123, "title": "T", "post_stream": {"posts": posts}});
+    std::fs::write(&input, topic.to_string()).unwrap();
+    input
+}
+
+/// [`write_topic`] with a single post of `cooked` HTML.
+fn one_post_topic(dir: &Path, cooked: &str) -> PathBuf {
+    write_topic(
+        dir,
+        serde_json::json!([{"post_number": 1, "cooked": cooked}]),
+    )
+}
+
 fn read_to_string(path: &Path) -> String {
     std::fs::read_to_string(path).unwrap()
 }
 
+/// The bundled stylesheet the dir-mode page of topic 123 links; its name carries a hash of its content.
+fn linked_css(out_dir: &Path) -> std::path::PathBuf {
+    let page = read_to_string(&out_dir.join("topic-123.html"));
+    let document = kuchiki::parse_html().one(page);
+    let link = document.select_first("link[rel=stylesheet]").unwrap();
+    let href = link.attributes.borrow().get("href").unwrap().to_string();
+    out_dir.join(href)
+}
+
 fn assert_no_remote_autoload(html: &str) {
     for pat in [
         "img src=\"http://",
@@ -84,62 +128,51 @@ async fn renders_dir_and_single_offline() {
     .unwrap();
     std::fs::write(
         &css,
-        format!(
-            r#"
+        r#"
 @import "imported.css";
-body {{
+body {
   background-image: url("/bg.png");
-}}
-.x {{
+}
+.x {
   background-image: url("./local.png");
-}}
-"#
-        ),
+}
+"#,
     )
     .unwrap();
 
     let base_url = Url::parse(&server.url("/")).unwrap();
-    let topic_json = format!(
-        r#"{{
+    let topic_json = r#"{
   "id": 123,
   "title": "Test Topic",
-  "post_stream": {{
+  "post_stream": {
     "posts": [
-      {{
+      {
         "id": 1,
         "post_number": 1,
         "username": "alice",
         "display_username": "alice",
-        "avatar_template": "/avatar/{{size}}.png",
+        "avatar_template": "/avatar/{size}.png",
         "created_at": "2026-01-30T00:00:00.000Z",
         "cooked": "<p>Hello</p><p><img src=\"/img.png\" srcset=\"/img.png 1x, /img2.png 2x\"></p><p><a class=\"lightbox\" href=\"/lightbox.png\"><img src=\"/thumb.png\"></a></p><p><iframe src=\"https://example.com/embed\"></iframe></p><p><a href=\"/t/slug/123/1\">jump</a></p>"
-      }}
+      }
     ]
-  }}
-}}"#
-    );
+  }
+}"#.to_string();
     std::fs::write(&input, topic_json).unwrap();
 
     // dir mode
     let out_dir = tmp.path().join("out");
     let args = discourse_topic_render::CliArgs {
-        input: input.clone(),
-        base_url: base_url.clone(),
         css: vec![css.clone()],
         builtin_css: false,
         mode: discourse_topic_render::Mode::Dir,
-        offline: discourse_topic_render::OfflineMode::Strict,
         out: Some(out_dir.clone()),
-        avatar_size: 120,
-        assets_dir_name: "assets".to_string(),
-        max_concurrency: 4,
-        user_agent: "test-agent".to_string(),
-        progress: discourse_topic_render::ProgressMode::Never,
+        ..base_args(&input, &base_url)
     };
     discourse_topic_render::run(args).await.unwrap();
 
     let html_path = out_dir.join("topic-123.html");
-    let css_path = out_dir.join("assets/css/site.css");
+    let css_path = linked_css(&out_dir);
     assert!(html_path.exists());
     assert!(css_path.exists());
 
@@ -152,18 +185,11 @@ body {{
     // single mode
     let out_single = tmp.path().join("topic-123-single.html");
     let args = discourse_topic_render::CliArgs {
-        input,
-        base_url,
         css: vec![css],
         builtin_css: false,
         mode: discourse_topic_render::Mode::Single,
-        offline: discourse_topic_render::OfflineMode::Strict,
         out: Some(out_single.clone()),
-        avatar_size: 120,
-        assets_dir_name: "assets".to_string(),
-        max_concurrency: 4,
-        user_agent: "test-agent".to_string(),
-        progress: discourse_topic_render::ProgressMode::Never,
+        ..base_args(&input, &base_url)
     };
     discourse_topic_render::run(args).await.unwrap();
 
@@ -256,23 +282,16 @@ body { background-image: url("/bg.png"); }
     // dir mode (no --css)
     let out_dir = tmp.path().join("out");
     let args = discourse_topic_render::CliArgs {
-        input: input.clone(),
-        base_url: base_url.clone(),
         css: vec![],
         builtin_css: false,
         mode: discourse_topic_render::Mode::Dir,
-        offline: discourse_topic_render::OfflineMode::Strict,
         out: Some(out_dir.clone()),
-        avatar_size: 120,
-        assets_dir_name: "assets".to_string(),
-        max_concurrency: 4,
-        user_agent: "test-agent".to_string(),
-        progress: discourse_topic_render::ProgressMode::Never,
+        ..base_args(&input, &base_url)
     };
     discourse_topic_render::run(args).await.unwrap();
 
     let html_path = out_dir.join("topic-123.html");
-    let css_path = out_dir.join("assets/css/site.css");
+    let css_path = linked_css(&out_dir);
     assert!(html_path.exists());
     assert!(css_path.exists());
 
@@ -285,18 +304,11 @@ body { background-image: url("/bg.png"); }
     // single mode (no --css)
     let out_single = tmp.path().join("topic-123-single.html");
     let args = discourse_topic_render::CliArgs {
-        input,
-        base_url,
         css: vec![],
         builtin_css: false,
         mode: discourse_topic_render::Mode::Single,
-        offline: discourse_topic_render::OfflineMode::Strict,
         out: Some(out_single.clone()),
-        avatar_size: 120,
-        assets_dir_name: "assets".to_string(),
-        max_concurrency: 4,
-        user_agent: "test-agent".to_string(),
-        progress: discourse_topic_render::ProgressMode::Never,
+        ..base_args(&input, &base_url)
     };
     discourse_topic_render::run(args).await.unwrap();
 
@@ -347,23 +359,16 @@ async fn builtin_css_skips_css_crawl() {
     // dir mode (builtin css, no --css)
     let out_dir = tmp.path().join("out");
     let args = discourse_topic_render::CliArgs {
-        input: input.clone(),
-        base_url: base_url.clone(),
         css: vec![],
         builtin_css: true,
         mode: discourse_topic_render::Mode::Dir,
-        offline: discourse_topic_render::OfflineMode::Strict,
         out: Some(out_dir.clone()),
-        avatar_size: 120,
-        assets_dir_name: "assets".to_string(),
-        max_concurrency: 4,
-        user_agent: "test-agent".to_string(),
-        progress: discourse_topic_render::ProgressMode::Never,
+        ..base_args(&input, &base_url)
     };
     discourse_topic_render::run(args).await.unwrap();
 
     let html_path = out_dir.join("topic-123.html");
-    let css_path = out_dir.join("assets/css/site.css");
+    let css_path = linked_css(&out_dir);
     assert!(html_path.exists());
     assert!(css_path.exists());
 
@@ -373,22 +378,19 @@ async fn builtin_css_skips_css_crawl() {
     assert!(html.contains("dtr-theme-toggle"));
     assert!(html.contains("class=\"dtr-post\""));
     assert!(css_out.contains(".dtr-post"));
+    let print = &css_out[css_out.find("@media print").unwrap()..];
+    assert!(print.contains("#dtr-theme-toggle"));
+    assert!(print.contains("break-inside: avoid"));
+    assert!(print.contains("white-space: pre-wrap"));
 
     // single mode (builtin css, no --css)
     let out_single = tmp.path().join("topic-123-single.html");
     let args = discourse_topic_render::CliArgs {
-        input,
-        base_url,
         css: vec![],
         builtin_css: true,
         mode: discourse_topic_render::Mode::Single,
-        offline: discourse_topic_render::OfflineMode::Strict,
         out: Some(out_single.clone()),
-        avatar_size: 120,
-        assets_dir_name: "assets".to_string(),
-        max_concurrency: 4,
-        user_agent: "test-agent".to_string(),
-        progress: discourse_topic_render::ProgressMode::Never,
+        ..base_args(&input, &base_url)
     };
     discourse_topic_render::run(args).await.unwrap();
 
@@ -398,3 +400,3809 @@ async fn builtin_css_skips_css_crawl() {
     assert!(html.contains(".dtr-post"));
     assert!(html.contains("data:image/png;base64,"));
 }
+
+#[tokio::test]
+async fn strict_report_lists_the_rules_and_what_they_inspected() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path("/img.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(tmp.path(), "<p><img src=\"/img.png\"></p>");
+
+    let report_path = tmp.path().join("strict-report.json");
+    let args = discourse_topic_render::CliArgs {
+        emit_strict_report: Some(report_path.clone()),
+        builtin_css: true,
+        out: Some(tmp.path().join("out")),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let report: serde_json::Value = serde_json::from_str(&read_to_string(&report_path)).unwrap();
+    assert!(report["violations"].as_array().unwrap().is_empty());
+    let inspected = |rule: &str| {
+        report["rules"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|r| r["rule"] == rule)
+            .unwrap()["inspected"]
+            .as_u64()
+            .unwrap()
+    };
+    assert!(inspected("img[src]") > 0);
+    assert!(inspected("link[href]") > 0);
+}
+
+#[tokio::test]
+async fn reply_to_links_point_at_local_or_remote_posts() {
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let topic_json = r#"{
+  "id": 123,
+  "title": "Test Topic",
+  "post_stream": {
+    "posts": [
+      { "id": 1, "post_number": 1, "username": "bob", "cooked": "<p>Hello</p>" },
+      {
+        "id": 2,
+        "post_number": 2,
+        "username": "alice",
+        "cooked": "<p>Hi bob</p>",
+        "reply_to_post_number": 1,
+        "reply_to_user": { "username": "bob" }
+      },
+      {
+        "id": 3,
+        "post_number": 3,
+        "username": "carol",
+        "cooked": "<p>Replying to a deleted post</p>",
+        "reply_to_post_number": 7
+      }
+    ]
+  }
+}"#;
+    std::fs::write(&input, topic_json).unwrap();
+
+    let out_single = tmp.path().join("topic-123.html");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_single);
+    assert!(html.contains("<a href=\"#post_1\">↪ in reply to @bob (#1)</a>"));
+    assert!(html.contains("<a href=\"https://forum.example.com/t/123/7\">↪ in reply to (#7)</a>"));
+}
+
+#[tokio::test]
+async fn injected_css_is_appended_after_bundle() {
+    let tmp = tempdir().unwrap();
+    let inject = tmp.path().join("override.css");
+    std::fs::write(&inject, ".dtr-post { border: 0; }\n").unwrap();
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let input = one_post_topic(tmp.path(), "<p>Hi</p>");
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        inject_css: vec![inject],
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let css_out = read_to_string(&linked_css(&out_dir));
+    let injected_at = css_out.find("/* injected */").unwrap();
+    assert!(css_out[..injected_at].contains(".dtr-post {"));
+    assert!(css_out[injected_at..].contains(".dtr-post { border: 0; }"));
+}
+
+#[tokio::test]
+async fn small_actions_render_compactly_and_whispers_are_skipped() {
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let topic_json = r#"{
+  "id": 123,
+  "title": "Test Topic",
+  "post_stream": {
+    "posts": [
+      { "post_number": 1, "username": "alice", "cooked": "<p>Hello</p>" },
+      { "post_number": 2, "username": "mod", "post_type": 3, "action_code": "closed.enabled", "cooked": "" },
+      { "post_number": 3, "username": "mod", "post_type": 4, "cooked": "<p>secret whisper</p>" }
+    ]
+  }
+}"#;
+    std::fs::write(&input, topic_json).unwrap();
+
+    let out_single = tmp.path().join("topic-123.html");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_single);
+    assert!(html.contains("<div id=\"post_2\" class=\"dtr-small-action\">"));
+    assert!(html.contains("closed this topic"));
+    assert!(!html.contains("secret whisper"));
+
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        small_actions: discourse_topic_render::SmallActionsMode::Skip,
+        include_whispers: true,
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_single);
+    assert!(!html.contains("dtr-small-action\""));
+    assert!(html.contains("secret whisper"));
+}
+
+#[tokio::test]
+async fn injected_js_is_copied_in_dir_mode_and_inlined_in_single_mode() {
+    let tmp = tempdir().unwrap();
+    let js = tmp.path().join("extra.js");
+    std::fs::write(&js, "console.log('extra');\n").unwrap();
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let input = one_post_topic(tmp.path(), "<p>Hi</p>");
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        inject_js: vec![js.clone()],
+        no_builtin_js: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert!(html.contains("<script src=\"assets/js/extra.js\"></script></body>"));
+    assert!(!html.contains("localStorage"));
+    assert!(out_dir.join("assets/js/extra.js").exists());
+
+    let out_single = tmp.path().join("single.html");
+    let args = discourse_topic_render::CliArgs {
+        inject_js: vec![js],
+        mode: discourse_topic_render::Mode::Single,
+        builtin_css: true,
+        out: Some(out_single.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_single);
+    assert!(html.contains("<script>console.log('extra');\n</script>"));
+    assert!(html.contains("localStorage"));
+}
+
+#[tokio::test]
+async fn injected_js_files_with_the_same_name_do_not_overwrite_each_other() {
+    let tmp = tempdir().unwrap();
+    let (a, b) = (tmp.path().join("a/extra.js"), tmp.path().join("b/extra.js"));
+    for (path, js) in [(&a, "console.log('a');\n"), (&b, "console.log('b');\n")] {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, js).unwrap();
+    }
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let input = one_post_topic(tmp.path(), "<p>Hi</p>");
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        inject_js: vec![a, b],
+        no_builtin_js: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    let srcs: Vec<&str> = html
+        .split("<script src=\"")
+        .skip(1)
+        .map(|s| &s[..s.find('"').unwrap()])
+        .collect();
+    assert_eq!(srcs.len(), 2);
+    assert_eq!(srcs[0], "assets/js/extra.js");
+    assert!(srcs[1].starts_with("assets/js/extra-") && srcs[1].ends_with(".js"));
+    assert_eq!(
+        read_to_string(&out_dir.join(srcs[0])),
+        "console.log('a');\n"
+    );
+    assert_eq!(
+        read_to_string(&out_dir.join(srcs[1])),
+        "console.log('b');\n"
+    );
+}
+
+#[tokio::test]
+async fn post_range_limits_output_and_sends_outside_links_to_the_forum() {
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let topic_json = r#"{
+  "id": 123,
+  "title": "Test Topic",
+  "posts_count": 4,
+  "post_stream": {
+    "posts": [
+      { "post_number": 1, "cooked": "<p>first</p>" },
+      { "post_number": 2, "cooked": "<p><a href=\"/t/slug/123/1\">up</a> <a href=\"/t/slug/123/3\">down</a></p>" },
+      { "post_number": 3, "cooked": "<p>third</p>" },
+      { "post_number": 4, "cooked": "<p>fourth</p>" }
+    ]
+  }
+}"#;
+    std::fs::write(&input, topic_json).unwrap();
+
+    let out_single = tmp.path().join("topic-123.html");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        posts: Some(discourse_topic_render::PostRange {
+            start: Some(2),
+            end: Some(3),
+        }),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_single);
+    assert!(!html.contains("<p>first</p>") && !html.contains("<p>fourth</p>"));
+    assert!(html.contains("<a href=\"https://forum.example.com/t/123/1\">up</a>"));
+    assert!(html.contains("<a href=\"#post_3\">down</a>"));
+    assert!(html.contains("showing posts 2–3 of 4"));
+}
+
+#[tokio::test]
+async fn quotes_of_posts_outside_the_range_leave_no_dangling_anchor() {
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let posts = |extra: &str| {
+        serde_json::json!([
+            {"post_number": 1, "cooked": "<p>first</p>"},
+            {"post_number": 2, "cooked": format!(r##"<aside class="quote" data-post="1" data-topic="123"><div class="title"><a href="#post_1">alice</a> said:</div><blockquote><p>first</p></blockquote></aside><p>reply{extra}</p>"##)},
+        ])
+    };
+    let input = write_topic(tmp.path(), posts(""));
+    let args = || discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(tmp.path().join("topic-123.html")),
+        posts: Some(discourse_topic_render::PostRange {
+            start: Some(2),
+            end: None,
+        }),
+        ..base_args(&input, &base_url)
+    };
+
+    // Post 1 is not rendered: the quote gets no jump link and its title links to the forum, so the strict
+    // check passes.
+    discourse_topic_render::run(args()).await.unwrap();
+    let html = read_to_string(&tmp.path().join("topic-123.html"));
+    assert!(!html.contains("class=\"dtr-quote-jump\""));
+    assert!(html.contains("<a href=\"https://forum.example.com/t/123/1\">alice</a>"));
+
+    // A same-page link with no target fails the render, unless it is allowed.
+    write_topic(tmp.path(), posts(r##"<a href="#fn9">[9]</a>"##));
+    let err = discourse_topic_render::run(args()).await.unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("<a href=\"#fn9\"> points at no element of the page"),
+        "{err}"
+    );
+    let allowed = discourse_topic_render::CliArgs {
+        allow_dangling_anchors: true,
+        ..args()
+    };
+    discourse_topic_render::run(allowed).await.unwrap();
+}
+
+#[tokio::test]
+async fn plain_links_wrapping_images_localize_the_original() {
+    let server = MockServer::start();
+    for path in [
+        "/uploads/default/original/1X/full.jpg",
+        "/uploads/default/optimized/1X/full_500.jpg",
+    ] {
+        server.mock(|when, then| {
+            when.method(GET).path(path);
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body(png_bytes());
+        });
+    }
+
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let topic_json = r#"{
+  "id": 123,
+  "title": "Test Topic",
+  "post_stream": {
+    "posts": [
+      {
+        "post_number": 1,
+        "cooked": "<p><a href=\"/uploads/default/original/1X/full.jpg\"><img src=\"/uploads/default/optimized/1X/full_500.jpg\"></a></p>"
+      }
+    ]
+  }
+}"#;
+    std::fs::write(&input, topic_json).unwrap();
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert_no_remote_autoload(&html);
+    assert!(html.contains("<a href=\"assets/img/"));
+    assert!(html.contains("<img decoding=\"async\" loading=\"lazy\" src=\"assets/img/"));
+    assert!(!html.contains("/uploads/default/original"));
+}
+
+#[tokio::test]
+async fn cookies_are_sent_to_the_site_host_only() {
+    let server = MockServer::start();
+    let site_img = server.mock(|when, then| {
+        when.method(GET).path("/private.png").matches(|req| {
+            let cookie = req
+                .headers
+                .as_ref()
+                .and_then(|h| h.iter().find(|(k, _)| k.eq_ignore_ascii_case("cookie")))
+                .map(|(_, v)| v.clone())
+                .unwrap_or_default();
+            cookie.contains("_t=session") && cookie.contains("theme=dark")
+        });
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+    let cdn_img = server.mock(|when, then| {
+        when.method(GET).path("/cdn.png").matches(|req| {
+            req.headers
+                .as_ref()
+                .is_none_or(|h| !h.iter().any(|(k, _)| k.eq_ignore_ascii_case("cookie")))
+        });
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let cookie_file = tmp.path().join("cookies.txt");
+    std::fs::write(
+        &cookie_file,
+        "# Netscape HTTP Cookie File\n127.0.0.1\tFALSE\t/\tFALSE\t0\ttheme\tdark\nother.example\tFALSE\t/\tFALSE\t0\tnope\tx\n",
+    )
+    .unwrap();
+    // Same server, different host name: stands in for a third-party CDN.
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let cdn_url = format!("http://localhost:{}/cdn.png", server.port());
+    let input = one_post_topic(
+        tmp.path(),
+        &format!(r#"<p><img src="/private.png"><img src="{cdn_url}"></p>"#),
+    );
+
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        cookie: vec!["_t=session".to_string()],
+        cookie_file: Some(cookie_file),
+        out: Some(tmp.path().join("out")),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    site_img.assert();
+    cdn_img.assert();
+}
+
+#[tokio::test]
+async fn image_timeout_fails_only_that_asset_with_keep_going() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path("/slow.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .delay(std::time::Duration::from_secs(3))
+            .body(png_bytes());
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/fast.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(
+        tmp.path(),
+        "<p><img src=\"/slow.png\"><img src=\"/fast.png\"></p>",
+    );
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        timeout_secs: 30,
+        timeout_image: Some(1),
+        keep_going: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert_no_remote_autoload(&html);
+    assert!(html.contains(&format!("<a href=\"{}slow.png\"", base_url)));
+    assert!(html.contains("<img decoding=\"async\" loading=\"lazy\" src=\"assets/img/"));
+}
+
+#[tokio::test]
+async fn dir_mode_keeps_every_srcset_candidate() {
+    let server = MockServer::start();
+    let mut candidates = Vec::new();
+    for path in ["/img.png", "/img_2x.png", "/img_3x.png"] {
+        candidates.push(server.mock(|when, then| {
+            when.method(GET).path(path);
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body(format!("{}{path}", String::from_utf8_lossy(&png_bytes())));
+        }));
+    }
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(
+        tmp.path(),
+        "<p><img alt=\"a\" src=\"/img.png\" srcset=\"/img.png, /img_2x.png 2x, /img_3x.png 3x, data:image/gif;base64,R0lGODlhAQABAAAAACw= 1.5x\" sizes=\"(max-width: 600px) 100vw, 50vw\"></p>",
+    );
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Dir,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    for candidate in &candidates {
+        candidate.assert();
+    }
+
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert_no_remote_autoload(&html);
+    {
+        let document = kuchiki::parse_html().one(html);
+        let img = document.select_first("img[alt=a]").unwrap();
+        let attrs = img.attributes.borrow();
+        let srcset: Vec<&str> = attrs.get("srcset").unwrap().split(", ").collect();
+        assert_eq!(srcset.len(), 4, "{srcset:?}");
+        assert!(srcset[0].starts_with("assets/img/") && !srcset[0].contains(' '));
+        assert!(srcset[1].starts_with("assets/img/") && srcset[1].ends_with(" 2x"));
+        assert!(srcset[2].starts_with("assets/img/") && srcset[2].ends_with(" 3x"));
+        assert_eq!(srcset[3], "data:image/gif;base64,R0lGODlhAQABAAAAACw= 1.5x");
+        // The best candidate is also the fallback `src`.
+        assert_eq!(Some(srcset[2].trim_end_matches(" 3x")), attrs.get("src"));
+        assert_eq!(attrs.get("sizes"), Some("(max-width: 600px) 100vw, 50vw"));
+        for local in &srcset[..3] {
+            assert!(out_dir.join(local.split(' ').next().unwrap()).is_file());
+        }
+    }
+
+    // Single mode inlines only the best candidate, and `sizes` goes with the `srcset`.
+    let out_single = tmp.path().join("single.html");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    let html = read_to_string(&out_single);
+    assert!(!html.contains("srcset"));
+    assert!(!html.contains("sizes="));
+}
+
+#[tokio::test]
+async fn post_images_load_lazily_but_avatars_do_not() {
+    let server = MockServer::start();
+    server.mock(|_, then| {
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = write_topic(
+        tmp.path(),
+        serde_json::json!([{"post_number": 1, "avatar_template": "/user_avatar/bob/{size}/1.png", "cooked": "<p><img src=\"/uploads/a.png\" alt=\"a\"><img src=\"/uploads/b.png\" alt=\"b\" loading=\"eager\"></p>"}]),
+    );
+
+    for mode in [
+        discourse_topic_render::Mode::Dir,
+        discourse_topic_render::Mode::Single,
+    ] {
+        let out = tmp.path().join(format!("{mode:?}"));
+        let args = discourse_topic_render::CliArgs {
+            builtin_css: true,
+            mode,
+            out: Some(out.clone()),
+            ..base_args(&input, &base_url)
+        };
+        discourse_topic_render::run(args).await.unwrap();
+
+        let html = match mode {
+            discourse_topic_render::Mode::Dir => read_to_string(&out.join("topic-123.html")),
+            _ => read_to_string(&out),
+        };
+        let document = kuchiki::parse_html().one(html);
+        let attr = |selector: &str, name: &str| {
+            let node = document.select_first(selector).unwrap();
+            node.attributes.borrow().get(name).map(str::to_string)
+        };
+        assert_eq!(attr("img[alt=a]", "loading").as_deref(), Some("lazy"));
+        assert_eq!(attr("img[alt=a]", "decoding").as_deref(), Some("async"));
+        assert_eq!(attr("img[alt=b]", "loading").as_deref(), Some("eager"));
+        assert_eq!(attr("img.dtr-avatar", "loading"), None);
+        assert_eq!(attr("img.dtr-avatar", "decoding"), None);
+    }
+}
+
+#[tokio::test]
+async fn require_alt_text_lists_images_without_alt() {
+    let server = MockServer::start();
+    for path in ["/chart.png", "/divider.png", "/logo.png"] {
+        server.mock(|when, then| {
+            when.method(GET).path(path);
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body(png_bytes());
+        });
+    }
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(
+        tmp.path(),
+        "<p><img src=\"/chart.png\"><img src=\"/divider.png\" alt=\"\"><img src=\"/logo.png\" alt=\"Logo\"></p>",
+    );
+
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        out: Some(tmp.path().join("warned")),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        require_alt_text: true,
+        out: Some(tmp.path().join("audited")),
+        ..base_args(&input, &base_url)
+    };
+    let err = format!("{:#}", discourse_topic_render::run(args).await.unwrap_err());
+    assert!(err.contains("1 image(s) without an alt attribute"), "{err}");
+    assert!(err.contains(&format!("{}chart.png", base_url)));
+    assert!(!err.contains("divider.png") && !err.contains("logo.png"));
+}
+
+#[tokio::test]
+async fn user_filters_select_posts_case_insensitively() {
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let topic_json = r#"{
+  "id": 123,
+  "title": "Test Topic",
+  "post_stream": {
+    "posts": [
+      { "post_number": 1, "username": "Author", "cooked": "<p>chapter one</p>" },
+      { "post_number": 2, "username": "reader", "cooked": "<p>nice! <a href=\"/t/slug/123/1\">see above</a></p>" },
+      { "post_number": 3, "username": "author", "cooked": "<p>chapter two <a href=\"/t/slug/123/2\">thanks</a></p>" }
+    ]
+  }
+}"#;
+    std::fs::write(&input, topic_json).unwrap();
+
+    let out_single = tmp.path().join("topic-123.html");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        only_user: vec!["AUTHOR".to_string()],
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_single);
+    assert!(html.contains("<p>chapter one</p>"));
+    assert!(!html.contains("nice!"));
+    assert!(html.contains("<a href=\"https://forum.example.com/t/123/2\">thanks</a>"));
+    assert!(html.contains("showing 2 of 3 posts"));
+
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        exclude_user: vec!["author".to_string(), "Reader".to_string()],
+        ..base_args(&input, &base_url)
+    };
+    let err = discourse_topic_render::run(args).await.unwrap_err();
+    assert!(err.to_string().contains("--only-user/--exclude-user"));
+}
+
+#[tokio::test]
+async fn downloads_go_through_the_proxy() {
+    let proxy = MockServer::start();
+    let image = proxy.mock(|when, then| {
+        when.method(GET).path("/uploads/a.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    // Not resolvable: the request can only succeed via the proxy.
+    let base_url = Url::parse("http://forum.invalid/").unwrap();
+    let input = one_post_topic(tmp.path(), "<p><img src=\"/uploads/a.png\"></p>");
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        proxy: Some(proxy.url("/")),
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    image.assert();
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert!(html.contains("<img decoding=\"async\" loading=\"lazy\" src=\"assets/img/"));
+}
+
+#[tokio::test]
+async fn paginate_splits_posts_and_links_across_pages() {
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let topic_json = r#"{
+  "id": 123,
+  "title": "Test Topic",
+  "post_stream": {
+    "posts": [
+      { "post_number": 1, "cooked": "<p>one <a href=\"/t/slug/123/2\">same page</a> <a href=\"/t/slug/123/5\">later</a></p>" },
+      { "post_number": 2, "cooked": "<p>two</p>" },
+      { "post_number": 3, "cooked": "<p>three</p>" },
+      { "post_number": 5, "cooked": "<p>five</p>", "reply_to_post_number": 1 }
+    ]
+  }
+}"#;
+    std::fs::write(&input, topic_json).unwrap();
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        paginate: Some(2),
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    assert!(!out_dir.join("topic-123.html").exists());
+    let p1 = read_to_string(&out_dir.join("topic-123-p1.html"));
+    let p2 = read_to_string(&out_dir.join("topic-123-p2.html"));
+    assert!(p1.contains("<p>two</p>") && !p1.contains("<p>three</p>"));
+    assert!(p2.contains("<p>three</p>") && p2.contains("<p>five</p>"));
+    assert!(p1.contains("<a href=\"#post_2\">same page</a>"));
+    assert!(p1.contains("<a href=\"topic-123-p2.html#post_5\">later</a>"));
+    assert!(p2.contains("href=\"topic-123-p1.html#post_1\""));
+    assert!(p1.contains("rel=\"next\" href=\"topic-123-p2.html\""));
+    assert!(p2.contains("rel=\"prev\" href=\"topic-123-p1.html\""));
+    let css_href = |page: &str| {
+        page.split("rel=\"stylesheet\" href=\"")
+            .nth(1)
+            .map(|rest| rest.split('"').next().unwrap().to_string())
+    };
+    let href = css_href(&p1).unwrap();
+    assert!(
+        href.starts_with("assets/css/site-") && href.ends_with(".css"),
+        "{href}"
+    );
+    assert_eq!(css_href(&p2), Some(href));
+}
+
+#[tokio::test]
+async fn css_bundle_dedups_identical_imports_and_enforces_size_limit() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path("/framework.css");
+        then.status(200)
+            .header("Content-Type", "text/css")
+            .body(".framework { color: red; }\n");
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(tmp.path(), "<p>hi</p>");
+    let css_path = tmp.path().join("site.css");
+    std::fs::write(
+        &css_path,
+        "@import \"/framework.css?v=1\";\n@import \"/framework.css?v=2\";\nbody { margin: 0; }\n",
+    )
+    .unwrap();
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        css: vec![css_path.clone()],
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let css = read_to_string(&linked_css(&out_dir));
+    assert_eq!(css.matches(".framework").count(), 1);
+    assert!(css.contains("same content as an already inlined stylesheet"));
+
+    let args = discourse_topic_render::CliArgs {
+        css: vec![css_path],
+        max_css_bytes: 100,
+        out: Some(tmp.path().join("out2")),
+        ..base_args(&input, &base_url)
+    };
+    let err = discourse_topic_render::run(args).await.unwrap_err();
+    let msg = format!("{:#}", err);
+    assert!(msg.contains("--max-css-bytes (100 bytes)"), "{msg}");
+    assert!(msg.contains("framework.css?v=1"), "{msg}");
+}
+
+#[tokio::test]
+async fn toc_nests_every_posts_headings_by_level() {
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let topic_json = r#"{
+  "id": 123,
+  "title": "Test Topic",
+  "post_stream": {
+    "posts": [
+      { "post_number": 1, "username": "alice", "cooked": "<h2>Getting started</h2><h3>Install</h3><h4>Too deep</h4><h3 id=\"faq\">FAQ</h3><h2>Usage</h2>" },
+      { "post_number": 2, "username": "bob", "cooked": "<h3>Reply notes</h3><h2>Reply section</h2>" }
+    ]
+  }
+}"#;
+    std::fs::write(&input, topic_json).unwrap();
+
+    let render = |toc_max_depth: u8| {
+        let out_single = tmp.path().join("topic-123.html");
+        let args = discourse_topic_render::CliArgs {
+            builtin_css: true,
+            toc: true,
+            toc_max_depth,
+            mode: discourse_topic_render::Mode::Single,
+            out: Some(out_single.clone()),
+            ..base_args(&input, &base_url)
+        };
+        async move {
+            discourse_topic_render::run(args).await.unwrap();
+            read_to_string(&out_single)
+        }
+    };
+
+    let html = render(3).await;
+    assert_no_remote_autoload(&html);
+    let start = html
+        .find("<nav class=\"dtr-toc\" aria-label=\"Table of contents\">")
+        .unwrap();
+    let toc = &html[start..start + html[start..].find("</nav>").unwrap()];
+    let entry = |level: u8, id: &str, text: &str| {
+        format!("<li class=\"dtr-toc-h{level}\"><a href=\"#{id}\">{text}</a>")
+    };
+    let expected = [
+        "<details open><summary>Contents</summary><ol>".to_string(),
+        entry(2, "getting-started_post1", "Getting started"),
+        "<ol>".to_string(),
+        entry(3, "install_post1", "Install") + "</li>",
+        entry(3, "faq", "FAQ") + "</li>",
+        "</ol></li>".to_string(),
+        entry(2, "usage_post1", "Usage") + "</li>",
+        // A post starting below the top level still starts its own entry.
+        entry(3, "reply-notes_post2", "Reply notes") + "</li>",
+        entry(2, "reply-section_post2", "Reply section") + "</li>",
+        "</ol></details>".to_string(),
+    ]
+    .concat();
+    assert!(toc.ends_with(&expected), "{toc}");
+    assert!(!toc.contains("Too deep"));
+    assert!(html.contains("<h4 id=\"too-deep_post1\">"));
+
+    let html = render(4).await;
+    assert!(html.contains(
+        &(entry(3, "install_post1", "Install") + "<ol>" + &entry(4, "too-deep_post1", "Too deep"))
+    ));
+
+    one_post_topic(tmp.path(), "<p>no headings</p>");
+    let html = render(3).await;
+    assert!(!html.contains("class=\"dtr-toc\""));
+}
+
+#[tokio::test]
+async fn search_embeds_post_text_and_script() {
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let input = write_topic(
+        tmp.path(),
+        serde_json::json!([{"post_number": 1, "username": "alice", "cooked": "<p>needle <b>in</b> a haystack</p>"}]),
+    );
+
+    let out_single = tmp.path().join("topic-123.html");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        search: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_single);
+    assert_no_remote_autoload(&html);
+    assert!(html.contains(
+        r#"<script type="application/json" id="dtr-search-data">[{"n":1,"t":"needle in a haystack","u":"alice"}]</script>"#
+    ));
+    assert!(html.contains("dtr-search-status"));
+}
+
+#[tokio::test]
+async fn paginated_pages_have_their_own_title_description_and_rel_links() {
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let topic_json = r#"{
+  "id": 123,
+  "title": "Story",
+  "post_stream": {
+    "posts": [
+      { "post_number": 1, "cooked": "<p>Chapter <b>one</b> begins</p>" },
+      { "post_number": 2, "cooked": "<p>two</p>" },
+      { "post_number": 3, "cooked": "<p>Chapter three</p>" },
+      { "post_number": 4, "cooked": "<p>four</p>" },
+      { "post_number": 5, "cooked": "<p>The end</p>" }
+    ]
+  }
+}"#;
+    std::fs::write(&input, topic_json).unwrap();
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        paginate: Some(2),
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let pages: Vec<String> = (1..=3)
+        .map(|n| read_to_string(&out_dir.join(format!("topic-123-p{n}.html"))))
+        .collect();
+
+    assert!(pages[0].contains("<title>Story — posts 1–2</title>"));
+    assert!(pages[1].contains("<title>Story — posts 3–4</title>"));
+    assert!(pages[2].contains("<title>Story — posts 5–5</title>"));
+    assert!(pages[0].contains(r#"<meta name="description" content="Chapter one begins">"#));
+    assert!(pages[1].contains(r#"<meta name="description" content="Chapter three">"#));
+    assert!(pages[2].contains(r#"<meta name="description" content="The end">"#));
+
+    assert!(!pages[0].contains(r#"<link rel="prev""#));
+    assert!(pages[0].contains(r#"<link rel="next" href="topic-123-p2.html">"#));
+    assert!(pages[1].contains(r#"<link rel="prev" href="topic-123-p1.html">"#));
+    assert!(pages[1].contains(r#"<link rel="next" href="topic-123-p3.html">"#));
+    assert!(pages[2].contains(r#"<link rel="prev" href="topic-123-p2.html">"#));
+    assert!(!pages[2].contains(r#"<link rel="next""#));
+}
+
+#[tokio::test]
+async fn no_js_output_has_no_scripts_or_theme_toggle() {
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let input = one_post_topic(tmp.path(), "<p>hi</p><script>alert(1)</script>");
+
+    let out_single = tmp.path().join("topic-123.html");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        toc: true,
+        no_js: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_single);
+    assert!(html.contains("<p>hi</p>"));
+    assert!(!html.contains("<script"));
+    assert!(!html.contains("dtr-theme-toggle\""));
+    assert!(html.contains(r#"<meta name="color-scheme" content="light dark">"#));
+}
+
+#[tokio::test]
+async fn a_render_failing_the_strict_check_writes_no_sidecar_files() {
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let input = one_post_topic(tmp.path(), "<p>hi</p>");
+    // Injected as is, so its remote url() is left for the strict check to find.
+    let inject = tmp.path().join("inject.css");
+    std::fs::write(
+        &inject,
+        "body { background: url(https://cdn.example.com/bg.png) }\n",
+    )
+    .unwrap();
+
+    let out_dir = tmp.path().join("out");
+    let feed = tmp.path().join("topic-123.rss");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        inject_css: vec![inject],
+        rss: Some(feed.clone()),
+        meta_sidecar: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap_err();
+
+    assert!(!out_dir.join("topic-123.html").exists());
+    assert!(!feed.exists());
+    assert!(!out_dir.join("topic-123.meta.json").exists());
+    assert!(!out_dir.join("topic-123.assets-manifest.json").exists());
+}
+
+#[tokio::test]
+async fn avatar_serving_html_falls_back_to_a_letter_avatar() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET)
+            .path("/user_avatar/forum.example.com/bob/120/1_2.png");
+        then.status(302).header("Location", "/privacy/interstitial");
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/privacy/interstitial");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body("<!doctype html><html><body>Continue to external site?</body></html>");
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = write_topic(
+        tmp.path(),
+        serde_json::json!([{"post_number": 1, "username": "bob", "avatar_template": "/user_avatar/forum.example.com/bob/{size}/1_2.png", "cooked": "<p>hi</p>"}]),
+    );
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    let start = html.find("src=\"assets/avatar/").expect("avatar img") + "src=\"".len();
+    let rel = &html[start..start + html[start..].find('"').unwrap()];
+    assert!(rel.ends_with(".svg"), "{rel}");
+    let svg = read_to_string(&out_dir.join(rel));
+    assert!(svg.contains(">B</text>"));
+}
+
+#[tokio::test]
+async fn event_handlers_and_javascript_urls_are_stripped() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path("/a.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(
+        tmp.path(),
+        "<p onmouseover=\"x()\"><img src=\"/a.png\" onerror=\"alert(1)\"><a href=\"javascript:alert(1)\">click</a><a href=\" JaVaScRiPt:alert(2)\">two</a></p>",
+    );
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert!(!html.contains("onerror") && !html.contains("onmouseover"));
+    assert!(!html.to_ascii_lowercase().contains("javascript:"));
+    assert!(html.contains("<a href=\"#\">click</a>"));
+    assert!(html.contains("<a href=\"#\">two</a>"));
+}
+
+#[tokio::test]
+async fn skip_large_assets_uses_a_placeholder_after_head_preflight() {
+    let server = MockServer::start();
+    let big_head = server.mock(|when, then| {
+        when.method(HEAD).path("/big.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .header("Content-Length", "5000000");
+    });
+    let big_get = server.mock(|when, then| {
+        when.method(GET).path("/big.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+    server.mock(|when, then| {
+        when.method(HEAD).path("/small.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .header("Content-Length", png_bytes().len().to_string());
+    });
+    let small_get = server.mock(|when, then| {
+        when.method(GET).path("/small.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+    server.mock(|when, then| {
+        when.method(HEAD).path("/big.woff2");
+        then.status(200)
+            .header("Content-Type", "font/woff2")
+            .header("Content-Length", "5000000");
+    });
+    let font_get = server.mock(|when, then| {
+        when.method(GET).path("/big.woff2");
+        then.status(200).header("Content-Type", "font/woff2");
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(
+        tmp.path(),
+        "<p><img src=\"/big.png\"><img src=\"/small.png\"></p>",
+    );
+    let css = tmp.path().join("site.css");
+    let font_url = server.url("/big.woff2");
+    std::fs::write(
+        &css,
+        format!("@font-face {{ font-family: Big; src: url(\"{font_url}\") format(\"woff2\"); }}\n"),
+    )
+    .unwrap();
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        css: vec![css],
+        preflight_above_bytes: Some(1_000_000),
+        skip_large_assets: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    big_head.assert();
+    big_get.assert_hits(0);
+    small_get.assert();
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert_no_remote_autoload(&html);
+    let placeholder = std::fs::read_dir(out_dir.join("assets/img"))
+        .unwrap()
+        .map(|e| std::fs::read_to_string(e.unwrap().path()).unwrap_or_default())
+        .find(|s| s.contains("large file skipped"));
+    assert!(placeholder.is_some_and(|s| s.contains("4.8 MiB")));
+    // Only images get the placeholder; a skipped font stays linked.
+    font_get.assert_hits(0);
+    let bundled = read_to_string(&linked_css(&out_dir));
+    assert!(
+        bundled.contains(&format!("url(\"{font_url}\")")),
+        "{bundled}"
+    );
+}
+
+#[tokio::test]
+async fn preflight_sizes_order_the_downloads() {
+    let server = MockServer::start();
+    let gets: Vec<_> = [("/large.png", "900000"), ("/tiny.png", "100")]
+        .into_iter()
+        .map(|(path, len)| {
+            server.mock(|when, then| {
+                when.method(HEAD).path(path);
+                then.status(200)
+                    .header("Content-Type", "image/png")
+                    .header("Content-Length", len);
+            });
+            server.mock(|when, then| {
+                when.method(GET).path(path);
+                then.status(200)
+                    .header("Content-Type", "image/png")
+                    .body(png_bytes());
+            })
+        })
+        .collect();
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(
+        tmp.path(),
+        "<p><img alt=\"a\" src=\"/large.png\"><img alt=\"b\" src=\"/tiny.png\"></p>",
+    );
+
+    // Room for one download: the image the preflight found smaller gets it, though the page lists it second.
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        max_concurrency: 1,
+        preflight_above_bytes: Some(1_000_000),
+        max_total_bytes: Some(png_bytes().len() as u64),
+        out: Some(tmp.path().join("out")),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    gets[0].assert_hits(0);
+    gets[1].assert();
+}
+
+#[tokio::test]
+async fn a_spent_download_budget_placeholders_the_remaining_assets() {
+    let server = MockServer::start();
+    let gets: Vec<_> = ["/avatar/120.png", "/small.png", "/thumb.png", "/big.png"]
+        .into_iter()
+        .map(|path| {
+            server.mock(|when, then| {
+                when.method(GET).path(path);
+                then.status(200)
+                    .header("Content-Type", "image/png")
+                    .body(png_bytes());
+            })
+        })
+        .collect();
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = write_topic(
+        tmp.path(),
+        serde_json::json!([{"post_number": 1, "username": "alice", "avatar_template": "/avatar/{size}.png", "cooked": "<p><a class=\"lightbox\" href=\"/big.png\"><img alt=\"a\" src=\"/thumb.png\"></a><img alt=\"b\" src=\"/small.png\"></p>"}]),
+    );
+    let placeholders = |out_dir: &Path| -> Vec<String> {
+        std::fs::read_dir(out_dir.join("assets/img"))
+            .unwrap()
+            .map(|e| std::fs::read_to_string(e.unwrap().path()).unwrap_or_default())
+            .filter(|s| s.contains("not downloaded"))
+            .collect()
+    };
+
+    // Room for the avatar and the two inline images; the full-size original comes last and is left out.
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        max_concurrency: 1,
+        max_total_bytes: Some(3 * png_bytes().len() as u64),
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    for get in &gets[..3] {
+        get.assert();
+    }
+    gets[3].assert_hits(0);
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert_no_remote_autoload(&html);
+    let document = kuchiki::parse_html().one(html);
+    let lightbox = document.select_first("a.lightbox").unwrap();
+    let href = lightbox
+        .attributes
+        .borrow()
+        .get("href")
+        .unwrap()
+        .to_string();
+    assert!(
+        href.starts_with("assets/img/") && href.ends_with(".svg"),
+        "{href}"
+    );
+    assert_eq!(
+        placeholders(&out_dir),
+        [read_to_string(&out_dir.join(&href))]
+    );
+    assert!(placeholders(&out_dir)[0].contains("not downloaded (--max-total-bytes reached)"));
+
+    // Past the deadline nothing is downloaded, and the render still succeeds.
+    let out_dir = tmp.path().join("late");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        fetch_deadline_secs: Some(0),
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    for get in &gets[..3] {
+        get.assert_hits(1);
+    }
+    // One placeholder file, stored once by content, stands in for all three images.
+    assert_eq!(placeholders(&out_dir).len(), 1);
+    let document = kuchiki::parse_html().one(read_to_string(&out_dir.join("topic-123.html")));
+    let refs: Vec<String> = document
+        .select("p img, a.lightbox")
+        .unwrap()
+        .map(|node| {
+            let attrs = node.attributes.borrow();
+            attrs.get("src").or(attrs.get("href")).unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(refs.len(), 3);
+    assert!(
+        refs.iter().all(|r| r == &refs[0] && r.ends_with(".svg")),
+        "{refs:?}"
+    );
+    let avatar = document.select_first("img.dtr-avatar").unwrap();
+    assert!(
+        avatar
+            .attributes
+            .borrow()
+            .get("src")
+            .unwrap()
+            .starts_with("assets/avatar/")
+    );
+}
+
+#[tokio::test]
+async fn html_error_pages_for_images_are_failures() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path("/pruned.png");
+        then.status(200)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body("<!DOCTYPE html><html><body>Not found</body></html>");
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(tmp.path(), "<p><img src=\"/pruned.png\"></p>");
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    let err = discourse_topic_render::run(args).await.unwrap_err();
+    assert!(format!("{:#}", err).contains("is an HTML page, not an image"));
+
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        keep_going: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert!(html.contains(&format!("<a href=\"{}pruned.png\"", base_url)));
+    let stored = std::fs::read_dir(out_dir.join("assets/img"))
+        .map(|d| d.count())
+        .unwrap_or(0);
+    assert_eq!(stored, 0);
+}
+
+#[tokio::test]
+async fn css_that_is_an_html_challenge_page_fails_unless_lenient() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(
+                r#"<html><head>
+<link rel="stylesheet" href="/site.css">
+<link rel="stylesheet" href="/challenge.css">
+</head></html>"#,
+            );
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/site.css");
+        then.status(200)
+            .header("Content-Type", "text/css")
+            .body("body { color: red; }");
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/challenge.css");
+        then.status(200)
+            .header("Content-Type", "text/css")
+            .body("<!DOCTYPE html><html><body>Checking your browser…</body></html>");
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(tmp.path(), "<p>hi</p>");
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    let err = format!("{:#}", discourse_topic_render::run(args).await.unwrap_err());
+    assert!(err.contains("got HTML instead of CSS from"), "{err}");
+    assert!(
+        err.contains("challenge.css") && err.contains("--cookie"),
+        "{err}"
+    );
+
+    let args = discourse_topic_render::CliArgs {
+        lenient_css: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    let css = read_to_string(&linked_css(&out_dir));
+    assert!(css.contains("body { color: red; }"));
+    assert!(css.contains("challenge.css: got HTML instead of CSS */"));
+    assert!(!css.contains("Checking your browser"));
+}
+
+#[tokio::test]
+async fn output_is_stamped_unless_disabled_and_reproducible_uses_last_activity() {
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    std::fs::write(
+        &input,
+        r#"{"id": 123, "title": "T", "last_posted_at": "2025-06-01T12:34:56.000Z", "post_stream": {"posts": [{"post_number": 1, "cooked": "<p>hi</p>"}]}}"#,
+    )
+    .unwrap();
+    let tool = format!(
+        "Generated by discourse-topic-render {}",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert!(html.starts_with(&format!("<!DOCTYPE html><!-- {} on 20", tool)));
+    let css = read_to_string(&linked_css(&out_dir));
+    assert!(css.starts_with(&format!("/* {} on 20", tool)));
+    assert!(!html.contains("last activity"));
+
+    let render_reproducible = || async {
+        let out_single = tmp.path().join("reproducible.html");
+        let args = discourse_topic_render::CliArgs {
+            builtin_css: true,
+            reproducible: true,
+            mode: discourse_topic_render::Mode::Single,
+            out: Some(out_single.clone()),
+            ..base_args(&input, &base_url)
+        };
+        discourse_topic_render::run(args).await.unwrap();
+        read_to_string(&out_single)
+    };
+    let first = render_reproducible().await;
+    let stamp = format!("{} on 2025-06-01T12:34:56.000Z (topic last activity)", tool);
+    assert!(first.contains(&format!("<!-- {} -->", stamp)));
+    assert!(first.contains(&format!("/* {} */", stamp)));
+    assert_eq!(first, render_reproducible().await);
+
+    let out_single = tmp.path().join("unstamped.html");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        no_stamp: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    assert!(!read_to_string(&out_single).contains("Generated by"));
+}
+
+#[tokio::test]
+async fn assets_over_max_asset_size_become_links() {
+    let server = MockServer::start();
+    let mut gif = b"GIF89a".to_vec();
+    gif.resize(10_000, 0);
+    server.mock(|when, then| {
+        when.method(GET).path("/huge.gif");
+        then.status(200)
+            .header("Content-Type", "image/gif")
+            .body(gif.clone());
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/small.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(
+        tmp.path(),
+        "<p><img src=\"/huge.gif\"><a class=\"lightbox\" href=\"/huge.gif\"><img src=\"/small.png\"></a></p>",
+    );
+    let css = tmp.path().join("site.css");
+    std::fs::write(&css, "body { background: url(/huge.gif) }\n").unwrap();
+
+    let out_single = tmp.path().join("topic-123.html");
+    let args = discourse_topic_render::CliArgs {
+        css: vec![css],
+        max_asset_size: Some(1024),
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_single);
+    assert_no_remote_autoload(&html);
+    let huge = format!("{}huge.gif", base_url);
+    assert!(html.contains(&format!(
+        "<a href=\"{huge}\" rel=\"noreferrer noopener\">huge.gif (9.8 KiB)</a>"
+    )));
+    assert!(html.contains(&format!("<a class=\"lightbox\" href=\"{huge}\">")));
+    assert!(html.contains("<img decoding=\"async\" loading=\"lazy\" src=\"data:image/png;base64,"));
+    assert!(!html.contains("data:image/gif"));
+    // A stylesheet `url()` over the limit is linked the same way instead of failing the render.
+    assert!(html.contains(&format!("background: url(\"{huge}\")")));
+}
+
+#[tokio::test]
+async fn inline_svg_keeps_attribute_case_and_localizes_references() {
+    let server = MockServer::start();
+    let diagram = server.mock(|when, then| {
+        when.method(GET).path("/diagram.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let cooked = r##"<p><svg viewBox="0 0 10 10" preserveAspectRatio="xMidYMid"><defs><symbol id="dot"><circle r="1"/></symbol></defs><use xlink:href="#dot"/><image xlink:href="/diagram.png" width="10"/><a xlink:href="javascript:alert(1)"><text>x</text></a></svg><math><mi mathvariant="bold">x</mi></math></p>"##;
+    let input = one_post_topic(tmp.path(), cooked);
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    diagram.assert();
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert_no_remote_autoload(&html);
+    assert!(html.contains(r#"<svg preserveAspectRatio="xMidYMid" viewBox="0 0 10 10">"#));
+    assert!(html.contains(r##"<use xlink:href="#dot"></use>"##));
+    assert!(html.contains(r#"<image width="10" xlink:href="assets/img/"#));
+    assert!(html.contains(r##"<a xlink:href="#"><text>x</text></a>"##));
+    assert!(html.contains(r#"<mi mathvariant="bold">x</mi>"#));
+}
+
+#[tokio::test]
+async fn strip_reactions_and_custom_selectors_remove_noise() {
+    let server = MockServer::start();
+    let icon = server.mock(|when, then| {
+        when.method(GET).path("/reactions/heart.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let cooked = r#"<p>body text</p><div class="post-likes"><img src="/reactions/heart.png"> 3 people liked this</div><div class="discourse-reactions-counter"><img src="/reactions/heart.png"></div><aside class="signature">-- sent from my phone</aside>"#;
+    let input = one_post_topic(tmp.path(), cooked);
+
+    // Default: reactions stay and their icons are localized.
+    let out_single = tmp.path().join("default.html");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    let html = read_to_string(&out_single);
+    assert!(html.contains("3 people liked this"));
+    icon.assert_hits(1);
+
+    let out_single = tmp.path().join("stripped.html");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        strip_reactions: true,
+        strip_selector: vec!["aside.signature".to_string()],
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    let html = read_to_string(&out_single);
+    assert!(html.contains("body text"));
+    assert!(!html.contains("liked this"));
+    assert!(!html.contains("discourse-reactions-counter"));
+    assert!(!html.contains("sent from my phone"));
+    icon.assert_hits(1);
+
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        strip_selector: vec!["div[".to_string()],
+        out: Some(tmp.path().join("x.html")),
+        ..base_args(&input, &base_url)
+    };
+    let err = discourse_topic_render::run(args).await.unwrap_err();
+    assert!(err.to_string().contains("invalid --strip-selector"));
+}
+
+#[tokio::test]
+async fn concurrent_rendering_keeps_post_order_and_output() {
+    let server = MockServer::start();
+    // Earlier posts get slower images so they finish last.
+    for n in 1..=6u64 {
+        server.mock(|when, then| {
+            when.method(GET).path(format!("/img{n}.png"));
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .delay(std::time::Duration::from_millis(30 * (7 - n)))
+                .body(png_bytes());
+        });
+    }
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let posts: Vec<serde_json::Value> = (1..=6)
+        .map(|n| {
+            serde_json::json!({
+                "post_number": n,
+                "username": format!("user{n}"),
+                "cooked": format!(r#"<p>post {n}<img src="/img{n}.png"></p>"#),
+            })
+        })
+        .collect();
+    let input = write_topic(tmp.path(), posts.into());
+
+    let render = |concurrency: &'static str| {
+        let out_single = tmp.path().join(format!("c{concurrency}.html"));
+        let mut args = base_args(&input, &base_url);
+        args.builtin_css = true;
+        args.reproducible = true;
+        args.mode = discourse_topic_render::Mode::Single;
+        args.out = Some(out_single.clone());
+        args.max_concurrency = concurrency.parse().unwrap();
+        async move {
+            discourse_topic_render::run(args).await.unwrap();
+            read_to_string(&out_single)
+        }
+    };
+    let sequential = render("1").await;
+    let concurrent = render("8").await;
+    assert_eq!(sequential, concurrent);
+
+    let positions: Vec<usize> = (1..=6)
+        .map(|n| concurrent.find(&format!("post {n}")).unwrap())
+        .collect();
+    assert!(positions.windows(2).all(|w| w[0] < w[1]));
+}
+
+#[tokio::test]
+async fn small_memory_budget_still_renders_large_assets() {
+    let server = MockServer::start();
+    let mut big = png_bytes();
+    big.resize(700 * 1024, 0);
+    for n in 1..=3 {
+        server.mock(|when, then| {
+            when.method(GET).path(format!("/big{n}.png"));
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body(big.clone());
+        });
+    }
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(
+        tmp.path(),
+        "<p><img src=\"/big1.png\"><img src=\"/big2.png\"><img src=\"/big3.png\"></p>",
+    );
+
+    let out_single = tmp.path().join("topic-123.html");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        max_memory_mb: Some(1),
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_single);
+    assert_eq!(
+        html.matches("<img decoding=\"async\" loading=\"lazy\" src=\"data:image/png;base64,")
+            .count(),
+        3
+    );
+}
+
+#[tokio::test]
+async fn private_messages_get_a_banner_and_noindex() {
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    std::fs::write(
+        &input,
+        r#"{
+  "id": 77,
+  "title": "Hello",
+  "archetype": "private_message",
+  "details": {
+    "allowed_users": [{"username": "alice"}, {"username": "bob"}],
+    "allowed_groups": [{"name": "moderators"}]
+  },
+  "post_stream": {"posts": [{"post_number": 1, "username": "alice", "cooked": "<p>hi</p>"}]}
+}"#,
+    )
+    .unwrap();
+
+    let render = |include_private: bool| {
+        let out_single = tmp.path().join(format!("pm-{include_private}.html"));
+        let mut args = base_args(&input, &base_url);
+        args.builtin_css = true;
+        args.include_private = include_private;
+        args.mode = discourse_topic_render::Mode::Single;
+        args.out = Some(out_single.clone());
+        async move {
+            discourse_topic_render::run(args).await.unwrap();
+            read_to_string(&out_single)
+        }
+    };
+
+    let html = render(false).await;
+    assert!(html.contains(
+        r#"<div class="dtr-pm-banner">Private message between alice, bob, moderators</div>"#
+    ));
+    assert!(html.contains(r#"<meta name="robots" content="noindex">"#));
+
+    let html = render(true).await;
+    assert!(html.contains("dtr-pm-banner"));
+    assert!(!html.contains("noindex"));
+}
+
+#[tokio::test]
+async fn code_copy_buttons_target_unique_ids() {
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let input = write_topic(
+        tmp.path(),
+        serde_json::json!([
+            {"post_number": 1, "cooked": "<pre><code>a\n</code></pre><pre><code>b\n</code></pre>"},
+            {"post_number": 2, "cooked": "<pre><code>c\n</code></pre>"}
+        ]),
+    );
+
+    let out_single = tmp.path().join("topic-123.html");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        code_copy_button: true,
+        line_numbers: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_single);
+    for id in ["dtr-code-1-1", "dtr-code-1-2", "dtr-code-2-1"] {
+        assert!(html.contains(&format!(
+            r#"<button aria-label="Copy code" class="dtr-copy-btn" data-target="{id}" type="button">Copy</button><pre class="dtr-line-numbered"><code id="{id}">"#
+        )));
+    }
+    assert_eq!(html.matches("navigator.clipboard.writeText").count(), 1);
+}
+
+#[tokio::test]
+async fn style_elements_in_cooked_html_are_localized() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path("/bg.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/font.woff2");
+        then.status(200)
+            .header("Content-Type", "font/woff2")
+            .body(woff2_bytes());
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/extra.css");
+        then.status(200)
+            .header("Content-Type", "text/css")
+            .body("@font-face{font-family:X;src:url(font.woff2)}");
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let cooked =
+        r#"<p>styled</p><style>@import url("/extra.css"); body{background:url(/bg.png)}</style>"#;
+    let input = one_post_topic(tmp.path(), cooked);
+
+    let site_css = tmp.path().join("site.css");
+    std::fs::write(&site_css, "").unwrap();
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        out: Some(out_dir.clone()),
+        css: vec![site_css],
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert!(!html.contains("@import"));
+    assert!(!html.contains("/bg.png"));
+    // Paths stay relative to the page, not to the bundled stylesheet.
+    assert!(html.contains("background:url(\"assets/"), "{html}");
+    assert!(html.contains("src:url(\"assets/"), "{html}");
+}
+
+#[tokio::test]
+async fn scripts_missing_from_embedded_fonts_get_system_fallbacks() {
+    let server = MockServer::start();
+    let font = std::fs::read(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/fonts/latin-only.ttf"),
+    )
+    .unwrap();
+    server.mock(|when, then| {
+        when.method(GET).path("/latin.ttf");
+        then.status(200)
+            .header("Content-Type", "font/ttf")
+            .body(font.clone());
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(tmp.path(), "<p>Hello 世界</p>");
+    let site_css = tmp.path().join("site.css");
+    std::fs::write(
+        &site_css,
+        "@font-face { font-family: \"Site Sans\"; src: url(/latin.ttf); }\nbody { font-family: \"Site Sans\"; }",
+    )
+    .unwrap();
+
+    let render = |name: &str, builtin_css: bool, no_injection: bool| {
+        let out = tmp.path().join(name);
+        let args = discourse_topic_render::CliArgs {
+            css: vec![site_css.clone()],
+            builtin_css,
+            no_font_fallback_injection: no_injection,
+            mode: discourse_topic_render::Mode::Single,
+            out: Some(out.clone()),
+            ..base_args(&input, &base_url)
+        };
+        async move {
+            discourse_topic_render::run(args).await.unwrap();
+            read_to_string(&out)
+        }
+    };
+
+    let html = render("site.html", false, false).await;
+    assert!(html.contains(
+        r#"@font-face { font-family: "Site Sans"; src: local("PingFang SC"), local("Hiragino Sans GB")"#
+    ));
+    assert!(html.contains("unicode-range: U+2E80-2FDF"));
+    // Latin is covered by the embedded font and Cyrillic is not used; neither gets a fallback.
+    assert!(!html.contains("Noto Sans Arabic") && !html.contains("Roboto"));
+
+    let html = render("plain.html", false, true).await;
+    assert!(!html.contains("local(\"PingFang SC\")"));
+
+    let html = render("builtin.html", true, false).await;
+    assert!(
+        html.contains(r#"@font-face { font-family: "dtr-fallback"; src: local("PingFang SC")"#)
+    );
+
+    // Dir mode reads the font back from the assets directory.
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        css: vec![site_css.clone()],
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    let css = read_to_string(&linked_css(&out_dir));
+    assert!(css.contains(r#"@font-face { font-family: "Site Sans"; src: local("PingFang SC")"#));
+    assert!(!css.contains("Roboto"));
+}
+
+#[tokio::test]
+async fn background_attributes_are_localized_and_plugins_become_links() {
+    let server = MockServer::start();
+    let bg = server.mock(|when, then| {
+        when.method(GET).path("/bg.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let cooked = r#"<table background="/bg.png"><tr><td background="/bg.png">cell</td></tr></table><object data="/movie.swf" type="application/x-shockwave-flash"><param name="movie" value="/movie.swf"></object><embed src="https://cdn.example.com/clip.swf">"#;
+    let input = one_post_topic(tmp.path(), cooked);
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    bg.assert();
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert_no_remote_autoload(&html);
+    assert_eq!(html.matches(r#"background="assets/img/"#).count(), 2);
+    assert!(!html.contains("<object") && !html.contains("<embed") && !html.contains("<param"));
+    let movie = format!("{}movie.swf", base_url);
+    assert!(html.contains(&format!(
+        r#"<a href="{movie}" rel="noreferrer noopener">/movie.swf</a>"#
+    )));
+    assert!(html.contains(
+        r#"<a href="https://cdn.example.com/clip.swf" rel="noreferrer noopener">https://cdn.example.com/clip.swf</a>"#
+    ));
+}
+
+#[tokio::test]
+async fn skip_unchanged_rerenders_only_when_inputs_or_flags_change() {
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let input = one_post_topic(tmp.path(), "<p>hi</p>");
+
+    let out_dir = tmp.path().join("out");
+    let page = out_dir.join("topic-123.html");
+    let render = |toc: bool| {
+        let args = discourse_topic_render::CliArgs {
+            builtin_css: true,
+            skip_unchanged: true,
+            toc,
+            out: Some(out_dir.clone()),
+            ..base_args(&input, &base_url)
+        };
+        discourse_topic_render::run(args)
+    };
+    // A marker appended to the page survives only when the run skips the topic.
+    let mark = || {
+        let html = read_to_string(&page);
+        assert!(html.contains("<meta name=\"dtr-fingerprint\""));
+        std::fs::write(&page, html + "<!-- previous run -->").unwrap();
+    };
+    let skipped = || read_to_string(&page).contains("<!-- previous run -->");
+
+    render(false).await.unwrap();
+    mark();
+    render(false).await.unwrap();
+    assert!(skipped());
+
+    one_post_topic(tmp.path(), "<p>hello</p>");
+    render(false).await.unwrap();
+    assert!(!skipped());
+    assert!(read_to_string(&page).contains("hello"));
+
+    mark();
+    render(true).await.unwrap();
+    assert!(!skipped());
+
+    // A newly asked-for feed is written rather than skipped.
+    mark();
+    let feed = tmp.path().join("topic-123.rss");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        skip_unchanged: true,
+        toc: true,
+        rss: Some(feed.clone()),
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    assert!(!skipped());
+    assert!(feed.exists());
+}
+
+#[tokio::test]
+async fn image_set_candidates_and_cursors_are_localized() {
+    let server = MockServer::start();
+    let mut mocks = Vec::new();
+    for path in [
+        "/img/banner.png",
+        "/img/banner@2x.png",
+        "/img/logo@2x.png",
+        "/img/hero.png",
+        "/img/hero-1x.png",
+        "/img/pointer.cur",
+    ] {
+        mocks.push(server.mock(|when, then| {
+            when.method(GET).path(path);
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body(png_bytes());
+        }));
+    }
+
+    let tmp = tempdir().unwrap();
+    let input = one_post_topic(tmp.path(), "<p>hi</p>");
+    let base_url = Url::parse(&server.base_url()).unwrap();
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/css/image-set.css");
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        css: vec![fixture],
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    for mock in &mocks {
+        mock.assert();
+    }
+
+    let css = read_to_string(&linked_css(&out_dir));
+    for name in ["banner", "logo@2x", "hero", "pointer.cur"] {
+        assert!(!css.contains(&format!("/img/{name}")), "{css}");
+    }
+    // Every declaration keeps its image-set() and gains a plain url() copy in front of it.
+    assert_eq!(css.matches("-webkit-image-set(url(\"../img/").count(), 1);
+    assert_eq!(css.matches("image-set(url(\"../img/").count(), 2);
+    assert!(css.contains("IMAGE-SET(url(\"../img/"));
+    assert!(css.contains("background: #fff url(data:image/png;base64,iVBORw0KGgo=) no-repeat;"));
+    assert!(css.contains("2x type(\"image/png\")) no-repeat;"));
+    let hero = css.find(".hero{").unwrap();
+    let one_x = &css[hero..css[hero..].find(';').unwrap() + hero];
+    assert!(
+        one_x.starts_with(".hero{background-image:url(\"../img/"),
+        "{one_x}"
+    );
+    assert!(css.contains("cursor: url(\"../img/"));
+
+    let report = discourse_topic_render::check_strict_offline(&[], &css);
+    assert!(report.violations.is_empty(), "{:?}", report.violations);
+}
+
+#[tokio::test]
+async fn qr_permalinks_are_inline_and_print_only_by_default() {
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+    let input = write_topic(
+        tmp.path(),
+        serde_json::json!([
+            {"post_number": 1, "username": "alice", "cooked": "<p>one</p>"},
+            {"post_number": 2, "username": "bob", "cooked": "<p>two</p>"},
+            {"post_number": 3, "post_type": 3, "action_code": "closed.enabled", "cooked": ""}
+        ]),
+    );
+
+    let render = |always: bool| {
+        let out = tmp.path().join(format!("qr-{always}.html"));
+        let args = discourse_topic_render::CliArgs {
+            builtin_css: true,
+            qr_permalinks: true,
+            qr_always: always,
+            mode: discourse_topic_render::Mode::Single,
+            out: Some(out.clone()),
+            ..base_args(&input, &base_url)
+        };
+        async move {
+            discourse_topic_render::run(args).await.unwrap();
+            read_to_string(&out)
+        }
+    };
+
+    let html = render(false).await;
+    assert_no_remote_autoload(&html);
+    assert_eq!(html.matches("<span class=\"dtr-qr-permalink\"").count(), 2);
+    assert_eq!(html.matches("<svg role=\"img\"").count(), 2);
+    for n in [1, 2] {
+        assert!(html.contains(&format!(
+            "<span class=\"dtr-qr-permalink\" title=\"https://forum.example.com/t/123/{n}\">"
+        )));
+    }
+    assert!(html.contains(".dtr-qr-permalink { display: none; }"));
+    assert!(html.contains("@media print {\n  .dtr-qr-permalink { display: inline-block;"));
+
+    let html = render(true).await;
+    assert_eq!(html.matches("<span class=\"dtr-qr-permalink\"").count(), 2);
+    assert!(!html.contains(".dtr-qr-permalink { display: none; }"));
+}
+
+#[tokio::test]
+async fn prune_fonts_drops_unused_font_faces_without_downloading_them() {
+    let server = MockServer::start();
+    let font = |path: &'static str| {
+        server.mock(|when, then| {
+            when.method(GET).path(path);
+            then.status(200)
+                .header("Content-Type", "font/woff2")
+                .body(woff2_bytes());
+        })
+    };
+    let (body, inline, injected, unused) = (
+        font("/fonts/body.woff2"),
+        font("/fonts/inline.woff2"),
+        font("/fonts/injected.woff2"),
+        font("/fonts/unused.woff2"),
+    );
+
+    let tmp = tempdir().unwrap();
+    let input = one_post_topic(tmp.path(), "<p style=\"font-family: 'Inline Face'\">hi</p>");
+    let css = tmp.path().join("site.css");
+    std::fs::write(
+        &css,
+        r#"@font-face { font-family: "Body Face"; src: url(/fonts/body.woff2) format("woff2"); }
+@font-face { font-family: "Inline Face"; src: url(/fonts/inline.woff2); }
+@font-face { font-family: "Injected Face"; src: url(/fonts/injected.woff2); }
+@font-face { font-family: "Unused Face"; src: url(/fonts/unused.woff2), url(data:font/woff2;base64,AA==); }
+:root { --font-family: "Body Face", sans-serif; }
+body { font-family: var(--font-family); }
+"#,
+    )
+    .unwrap();
+    let inject = tmp.path().join("inject.css");
+    std::fs::write(&inject, "h1 { font-family: 'Injected Face'; }\n").unwrap();
+    let base_url = Url::parse(&server.base_url()).unwrap();
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        css: vec![css],
+        inject_css: vec![inject],
+        prune_fonts: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    body.assert();
+    inline.assert();
+    injected.assert();
+    unused.assert_hits(0);
+    let css = read_to_string(&linked_css(&out_dir));
+    assert!(
+        css.contains("/* pruned unused @font-face unused face */"),
+        "{css}"
+    );
+    assert_eq!(css.matches("@font-face").count(), 4, "{css}");
+    assert!(css.contains("font-family: \"Body Face\"; src: url(\"../font/"));
+    assert!(!css.contains("dtr-font-face"));
+}
+
+#[tokio::test]
+async fn same_topic_oneboxes_become_local_and_others_stay_remote() {
+    let server = MockServer::start();
+    let avatar = server.mock(|when, then| {
+        when.method(GET).path("/user_avatar/bob/90.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let same = base_url.join("/t/slug/123/1").unwrap();
+    let other = base_url.join("/t/other/456/1").unwrap();
+    let onebox = |src: &Url| {
+        format!(
+            "<aside class=\"onebox discoursetopic\" data-onebox-src=\"{src}\"><header class=\"source\"><a href=\"{src}\">forum</a></header><article class=\"onebox-body\"><img class=\"avatar\" src=\"/user_avatar/bob/90.png\"><h3><a href=\"{src}\">Title</a></h3><blockquote>excerpt</blockquote></article></aside>"
+        )
+    };
+    let topic_json = serde_json::json!({
+        "id": 123,
+        "title": "Test Topic",
+        "post_stream": {"posts": [
+            {"post_number": 1, "cooked": "<p>First</p>"},
+            {"post_number": 2, "cooked": format!("{}{}", onebox(&same), onebox(&other))},
+        ]},
+    });
+    std::fs::write(&input, topic_json.to_string()).unwrap();
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    avatar.assert();
+
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert_no_remote_autoload(&html);
+    assert_eq!(html.matches("<a href=\"#post_1\">").count(), 2);
+    assert_eq!(html.matches("(in this archive)").count(), 1);
+    assert_eq!(html.matches(&format!("<a href=\"{other}\">")).count(), 2);
+    assert_eq!(
+        html.matches("<img class=\"avatar\" decoding=\"async\" loading=\"lazy\" src=\"assets/img/")
+            .count(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn oversized_and_deeply_nested_inputs_fail_cleanly() {
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+
+    let big = tmp.path().join("category.json");
+    let post = r#"{"post_number": 1, "cooked": "<p>filler</p>"},"#;
+    let posts = post.repeat(1024 * 1024 / post.len() + 1);
+    std::fs::write(
+        &big,
+        format!(
+            r#"{{"id": 1, "title": "T", "post_stream": {{"posts": [{}]}}}}"#,
+            posts.trim_end_matches(',')
+        ),
+    )
+    .unwrap();
+    let args = discourse_topic_render::CliArgs {
+        max_input_mb: 1,
+        out: Some(tmp.path().join("big")),
+        ..base_args(&big, &base_url)
+    };
+    let err = discourse_topic_render::run(args).await.unwrap_err();
+    let typed = err
+        .downcast_ref::<discourse_topic_render::InputTooLarge>()
+        .unwrap();
+    assert_eq!(typed.limit, 1024 * 1024);
+    assert!(typed.bytes.unwrap() > typed.limit);
+    assert!(err.to_string().contains("--max-input-mb"));
+
+    // Unknown fields are skipped without recursing, however deep; nesting where the model expects a value fails
+    // at the first mismatch. Neither may overflow the stack.
+    let depth = 100_000;
+    let nesting = format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+    let skipped = tmp.path().join("skipped.json");
+    std::fs::write(
+        &skipped,
+        format!(
+            r#"{{"id": 1, "title": "T", "extra": {nesting}, "post_stream": {{"posts": [{{"post_number": 1, "cooked": "<p>hi</p>"}}]}}}}"#
+        ),
+    )
+    .unwrap();
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        out: Some(tmp.path().join("skipped")),
+        ..base_args(&skipped, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let nested = tmp.path().join("nested.json");
+    std::fs::write(
+        &nested,
+        format!(
+            r#"{{"id": 1, "title": "T", "post_stream": {{"posts": [{{"post_number": 1, "cooked": {nesting}}}]}}}}"#
+        ),
+    )
+    .unwrap();
+    let args = discourse_topic_render::CliArgs {
+        out: Some(tmp.path().join("nested")),
+        ..base_args(&nested, &base_url)
+    };
+    let err = discourse_topic_render::run(args).await.unwrap_err();
+    let typed = err.downcast_ref::<serde_json::Error>().unwrap();
+    assert!(typed.is_data(), "{typed}");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn read_only_output_fails_before_any_request() {
+    use std::os::unix::fs::{MetadataExt as _, PermissionsExt as _};
+
+    let server = MockServer::start();
+    let any = server.mock(|_, then| {
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let topic_json = r#"{
+  "id": 123,
+  "title": "Test Topic",
+  "post_stream": {
+    "posts": [
+      { "post_number": 1, "avatar_template": "/user_avatar/bob/{size}/1.png", "cooked": "<p><img src=\"/uploads/a.png\"></p>" }
+    ]
+  }
+}"#;
+    std::fs::write(&input, topic_json).unwrap();
+
+    // Holds for every user, root included: an --out beneath a regular file fails the probe, not a download.
+    let args = discourse_topic_render::CliArgs {
+        out: Some(input.join("out")),
+        ..base_args(&input, &base_url)
+    };
+    let err = discourse_topic_render::run(args).await.unwrap_err();
+    assert!(format!("{err:#}").contains("topic.json"), "{err:#}");
+    any.assert_hits(0);
+
+    if std::fs::metadata(&input).unwrap().uid() == 0 {
+        eprintln!(
+            "skipping the permission half of read_only_output_fails_before_any_request: running as root"
+        );
+        return;
+    }
+    let locked = tmp.path().join("locked");
+    std::fs::create_dir(&locked).unwrap();
+    std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o555)).unwrap();
+
+    for (mode, out) in [
+        (discourse_topic_render::Mode::Dir, locked.join("out")),
+        (
+            discourse_topic_render::Mode::Single,
+            locked.join("topic.html"),
+        ),
+    ] {
+        let args = discourse_topic_render::CliArgs {
+            mode,
+            out: Some(out),
+            ..base_args(&input, &base_url)
+        };
+        let err = discourse_topic_render::run(args).await.unwrap_err();
+        let typed = err
+            .downcast_ref::<discourse_topic_render::OutputNotWritable>()
+            .unwrap();
+        assert!(typed.path.starts_with(&locked));
+        assert!(err.to_string().contains("--out"));
+    }
+    any.assert_hits(0);
+
+    std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[tokio::test]
+async fn font_faces_download_only_their_best_source() {
+    let server = MockServer::start();
+    let font = |path: &'static str| {
+        server.mock(|when, then| {
+            when.method(GET).path(path);
+            then.status(200)
+                .header("Content-Type", "font/woff2")
+                .body(woff2_bytes());
+        })
+    };
+    let (eot, woff2, woff, ttf) = (
+        font("/fonts/a.eot"),
+        font("/fonts/a.woff2"),
+        font("/fonts/a.woff"),
+        font("/fonts/a.ttf"),
+    );
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(tmp.path(), "<p>hi</p>");
+    let css_path = tmp.path().join("site.css");
+    std::fs::write(
+        &css_path,
+        "@font-face {\n  font-family: A;\n  src: url(/fonts/a.eot);\n  src: local(\"A\"), url(/fonts/a.eot?#iefix) format(\"embedded-opentype\"), url(/fonts/a.woff2) format(\"woff2\"), url(/fonts/a.woff) format(\"woff\"), url(/fonts/a.ttf) format(\"truetype\");\n}\nbody { font-family: A; }\n",
+    )
+    .unwrap();
+
+    let out = tmp.path().join("topic.html");
+    let args = discourse_topic_render::CliArgs {
+        css: vec![css_path],
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    woff2.assert();
+    for mock in [eot, woff, ttf] {
+        mock.assert_hits(0);
+    }
+    let html = read_to_string(&out);
+    assert_eq!(html.matches("src:").count(), 1);
+    assert!(html.contains("src: local(\"A\"), url(\"data:font/woff2;base64,"));
+}
+
+#[tokio::test]
+async fn discovery_keeps_inline_styles_and_media_in_document_order() {
+    let server = MockServer::start();
+    let home = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(
+                r#"<html><head><style>.critical { background: url(/images/hero.png); }</style>
+<link rel="stylesheet" media="all" href="/light.css">
+<link rel="stylesheet" media="(prefers-color-scheme: dark)" href="/dark.css" data-theme-id="2">
+</head><body><style media="print">.no-print { display: none; }</style></body></html>"#,
+            );
+    });
+    let hero = server.mock(|when, then| {
+        when.method(GET).path("/images/hero.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+    for (path, body) in [
+        ("/light.css", ".light { color: black; }\n"),
+        ("/dark.css", ".dark { color: white; }\n"),
+    ] {
+        server.mock(|when, then| {
+            when.method(GET).path(path);
+            then.status(200)
+                .header("Content-Type", "text/css")
+                .body(body);
+        });
+    }
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(tmp.path(), "<p>hi</p>");
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    home.assert();
+    hero.assert();
+    let bundled = read_to_string(&linked_css(&out_dir));
+    let position = |needle: &str| {
+        bundled
+            .find(needle)
+            .unwrap_or_else(|| panic!("{needle} missing from {bundled}"))
+    };
+    assert!(bundled.contains(".critical { background: url(\"../img/"));
+    assert!(!bundled.contains("@media all"));
+    assert!(bundled.contains("@media (prefers-color-scheme: dark) {\n.dark { color: white; }"));
+    assert!(bundled.contains("@media print {\n.no-print { display: none; }"));
+    assert!(position(".critical") < position(".light"));
+    assert!(position(".light") < position(".dark"));
+    assert!(position(".dark") < position(".no-print"));
+}
+
+#[tokio::test]
+async fn a_stylesheet_linked_twice_is_downloaded_once() {
+    let server = MockServer::start();
+    let home = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(
+                r#"<link rel="preload" as="style" href="/site.css#preload"><link rel="stylesheet" href="/site.css">"#,
+            );
+    });
+    let css = server.mock(|when, then| {
+        when.method(GET).path("/site.css");
+        then.status(200)
+            .header("Content-Type", "text/css")
+            .body(".site { color: red; }\n");
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(tmp.path(), "<p>hi</p>");
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    home.assert();
+    css.assert_hits(1);
+    let bundled = read_to_string(&linked_css(&out_dir));
+    assert_eq!(bundled.matches(".site").count(), 1);
+}
+
+#[tokio::test]
+async fn css_urls_resolve_relative_references_against_their_origin() {
+    let server = MockServer::start();
+    let home = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200).body("<html></html>");
+    });
+    let desktop = server.mock(|when, then| {
+        when.method(GET).path("/stylesheets/desktop.css");
+        then.status(200)
+            .header("Content-Type", "text/css")
+            .body("@import \"print.css\";\n.banner { background: url(../images/bg.png); }\n");
+    });
+    let print = server.mock(|when, then| {
+        when.method(GET).path("/stylesheets/print.css");
+        then.status(200)
+            .header("Content-Type", "text/css")
+            .body(".print-only { display: none; }\n");
+    });
+    let bg = server.mock(|when, then| {
+        when.method(GET).path("/images/bg.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(tmp.path(), "<p>hi</p>");
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        css_url: vec![base_url.join("stylesheets/desktop.css").unwrap()],
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    home.assert_hits(0);
+    desktop.assert();
+    print.assert();
+    bg.assert();
+    let bundled = read_to_string(&linked_css(&out_dir));
+    assert!(
+        bundled.contains(".print-only { display: none; }"),
+        "{bundled}"
+    );
+    assert!(
+        bundled.contains(".banner { background: url(\"../img/"),
+        "{bundled}"
+    );
+}
+
+#[tokio::test]
+async fn stylesheets_are_decoded_with_their_declared_charset() {
+    let server = MockServer::start();
+    let home = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(
+                r#"<link rel="stylesheet" href="/latin1.css"><link rel="stylesheet" href="/cyrillic.css">"#,
+            );
+    });
+    // "café" in Latin-1, in a comment and a string.
+    let latin1 = server.mock(|when, then| {
+        when.method(GET).path("/latin1.css");
+        then.status(200)
+            .header("Content-Type", "text/css; charset=ISO-8859-1")
+            .body(b"/* caf\xe9 */\n.latin1::before { content: \"caf\xe9\"; }\n");
+    });
+    // "Привет" in windows-1251, declared only by the stylesheet itself.
+    let cyrillic = server.mock(|when, then| {
+        when.method(GET).path("/cyrillic.css");
+        then.status(200)
+            .header("Content-Type", "text/css")
+            .body(b"@charset \"windows-1251\";\n.cyrillic::before { content: \"\xcf\xf0\xe8\xe2\xe5\xf2\"; }\n");
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = one_post_topic(tmp.path(), "<p>hi</p>");
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    home.assert();
+    latin1.assert();
+    cyrillic.assert();
+    let bundled = read_to_string(&linked_css(&out_dir));
+    assert!(bundled.contains("/* café */"), "{bundled}");
+    assert!(bundled.contains(r#".latin1::before { content: "café"; }"#));
+    assert!(bundled.contains(r#".cyrillic::before { content: "Привет"; }"#));
+    // Written as UTF-8, so the original declaration would be wrong.
+    assert!(!bundled.contains("@charset"));
+}
+
+#[tokio::test]
+async fn google_fonts_css_is_requested_as_a_browser() {
+    // Both hosts are reached through the proxy, which lets the mock see their requests.
+    let proxy = MockServer::start();
+    let google_css = proxy.mock(|when, then| {
+        when.method(GET)
+            .path("/css2")
+            .query_param("family", "Inter")
+            .matches(|req| {
+                req.headers.iter().flatten().any(|(name, value)| {
+                    name.eq_ignore_ascii_case("user-agent") && value.contains("Chrome/")
+                })
+            });
+        then.status(200).header("Content-Type", "text/css").body(
+            "@font-face { font-family: 'Inter'; src: url(http://fonts.gstatic.com/s/inter/v1/a.woff2) format('woff2'); }\n",
+        );
+    });
+    let font = proxy.mock(|when, then| {
+        when.method(GET)
+            .path("/s/inter/v1/a.woff2")
+            .header("user-agent", "test-agent");
+        then.status(200)
+            .header("Content-Type", "font/woff2")
+            .body(woff2_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse("http://forum.invalid/").unwrap();
+    let input = one_post_topic(tmp.path(), "<p>hi</p>");
+    let css_path = tmp.path().join("site.css");
+    std::fs::write(
+        &css_path,
+        "@import url(\"http://fonts.googleapis.com/css2?family=Inter\");\nbody { font-family: Inter; }\n",
+    )
+    .unwrap();
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        css: vec![css_path],
+        proxy: Some(proxy.url("/")),
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    google_css.assert();
+    font.assert();
+    let css = read_to_string(&linked_css(&out_dir));
+    assert!(css.contains("url(\"../font/"));
+}
+
+#[tokio::test]
+async fn css_integrity_hashes_the_written_stylesheet() {
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse("http://forum.invalid/").unwrap();
+    let input = one_post_topic(tmp.path(), "<p>hi</p>");
+
+    for css_integrity in [false, true] {
+        let out_dir = tmp.path().join(format!("out-{css_integrity}"));
+        let args = discourse_topic_render::CliArgs {
+            builtin_css: true,
+            css_integrity,
+            out: Some(out_dir.clone()),
+            ..base_args(&input, &base_url)
+        };
+        discourse_topic_render::run(args).await.unwrap();
+
+        let html = read_to_string(&out_dir.join("topic-123.html"));
+        let css = std::fs::read(linked_css(&out_dir)).unwrap();
+        let digest = ring::digest::digest(&ring::digest::SHA384, &css);
+        let expected = format!(
+            "integrity=\"sha384-{}\" crossorigin=\"anonymous\"",
+            base64::engine::general_purpose::STANDARD.encode(digest.as_ref())
+        );
+        assert_eq!(html.contains(&expected), css_integrity);
+        assert_eq!(html.contains("integrity="), css_integrity);
+    }
+}
+
+#[tokio::test]
+async fn purge_css_drops_unmatched_rules_before_downloading_their_assets() {
+    let server = MockServer::start();
+    let image = |path: &'static str| {
+        server.mock(|when, then| {
+            when.method(GET).path(path);
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body(png_bytes());
+        })
+    };
+    let (used, hover, unused) = (
+        image("/img/used.png"),
+        image("/img/hover.png"),
+        image("/img/unused.png"),
+    );
+
+    let tmp = tempdir().unwrap();
+    let input = one_post_topic(
+        tmp.path(),
+        "<p>hi <a href=\"https://example.com/\">link</a></p>",
+    );
+    let css = tmp.path().join("site.css");
+    let source = r#".cooked p { background: url(/img/used.png); }
+.cooked a:hover, .sidebar a { background: url(/img/hover.png); }
+.composer-popup { background: url(/img/unused.png); }
+@media print { .composer-popup { display: none; } .cooked { color: black; } }
+@keyframes fade { from { opacity: 0; } to { opacity: 1; } }
+"#;
+    std::fs::write(&css, source).unwrap();
+    let base_url = Url::parse(&server.base_url()).unwrap();
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        css: vec![css],
+        purge_css: true,
+        no_stamp: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    used.assert();
+    hover.assert();
+    unused.assert_hits(0);
+    let css = read_to_string(&linked_css(&out_dir));
+    assert!(!css.contains("composer-popup"), "{css}");
+    assert!(
+        css.contains(".cooked a:hover, .sidebar a { background: url(\"../img/"),
+        "{css}"
+    );
+    assert!(
+        css.contains("@media print {  .cooked { color: black; } }"),
+        "{css}"
+    );
+    assert!(css.contains("@keyframes fade"), "{css}");
+}
+
+#[tokio::test]
+async fn truncated_exports_warn_and_fail_under_require_complete() {
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    let posts: Vec<_> = (1..=20)
+        .map(|n| serde_json::json!({"post_number": n, "cooked": format!("<p>post {n}</p>")}))
+        .collect();
+    std::fs::write(
+        &input,
+        serde_json::json!({
+            "id": 123,
+            "title": "Long topic",
+            "posts_count": 800,
+            "post_stream": {"posts": posts},
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+
+    // A warning by default: the 20 exported posts are still rendered.
+    let out = tmp.path().join("out.html");
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_discourse-topic-render"))
+        .args(["--input".as_ref(), input.as_os_str()])
+        .args(["--base-url", base_url.as_str()])
+        .args(["--builtin-css", "--mode", "single", "--progress", "never"])
+        // Log lines go to stderr next to the JSON summary.
+        .args(["--summary", "json"])
+        .args(["--out".as_ref(), out.as_os_str()])
+        .env("NO_COLOR", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let warning = stderr
+        .lines()
+        .find(|line| line.contains("WARN"))
+        .unwrap_or_else(|| panic!("no warning in {stderr}"));
+    assert!(
+        warning.contains("topic.json holds 20 of the 800 posts topic 123 declares (2.5%)"),
+        "{warning}"
+    );
+    assert!(read_to_string(&out).contains("post 20"));
+
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(tmp.path().join("strict.html")),
+        require_complete: true,
+        ..base_args(&input, &base_url)
+    };
+    let err = discourse_topic_render::run(args).await.unwrap_err();
+    let typed = err
+        .downcast_ref::<discourse_topic_render::IncompleteExport>()
+        .unwrap();
+    assert_eq!((typed.exported, typed.declared), (20, 800));
+    assert!(!tmp.path().join("strict.html").exists());
+
+    // A lower threshold accepts the same export.
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(tmp.path().join("lenient.html")),
+        require_complete: true,
+        min_completeness: 2,
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+}
+
+#[tokio::test]
+async fn repeated_avatar_sizes_become_a_density_srcset() {
+    let server = MockServer::start();
+    let sizes: Vec<_> = ["/avatar/45.png", "/avatar/90.png"]
+        .into_iter()
+        .map(|path| {
+            server.mock(|when, then| {
+                when.method(GET).path(path);
+                then.status(200)
+                    .header("Content-Type", "image/png")
+                    .body(format!("{}{path}", String::from_utf8_lossy(&png_bytes())));
+            })
+        })
+        .collect();
+
+    let tmp = tempdir().unwrap();
+    let input = write_topic(
+        tmp.path(),
+        serde_json::json!([{"post_number": 1, "username": "alice", "avatar_template": "/avatar/{size}.png", "cooked": "<p>hi</p>"}]),
+    );
+    let css = tmp.path().join("site.css");
+    std::fs::write(&css, ".topic-avatar { float: left; }").unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+
+    // The site template in dir mode: local paths.
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        css: vec![css],
+        mode: discourse_topic_render::Mode::Dir,
+        avatar_size: vec![90, 45],
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    for size in &sizes {
+        size.assert();
+    }
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert_no_remote_autoload(&html);
+    {
+        let document = kuchiki::parse_html().one(html);
+        let avatar = document.select_first("img.avatar").unwrap();
+        let attrs = avatar.attributes.borrow();
+        let src = attrs.get("src").unwrap();
+        let srcset: Vec<&str> = attrs.get("srcset").unwrap().split(", ").collect();
+        assert_eq!(srcset.len(), 1, "{srcset:?}");
+        assert!(srcset[0].starts_with("assets/") && srcset[0].ends_with(" 2x"));
+        assert_ne!(srcset[0], format!("{src} 2x"));
+    }
+
+    // The builtin template in single mode: data URIs.
+    let out_single = tmp.path().join("single.html");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Single,
+        avatar_size: vec![45, 90],
+        out: Some(out_single.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    let html = read_to_string(&out_single);
+    assert_no_remote_autoload(&html);
+    let document = kuchiki::parse_html().one(html);
+    let avatar = document.select_first("img.dtr-avatar").unwrap();
+    let attrs = avatar.attributes.borrow();
+    let srcset = attrs.get("srcset").unwrap();
+    // The 1x data URI is only in the `src`.
+    assert!(srcset.starts_with("data:image/png;base64,"), "{srcset}");
+    assert!(
+        srcset.ends_with(" 2x") && !srcset.contains(", "),
+        "{srcset}"
+    );
+    assert_ne!(srcset, format!("{} 2x", attrs.get("src").unwrap()));
+}
+
+#[test]
+fn user_agent_settings_come_from_flags_over_the_environment() {
+    let config = |env: &[(&str, &str)], flags: &[&str]| -> serde_json::Value {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_discourse-topic-render"))
+            .args([
+                "--input",
+                "topic.json",
+                "--base-url",
+                "https://forum.example.com",
+            ])
+            .args(flags)
+            .arg("--print-config")
+            .env_remove("DTR_USER_AGENT")
+            .env_remove("DTR_UA_CONTACT")
+            .envs(env.iter().copied())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        serde_json::from_slice(&output.stdout).unwrap()
+    };
+
+    let defaults = config(&[], &[]);
+    assert_eq!(
+        defaults["user_agent"],
+        serde_json::json!({"value": null, "source": "default"})
+    );
+
+    let from_env = config(
+        &[
+            ("DTR_UA_CONTACT", "mailto:a@example.org"),
+            ("DTR_USER_AGENT", "EnvAgent/1"),
+        ],
+        &[],
+    );
+    assert_eq!(
+        from_env["ua_contact"],
+        serde_json::json!({"value": "mailto:a@example.org", "source": "env"})
+    );
+    assert_eq!(
+        from_env["user_agent"],
+        serde_json::json!({"value": "EnvAgent/1", "source": "env"})
+    );
+
+    let from_flags = config(
+        &[
+            ("DTR_UA_CONTACT", "mailto:a@example.org"),
+            ("DTR_USER_AGENT", "EnvAgent/1"),
+        ],
+        &[
+            "--ua-contact",
+            "https://example.org/archive",
+            "--user-agent",
+            "CliAgent/1",
+        ],
+    );
+    assert_eq!(
+        from_flags["ua_contact"],
+        serde_json::json!({"value": "https://example.org/archive", "source": "cli"})
+    );
+    assert_eq!(
+        from_flags["user_agent"],
+        serde_json::json!({"value": "CliAgent/1", "source": "cli"})
+    );
+}
+
+#[tokio::test]
+async fn picture_sources_stay_in_dir_mode_and_collapse_in_single_mode() {
+    let server = MockServer::start();
+    let mut images = Vec::new();
+    for (path, content_type) in [
+        ("/a.avif", "image/avif"),
+        ("/a_2x.avif", "image/avif"),
+        ("/a.webp", "image/webp"),
+        ("/a.jpg", "image/jpeg"),
+        ("/narrow.webp", "image/webp"),
+        ("/fallback.png", "image/png"),
+    ] {
+        let mock = server.mock(|when, then| {
+            when.method(GET).path(path);
+            then.status(200)
+                .header("Content-Type", content_type)
+                .body(format!("{}{path}", String::from_utf8_lossy(&png_bytes())));
+        });
+        images.push(mock);
+    }
+
+    let tmp = tempdir().unwrap();
+    let cooked = r#"<picture><source media="(max-width: 400px)" srcset="/narrow.webp"><source type="image/avif" srcset="/a.avif 1x, /a_2x.avif 2x"><source type="image/webp" srcset="/a.webp"><source type="image/jpeg" srcset="/a.jpg"><img alt="pic" src="/fallback.png"></picture>"#;
+    let input = one_post_topic(tmp.path(), cooked);
+    let base_url = Url::parse(&server.url("/")).unwrap();
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Dir,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    for mock in &images {
+        mock.assert();
+    }
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert_no_remote_autoload(&html);
+    {
+        let document = kuchiki::parse_html().one(html);
+        let sources: Vec<(Option<String>, Option<String>, String)> = document
+            .select("picture > source")
+            .unwrap()
+            .map(|source| {
+                let attrs = source.attributes.borrow();
+                assert!(attrs.get("src").is_none());
+                (
+                    attrs.get("type").map(str::to_string),
+                    attrs.get("media").map(str::to_string),
+                    attrs.get("srcset").unwrap().to_string(),
+                )
+            })
+            .collect();
+        let types: Vec<_> = sources.iter().map(|(t, _, _)| t.as_deref()).collect();
+        assert_eq!(
+            types,
+            [
+                None,
+                Some("image/avif"),
+                Some("image/webp"),
+                Some("image/jpeg")
+            ]
+        );
+        assert_eq!(sources[0].1.as_deref(), Some("(max-width: 400px)"));
+        let avif: Vec<&str> = sources[1].2.split(", ").collect();
+        assert_eq!(avif.len(), 2);
+        assert!(avif[0].starts_with("assets/img/") && avif[0].ends_with(" 1x"));
+        assert!(avif[1].starts_with("assets/img/") && avif[1].ends_with(" 2x"));
+        let img = document.select_first("picture > img").unwrap();
+        assert!(
+            img.attributes
+                .borrow()
+                .get("src")
+                .unwrap()
+                .starts_with("assets/img/")
+        );
+    }
+
+    // Single mode: the first unconditional source's best candidate, inlined into the <img>.
+    let out_single = tmp.path().join("single.html");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Single,
+        out: Some(out_single.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    let html = read_to_string(&out_single);
+    assert_no_remote_autoload(&html);
+    assert!(!html.contains("<picture") && !html.contains("<source"));
+    let document = kuchiki::parse_html().one(html);
+    let img = document.select_first("img[alt=pic]").unwrap();
+    let src = img.attributes.borrow().get("src").unwrap().to_string();
+    assert!(src.starts_with("data:image/avif;base64,"), "{src}");
+}
+
+#[test]
+fn preload_hints_list_the_stylesheet_and_the_first_images_in_dir_mode_only() {
+    let server = MockServer::start();
+    for (path, tag) in [
+        ("/avatar/45.png", b'v'),
+        ("/img/a.png", b'a'),
+        ("/img/b.png", b'b'),
+        ("/img/c.png", b'c'),
+    ] {
+        let mut body = png_bytes();
+        body.push(tag);
+        server.mock(|when, then| {
+            when.method(GET).path(path);
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body(body);
+        });
+    }
+
+    let tmp = tempdir().unwrap();
+    let input = write_topic(
+        tmp.path(),
+        serde_json::json!([
+            {"post_number": 1, "avatar_template": "/avatar/{size}.png", "cooked": "<p><img src=\"/img/a.png\"><img src=\"/img/b.png\"></p>"},
+            {"post_number": 2, "avatar_template": "/avatar/{size}.png", "cooked": "<p><img src=\"/img/c.png\"></p>"}
+        ]),
+    );
+    let render = |mode: &str, out: &Path| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_discourse-topic-render"))
+            .args(["--input".as_ref(), input.as_os_str()])
+            .args(["--base-url", &server.base_url()])
+            .args([
+                "--builtin-css",
+                "--avatar-size",
+                "45",
+                "--progress",
+                "never",
+            ])
+            .args(["--emit-preload-hints", "3", "--mode", mode])
+            .args(["--out".as_ref(), out.as_os_str()])
+            .env("RUST_LOG", "warn")
+            .output()
+            .unwrap()
+    };
+
+    let out_dir = tmp.path().join("out");
+    let output = render("dir", &out_dir);
+    assert!(output.status.success(), "{output:?}");
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    let document = kuchiki::parse_html().one(html.as_str());
+    let preloads: Vec<(String, String)> = document
+        .select("link[rel=preload]")
+        .unwrap()
+        .map(|link| {
+            let attributes = link.attributes.borrow();
+            (
+                attributes.get("as").unwrap().to_string(),
+                attributes.get("href").unwrap().to_string(),
+            )
+        })
+        .collect();
+    let src = |selector: &str| -> String {
+        let img = document.select_first(selector).unwrap();
+        img.attributes.borrow().get("src").unwrap().to_string()
+    };
+    // The avatar is shared by both posts and listed once; the third image is past N.
+    assert_eq!(
+        preloads,
+        [
+            (
+                "style".to_string(),
+                linked_css(&out_dir)
+                    .strip_prefix(&out_dir)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            ),
+            ("image".to_string(), src("#post_1 img.dtr-avatar")),
+            ("image".to_string(), src("#post_1 .cooked img")),
+            ("image".to_string(), src("#post_1 .cooked img + img")),
+        ]
+    );
+    assert!(html.find("rel=\"preload\"") < html.find("rel=\"stylesheet\""));
+
+    let single = tmp.path().join("single.html");
+    let output = render("single", &single);
+    assert!(output.status.success(), "{output:?}");
+    assert!(!read_to_string(&single).contains("rel=\"preload\""));
+    let log = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        log.contains("--emit-preload-hints only applies to --mode dir"),
+        "{log}"
+    );
+}
+
+/// Entries of a ZIP archive in file order: name, compression method and uncompressed contents.
+fn read_zip(bytes: &[u8]) -> Vec<(String, u16, Vec<u8>)> {
+    let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+    let mut entries = Vec::new();
+    let mut at = 0;
+    while u32_at(at) == 0x0403_4b50 {
+        let method = u16_at(at + 8);
+        let compressed = u32_at(at + 18) as usize;
+        let (name_len, extra_len) = (u16_at(at + 26) as usize, u16_at(at + 28) as usize);
+        let name = String::from_utf8(bytes[at + 30..at + 30 + name_len].to_vec()).unwrap();
+        let start = at + 30 + name_len + extra_len;
+        let raw = &bytes[start..start + compressed];
+        let data = match method {
+            0 => raw.to_vec(),
+            8 => {
+                let mut out = Vec::new();
+                std::io::Read::read_to_end(&mut flate2::read::DeflateDecoder::new(raw), &mut out)
+                    .unwrap();
+                out
+            }
+            other => panic!("unexpected compression method {other}"),
+        };
+        entries.push((name, method, data));
+        at = start + compressed;
+    }
+    entries
+}
+
+#[tokio::test]
+async fn epub_mode_packs_chapters_assets_and_a_cover() {
+    let server = MockServer::start();
+    let image = server.mock(|when, then| {
+        when.method(GET).path("/uploads/cover.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    std::fs::write(
+        &input,
+        r#"{"id": 123, "title": "Rust & e-ink", "post_stream": {"posts": [
+          {"post_number": 1, "username": "alice", "cooked": "<p>one<br><img src=\"/uploads/cover.png\" alt=\"c\"></p>"},
+          {"post_number": 2, "username": "bob", "cooked": "<p>two</p>"},
+          {"post_number": 3, "username": "carol", "cooked": "<p>three</p>", "reply_to_post_number": 1}
+        ]}}"#,
+    )
+    .unwrap();
+    let base_url = Url::parse(&server.base_url()).unwrap();
+
+    let out = tmp.path().join("book.epub");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        mode: discourse_topic_render::Mode::Epub,
+        epub_posts_per_chapter: 2,
+        reproducible: true,
+        out: Some(out.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+    image.assert();
+
+    let archive = std::fs::read(&out).unwrap();
+    // Readers sniff the format from a `mimetype` entry without an extra field.
+    assert_eq!(&archive[30..58], b"mimetypeapplication/epub+zip");
+    let entries = read_zip(&archive);
+    let (name, method, data) = &entries[0];
+    assert_eq!(
+        (name.as_str(), *method, data.as_slice()),
+        ("mimetype", 0, b"application/epub+zip".as_slice())
+    );
+    let bytes = |name: &str| -> Vec<u8> {
+        let (_, _, data) = entries
+            .iter()
+            .find(|(n, _, _)| n == name)
+            .unwrap_or_else(|| panic!("{name} is missing"));
+        data.clone()
+    };
+    let file = |name: &str| String::from_utf8(bytes(name)).unwrap();
+    assert!(file("META-INF/container.xml").contains("full-path=\"OEBPS/content.opf\""));
+
+    let p1 = file("OEBPS/topic-123-p1.html");
+    let p2 = file("OEBPS/topic-123-p2.html");
+    assert!(p1.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>"));
+    assert!(p1.contains("<br/>") && p1.contains("<p>two</p>") && !p1.contains("three"));
+    assert!(p2.contains("href=\"topic-123-p1.html#post_1\""), "{p2}");
+    assert!(!p1.contains("<script"));
+
+    let opf = file("OEBPS/content.opf");
+    assert!(
+        opf.contains("<dc:title>Rust &amp; e-ink</dc:title>"),
+        "{opf}"
+    );
+    assert!(opf.contains("<itemref idref=\"chapter-1\"/>\n    <itemref idref=\"chapter-2\"/>"));
+    let document = kuchiki::parse_html().one(p1.as_str());
+    let cover = document.select_first(".cooked img").unwrap();
+    let cover = cover.attributes.borrow().get("src").unwrap().to_string();
+    assert!(
+        opf.contains(&format!(
+            "<item id=\"cover-image\" href=\"{cover}\" media-type=\"image/png\" properties=\"cover-image\"/>"
+        )),
+        "{opf}"
+    );
+    assert!(opf.contains("href=\"assets/css/site-"), "{opf}");
+    assert!(opf.contains(".css\" media-type=\"text/css\""), "{opf}");
+    assert_eq!(bytes(&format!("OEBPS/{cover}")), png_bytes());
+
+    let nav = file("OEBPS/nav.xhtml");
+    assert!(nav.contains("<nav epub:type=\"toc\" id=\"toc\">"), "{nav}");
+    assert!(
+        nav.contains("<a href=\"topic-123-p2.html\">#3 carol</a>"),
+        "{nav}"
+    );
+    assert!(
+        nav.contains("<a href=\"topic-123-p1.html\">Posts 1–2</a>"),
+        "{nav}"
+    );
+}
+
+#[tokio::test]
+async fn avatars_missing_at_the_requested_size_fall_back_down_the_size_ladder() {
+    let server = MockServer::start();
+    let served = server.mock(|when, then| {
+        when.method(GET).path("/user_avatar/alice/120/1.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+    let alice_240 = server.mock(|when, then| {
+        when.method(GET).path("/user_avatar/alice/240/1.png");
+        then.status(404);
+    });
+    let bob = server.mock(|when, then| {
+        when.method(GET).path_contains("/user_avatar/bob/");
+        then.status(404);
+    });
+
+    let tmp = tempdir().unwrap();
+    let input = write_topic(
+        tmp.path(),
+        serde_json::json!([
+            {"post_number": 1, "username": "alice", "avatar_template": "/user_avatar/alice/{size}/1.png", "cooked": "<p>a</p>"},
+            {"post_number": 2, "username": "bob", "avatar_template": "/user_avatar/bob/{size}/1.png", "cooked": "<p>b</p>"}
+        ]),
+    );
+    let base_url = Url::parse(&server.base_url()).unwrap();
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        avatar_size: vec![240],
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    alice_240.assert();
+    served.assert();
+    // 240, 120, 96, 48 and 25, each once, before settling for a letter avatar.
+    bob.assert_hits(5);
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    let document = kuchiki::parse_html().one(html);
+    let avatar = |post: u64| {
+        let img = document
+            .select_first(&format!("#post_{post} img.dtr-avatar"))
+            .unwrap();
+        img.attributes.borrow().get("src").unwrap().to_string()
+    };
+    assert!(avatar(1).ends_with(".png"), "{}", avatar(1));
+    assert_eq!(std::fs::read(out_dir.join(avatar(1))).unwrap(), png_bytes());
+    assert!(avatar(2).ends_with(".svg"), "{}", avatar(2));
+}
+
+#[tokio::test]
+async fn rss_feed_has_an_item_per_rendered_post() {
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    let long = "word ".repeat(200);
+    std::fs::write(
+        &input,
+        serde_json::json!({
+            "id": 123,
+            "title": "Fish & <chips>",
+            "slug": "fish-chips",
+            "post_stream": {"posts": [
+                {"post_number": 1, "username": "alice", "created_at": "2024-03-05T07:08:09.000Z", "cooked": "<p>Salt &amp; <b>vinegar</b></p>"},
+                {"post_number": 2, "username": "bob", "cooked": format!("<p>{long}</p>")}
+            ]}
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+
+    let feed = tmp.path().join("feeds/topic-123.xml");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        rss: Some(feed.clone()),
+        out: Some(tmp.path().join("out")),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let rss = read_to_string(&feed);
+    assert!(rss.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<rss version=\"2.0\">"));
+    assert!(
+        rss.contains("<title>Fish &amp; &lt;chips&gt;</title>"),
+        "{rss}"
+    );
+    assert!(rss.contains("<link>https://forum.example.com/t/fish-chips/123</link>"));
+    assert_eq!(rss.matches("<item>").count(), 2);
+    assert!(rss.contains(
+        "      <title>Post #1 by alice</title>\n      <link>https://forum.example.com/t/fish-chips/123/1</link>"
+    ));
+    assert!(rss.contains("<pubDate>Tue, 5 Mar 2024 07:08:09 +0000</pubDate>"));
+    assert!(
+        rss.contains("<description>Salt &amp; vinegar</description>"),
+        "{rss}"
+    );
+    let description = rss
+        .split("<description>")
+        .last()
+        .unwrap()
+        .split("</description>")
+        .next()
+        .unwrap();
+    assert!(description.ends_with('…') && description.chars().count() <= 501);
+}
+
+#[tokio::test]
+async fn keep_original_urls_records_where_localized_assets_came_from() {
+    let server = MockServer::start();
+    for path in ["/avatar/120.png", "/thumb.png", "/full.png"] {
+        server.mock(|when, then| {
+            when.method(GET).path(path);
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body(png_bytes());
+        });
+    }
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = write_topic(
+        tmp.path(),
+        serde_json::json!([{"post_number": 1, "username": "alice", "avatar_template": "/avatar/{size}.png", "cooked": "<p><a class=\"lightbox\" href=\"/full.png\"><img src=\"/thumb.png\" alt=\"\"></a><a href=\"https://example.com/\">elsewhere</a></p>"}]),
+    );
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        keep_original_urls: true,
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    for (attr, path) in [
+        ("data-dtr-original-src", "avatar/120.png"),
+        ("data-dtr-original-href", "full.png"),
+        ("data-dtr-original-src", "thumb.png"),
+    ] {
+        assert!(
+            html.contains(&format!("{attr}=\"{base_url}{path}\"")),
+            "{attr} {path} in {html}"
+        );
+    }
+    assert_eq!(html.matches("data-dtr-original-").count(), 3);
+    let report = discourse_topic_render::check_strict_offline(&[&html], "");
+    assert!(report.violations.is_empty(), "{:?}", report.violations);
+}
+
+#[tokio::test]
+async fn atom_feed_has_an_entry_per_rendered_post() {
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    std::fs::write(
+        &input,
+        r#"{"id": 123, "title": "Fish & chips", "slug": "fish-chips", "post_stream": {"posts": [
+            {"post_number": 1, "username": "alice", "created_at": "2024-03-05T07:08:09.000Z", "updated_at": "2024-03-06T10:00:00.000Z", "cooked": "<p>Salt &amp; <code>]]&gt;</code></p>"},
+            {"post_number": 2, "username": "bob", "created_at": "2024-03-05T08:00:00.000Z", "cooked": "<p>Vinegar</p>"}
+        ]}}"#,
+    )
+    .unwrap();
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+
+    let feed = tmp.path().join("topic-123.atom");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        atom: Some(feed.clone()),
+        out: Some(tmp.path().join("out")),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let atom = read_to_string(&feed);
+    assert!(atom.contains("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n  <id>urn:discourse:123</id>\n  <title>Fish &amp; chips</title>\n  <updated>2024-03-06T10:00:00Z</updated>"), "{atom}");
+    assert!(
+        atom.contains("<link rel=\"self\" type=\"application/atom+xml\" href=\"topic-123.atom\"/>")
+    );
+    assert_eq!(atom.matches("<entry>").count(), 2);
+    assert!(atom.contains(
+        "    <id>urn:discourse:123:post_1</id>\n    <title>Post #1 by alice</title>\n    <updated>2024-03-06T10:00:00Z</updated>\n    <published>2024-03-05T07:08:09Z</published>\n    <author>\n      <name>alice</name>\n    </author>\n    <link rel=\"alternate\" type=\"text/html\" href=\"https://forum.example.com/t/fish-chips/123/1\"/>"
+    ));
+    assert!(atom.contains("<updated>2024-03-05T08:00:00Z</updated>"));
+    assert!(
+        atom.contains(
+            "<content type=\"html\"><![CDATA[<p>Salt &amp; <code>]]&gt;</code></p>]]></content>"
+        ),
+        "{atom}"
+    );
+}
+
+#[tokio::test]
+async fn atom_content_keeps_the_forum_urls_of_localized_media() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path("/sub/uploads/a.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let input = one_post_topic(
+        tmp.path(),
+        "<p><img src=\"uploads/a.png\" srcset=\"uploads/a.png 2x\"><a href=\"/t/other/9\">other</a></p>",
+    );
+    let base_url = Url::parse(&server.url("/sub/")).unwrap();
+
+    let feed = tmp.path().join("topic-123.atom");
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        atom: Some(feed.clone()),
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let html = read_to_string(&out_dir.join("topic-123.html"));
+    assert!(html.contains("src=\"assets/img/"), "{html}");
+    let atom = read_to_string(&feed);
+    let origin = server.base_url();
+    assert!(
+        atom.contains(&format!(
+            "<content type=\"html\"><![CDATA[<p><img src=\"{origin}/sub/uploads/a.png\" srcset=\"{origin}/sub/uploads/a.png 2x\"><a href=\"{origin}/t/other/9\">other</a></p>]]></content>"
+        )),
+        "{atom}"
+    );
+}
+
+#[tokio::test]
+async fn duplicate_css_rules_from_several_origins_are_bundled_once() {
+    let tmp = tempdir().unwrap();
+    let input = one_post_topic(tmp.path(), "<p>Hi</p>");
+    let common = ".d-header { height: 4em; background: #fff }";
+    let base = tmp.path().join("base.css");
+    let theme = tmp.path().join("theme.css");
+    std::fs::write(
+        &base,
+        format!("{common}\n.btn {{ color: red }}\n@media print {{ .btn {{ display: none }} }}\n"),
+    )
+    .unwrap();
+    std::fs::write(
+        &theme,
+        format!("{common}\n.btn {{ color: blue }}\n.btn {{ display: none }}\n"),
+    )
+    .unwrap();
+    let base_url = Url::parse("https://forum.example.com/").unwrap();
+
+    for (no_dedupe_css, copies) in [(false, 1), (true, 2)] {
+        let out_dir = tmp.path().join(format!("out-{no_dedupe_css}"));
+        let args = discourse_topic_render::CliArgs {
+            css: vec![base.clone(), theme.clone()],
+            no_dedupe_css,
+            out: Some(out_dir.clone()),
+            ..base_args(&input, &base_url)
+        };
+        discourse_topic_render::run(args).await.unwrap();
+
+        let css = read_to_string(&linked_css(&out_dir));
+        assert_eq!(css.matches(common).count(), copies, "{css}");
+        // The override pair and the same rule in another context all stay, in order.
+        let red = css.find(".btn { color: red }").unwrap();
+        let blue = css.find(".btn { color: blue }").unwrap();
+        assert!(red < blue);
+        assert_eq!(css.matches(".btn { display: none }").count(), 2);
+    }
+}
+
+#[tokio::test]
+async fn dir_mode_writes_a_manifest_of_the_stored_assets() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path("/img.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let base_url = Url::parse(&server.url("/")).unwrap();
+    let input = write_topic(
+        tmp.path(),
+        serde_json::json!([{"post_number": 1, "username": "alice", "avatar_template": "/missing/{size}.png", "cooked": "<p><img src=\"/img.png\" alt=\"\"><img src=\"/img.png?again\" alt=\"\"></p>"}]),
+    );
+    let script = tmp.path().join("extra.js");
+    std::fs::write(&script, "console.log(1);\n").unwrap();
+
+    let out_dir = tmp.path().join("out");
+    let args = discourse_topic_render::CliArgs {
+        builtin_css: true,
+        inject_js: vec![script.clone()],
+        out: Some(out_dir.clone()),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let manifest: serde_json::Value = serde_json::from_str(&read_to_string(
+        &out_dir.join("topic-123.assets-manifest.json"),
+    ))
+    .unwrap();
+    let entries = manifest.as_array().unwrap();
+    let images: Vec<_> = entries.iter().filter(|e| e["kind"] == "image").collect();
+    assert_eq!(images.len(), 2, "{manifest:#}");
+    let hash = blake3::hash(&png_bytes()).to_hex().to_string();
+    for image in &images {
+        assert_eq!(image["hash"], hash.as_str());
+        assert_eq!(image["bytes"], png_bytes().len());
+        assert_eq!(image["mime"], "image/png");
+        assert_eq!(image["path"], format!("assets/img/{hash}.png").as_str());
+        assert_eq!(image["generated"], false);
+        assert!(out_dir.join(image["path"].as_str().unwrap()).is_file());
+    }
+    assert_eq!(images[0]["source"], format!("{base_url}img.png").as_str());
+    assert_eq!(
+        images[1]["source"],
+        format!("{base_url}img.png?again").as_str()
+    );
+
+    let avatar = entries.iter().find(|e| e["kind"] == "avatar").unwrap();
+    assert_eq!(avatar["generated"], true);
+    assert_eq!(avatar["mime"], "image/svg+xml");
+    assert_eq!(
+        avatar["source"],
+        format!("{base_url}missing/120.png").as_str()
+    );
+
+    // The files the render writes itself are listed too.
+    let css = entries.iter().find(|e| e["mime"] == "text/css").unwrap();
+    assert_eq!(css["mime"], "text/css");
+    assert_eq!(css["generated"], true);
+    let css_bytes = std::fs::read(linked_css(&out_dir)).unwrap();
+    assert_eq!(css["hash"], blake3::hash(&css_bytes).to_hex().as_str());
+    let js = entries
+        .iter()
+        .find(|e| e["path"] == "assets/js/extra.js")
+        .unwrap();
+    assert_eq!(js["source"], script.display().to_string().as_str());
+    assert_eq!(js["mime"], "text/javascript");
+}
+
+#[test]
+fn summary_json_reports_requests_bytes_and_the_largest_downloads() {
+    let server = MockServer::start();
+    for (path, extra) in [("/small.png", 0), ("/large.png", 4096)] {
+        let mut body = png_bytes();
+        body.extend(std::iter::repeat_n(0u8, extra));
+        server.mock(|when, then| {
+            when.method(GET).path(path);
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body(body);
+        });
+    }
+
+    let tmp = tempdir().unwrap();
+    let input = one_post_topic(
+        tmp.path(),
+        "<p><img src=\"/small.png\" alt=\"\"><img src=\"/large.png\" alt=\"\"><img src=\"/small.png\" alt=\"\"></p>",
+    );
+    let out_dir = tmp.path().join("out");
+    let render = || {
+        std::process::Command::new(env!("CARGO_BIN_EXE_discourse-topic-render"))
+            .args(["--input".as_ref(), input.as_os_str()])
+            .args(["--base-url", &server.base_url()])
+            .args(["--builtin-css", "--progress", "never", "--summary", "json"])
+            .arg("--skip-unchanged")
+            .args(["--out".as_ref(), out_dir.as_os_str()])
+            .output()
+            .unwrap()
+    };
+    let output = render();
+    assert!(output.status.success(), "{output:?}");
+
+    // Log lines go to stderr, so stdout is only the summary.
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 1, "{stdout}");
+    let summary: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(summary["posts"], 1);
+    assert_eq!(summary["http_requests"], 2);
+    assert_eq!(summary["http_failed"], 0);
+    assert_eq!(summary["timeouts_by_kind"], serde_json::json!({}));
+    assert_eq!(summary["timeouts_by_host"], serde_json::json!({}));
+    let bytes = 2 * png_bytes().len() as u64 + 4096;
+    assert_eq!(summary["bytes"], bytes);
+    assert_eq!(
+        summary["bytes_by_kind"],
+        serde_json::json!({"image": bytes})
+    );
+    // The prefetch pass and the render pass both ask for each image.
+    assert_eq!(summary["unique_assets"], 2);
+    assert_eq!(
+        summary["asset_references"].as_u64(),
+        Some(2 + summary["cache_hits"].as_u64().unwrap())
+    );
+    // The page and the assets in its manifest; not the manifest, nor what other topics put in the directory.
+    let manifest: Vec<serde_json::Value> = serde_json::from_str(&read_to_string(
+        &out_dir.join("topic-123.assets-manifest.json"),
+    ))
+    .unwrap();
+    let on_disk = |rel: &str| std::fs::metadata(out_dir.join(rel)).unwrap().len();
+    let paths: std::collections::BTreeSet<&str> = manifest
+        .iter()
+        .map(|e| e["path"].as_str().unwrap())
+        .collect();
+    let expected = on_disk("topic-123.html") + paths.iter().map(|p| on_disk(p)).sum::<u64>();
+    assert_eq!(summary["output_bytes"], expected);
+    assert!(expected > bytes);
+    assert_eq!(summary["error"], serde_json::Value::Null);
+    assert_eq!(summary["skipped"], false);
+    let largest = summary["largest_downloads"].as_array().unwrap();
+    assert_eq!(largest.len(), 2);
+    assert_eq!(largest[0]["url"], server.url("/large.png").as_str());
+    assert_eq!(largest[0]["kind"], "image");
+    assert_eq!(largest[0]["bytes"], png_bytes().len() as u64 + 4096);
+    assert_eq!(largest[1]["url"], server.url("/small.png").as_str());
+
+    // An up-to-date output is skipped, and the summary still printed.
+    std::fs::write(out_dir.join("topic-456.html"), vec![b'x'; 10_000]).unwrap();
+    let output = render();
+    assert!(output.status.success(), "{output:?}");
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(summary["skipped"], true);
+    assert_eq!(summary["http_requests"], 0);
+    assert_eq!(summary["output_bytes"], expected);
+}
+
+#[test]
+fn a_post_waiting_on_a_slow_download_tops_the_slowest_posts() {
+    let server = MockServer::start();
+    for (path, delay) in [("/fast.png", 0), ("/slow.png", 1500)] {
+        server.mock(|when, then| {
+            when.method(GET).path(path);
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .delay(std::time::Duration::from_millis(delay))
+                .body(png_bytes());
+        });
+    }
+
+    let tmp = tempdir().unwrap();
+    let input = write_topic(
+        tmp.path(),
+        serde_json::json!([
+            {"post_number": 1, "cooked": "<p><img src=\"/fast.png\" alt=\"\"></p>"},
+            {"post_number": 2, "cooked": "<p><img src=\"/slow.png\" alt=\"\"></p>"}
+        ]),
+    );
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_discourse-topic-render"))
+        .args(["--input".as_ref(), input.as_os_str()])
+        .args(["--base-url", &server.base_url()])
+        .args(["--builtin-css", "--progress", "never", "--summary", "json"])
+        .args(["--slow-post-secs", "1"])
+        .args(["--out".as_ref(), tmp.path().join("out.html").as_os_str()])
+        .env("NO_COLOR", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let slowest = summary["slowest_posts"].as_array().unwrap();
+    assert_eq!(slowest.len(), 2);
+    assert_eq!(slowest[0]["post_number"], 2);
+    assert_eq!(slowest[0]["dominant"], "downloads");
+    assert!(slowest[0]["download_secs"].as_f64().unwrap() >= 1.5);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("slow post") && stderr.contains("post=2"),
+        "{stderr}"
+    );
+    assert!(!stderr.contains("post=1"), "{stderr}");
+}
+
+#[tokio::test]
+async fn meta_sidecar_describes_the_topic_and_its_assets() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path("/a.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("topic.json");
+    std::fs::write(
+        &input,
+        r#"{"id": 123, "title": "T", "slug": "t", "category_id": 5, "tags": ["rust", {"id": 2, "name": "cli", "slug": "cli"}],
+            "posts_count": 2, "views": 42, "closed": true, "created_at": "2024-03-05T07:08:09.000Z",
+            "last_posted_at": "2024-03-06T10:00:00.000Z", "post_stream": {"posts": [
+            {"post_number": 1, "cooked": "<p><img src=\"/a.png\" alt=\"\"></p>"},
+            {"post_number": 2, "cooked": "<p><img src=\"/a.png\" alt=\"\"></p>"}
+        ]}}"#,
+    )
+    .unwrap();
+    let base_url = Url::parse(&server.base_url()).unwrap();
+
+    let out = tmp.path().join("site").join("page.html");
+    let args = discourse_topic_render::CliArgs {
+        mode: discourse_topic_render::Mode::Single,
+        builtin_css: true,
+        reproducible: true,
+        meta_sidecar: true,
+        out: Some(out),
+        ..base_args(&input, &base_url)
+    };
+    discourse_topic_render::run(args).await.unwrap();
+
+    let meta: serde_json::Value = serde_json::from_str(&read_to_string(
+        &tmp.path().join("site/topic-123.meta.json"),
+    ))
+    .unwrap();
+    assert_eq!(
+        meta,
+        serde_json::json!({
+            "id": 123,
+            "title": "T",
+            "slug": "t",
+            "category": 5,
+            "tags": ["rust", "cli"],
+            "posts_count": 2,
+            "views": 42,
+            "created_at": "2024-03-05T07:08:09.000Z",
+            "last_posted_at": "2024-03-06T10:00:00.000Z",
+            "closed": true,
+            "archived": false,
+            "rendered_at": "2024-03-06T10:00:00Z",
+            "tool_version": env!("CARGO_PKG_VERSION"),
+            "user_agent": "test-agent",
+            "output_mode": "single",
+            "asset_count": 1,
+            "asset_bytes": png_bytes().len(),
+        })
+    );
+}
+
+#[test]
+fn progress_json_writes_one_event_per_line_to_stderr() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path("/a.png");
+        then.status(200)
+            .header("Content-Type", "image/png")
+            .body(png_bytes());
+    });
+
+    let tmp = tempdir().unwrap();
+    let input = write_topic(
+        tmp.path(),
+        serde_json::json!([
+            {"post_number": 1, "cooked": "<p><img src=\"/a.png\" alt=\"\"></p>"},
+            {"post_number": 2, "cooked": "<p>No images</p>"}
+        ]),
+    );
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_discourse-topic-render"))
+        .args(["--input".as_ref(), input.as_os_str()])
+        .args(["--base-url", &server.base_url()])
+        .args(["--builtin-css", "--progress", "json", "--summary", "json"])
+        // Only applies to dir mode, so it logs a warning.
+        .args(["--css-integrity", "--mode", "single"])
+        .args(["--out".as_ref(), tmp.path().join("out.html").as_os_str()])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(summary["posts"], 2);
+
+    // Only events, log lines included: no bars, no summary table.
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let events: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|e| panic!("{e}: {line}")))
+        .collect();
+    let of = |name: &str| -> Vec<&serde_json::Value> {
+        events.iter().filter(|e| e["event"] == name).collect()
+    };
+    assert_eq!(
+        *of("stage")[0],
+        serde_json::json!({"event": "stage", "msg": "读取 topic.json"})
+    );
+    assert_eq!(
+        of("posts_total"),
+        [&serde_json::json!({"event": "posts_total", "total": 2})]
+    );
+    let done: Vec<_> = of("post_done")
+        .iter()
+        .map(|e| (e["done"].as_u64().unwrap(), e["total"].as_u64().unwrap()))
+        .collect();
+    assert_eq!(done, [(1, 2), (2, 2)]);
+    assert_eq!(
+        of("http_ok"),
+        [&serde_json::json!({
+            "event": "http_ok",
+            "url": server.url("/a.png"),
+            "bytes": png_bytes().len(),
+            "kind": "image",
+        })]
+    );
+    assert_eq!(events.last().unwrap()["event"], "finish");
+    let warning = of("log")
+        .into_iter()
+        .find(|e| e["level"] == "warn")
+        .unwrap();
+    assert!(
+        warning["msg"]
+            .as_str()
+            .unwrap()
+            .starts_with("--css-integrity only applies to --mode dir"),
+        "{warning}"
+    );
+    assert_eq!(warning["target"], "discourse_topic_render");
+}
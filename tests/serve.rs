@@ -0,0 +1,51 @@
+use std::io::{Read as _, Write as _};
+
+use tempfile::tempdir;
+
+/// Raw GET over a fresh connection; returns (status line, headers + body).
+fn get(addr: std::net::SocketAddr, path: &str) -> (String, String) {
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (status, rest) = response.split_once("\r\n").unwrap();
+    (status.to_string(), rest.to_string())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn serves_topic_and_assets_and_refuses_traversal() {
+    let tmp = tempdir().unwrap();
+    let out = tmp.path().join("out");
+    std::fs::create_dir_all(out.join("assets/css")).unwrap();
+    std::fs::write(out.join("topic-123.html"), "<p>topic</p>").unwrap();
+    std::fs::write(out.join("assets/css/site.css"), "body{}").unwrap();
+    std::fs::write(tmp.path().join("secret.txt"), "secret").unwrap();
+
+    let server = discourse_topic_render::Server::bind(&out, 0).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+
+    let (status, rest) = tokio::task::spawn_blocking(move || get(addr, "/"))
+        .await
+        .unwrap();
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(rest.contains("Content-Type: text/html; charset=utf-8"));
+    assert!(rest.ends_with("<p>topic</p>"));
+
+    let (status, rest) = tokio::task::spawn_blocking(move || get(addr, "/assets/css/site.css"))
+        .await
+        .unwrap();
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(rest.contains("Content-Type: text/css; charset=utf-8"));
+
+    for path in [
+        "/../secret.txt",
+        "/assets/%2e%2e/%2e%2e/../secret.txt",
+        "/missing.png",
+    ] {
+        let (status, _) = tokio::task::spawn_blocking(move || get(addr, path))
+            .await
+            .unwrap();
+        assert_eq!(status, "HTTP/1.1 404 Not Found", "{path}");
+    }
+}
@@ -0,0 +1,103 @@
+use tempfile::tempdir;
+
+#[test]
+fn verify_reports_every_violation_in_pages_and_linked_css() {
+    let tmp = tempdir().unwrap();
+    let out = tmp.path().join("out");
+    std::fs::create_dir_all(out.join("assets/css")).unwrap();
+    let page = out.join("topic-123.html");
+    std::fs::write(
+        &page,
+        r#"<!DOCTYPE html><html><head><link rel="stylesheet" href="assets/css/site.css"></head><body><img src="assets/img/a.png"><img src="https://cdn.example.com/b.png"><p style="background: URL( 'https://cdn.example.com/c.png' )">x</p></body></html>"#,
+    )
+    .unwrap();
+    let css = "/* url(https://ignored.example.com/) */\nbody { background: url(//cdn.example.com/bg.png); }\n";
+    std::fs::write(out.join("assets/css/site.css"), css).unwrap();
+
+    let (report, stylesheets) =
+        discourse_topic_render::verify_files(std::slice::from_ref(&page), false).unwrap();
+    assert_eq!(stylesheets, [out.join("assets/css/site.css")]);
+
+    let found: Vec<_> = report
+        .violations
+        .iter()
+        .map(|v| {
+            (
+                v.page,
+                v.element.as_deref(),
+                v.attribute.as_deref(),
+                v.value.as_str(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        found,
+        [
+            (None, None, None, "//cdn.example.com/bg.png"),
+            (
+                Some(0),
+                Some("img"),
+                Some("src"),
+                "https://cdn.example.com/b.png"
+            ),
+            (
+                Some(0),
+                Some("p"),
+                Some("style"),
+                "https://cdn.example.com/c.png"
+            ),
+        ]
+    );
+    assert_eq!(report.violations[0].offset, css.find("url(//"));
+
+    let html = std::fs::read_to_string(&page).unwrap();
+    let img = &report.violations[1];
+    assert_eq!(
+        &html[img.offset.unwrap()..][..img.value.len()],
+        "https://cdn.example.com/b.png"
+    );
+
+    let err = report.into_result().unwrap_err().to_string();
+    assert!(err.starts_with("strict offline check failed with 3 violation(s):"));
+    assert_eq!(err.lines().count(), 4, "{err}");
+}
+
+#[test]
+fn verify_passes_clean_pages() {
+    let tmp = tempdir().unwrap();
+    let page = tmp.path().join("topic-1.html");
+    std::fs::write(
+        &page,
+        r#"<html><body><img src="data:image/png;base64,AAAA"><script>1</script></body></html>"#,
+    )
+    .unwrap();
+
+    let (report, _) =
+        discourse_topic_render::verify_files(std::slice::from_ref(&page), false).unwrap();
+    assert!(report.violations.is_empty());
+    let (report, _) = discourse_topic_render::verify_files(&[page], true).unwrap();
+    assert_eq!(report.violations.len(), 1);
+    assert_eq!(report.violations[0].element.as_deref(), Some("script"));
+}
+
+#[test]
+fn dangling_anchors_fail_verify_unless_allowed() {
+    use clap::Parser as _;
+
+    let tmp = tempdir().unwrap();
+    let page = tmp.path().join("topic-1.html");
+    std::fs::write(
+        &page,
+        r##"<html><body><article id="post_1"><a href="#post_7">quote</a></article></body></html>"##,
+    )
+    .unwrap();
+    let args = |extra: &[&str]| {
+        let mut argv = vec!["verify", page.to_str().unwrap(), "--no-color"];
+        argv.extend_from_slice(extra);
+        discourse_topic_render::VerifyArgs::parse_from(argv)
+    };
+
+    let err = discourse_topic_render::verify(args(&[])).unwrap_err();
+    assert_eq!(err.to_string(), "1 strict-offline violation(s) found");
+    discourse_topic_render::verify(args(&["--allow-dangling-anchors"])).unwrap();
+}